@@ -0,0 +1,14 @@
+fn main() -> Result<(), Box<dyn std::error::Error>> {
+    #[cfg(feature = "grpc-transport")]
+    {
+        // Messages come from monitoring-common; only the client is generated here
+        tonic_build::configure()
+            .build_server(false)
+            .extern_path(".monitoring", "::monitoring_common::proto::monitoring")
+            .compile(
+                &["../monitoring-common/proto/monitoring.proto"],
+                &["../monitoring-common/proto/"],
+            )?;
+    }
+    Ok(())
+}
@@ -0,0 +1,526 @@
+use anyhow::{Context, Result};
+use monitoring_common::Batch;
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::fs::{self, File, OpenOptions};
+use std::io::{BufWriter, Read, Write};
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use tracing::{debug, info, warn};
+
+const SEGMENT_EXTENSION: &str = "wal";
+const REJECTED_DIR: &str = "rejected";
+
+const RECORD_BATCH: u8 = 1;
+const RECORD_ACK: u8 = 2;
+/// Record kind plus payload length
+const RECORD_HEADER_LEN: u64 = 5;
+
+/// When appended records are flushed to stable storage
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum FsyncPolicy {
+    /// After every record
+    Always,
+    /// At most once per interval
+    Interval(Duration),
+    /// Left to the operating system
+    Never,
+}
+
+impl FsyncPolicy {
+    pub fn parse(policy: &str, interval: Duration) -> Self {
+        match policy {
+            "always" => FsyncPolicy::Always,
+            "never" => FsyncPolicy::Never,
+            _ => FsyncPolicy::Interval(interval),
+        }
+    }
+}
+
+/// Disk queue shared by the transport and the fan-out, which spills batches
+/// to it when the collector falls behind
+pub type SharedDiskQueue = Arc<parking_lot::Mutex<DiskQueue>>;
+
+/// What is known about one segment file
+#[derive(Debug, Default)]
+struct Segment {
+    bytes: u64,
+    /// Batches in this segment not yet acknowledged
+    pending: HashSet<String>,
+}
+
+struct ActiveSegment {
+    id: u64,
+    writer: BufWriter<File>,
+}
+
+/// Write-ahead queue of batches on disk. Batches are appended before they
+/// are sent and stay on disk until acknowledged, so they survive collector
+/// outages and agent restarts.
+///
+/// The queue is a directory of numbered segment files. Each holds batch and
+/// ack records; a segment is deleted once it and every older segment have no
+/// unacknowledged batches left. When `max_bytes` is exceeded the oldest
+/// segments are dropped.
+pub struct DiskQueue {
+    dir: PathBuf,
+    max_bytes: u64,
+    segment_bytes: u64,
+    fsync: FsyncPolicy,
+    segments: BTreeMap<u64, Segment>,
+    /// Segment holding each unacknowledged batch
+    locations: HashMap<String, u64>,
+    /// Failed delivery attempts of pending batches since the queue was opened
+    failures: HashMap<String, u32>,
+    active: ActiveSegment,
+    last_sync: Instant,
+}
+
+impl DiskQueue {
+    /// Open the queue in `dir`, recovering unacknowledged batches left by a
+    /// previous run. New records always go to a fresh segment.
+    pub fn open(dir: impl AsRef<Path>, max_bytes: u64, segment_bytes: u64, fsync: FsyncPolicy) -> Result<Self> {
+        let dir = dir.as_ref().to_path_buf();
+        fs::create_dir_all(&dir)
+            .with_context(|| format!("Failed to create buffer directory {:?}", dir))?;
+
+        let mut segments = BTreeMap::new();
+        let mut locations = HashMap::new();
+        let mut acked = HashSet::new();
+
+        for id in list_segments(&dir)? {
+            let path = segment_path(&dir, id);
+            let mut segment = Segment {
+                bytes: fs::metadata(&path)?.len(),
+                ..Default::default()
+            };
+            for record in read_records(&path)? {
+                match record {
+                    Record::Batch(bytes) => match serde_json::from_slice::<Batch>(&bytes) {
+                        Ok(batch) => {
+                            locations.insert(batch.batch_id.clone(), id);
+                            segment.pending.insert(batch.batch_id);
+                        }
+                        Err(e) => warn!("Skipping unreadable batch in {:?}: {}", path, e),
+                    },
+                    Record::Ack(batch_id) => {
+                        acked.insert(batch_id);
+                    }
+                }
+            }
+            segments.insert(id, segment);
+        }
+
+        for batch_id in acked {
+            if let Some(id) = locations.remove(&batch_id) {
+                if let Some(segment) = segments.get_mut(&id) {
+                    segment.pending.remove(&batch_id);
+                }
+            }
+        }
+
+        let next_id = segments.keys().next_back().map_or(0, |id| id + 1);
+        let active = create_segment(&dir, next_id)?;
+        segments.insert(next_id, Segment::default());
+
+        let mut queue = Self {
+            dir,
+            max_bytes,
+            segment_bytes: segment_bytes.max(1),
+            fsync,
+            segments,
+            locations,
+            failures: HashMap::new(),
+            active,
+            last_sync: Instant::now(),
+        };
+        queue.compact()?;
+
+        if !queue.locations.is_empty() {
+            info!(
+                "Recovered {} unacknowledged batches from {:?}",
+                queue.locations.len(),
+                queue.dir
+            );
+        }
+
+        Ok(queue)
+    }
+
+    /// Persist a batch before it is sent
+    pub fn append(&mut self, batch: &Batch) -> Result<()> {
+        let payload = serde_json::to_vec(batch)?;
+        let record_len = RECORD_HEADER_LEN + payload.len() as u64;
+
+        self.enforce_cap(record_len)?;
+        if self.segments[&self.active.id].bytes + record_len > self.segment_bytes
+            && self.segments[&self.active.id].bytes > 0
+        {
+            self.roll()?;
+        }
+
+        self.write_record(RECORD_BATCH, &payload)?;
+        let segment = self.segments.get_mut(&self.active.id).expect("active segment is tracked");
+        segment.pending.insert(batch.batch_id.clone());
+        self.locations.insert(batch.batch_id.clone(), self.active.id);
+
+        Ok(())
+    }
+
+    /// Mark a batch as delivered, deleting segments that no longer hold
+    /// anything undelivered
+    pub fn ack(&mut self, batch_id: &str) -> Result<()> {
+        self.failures.remove(batch_id);
+        let id = match self.locations.remove(batch_id) {
+            Some(id) => id,
+            None => return Ok(()),
+        };
+
+        if let Some(segment) = self.segments.get_mut(&id) {
+            segment.pending.remove(batch_id);
+        }
+        self.write_record(RECORD_ACK, batch_id.as_bytes())?;
+        self.compact()
+    }
+
+    /// Move a batch the collector refused, or keeps failing to store, into
+    /// the dead-letter directory so it no longer holds up the queue
+    pub fn reject(&mut self, batch: &Batch) -> Result<()> {
+        let rejected = self.dir.join(REJECTED_DIR);
+        fs::create_dir_all(&rejected)?;
+        let path = rejected.join(format!("{}.json", batch.batch_id));
+        fs::write(&path, serde_json::to_vec(batch)?)
+            .with_context(|| format!("Failed to write rejected batch {:?}", path))?;
+        warn!("Batch {} not delivered, kept in {:?}", batch.batch_id, path);
+
+        self.ack(&batch.batch_id)
+    }
+
+    /// Count a failed delivery of a pending batch, returning how many there
+    /// have been
+    pub fn record_failure(&mut self, batch_id: &str) -> u32 {
+        let failures = self.failures.entry(batch_id.to_string()).or_default();
+        *failures += 1;
+        *failures
+    }
+
+    /// Number of batches not yet acknowledged
+    pub fn pending_count(&self) -> usize {
+        self.locations.len()
+    }
+
+    /// Segments holding unacknowledged batches, oldest first
+    pub fn pending_segments(&self) -> Vec<u64> {
+        self.segments
+            .iter()
+            .filter(|(_, segment)| !segment.pending.is_empty())
+            .map(|(id, _)| *id)
+            .collect()
+    }
+
+    /// Read the unacknowledged batches of one segment in append order
+    pub fn read_pending(&mut self, id: u64) -> Result<Vec<Batch>> {
+        if id == self.active.id {
+            self.active.writer.flush()?;
+        }
+
+        let pending = match self.segments.get(&id) {
+            Some(segment) if !segment.pending.is_empty() => &segment.pending,
+            _ => return Ok(Vec::new()),
+        };
+
+        let mut batches = Vec::new();
+        for record in read_records(&segment_path(&self.dir, id))? {
+            if let Record::Batch(bytes) = record {
+                if let Ok(batch) = serde_json::from_slice::<Batch>(&bytes) {
+                    if pending.contains(&batch.batch_id) {
+                        batches.push(batch);
+                    }
+                }
+            }
+        }
+
+        Ok(batches)
+    }
+
+    fn total_bytes(&self) -> u64 {
+        self.segments.values().map(|s| s.bytes).sum()
+    }
+
+    /// Drop the oldest segments until a record of `record_len` fits
+    fn enforce_cap(&mut self, record_len: u64) -> Result<()> {
+        while self.total_bytes() + record_len > self.max_bytes {
+            let oldest = *self.segments.keys().next().expect("active segment is tracked");
+            if oldest == self.active.id {
+                if self.segments[&oldest].bytes == 0 {
+                    // A single record larger than the cap, nothing left to drop
+                    break;
+                }
+                self.roll()?;
+                continue;
+            }
+
+            let segment = self.segments.remove(&oldest).expect("segment exists");
+            if !segment.pending.is_empty() {
+                warn!(
+                    "Disk buffer over {} bytes, dropping {} undelivered batches",
+                    self.max_bytes,
+                    segment.pending.len()
+                );
+            }
+            for batch_id in &segment.pending {
+                self.locations.remove(batch_id);
+            }
+            remove_segment(&self.dir, oldest);
+        }
+        Ok(())
+    }
+
+    /// Delete leading segments that are fully acknowledged. Later segments
+    /// are kept even when empty because their ack records may still refer
+    /// to batches in older ones.
+    fn compact(&mut self) -> Result<()> {
+        while let Some((&id, segment)) = self.segments.iter().next() {
+            if !segment.pending.is_empty() {
+                break;
+            }
+            if id == self.active.id {
+                if segment.bytes < self.segment_bytes {
+                    break;
+                }
+                self.roll()?;
+            }
+            self.segments.remove(&id);
+            remove_segment(&self.dir, id);
+        }
+        Ok(())
+    }
+
+    /// Start a new active segment
+    fn roll(&mut self) -> Result<()> {
+        self.active.writer.flush()?;
+        self.active.writer.get_ref().sync_data()?;
+
+        let id = self.active.id + 1;
+        self.active = create_segment(&self.dir, id)?;
+        self.segments.insert(id, Segment::default());
+        self.last_sync = Instant::now();
+        debug!("Rolled disk buffer to segment {}", id);
+        Ok(())
+    }
+
+    fn write_record(&mut self, kind: u8, payload: &[u8]) -> Result<()> {
+        let writer = &mut self.active.writer;
+        writer.write_all(&[kind])?;
+        writer.write_all(&(payload.len() as u32).to_le_bytes())?;
+        writer.write_all(payload)?;
+
+        if let Some(segment) = self.segments.get_mut(&self.active.id) {
+            segment.bytes += RECORD_HEADER_LEN + payload.len() as u64;
+        }
+
+        let sync = match self.fsync {
+            FsyncPolicy::Always => true,
+            FsyncPolicy::Interval(interval) => self.last_sync.elapsed() >= interval,
+            FsyncPolicy::Never => false,
+        };
+        writer.flush()?;
+        if sync {
+            writer.get_ref().sync_data()?;
+            self.last_sync = Instant::now();
+        }
+
+        Ok(())
+    }
+}
+
+enum Record {
+    Batch(Vec<u8>),
+    Ack(String),
+}
+
+/// Read every complete record of a segment. A torn record at the end, left
+/// by a crash mid-write, ends the segment.
+fn read_records(path: &Path) -> Result<Vec<Record>> {
+    let mut data = Vec::new();
+    File::open(path)
+        .with_context(|| format!("Failed to open segment {:?}", path))?
+        .read_to_end(&mut data)?;
+
+    let mut records = Vec::new();
+    let mut offset = 0;
+    while offset + RECORD_HEADER_LEN as usize <= data.len() {
+        let kind = data[offset];
+        let len = u32::from_le_bytes(data[offset + 1..offset + 5].try_into().expect("4 bytes")) as usize;
+        let start = offset + RECORD_HEADER_LEN as usize;
+        if start + len > data.len() {
+            warn!("Truncated record at offset {} in {:?}", offset, path);
+            break;
+        }
+
+        let payload = data[start..start + len].to_vec();
+        match kind {
+            RECORD_BATCH => records.push(Record::Batch(payload)),
+            RECORD_ACK => records.push(Record::Ack(String::from_utf8_lossy(&payload).into_owned())),
+            _ => {
+                warn!("Unknown record kind {} at offset {} in {:?}", kind, offset, path);
+                break;
+            }
+        }
+        offset = start + len;
+    }
+
+    Ok(records)
+}
+
+fn segment_path(dir: &Path, id: u64) -> PathBuf {
+    dir.join(format!("{:020}.{}", id, SEGMENT_EXTENSION))
+}
+
+fn list_segments(dir: &Path) -> Result<Vec<u64>> {
+    let mut ids: Vec<u64> = fs::read_dir(dir)?
+        .filter_map(|entry| entry.ok())
+        .map(|entry| entry.path())
+        .filter(|path| path.extension().is_some_and(|ext| ext == SEGMENT_EXTENSION))
+        .filter_map(|path| path.file_stem()?.to_str()?.parse().ok())
+        .collect();
+    ids.sort_unstable();
+    Ok(ids)
+}
+
+fn create_segment(dir: &Path, id: u64) -> Result<ActiveSegment> {
+    let path = segment_path(dir, id);
+    let file = OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(&path)
+        .with_context(|| format!("Failed to create segment {:?}", path))?;
+    Ok(ActiveSegment {
+        id,
+        writer: BufWriter::new(file),
+    })
+}
+
+fn remove_segment(dir: &Path, id: u64) {
+    let path = segment_path(dir, id);
+    if let Err(e) = fs::remove_file(&path) {
+        warn!("Failed to remove segment {:?}: {}", path, e);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use monitoring_common::{CompressionType, PayloadEncoding};
+
+    fn temp_dir() -> PathBuf {
+        std::env::temp_dir().join(format!("monitoring-agent-wal-{}", uuid::Uuid::new_v4()))
+    }
+
+    fn batch(id: &str, size: usize) -> Batch {
+        Batch {
+            batch_id: id.to_string(),
+            agent_id: "agent".to_string(),
+            hostname: "host".to_string(),
+            timestamp: 0,
+            event_count: 1,
+            compression: CompressionType::None,
+            encoding: PayloadEncoding::Json,
+            dictionary_id: None,
+            compressed_data: vec![7; size],
+            checksum: String::new(),
+        }
+    }
+
+    fn pending_ids(queue: &mut DiskQueue) -> Vec<String> {
+        let mut ids = Vec::new();
+        for segment in queue.pending_segments() {
+            ids.extend(queue.read_pending(segment).unwrap().into_iter().map(|b| b.batch_id));
+        }
+        ids
+    }
+
+    #[test]
+    fn test_replay_after_restart() {
+        let dir = temp_dir();
+
+        {
+            let mut queue = DiskQueue::open(&dir, 1 << 20, 1 << 16, FsyncPolicy::Always).unwrap();
+            for id in ["a", "b", "c"] {
+                queue.append(&batch(id, 10)).unwrap();
+            }
+            queue.ack("b").unwrap();
+        }
+
+        let mut queue = DiskQueue::open(&dir, 1 << 20, 1 << 16, FsyncPolicy::Always).unwrap();
+        assert_eq!(queue.pending_count(), 2);
+        assert_eq!(pending_ids(&mut queue), vec!["a", "c"]);
+
+        queue.ack("a").unwrap();
+        queue.ack("c").unwrap();
+        assert_eq!(queue.pending_count(), 0);
+        drop(queue);
+
+        let mut queue = DiskQueue::open(&dir, 1 << 20, 1 << 16, FsyncPolicy::Always).unwrap();
+        assert!(pending_ids(&mut queue).is_empty());
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_acknowledged_segments_are_deleted() {
+        let dir = temp_dir();
+        let mut queue = DiskQueue::open(&dir, 1 << 20, 1024, FsyncPolicy::Never).unwrap();
+
+        for i in 0..10 {
+            queue.append(&batch(&i.to_string(), 200)).unwrap();
+        }
+        let before = list_segments(&dir).unwrap().len();
+        assert!(before > 1);
+
+        for i in 0..10 {
+            queue.ack(&i.to_string()).unwrap();
+        }
+        assert!(list_segments(&dir).unwrap().len() < before);
+        assert!(queue.pending_segments().is_empty());
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_size_cap_drops_oldest() {
+        let dir = temp_dir();
+        let mut queue = DiskQueue::open(&dir, 8 * 1024, 1024, FsyncPolicy::Never).unwrap();
+
+        for i in 0..100 {
+            queue.append(&batch(&i.to_string(), 200)).unwrap();
+        }
+
+        assert!(queue.total_bytes() <= 8 * 1024);
+        let ids = pending_ids(&mut queue);
+        assert!(ids.len() < 100);
+        assert_eq!(ids.last().map(String::as_str), Some("99"));
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_truncated_tail_is_ignored() {
+        let dir = temp_dir();
+
+        {
+            let mut queue = DiskQueue::open(&dir, 1 << 20, 1 << 16, FsyncPolicy::Always).unwrap();
+            queue.append(&batch("a", 10)).unwrap();
+        }
+
+        // Simulate a crash halfway through a record
+        let segment = segment_path(&dir, list_segments(&dir).unwrap()[0]);
+        let mut file = OpenOptions::new().append(true).open(&segment).unwrap();
+        file.write_all(&[RECORD_BATCH, 100, 0, 0, 0, b'{']).unwrap();
+
+        let mut queue = DiskQueue::open(&dir, 1 << 20, 1 << 16, FsyncPolicy::Always).unwrap();
+        assert_eq!(pending_ids(&mut queue), vec!["a"]);
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -1,3 +1,8 @@
+mod disk_queue;
 mod ring_buffer;
+mod sizing;
+mod throttle;
 
+pub use disk_queue::{DiskQueue, FsyncPolicy, SharedDiskQueue};
 pub use ring_buffer::RingBuffer;
+pub use throttle::Throttle;
@@ -1,72 +1,471 @@
+use super::sizing::truncate_event;
+use super::throttle::Throttle;
+use crate::config::BufferSettings;
 use crossbeam::queue::ArrayQueue;
-use monitoring_common::{Event, MonitoringError};
-use std::sync::Arc;
+use monitoring_common::{Event, LogLevel, MonitoringError};
+use parking_lot::{Condvar, Mutex};
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+use std::time::{Duration, Instant};
+use tokio::sync::Notify;
+
+/// Longest single wait while blocked on a full buffer. Waits are sliced so a
+/// wakeup that races with the full check costs at most this much.
+const BLOCK_WAIT_SLICE: Duration = Duration::from_millis(10);
+
+/// What happens when an event arrives for a full lane
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum OverflowPolicy {
+    /// Reject the incoming event
+    DropNewest,
+    /// Evict the oldest event in the lane to make room
+    DropOldest,
+    /// Wait up to the timeout for room, then reject
+    Block(Duration),
+}
+
+impl OverflowPolicy {
+    pub fn parse(policy: &str, timeout: Duration) -> Self {
+        match policy {
+            "drop_oldest" => OverflowPolicy::DropOldest,
+            "block" => OverflowPolicy::Block(timeout),
+            _ => OverflowPolicy::DropNewest,
+        }
+    }
+}
+
+/// Queue for one class of events, bounded by count and by estimated size
+struct Lane {
+    name: &'static str,
+    queue: ArrayQueue<Event>,
+    bytes: AtomicUsize,
+    max_bytes: usize,
+    dropped: AtomicU64,
+}
+
+impl Lane {
+    fn new(name: &'static str, capacity: usize, max_bytes: usize) -> Self {
+        Self {
+            name,
+            queue: ArrayQueue::new(capacity.max(1)),
+            bytes: AtomicUsize::new(0),
+            max_bytes,
+            dropped: AtomicU64::new(0),
+        }
+    }
+
+    /// Queue an event of `size` bytes if both bounds allow it. An empty lane
+    /// always takes one event, however large.
+    #[allow(clippy::result_large_err)] // hands the event back, like ArrayQueue::push
+    fn try_push(&self, event: Event, size: usize) -> Result<(), Event> {
+        let previous = self.bytes.fetch_add(size, Ordering::AcqRel);
+        if previous > 0 && previous + size > self.max_bytes {
+            self.bytes.fetch_sub(size, Ordering::AcqRel);
+            return Err(event);
+        }
+
+        self.queue.push(event).inspect_err(|_| {
+            self.bytes.fetch_sub(size, Ordering::AcqRel);
+        })
+    }
+
+    fn pop(&self) -> Option<Event> {
+        let event = self.queue.pop()?;
+        self.bytes.fetch_sub(event.estimated_size(), Ordering::AcqRel);
+        Some(event)
+    }
+}
+
+/// Lane indices for each class of event
+#[derive(Clone, Copy)]
+struct Routing {
+    priority_logs: usize,
+    logs: usize,
+    metrics: usize,
+    traffic: usize,
+}
 
 /// Lock-free ring buffer for event storage
+///
+/// Events are split into lanes with their own capacity, so a flood of one
+/// event type cannot push out the others. Error and critical logs get a
+/// reserved lane and spill into the regular log lane when it is full.
 pub struct RingBuffer {
-    queue: Arc<ArrayQueue<Event>>,
+    lanes: Vec<Lane>,
+    routing: Routing,
+    policy: OverflowPolicy,
+    /// Larger events are truncated before they are queued
+    max_event_bytes: usize,
+    /// Signalled when events are removed, for the blocking policy: the
+    /// condvar wakes producer threads, the notify wakes async producers
+    space: (Mutex<()>, Condvar),
+    space_notify: Notify,
+    /// Rate limit for log events, applied before they take up room
+    throttle: Option<Mutex<Throttle>>,
+}
+
+/// An event waiting for room in a full lane under the blocking policy
+struct Blocked {
+    lane: usize,
+    event: Event,
+    size: usize,
+    deadline: Instant,
 }
 
 impl RingBuffer {
+    /// Single shared lane, bounded by count only, that rejects new events
+    /// when full
     pub fn new(capacity: usize) -> Self {
         Self {
-            queue: Arc::new(ArrayQueue::new(capacity)),
+            lanes: vec![Lane::new("all", capacity, usize::MAX)],
+            routing: Routing {
+                priority_logs: 0,
+                logs: 0,
+                metrics: 0,
+                traffic: 0,
+            },
+            policy: OverflowPolicy::DropNewest,
+            max_event_bytes: usize::MAX,
+            space: (Mutex::new(()), Condvar::new()),
+            space_notify: Notify::new(),
+            throttle: None,
+        }
+    }
+
+    /// Lanes sized by the configured quotas, sharing `max_events` and
+    /// `max_bytes`
+    pub fn with_settings(settings: &BufferSettings) -> Self {
+        let quotas = &settings.quotas;
+        let shares = [
+            quotas.priority_logs,
+            quotas.logs,
+            quotas.metrics,
+            quotas.traffic,
+        ];
+        let total: f64 = shares.iter().map(|s| s.max(0.0)).sum();
+        let portion = |limit: usize, share: f64| {
+            if total > 0.0 {
+                (limit as f64 * share.max(0.0) / total) as usize
+            } else {
+                limit / shares.len()
+            }
+        };
+        let lane = |name, share| {
+            Lane::new(
+                name,
+                portion(settings.max_events, share),
+                portion(settings.max_bytes, share),
+            )
+        };
+
+        Self {
+            lanes: vec![
+                lane("priority_logs", quotas.priority_logs),
+                lane("logs", quotas.logs),
+                lane("metrics", quotas.metrics),
+                lane("traffic", quotas.traffic),
+            ],
+            routing: Routing {
+                priority_logs: 0,
+                logs: 1,
+                metrics: 2,
+                traffic: 3,
+            },
+            policy: OverflowPolicy::parse(
+                &settings.overflow,
+                Duration::from_millis(settings.block_timeout_ms),
+            ),
+            max_event_bytes: settings.max_event_bytes,
+            space: (Mutex::new(()), Condvar::new()),
+            space_notify: Notify::new(),
+            throttle: None,
+        }
+    }
+
+    /// Throttle log events as they arrive, so a flood from one source is
+    /// suppressed before it can crowd out other events
+    pub fn with_log_throttle(mut self, throttle: Throttle) -> Self {
+        self.throttle = Some(Mutex::new(throttle));
+        self
+    }
+
+    fn route(&self, event: &Event) -> usize {
+        match event {
+            Event::Log(log) if matches!(log.level, LogLevel::Error | LogLevel::Critical) => {
+                self.routing.priority_logs
+            }
+            Event::Log(_) => self.routing.logs,
+            Event::Metric(_) => self.routing.metrics,
+            Event::Traffic(_) => self.routing.traffic,
         }
     }
 
-    /// Push an event into the buffer
-    /// Returns error if buffer is full
+    /// Push an event into the buffer, truncating it if it is over the
+    /// per-event size limit
+    ///
+    /// Returns error if the event's lane is full and the overflow policy
+    /// does not make room
+    ///
+    /// With the blocking policy this parks the calling thread, so it is for
+    /// producers on their own threads; async producers use `push_async`.
     pub fn push(&self, event: Event) -> Result<(), MonitoringError> {
-        self.queue
-            .push(event)
-            .map_err(|_| MonitoringError::BufferOverflow)
+        let mut blocked = match self.admit(event)? {
+            Some(blocked) => blocked,
+            None => return Ok(()),
+        };
+
+        loop {
+            let now = Instant::now();
+            if now >= blocked.deadline {
+                return Err(self.give_up(&blocked));
+            }
+
+            let mut guard = self.space.0.lock();
+            self.space.1.wait_for(&mut guard, (blocked.deadline - now).min(BLOCK_WAIT_SLICE));
+            drop(guard);
+
+            blocked.event = match self.lanes[blocked.lane].try_push(blocked.event, blocked.size) {
+                Ok(()) => return Ok(()),
+                Err(event) => event,
+            };
+        }
     }
 
-    /// Pop an event from the buffer
+    /// Like `push`, but waits for room without blocking the runtime thread
+    pub async fn push_async(&self, event: Event) -> Result<(), MonitoringError> {
+        let mut blocked = match self.admit(event)? {
+            Some(blocked) => blocked,
+            None => return Ok(()),
+        };
+
+        loop {
+            // Registered before the retry so a drain in between is not missed
+            let notified = self.space_notify.notified();
+            tokio::pin!(notified);
+            notified.as_mut().enable();
+
+            blocked.event = match self.lanes[blocked.lane].try_push(blocked.event, blocked.size) {
+                Ok(()) => return Ok(()),
+                Err(event) => event,
+            };
+
+            let deadline = tokio::time::Instant::from_std(blocked.deadline);
+            if tokio::time::timeout_at(deadline, notified).await.is_err() {
+                return Err(self.give_up(&blocked));
+            }
+        }
+    }
+
+    /// Throttle, then queue the event or apply the overflow policy. Returns
+    /// the event when the policy is to wait for room.
+    fn admit(&self, event: Event) -> Result<Option<Blocked>, MonitoringError> {
+        if let (Some(throttle), Event::Log(log)) = (&self.throttle, &event) {
+            if !throttle.lock().admit(log) {
+                return Ok(None);
+            }
+        }
+        self.enqueue(event)
+    }
+
+    fn enqueue(&self, mut event: Event) -> Result<Option<Blocked>, MonitoringError> {
+        truncate_event(&mut event, self.max_event_bytes);
+        let size = event.estimated_size();
+
+        let mut lane = self.route(&event);
+        let mut event = match self.lanes[lane].try_push(event, size) {
+            Ok(()) => return Ok(None),
+            Err(event) => event,
+        };
+
+        // Priority logs borrow room from the regular log lane before anything is dropped
+        if lane == self.routing.priority_logs && lane != self.routing.logs {
+            lane = self.routing.logs;
+            event = match self.lanes[lane].try_push(event, size) {
+                Ok(()) => return Ok(None),
+                Err(event) => event,
+            };
+        }
+
+        match self.policy {
+            OverflowPolicy::DropNewest => {
+                self.lanes[lane].dropped.fetch_add(1, Ordering::Relaxed);
+                Err(MonitoringError::BufferOverflow)
+            }
+            OverflowPolicy::DropOldest => loop {
+                if self.lanes[lane].pop().is_some() {
+                    self.lanes[lane].dropped.fetch_add(1, Ordering::Relaxed);
+                }
+                event = match self.lanes[lane].try_push(event, size) {
+                    Ok(()) => return Ok(None),
+                    Err(event) => event,
+                };
+            },
+            OverflowPolicy::Block(timeout) => Ok(Some(Blocked {
+                lane,
+                event,
+                size,
+                deadline: Instant::now() + timeout,
+            })),
+        }
+    }
+
+    /// Queue the "message repeated N times" summaries that are due. They
+    /// never wait for room, so the caller is not held up.
+    pub fn flush_throttled(&self, now: i64) {
+        let Some(throttle) = &self.throttle else {
+            return;
+        };
+
+        let mut summaries = Vec::new();
+        throttle.lock().emit(now, &mut summaries);
+        for summary in summaries {
+            if let Ok(Some(blocked)) = self.enqueue(summary) {
+                self.give_up(&blocked);
+            }
+        }
+    }
+
+    fn give_up(&self, blocked: &Blocked) -> MonitoringError {
+        self.lanes[blocked.lane].dropped.fetch_add(1, Ordering::Relaxed);
+        MonitoringError::BufferOverflow
+    }
+
+    /// Pop an event from the buffer, taking priority logs first
     pub fn pop(&self) -> Option<Event> {
-        self.queue.pop()
+        let event = self.lanes.iter().find_map(Lane::pop);
+        if event.is_some() {
+            self.notify_space();
+        }
+        event
     }
 
-    /// Drain up to N events from the buffer
+    /// Drain up to N events from the buffer, taking from each lane in turn
     pub fn drain(&self, max_count: usize) -> Vec<Event> {
+        self.drain_batch(max_count, usize::MAX)
+    }
+
+    /// Drain up to `max_count` events, stopping once their estimated size
+    /// reaches `max_bytes`
+    pub fn drain_batch(&self, max_count: usize, max_bytes: usize) -> Vec<Event> {
         let mut events = Vec::with_capacity(max_count.min(self.len()));
-        
-        for _ in 0..max_count {
-            match self.pop() {
-                Some(event) => events.push(event),
-                None => break,
+        let mut bytes = 0;
+
+        'drain: while events.len() < max_count {
+            let before = events.len();
+            for lane in &self.lanes {
+                if events.len() >= max_count || bytes >= max_bytes {
+                    break 'drain;
+                }
+                if let Some(event) = lane.pop() {
+                    bytes += event.estimated_size();
+                    events.push(event);
+                }
+            }
+            if events.len() == before {
+                break;
             }
         }
 
+        if !events.is_empty() {
+            self.notify_space();
+        }
         events
     }
 
+    fn notify_space(&self) {
+        if matches!(self.policy, OverflowPolicy::Block(_)) {
+            self.space.1.notify_all();
+            self.space_notify.notify_waiters();
+        }
+    }
+
+    /// Events dropped so far, per lane
+    pub fn dropped(&self) -> Vec<(&'static str, u64)> {
+        self.lanes
+            .iter()
+            .map(|lane| (lane.name, lane.dropped.load(Ordering::Relaxed)))
+            .collect()
+    }
+
+    /// Estimated size of the buffered events
+    pub fn bytes(&self) -> usize {
+        self.lanes.iter().map(|lane| lane.bytes.load(Ordering::Acquire)).sum()
+    }
+
     /// Get current buffer length
     pub fn len(&self) -> usize {
-        self.queue.len()
+        self.lanes.iter().map(|lane| lane.queue.len()).sum()
     }
 
     /// Check if buffer is empty
     pub fn is_empty(&self) -> bool {
-        self.queue.is_empty()
+        self.lanes.iter().all(|lane| lane.queue.is_empty())
     }
 
     /// Check if buffer is full
     pub fn is_full(&self) -> bool {
-        self.queue.is_full()
+        self.lanes.iter().all(|lane| lane.queue.is_full())
     }
 
     /// Get buffer capacity
     pub fn capacity(&self) -> usize {
-        self.queue.capacity()
+        self.lanes.iter().map(|lane| lane.queue.capacity()).sum()
     }
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
-    use monitoring_common::{LogEvent, LogLevel};
+    use crate::config::{BufferQuotas, LogThrottleConfig};
+    use monitoring_common::{LogEvent, LogLevel, MetricEvent, MetricType};
     use std::collections::HashMap;
+    use std::sync::Arc;
+
+    fn log(level: LogLevel, timestamp: i64) -> Event {
+        Event::Log(LogEvent {
+            timestamp,
+            source: "test".to_string(),
+            level,
+            message: "test".to_string(),
+            fields: HashMap::new(),
+            tags: vec![],
+        })
+    }
+
+    fn metric(timestamp: i64) -> Event {
+        Event::Metric(MetricEvent {
+            timestamp,
+            name: "test".to_string(),
+            value: 1.0,
+            metric_type: MetricType::Gauge,
+            tags: HashMap::new(),
+            unit: None,
+        })
+    }
+
+    fn settings(overflow: &str) -> BufferSettings {
+        BufferSettings {
+            max_events: 40,
+            max_bytes: 1 << 20,
+            max_event_bytes: 1 << 16,
+            flush_interval_secs: 60,
+            max_batch_size: 1000,
+            max_batch_bytes: 1 << 20,
+            max_event_age_ms: 5000,
+            compression: "none".to_string(),
+            compression_level: 3,
+            compression_dictionary: false,
+            encoding: "json".to_string(),
+            disk: Default::default(),
+            overflow: overflow.to_string(),
+            block_timeout_ms: 50,
+            quotas: BufferQuotas {
+                priority_logs: 0.25,
+                logs: 0.25,
+                metrics: 0.25,
+                traffic: 0.25,
+            },
+        }
+    }
 
     #[test]
     fn test_ring_buffer() {
@@ -131,4 +530,135 @@ mod tests {
         assert_eq!(events.len(), 3);
         assert_eq!(buffer.len(), 2);
     }
+
+    #[test]
+    fn test_metric_flood_does_not_starve_error_logs() {
+        let buffer = RingBuffer::with_settings(&settings("drop_newest"));
+
+        for i in 0..100 {
+            let _ = buffer.push(metric(i));
+        }
+        for i in 0..20 {
+            buffer.push(log(LogLevel::Error, i)).unwrap();
+        }
+        // Both log lanes are now full
+        assert!(buffer.push(log(LogLevel::Critical, 0)).is_err());
+
+        let dropped: HashMap<_, _> = buffer.dropped().into_iter().collect();
+        assert_eq!(dropped["metrics"], 90);
+        assert_eq!(dropped["logs"], 1);
+    }
+
+    #[test]
+    fn test_drop_oldest_keeps_latest() {
+        let buffer = RingBuffer::with_settings(&settings("drop_oldest"));
+
+        for i in 0..25 {
+            buffer.push(metric(i)).unwrap();
+        }
+
+        let timestamps: Vec<i64> = buffer.drain(100).iter().map(Event::timestamp).collect();
+        assert_eq!(timestamps, (15..25).collect::<Vec<_>>());
+    }
+
+    #[test]
+    fn test_block_waits_for_room() {
+        let buffer = Arc::new(RingBuffer::with_settings(&settings("block")));
+        for i in 0..10 {
+            buffer.push(metric(i)).unwrap();
+        }
+
+        // Times out while nothing is drained
+        let started = Instant::now();
+        assert!(buffer.push(metric(10)).is_err());
+        assert!(started.elapsed() >= Duration::from_millis(50));
+
+        let consumer = {
+            let buffer = buffer.clone();
+            std::thread::spawn(move || {
+                std::thread::sleep(Duration::from_millis(10));
+                buffer.drain(1)
+            })
+        };
+        buffer.push(metric(11)).unwrap();
+        assert_eq!(consumer.join().unwrap().len(), 1);
+    }
+
+    #[tokio::test(flavor = "current_thread")]
+    async fn test_async_block_leaves_runtime_free() {
+        let buffer = Arc::new(RingBuffer::with_settings(&settings("block")));
+        for i in 0..10 {
+            buffer.push_async(metric(i)).await.unwrap();
+        }
+
+        // The only runtime thread must stay free to run the consumer
+        let consumer = {
+            let buffer = buffer.clone();
+            tokio::spawn(async move {
+                tokio::time::sleep(Duration::from_millis(10)).await;
+                buffer.drain(1)
+            })
+        };
+        buffer.push_async(metric(11)).await.unwrap();
+        assert_eq!(consumer.await.unwrap().len(), 1);
+
+        assert!(buffer.push_async(metric(12)).await.is_err());
+        let dropped: HashMap<_, _> = buffer.dropped().into_iter().collect();
+        assert_eq!(dropped["metrics"], 1);
+    }
+
+    #[test]
+    fn test_log_flood_throttled_before_buffering() {
+        let throttle = Throttle::new(&LogThrottleConfig {
+            key: "source".to_string(),
+            rate: 1.0,
+            burst: 2,
+            summary_interval_secs: 10,
+        })
+        .unwrap();
+        let buffer = RingBuffer::with_settings(&settings("drop_newest")).with_log_throttle(throttle);
+
+        // A crash loop far beyond the log lane's capacity drops nothing
+        for i in 0..1000 {
+            buffer.push(log(LogLevel::Info, i)).unwrap();
+        }
+        buffer.push(metric(0)).unwrap();
+        assert_eq!(buffer.len(), 3);
+        assert!(buffer.dropped().iter().all(|(_, dropped)| *dropped == 0));
+
+        buffer.flush_throttled(20_000);
+        let summary = buffer.drain(10).into_iter().find_map(|event| match event {
+            Event::Log(log) if log.fields.contains_key("repeated") => Some(log),
+            _ => None,
+        });
+        assert_eq!(summary.unwrap().fields["repeated"], "998");
+    }
+
+    #[test]
+    fn test_bounded_by_bytes() {
+        let mut settings = settings("drop_newest");
+        settings.max_bytes = 4 * 4096;
+        settings.max_event_bytes = 2048;
+        let buffer = RingBuffer::with_settings(&settings);
+
+        // Each log is truncated to ~2KB, so a 4KB lane holds two of them
+        for i in 0..2 {
+            buffer.push(log(LogLevel::Info, i)).unwrap();
+            let mut big = log(LogLevel::Info, i);
+            if let Event::Log(ref mut log) = big {
+                log.message = "x".repeat(100_000);
+            }
+            let _ = buffer.push(big);
+        }
+
+        let events = buffer.drain(100);
+        assert_eq!(events.len(), 3);
+        assert!(events.iter().all(|e| e.estimated_size() <= 2048));
+        let truncated = events
+            .iter()
+            .filter(|e| matches!(e, Event::Log(log) if log.tags.iter().any(|t| t == "truncated")))
+            .count();
+        assert_eq!(truncated, 1);
+        assert_eq!(buffer.lanes[1].bytes.load(Ordering::Relaxed), 0);
+    }
 }
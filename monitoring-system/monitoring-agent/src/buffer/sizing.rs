@@ -0,0 +1,119 @@
+use monitoring_common::Event;
+
+/// Tag (or tag key) marking an event whose content was cut to fit
+pub const TRUNCATED_TAG: &str = "truncated";
+
+/// Cut an event down to roughly `max_bytes` of estimated size, shortening its
+/// longest strings first. Returns whether anything was truncated.
+pub fn truncate_event(event: &mut Event, max_bytes: usize) -> bool {
+    if event.estimated_size() <= max_bytes {
+        return false;
+    }
+
+    // Mark first so the marker itself is accounted for
+    match event {
+        Event::Log(log) => log.tags.push(TRUNCATED_TAG.to_string()),
+        Event::Metric(metric) => {
+            metric.tags.insert(TRUNCATED_TAG.to_string(), "true".to_string());
+        }
+        Event::Traffic(traffic) => {
+            traffic.metadata.insert(TRUNCATED_TAG.to_string(), "true".to_string());
+        }
+    }
+
+    loop {
+        let excess = event.estimated_size().saturating_sub(max_bytes);
+        if excess == 0 {
+            break;
+        }
+
+        let longest = match truncatable_strings(event).into_iter().max_by_key(|s| s.len()) {
+            Some(longest) if !longest.is_empty() => longest,
+            _ => break,
+        };
+        let keep = floor_char_boundary(longest, longest.len().saturating_sub(excess));
+        longest.truncate(keep);
+    }
+
+    true
+}
+
+/// Free-form content that can be shortened without changing what the event is
+fn truncatable_strings(event: &mut Event) -> Vec<&mut String> {
+    match event {
+        Event::Log(log) => {
+            let mut strings: Vec<&mut String> = log.fields.values_mut().collect();
+            strings.push(&mut log.message);
+            strings
+        }
+        Event::Metric(metric) => metric
+            .tags
+            .iter_mut()
+            .filter(|(key, _)| key.as_str() != TRUNCATED_TAG)
+            .map(|(_, value)| value)
+            .collect(),
+        Event::Traffic(traffic) => traffic
+            .metadata
+            .iter_mut()
+            .filter(|(key, _)| key.as_str() != TRUNCATED_TAG)
+            .map(|(_, value)| value)
+            .collect(),
+    }
+}
+
+/// Largest index not above `index` that falls on a UTF-8 boundary
+fn floor_char_boundary(s: &str, index: usize) -> usize {
+    let mut index = index.min(s.len());
+    while !s.is_char_boundary(index) {
+        index -= 1;
+    }
+    index
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use monitoring_common::{LogEvent, LogLevel};
+    use std::collections::HashMap;
+
+    fn log(message: String, fields: HashMap<String, String>) -> Event {
+        Event::Log(LogEvent {
+            timestamp: 0,
+            source: "test".to_string(),
+            level: LogLevel::Info,
+            message,
+            fields,
+            tags: vec![],
+        })
+    }
+
+    #[test]
+    fn test_small_event_untouched() {
+        let mut event = log("hello".to_string(), HashMap::new());
+        assert!(!truncate_event(&mut event, 1024));
+        match event {
+            Event::Log(log) => assert!(log.tags.is_empty()),
+            _ => unreachable!(),
+        }
+    }
+
+    #[test]
+    fn test_truncates_longest_content_on_char_boundary() {
+        let mut fields = HashMap::new();
+        fields.insert("stack".to_string(), "x".repeat(300));
+        let mut event = log("é".repeat(50_000), fields);
+
+        assert!(truncate_event(&mut event, 1024));
+        assert!(event.estimated_size() <= 1024);
+
+        match event {
+            Event::Log(log) => {
+                assert_eq!(log.tags, vec![TRUNCATED_TAG]);
+                // Shorter content is left alone while the message can absorb the cut
+                assert_eq!(log.fields["stack"].len(), 300);
+                assert!(log.message.chars().all(|c| c == 'é'));
+            }
+            _ => unreachable!(),
+        }
+    }
+}
@@ -0,0 +1,233 @@
+use crate::config::LogThrottleConfig;
+use anyhow::Result;
+use monitoring_common::{Event, LogEvent};
+use std::collections::hash_map::DefaultHasher;
+use std::collections::HashMap;
+use std::hash::{Hash, Hasher};
+
+enum Key {
+    Source,
+    /// Source, level and message with digits masked, so lines differing
+    /// only in ids or counters are treated as repeats
+    Fingerprint,
+}
+
+struct Bucket {
+    tokens: f64,
+    refilled: i64,
+    /// First suppressed event since the last summary
+    suppressed: Option<Suppressed>,
+}
+
+struct Suppressed {
+    event: LogEvent,
+    count: u64,
+    first: i64,
+    last: i64,
+}
+
+/// Rate limit log events per source or message fingerprint with a token
+/// bucket, replacing what is dropped by a periodic "repeated N times" summary.
+/// Buckets refill by event time, so a backlog read at once is limited the
+/// same way as lines arriving live.
+pub struct Throttle {
+    key: Key,
+    rate: f64,
+    burst: f64,
+    summary_ms: i64,
+    buckets: HashMap<u64, Bucket>,
+    last_summary: i64,
+}
+
+impl Throttle {
+    pub fn new(config: &LogThrottleConfig) -> Result<Self> {
+        let key = match config.key.as_str() {
+            "source" => Key::Source,
+            "fingerprint" => Key::Fingerprint,
+            other => anyhow::bail!("Unknown throttle key {:?}", other),
+        };
+        if config.rate <= 0.0 {
+            anyhow::bail!("rate must be positive");
+        }
+
+        Ok(Self {
+            key,
+            rate: config.rate,
+            burst: config.burst.max(1) as f64,
+            summary_ms: config.summary_interval_secs as i64 * 1000,
+            buckets: HashMap::new(),
+            last_summary: 0,
+        })
+    }
+
+    fn key(&self, log: &LogEvent) -> u64 {
+        let mut hasher = DefaultHasher::new();
+        log.source.hash(&mut hasher);
+        if let Key::Fingerprint = self.key {
+            std::mem::discriminant(&log.level).hash(&mut hasher);
+            let mut digits = false;
+            for c in log.message.chars() {
+                // A run of digits hashes as a single placeholder
+                if c.is_ascii_digit() {
+                    if !digits {
+                        '#'.hash(&mut hasher);
+                    }
+                    digits = true;
+                } else {
+                    c.hash(&mut hasher);
+                    digits = false;
+                }
+            }
+        }
+        hasher.finish()
+    }
+
+    /// Whether the log may pass; otherwise it is counted for the summary
+    pub fn admit(&mut self, log: &LogEvent) -> bool {
+        let now = log.timestamp;
+        let (rate, burst) = (self.rate, self.burst);
+        let bucket = self.buckets.entry(self.key(log)).or_insert(Bucket {
+            tokens: burst,
+            refilled: now,
+            suppressed: None,
+        });
+
+        // Out-of-order timestamps must not refill the same interval twice
+        let elapsed = (now - bucket.refilled).max(0) as f64 / 1000.0;
+        bucket.tokens = (bucket.tokens + elapsed * rate).min(burst);
+        bucket.refilled = bucket.refilled.max(now);
+        if bucket.tokens >= 1.0 {
+            bucket.tokens -= 1.0;
+            return true;
+        }
+
+        match &mut bucket.suppressed {
+            Some(suppressed) => {
+                suppressed.count += 1;
+                suppressed.first = suppressed.first.min(log.timestamp);
+                suppressed.last = suppressed.last.max(log.timestamp);
+            }
+            None => {
+                bucket.suppressed = Some(Suppressed {
+                    event: log.clone(),
+                    count: 1,
+                    first: log.timestamp,
+                    last: log.timestamp,
+                })
+            }
+        }
+        false
+    }
+
+    fn summarize(&mut self, now: i64, out: &mut Vec<Event>) {
+        let (rate, burst) = (self.rate, self.burst);
+        self.buckets.retain(|_, bucket| {
+            if let Some(suppressed) = bucket.suppressed.take() {
+                let mut event = suppressed.event;
+                event.timestamp = suppressed.last;
+                event.message = format!("message repeated {} times: {}", suppressed.count, event.message);
+                event
+                    .fields
+                    .insert("repeated".to_string(), suppressed.count.to_string());
+                event
+                    .fields
+                    .insert("first_timestamp".to_string(), suppressed.first.to_string());
+                event
+                    .fields
+                    .insert("last_timestamp".to_string(), suppressed.last.to_string());
+                out.push(Event::Log(event));
+            }
+
+            // Forget keys whose bucket would be full again
+            let elapsed = (now - bucket.refilled).max(0) as f64 / 1000.0;
+            bucket.tokens + elapsed * rate < burst
+        });
+    }
+
+    /// Report suppressed messages once per summary interval
+    pub fn emit(&mut self, now: i64, out: &mut Vec<Event>) {
+        if now - self.last_summary < self.summary_ms {
+            return;
+        }
+        self.last_summary = now;
+        self.summarize(now, out);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use monitoring_common::LogLevel;
+
+    fn log(source: &str, message: &str, timestamp: i64) -> LogEvent {
+        LogEvent {
+            timestamp,
+            source: source.to_string(),
+            level: LogLevel::Error,
+            message: message.to_string(),
+            fields: HashMap::new(),
+            tags: vec![],
+        }
+    }
+
+    fn throttle(key: &str, rate: f64, burst: u32) -> Throttle {
+        Throttle::new(&LogThrottleConfig {
+            key: key.to_string(),
+            rate,
+            burst,
+            summary_interval_secs: 10,
+        })
+        .unwrap()
+    }
+
+    #[test]
+    fn test_burst_then_rate() {
+        let mut throttle = throttle("fingerprint", 2.0, 3);
+
+        let admitted = (0..10)
+            .filter(|i| throttle.admit(&log("app", &format!("request {} failed", i), *i)))
+            .count();
+        assert_eq!(admitted, 3);
+
+        // Half a second of event time refills one token at 2/s
+        assert!(throttle.admit(&log("app", "request 11 failed", 509)));
+        assert!(!throttle.admit(&log("app", "request 12 failed", 510)));
+        // Late lines do not refill the bucket again
+        assert!(!throttle.admit(&log("app", "request 13 failed", 5)));
+        // A different message has its own bucket
+        assert!(throttle.admit(&log("app", "disk full", 511)));
+
+        let mut out = Vec::new();
+        throttle.summarize(510, &mut out);
+        assert_eq!(out.len(), 1);
+        match &out[0] {
+            Event::Log(log) => {
+                assert_eq!(log.message, "message repeated 9 times: request 3 failed");
+                assert_eq!(log.fields["repeated"], "9");
+                assert_eq!(log.fields["first_timestamp"], "3");
+                assert_eq!(log.fields["last_timestamp"], "510");
+                assert_eq!(log.timestamp, 510);
+            }
+            _ => unreachable!(),
+        }
+    }
+
+    #[test]
+    fn test_source_key_and_idle_eviction() {
+        let mut throttle = throttle("source", 1.0, 1);
+        assert!(throttle.admit(&log("a", "one", 0)));
+        assert!(!throttle.admit(&log("a", "two", 0)));
+        assert!(throttle.admit(&log("b", "one", 0)));
+
+        let mut out = Vec::new();
+        throttle.summarize(10_000, &mut out);
+        assert_eq!(out.len(), 1);
+        assert!(throttle.buckets.is_empty());
+
+        let invalid = LogThrottleConfig {
+            key: "level".to_string(),
+            ..Default::default()
+        };
+        assert!(Throttle::new(&invalid).is_err());
+    }
+}
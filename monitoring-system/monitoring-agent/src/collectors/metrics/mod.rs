@@ -3,6 +3,7 @@ pub mod prometheus;
 
 use crate::config::MetricsCollectorConfig;
 use crate::buffer::RingBuffer;
+use crate::proxy::ProxyRules;
 use anyhow::Result;
 use std::sync::Arc;
 use tracing::{error, info};
@@ -40,10 +41,13 @@ impl MetricsCollector {
             info!("Starting Prometheus scrapers for {} endpoints",
                 self.config.prometheus_endpoints.len());
             
+            // One client, so scrapers share its connection pool
+            let client = ProxyRules::from_settings(&self.config.proxy)?.reqwest_client()?;
             for endpoint in self.config.prometheus_endpoints.clone() {
                 let scraper = prometheus::PrometheusScaper::new(
                     endpoint,
                     self.buffer.clone(),
+                    client.clone(),
                 );
                 let handle = tokio::spawn(async move {
                     if let Err(e) = scraper.run().await {
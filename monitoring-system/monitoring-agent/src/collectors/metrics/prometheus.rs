@@ -12,11 +12,11 @@ pub struct PrometheusScaper {
 }
 
 impl PrometheusScaper {
-    pub fn new(endpoint: String, buffer: Arc<RingBuffer>) -> Self {
+    pub fn new(endpoint: String, buffer: Arc<RingBuffer>, client: reqwest::Client) -> Self {
         Self {
             endpoint,
             buffer,
-            client: reqwest::Client::new(),
+            client,
         }
     }
 
@@ -46,13 +46,18 @@ impl PrometheusScaper {
         let timestamp = chrono::Utc::now().timestamp_millis();
 
         // Parse Prometheus text format
-        self.parse_prometheus_metrics(&text, timestamp)?;
+        for event in self.parse_prometheus_metrics(&text, timestamp)? {
+            if let Err(e) = self.buffer.push_async(event).await {
+                warn!("Buffer full, dropping Prometheus metric: {}", e);
+            }
+        }
 
         Ok(())
     }
 
-    fn parse_prometheus_metrics(&self, text: &str, timestamp: i64) -> Result<()> {
+    fn parse_prometheus_metrics(&self, text: &str, timestamp: i64) -> Result<Vec<Event>> {
         let lines = prometheus_parse::Scrape::parse(text.lines().map(|s| Ok(s.to_owned())))?;
+        let mut events = Vec::with_capacity(lines.samples.len());
 
         for sample in lines.samples {
             let mut tags = HashMap::new();
@@ -72,20 +77,16 @@ impl PrometheusScaper {
                 _ => MetricType::Gauge,
             };
 
-            let event = Event::Metric(MetricEvent {
+            events.push(Event::Metric(MetricEvent {
                 timestamp,
                 name: format!("prometheus.{}", sample.metric),
                 value: sample.value,
                 metric_type,
                 tags,
                 unit: None,
-            });
-
-            if let Err(e) = self.buffer.push(event) {
-                warn!("Buffer full, dropping Prometheus metric: {}", e);
-            }
+            }));
         }
 
-        Ok(())
+        Ok(events)
     }
 }
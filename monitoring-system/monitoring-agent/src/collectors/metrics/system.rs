@@ -1,6 +1,7 @@
 use crate::buffer::RingBuffer;
 use anyhow::Result;
 use monitoring_common::{Event, MetricEvent, MetricType};
+use std::cell::RefCell;
 use std::collections::HashMap;
 use std::sync::Arc;
 use sysinfo::{System, SystemExt, CpuExt, DiskExt, NetworkExt, ProcessExt};
@@ -11,6 +12,8 @@ pub struct SystemMetrics {
     include_process_metrics: bool,
     buffer: Arc<RingBuffer>,
     sys: System,
+    /// Metrics from the current collection, pushed once it is done
+    pending: RefCell<Vec<Event>>,
 }
 
 impl SystemMetrics {
@@ -24,6 +27,7 @@ impl SystemMetrics {
             include_process_metrics,
             buffer,
             sys: System::new_all(),
+            pending: RefCell::new(Vec::new()),
         }
     }
 
@@ -54,6 +58,12 @@ impl SystemMetrics {
             if self.include_process_metrics {
                 self.collect_process_metrics();
             }
+
+            for event in self.pending.get_mut().drain(..) {
+                if let Err(e) = self.buffer.push_async(event).await {
+                    warn!("Buffer full, dropping metric: {}", e);
+                }
+            }
         }
     }
 
@@ -344,9 +354,6 @@ impl SystemMetrics {
     }
 
     fn emit_metric(&self, metric: MetricEvent) {
-        let event = Event::Metric(metric);
-        if let Err(e) = self.buffer.push(event) {
-            warn!("Buffer full, dropping metric: {}", e);
-        }
+        self.pending.borrow_mut().push(Event::Metric(metric));
     }
 }
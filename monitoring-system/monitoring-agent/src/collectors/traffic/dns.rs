@@ -0,0 +1,483 @@
+use super::flow::FlowKey;
+use monitoring_common::{MetricEvent, MetricType, Protocol, TrafficEvent};
+use std::collections::HashMap;
+use std::net::{Ipv4Addr, Ipv6Addr, SocketAddr};
+
+/// Well-known DNS port
+pub const DNS_PORT: u16 = 53;
+/// Queries without a response after this long are counted as timeouts
+const QUERY_TIMEOUT_MS: i64 = 10_000;
+/// Maximum number of outstanding queries remembered at once
+const MAX_PENDING_QUERIES: usize = 16384;
+/// Limit on compression pointer hops while decoding a name
+const MAX_POINTER_HOPS: usize = 16;
+
+const RCODE_SERVFAIL: u8 = 2;
+const RCODE_NXDOMAIN: u8 = 3;
+
+/// A decoded DNS message (only the parts we report on)
+#[derive(Debug, Clone, PartialEq)]
+pub struct DnsMessage {
+    pub id: u16,
+    pub is_response: bool,
+    pub rcode: u8,
+    pub qname: String,
+    pub qtype: u16,
+    pub answers: Vec<String>,
+}
+
+/// A query matched with its response
+#[derive(Debug, Clone, PartialEq)]
+pub struct DnsTransaction {
+    pub client: SocketAddr,
+    pub server: SocketAddr,
+    pub id: u16,
+    pub qname: String,
+    pub qtype: u16,
+    pub rcode: u8,
+    pub answers: Vec<String>,
+    pub query_timestamp: i64,
+    pub latency_ms: i64,
+}
+
+impl DnsTransaction {
+    /// Traffic event with the query, answers and latency as metadata
+    pub fn into_event(self) -> TrafficEvent {
+        let mut metadata = HashMap::new();
+        metadata.insert("dns.id".to_string(), self.id.to_string());
+        metadata.insert("dns.qname".to_string(), self.qname);
+        metadata.insert("dns.qtype".to_string(), qtype_name(self.qtype));
+        metadata.insert("dns.rcode".to_string(), rcode_name(self.rcode));
+        metadata.insert("dns.answer_count".to_string(), self.answers.len().to_string());
+        metadata.insert("dns.answers".to_string(), self.answers.join(","));
+        metadata.insert("dns.latency_ms".to_string(), self.latency_ms.to_string());
+
+        TrafficEvent {
+            timestamp: self.query_timestamp,
+            protocol: Protocol::DNS,
+            src_ip: self.client.ip().to_string(),
+            dst_ip: self.server.ip().to_string(),
+            src_port: self.client.port(),
+            dst_port: self.server.port(),
+            bytes: 0,
+            packets: 0,
+            metadata,
+            sample_rate: 1.0,
+        }
+    }
+}
+
+struct PendingQuery {
+    qname: String,
+    qtype: u16,
+    timestamp: i64,
+}
+
+/// Running response counts, reported as cumulative counters and window rates
+#[derive(Default)]
+struct DnsStats {
+    responses_by_rcode: HashMap<u8, u64>,
+    timeouts: u64,
+    window_responses: u64,
+    window_nxdomain: u64,
+    window_servfail: u64,
+}
+
+/// Matches DNS queries to responses and aggregates response codes
+#[derive(Default)]
+pub struct DnsTracker {
+    /// Outstanding queries keyed by client-to-server flow and query ID
+    pending: HashMap<(FlowKey, u16), PendingQuery>,
+    stats: DnsStats,
+}
+
+impl DnsTracker {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Feed one UDP datagram. Returns whether it decoded as DNS and the
+    /// transaction it completed, if any.
+    pub fn process_datagram(
+        &mut self,
+        flow: FlowKey,
+        payload: &[u8],
+        timestamp: i64,
+    ) -> (bool, Option<DnsTransaction>) {
+        let message = match parse_message(payload) {
+            Some(message) => message,
+            None => return (false, None),
+        };
+
+        if !message.is_response {
+            if self.pending.len() < MAX_PENDING_QUERIES {
+                self.pending.insert(
+                    (flow, message.id),
+                    PendingQuery {
+                        qname: message.qname,
+                        qtype: message.qtype,
+                        timestamp,
+                    },
+                );
+            }
+            return (true, None);
+        }
+
+        let client_flow = flow.reversed();
+        let query = match self.pending.remove(&(client_flow, message.id)) {
+            Some(query) if query.qname.eq_ignore_ascii_case(&message.qname) => query,
+            _ => return (true, None),
+        };
+
+        self.record_response(message.rcode);
+
+        let transaction = DnsTransaction {
+            client: client_flow.src,
+            server: client_flow.dst,
+            id: message.id,
+            qname: query.qname,
+            qtype: query.qtype,
+            rcode: message.rcode,
+            answers: message.answers,
+            query_timestamp: query.timestamp,
+            latency_ms: (timestamp - query.timestamp).max(0),
+        };
+
+        (true, Some(transaction))
+    }
+
+    /// Forget queries that were never answered, counting them as timeouts
+    pub fn expire(&mut self, now: i64) {
+        let before = self.pending.len();
+        self.pending
+            .retain(|_, query| now - query.timestamp <= QUERY_TIMEOUT_MS);
+        self.stats.timeouts += (before - self.pending.len()) as u64;
+    }
+
+    /// Build metric events from the counters and start a new rate window
+    pub fn metrics(&mut self, timestamp: i64) -> Vec<MetricEvent> {
+        let stats = &mut self.stats;
+        let mut metrics = Vec::new();
+
+        for (rcode, count) in &stats.responses_by_rcode {
+            metrics.push(MetricEvent {
+                timestamp,
+                name: "traffic.dns.responses".to_string(),
+                value: *count as f64,
+                metric_type: MetricType::Counter,
+                tags: HashMap::from([("rcode".to_string(), rcode_name(*rcode))]),
+                unit: None,
+            });
+        }
+
+        metrics.push(MetricEvent {
+            timestamp,
+            name: "traffic.dns.timeouts".to_string(),
+            value: stats.timeouts as f64,
+            metric_type: MetricType::Counter,
+            tags: HashMap::new(),
+            unit: None,
+        });
+
+        if stats.window_responses > 0 {
+            let total = stats.window_responses as f64;
+            for (name, count) in [
+                ("traffic.dns.nxdomain_rate", stats.window_nxdomain),
+                ("traffic.dns.servfail_rate", stats.window_servfail),
+            ] {
+                metrics.push(MetricEvent {
+                    timestamp,
+                    name: name.to_string(),
+                    value: count as f64 / total * 100.0,
+                    metric_type: MetricType::Gauge,
+                    tags: HashMap::new(),
+                    unit: Some("%".to_string()),
+                });
+            }
+        }
+
+        stats.window_responses = 0;
+        stats.window_nxdomain = 0;
+        stats.window_servfail = 0;
+
+        metrics
+    }
+
+    fn record_response(&mut self, rcode: u8) {
+        let stats = &mut self.stats;
+        *stats.responses_by_rcode.entry(rcode).or_default() += 1;
+        stats.window_responses += 1;
+        match rcode {
+            RCODE_NXDOMAIN => stats.window_nxdomain += 1,
+            RCODE_SERVFAIL => stats.window_servfail += 1,
+            _ => {}
+        }
+    }
+}
+
+/// Decode a DNS message carrying at least one question
+pub fn parse_message(data: &[u8]) -> Option<DnsMessage> {
+    if data.len() < 12 {
+        return None;
+    }
+
+    let id = read_u16(data, 0)?;
+    let flags = read_u16(data, 2)?;
+    let qdcount = read_u16(data, 4)?;
+    let ancount = read_u16(data, 6)?;
+
+    let is_response = flags & 0x8000 != 0;
+    let opcode = (flags >> 11) & 0x0f;
+    let rcode = (flags & 0x000f) as u8;
+
+    // Standard queries only; anything else is unlikely to be DNS on the wire
+    if opcode != 0 || qdcount == 0 {
+        return None;
+    }
+
+    let (qname, mut offset) = read_name(data, 12)?;
+    let qtype = read_u16(data, offset)?;
+    offset += 4;
+
+    // Skip any additional questions
+    for _ in 1..qdcount {
+        let (_, next) = read_name(data, offset)?;
+        offset = next + 4;
+    }
+
+    let mut answers = Vec::with_capacity(ancount as usize);
+    for _ in 0..ancount {
+        let (_, next) = read_name(data, offset)?;
+        let rtype = read_u16(data, next)?;
+        let rdlength = read_u16(data, next + 8)? as usize;
+        let rdata_start = next + 10;
+        let rdata = data.get(rdata_start..rdata_start + rdlength)?;
+
+        answers.push(format_rdata(data, rtype, rdata_start, rdata));
+        offset = rdata_start + rdlength;
+    }
+
+    Some(DnsMessage {
+        id,
+        is_response,
+        rcode,
+        qname,
+        qtype,
+        answers,
+    })
+}
+
+fn format_rdata(message: &[u8], rtype: u16, rdata_start: usize, rdata: &[u8]) -> String {
+    match rtype {
+        1 if rdata.len() == 4 => Ipv4Addr::new(rdata[0], rdata[1], rdata[2], rdata[3]).to_string(),
+        28 if rdata.len() == 16 => {
+            let mut octets = [0u8; 16];
+            octets.copy_from_slice(rdata);
+            Ipv6Addr::from(octets).to_string()
+        }
+        // CNAME, NS, PTR
+        2 | 5 | 12 => read_name(message, rdata_start)
+            .map(|(name, _)| name)
+            .unwrap_or_default(),
+        // MX: preference followed by exchange name
+        15 => read_name(message, rdata_start + 2)
+            .map(|(name, _)| name)
+            .unwrap_or_default(),
+        _ => qtype_name(rtype),
+    }
+}
+
+/// Read a possibly compressed domain name, returning it with the offset
+/// just past the name at its original position
+fn read_name(data: &[u8], start: usize) -> Option<(String, usize)> {
+    let mut labels: Vec<String> = Vec::new();
+    let mut offset = start;
+    let mut end = None;
+    let mut hops = 0;
+    let mut length = 0;
+
+    loop {
+        let len = *data.get(offset)? as usize;
+        match len & 0xc0 {
+            0x00 => {
+                if len == 0 {
+                    offset += 1;
+                    break;
+                }
+                let label = data.get(offset + 1..offset + 1 + len)?;
+                length += len + 1;
+                if length > 255 {
+                    return None;
+                }
+                labels.push(String::from_utf8_lossy(label).to_string());
+                offset += len + 1;
+            }
+            0xc0 => {
+                hops += 1;
+                if hops > MAX_POINTER_HOPS {
+                    return None;
+                }
+                let pointer = (read_u16(data, offset)? & 0x3fff) as usize;
+                end.get_or_insert(offset + 2);
+                offset = pointer;
+            }
+            _ => return None,
+        }
+    }
+
+    let name = if labels.is_empty() {
+        ".".to_string()
+    } else {
+        labels.join(".")
+    };
+
+    Some((name, end.unwrap_or(offset)))
+}
+
+fn read_u16(data: &[u8], offset: usize) -> Option<u16> {
+    let bytes = data.get(offset..offset + 2)?;
+    Some(u16::from_be_bytes([bytes[0], bytes[1]]))
+}
+
+pub fn qtype_name(qtype: u16) -> String {
+    match qtype {
+        1 => "A".to_string(),
+        2 => "NS".to_string(),
+        5 => "CNAME".to_string(),
+        6 => "SOA".to_string(),
+        12 => "PTR".to_string(),
+        15 => "MX".to_string(),
+        16 => "TXT".to_string(),
+        28 => "AAAA".to_string(),
+        33 => "SRV".to_string(),
+        65 => "HTTPS".to_string(),
+        255 => "ANY".to_string(),
+        other => format!("TYPE{}", other),
+    }
+}
+
+pub fn rcode_name(rcode: u8) -> String {
+    match rcode {
+        0 => "NOERROR".to_string(),
+        1 => "FORMERR".to_string(),
+        2 => "SERVFAIL".to_string(),
+        3 => "NXDOMAIN".to_string(),
+        4 => "NOTIMP".to_string(),
+        5 => "REFUSED".to_string(),
+        other => format!("RCODE{}", other),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn flow(src: &str, dst: &str) -> FlowKey {
+        FlowKey::new(17, src.parse().unwrap(), dst.parse().unwrap())
+    }
+
+    fn query(id: u16, name: &str, qtype: u16) -> Vec<u8> {
+        let mut data = Vec::new();
+        data.extend_from_slice(&id.to_be_bytes());
+        data.extend_from_slice(&0x0100u16.to_be_bytes()); // RD
+        data.extend_from_slice(&1u16.to_be_bytes());
+        data.extend_from_slice(&[0, 0, 0, 0, 0, 0]);
+        for label in name.split('.') {
+            data.push(label.len() as u8);
+            data.extend_from_slice(label.as_bytes());
+        }
+        data.push(0);
+        data.extend_from_slice(&qtype.to_be_bytes());
+        data.extend_from_slice(&1u16.to_be_bytes());
+        data
+    }
+
+    fn response(id: u16, name: &str, rcode: u8, addrs: &[[u8; 4]]) -> Vec<u8> {
+        let mut data = query(id, name, 1);
+        data[2] = 0x81;
+        data[3] = 0x80 | rcode;
+        data[6..8].copy_from_slice(&(addrs.len() as u16).to_be_bytes());
+        for addr in addrs {
+            data.extend_from_slice(&[0xc0, 12]); // pointer to the question name
+            data.extend_from_slice(&1u16.to_be_bytes());
+            data.extend_from_slice(&1u16.to_be_bytes());
+            data.extend_from_slice(&300u32.to_be_bytes());
+            data.extend_from_slice(&4u16.to_be_bytes());
+            data.extend_from_slice(addr);
+        }
+        data
+    }
+
+    #[test]
+    fn test_parse_response_with_compression() {
+        let data = response(0x1234, "www.example.com", 0, &[[93, 184, 216, 34]]);
+        let message = parse_message(&data).unwrap();
+
+        assert!(message.is_response);
+        assert_eq!(message.id, 0x1234);
+        assert_eq!(message.qname, "www.example.com");
+        assert_eq!(message.qtype, 1);
+        assert_eq!(message.answers, vec!["93.184.216.34".to_string()]);
+    }
+
+    #[test]
+    fn test_rejects_garbage() {
+        assert!(parse_message(b"not dns").is_none());
+        assert!(parse_message(&[0u8; 12]).is_none());
+
+        // Pointer loop
+        let mut data = query(1, "a", 1);
+        data.truncate(12);
+        data.extend_from_slice(&[0xc0, 12]);
+        assert!(parse_message(&data).is_none());
+    }
+
+    #[test]
+    fn test_query_response_matching() {
+        let mut tracker = DnsTracker::new();
+        let client = flow("10.0.0.1:40000", "10.0.0.53:53");
+
+        let (is_dns, tx) = tracker.process_datagram(client, &query(7, "example.com", 1), 1000);
+        assert!(is_dns);
+        assert!(tx.is_none());
+
+        let (_, tx) = tracker.process_datagram(
+            client.reversed(),
+            &response(7, "example.com", 0, &[[1, 2, 3, 4], [5, 6, 7, 8]]),
+            1012,
+        );
+        let tx = tx.unwrap();
+        assert_eq!(tx.client, client.src);
+        assert_eq!(tx.qname, "example.com");
+        assert_eq!(tx.answers.len(), 2);
+        assert_eq!(tx.latency_ms, 12);
+
+        let event = tx.into_event();
+        assert_eq!(event.protocol, Protocol::DNS);
+        assert_eq!(event.metadata["dns.rcode"], "NOERROR");
+        assert_eq!(event.metadata["dns.qtype"], "A");
+    }
+
+    #[test]
+    fn test_error_rates_and_timeouts() {
+        let mut tracker = DnsTracker::new();
+        let client = flow("10.0.0.1:40000", "10.0.0.53:53");
+
+        for (id, rcode) in [(1, 0), (2, RCODE_NXDOMAIN), (3, RCODE_NXDOMAIN), (4, RCODE_SERVFAIL)] {
+            tracker.process_datagram(client, &query(id, "example.com", 1), 0);
+            tracker.process_datagram(client.reversed(), &response(id, "example.com", rcode, &[]), 5);
+        }
+        tracker.process_datagram(client, &query(5, "slow.example.com", 1), 0);
+        tracker.expire(QUERY_TIMEOUT_MS + 1);
+
+        let metrics = tracker.metrics(100);
+        let value = |name: &str| metrics.iter().find(|m| m.name == name).unwrap().value;
+        assert_eq!(value("traffic.dns.nxdomain_rate"), 50.0);
+        assert_eq!(value("traffic.dns.servfail_rate"), 25.0);
+        assert_eq!(value("traffic.dns.timeouts"), 1.0);
+
+        // Rates start over with each window
+        let metrics = tracker.metrics(200);
+        assert!(metrics.iter().all(|m| m.name != "traffic.dns.nxdomain_rate"));
+    }
+}
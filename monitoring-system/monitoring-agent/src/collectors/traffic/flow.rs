@@ -0,0 +1,132 @@
+use siphasher::sip::SipHasher13;
+use std::hash::Hasher;
+use std::net::{IpAddr, SocketAddr};
+
+const SHARD_SALT: u8 = 0;
+const SAMPLE_SALT: u8 = 1;
+
+/// Directed 5-tuple identifying one side of a conversation
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct FlowKey {
+    /// IP next-header protocol number (6 = TCP, 17 = UDP)
+    pub protocol: u8,
+    pub src: SocketAddr,
+    pub dst: SocketAddr,
+}
+
+impl FlowKey {
+    pub fn new(protocol: u8, src: SocketAddr, dst: SocketAddr) -> Self {
+        Self { protocol, src, dst }
+    }
+
+    /// The same flow seen from the opposite direction
+    pub fn reversed(&self) -> Self {
+        Self {
+            protocol: self.protocol,
+            src: self.dst,
+            dst: self.src,
+        }
+    }
+
+    /// Pick one of `shards` workers, the same for both directions of the flow
+    pub fn shard(&self, shards: usize) -> usize {
+        (self.hash_with(SHARD_SALT) % shards.max(1) as u64) as usize
+    }
+
+    /// Whether the flow falls inside a `rate` sample. Every packet of a flow,
+    /// in either direction, gets the same answer.
+    pub fn sampled(&self, rate: f64) -> bool {
+        if rate >= 1.0 {
+            return true;
+        }
+        // Top 53 bits give a uniform value in [0, 1)
+        let position = (self.hash_with(SAMPLE_SALT) >> 11) as f64 / (1u64 << 53) as f64;
+        position < rate
+    }
+
+    /// Direction-independent hash. The salt keeps worker choice and sampling
+    /// decisions uncorrelated.
+    ///
+    /// SipHash-1-3 with zero keys over a fixed byte encoding, so agents built
+    /// with any toolchain sample the same flows: the salt, the protocol, then
+    /// for each endpoint (lower first) 4 for IPv4 or 6 for IPv6, the address
+    /// octets and the big-endian port.
+    fn hash_with(&self, salt: u8) -> u64 {
+        let (a, b) = if self.src <= self.dst {
+            (self.src, self.dst)
+        } else {
+            (self.dst, self.src)
+        };
+
+        let mut hasher = SipHasher13::new_with_keys(0, 0);
+        hasher.write(&[salt, self.protocol]);
+        for address in [a, b] {
+            match address.ip() {
+                IpAddr::V4(ip) => {
+                    hasher.write_u8(4);
+                    hasher.write(&ip.octets());
+                }
+                IpAddr::V6(ip) => {
+                    hasher.write_u8(6);
+                    hasher.write(&ip.octets());
+                }
+            }
+            hasher.write(&address.port().to_be_bytes());
+        }
+        hasher.finish()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_shard_is_direction_independent() {
+        let flow = FlowKey::new(
+            6,
+            "10.0.0.1:51000".parse().unwrap(),
+            "10.0.0.2:443".parse().unwrap(),
+        );
+
+        for shards in 1..8 {
+            assert_eq!(flow.shard(shards), flow.reversed().shard(shards));
+            assert!(flow.shard(shards) < shards);
+        }
+    }
+
+    #[test]
+    fn test_hash_is_stable() {
+        // Agents on both ends of a connection must agree, whatever they were
+        // built with
+        let flow = FlowKey::new(
+            6,
+            "10.0.0.1:51000".parse().unwrap(),
+            "10.0.0.2:443".parse().unwrap(),
+        );
+        assert_eq!(flow.hash_with(SAMPLE_SALT), 8659590272105896885);
+    }
+
+    #[test]
+    fn test_sampling_is_flow_consistent() {
+        let flows: Vec<FlowKey> = (0..1000u16)
+            .map(|port| {
+                FlowKey::new(
+                    6,
+                    SocketAddr::new("10.0.0.1".parse().unwrap(), 40000 + port),
+                    "10.0.0.2:443".parse().unwrap(),
+                )
+            })
+            .collect();
+
+        for flow in &flows {
+            assert_eq!(flow.sampled(0.3), flow.sampled(0.3));
+            assert_eq!(flow.sampled(0.3), flow.reversed().sampled(0.3));
+            assert!(flow.sampled(1.0));
+            assert!(!flow.sampled(0.0));
+        }
+
+        let kept = flows.iter().filter(|flow| flow.sampled(0.3)).count();
+        assert!((200..400).contains(&kept), "kept {} of 1000", kept);
+    }
+}
@@ -0,0 +1,561 @@
+use super::flow::FlowKey;
+use monitoring_common::{Protocol, TrafficEvent};
+use std::collections::{HashMap, VecDeque};
+use std::net::SocketAddr;
+
+/// Maximum bytes buffered per direction while waiting for a complete message head
+const MAX_BUFFER_BYTES: usize = 64 * 1024;
+/// Maximum header count accepted in a single HTTP message
+const MAX_HEADERS: usize = 64;
+/// Maximum unanswered requests remembered per connection
+const MAX_PENDING_REQUESTS: usize = 64;
+/// Maximum number of directed streams tracked at once
+const MAX_STREAMS: usize = 16384;
+/// Streams idle for longer than this are forgotten
+const IDLE_TIMEOUT_MS: i64 = 60_000;
+
+/// A request matched with its response
+#[derive(Debug, Clone, PartialEq)]
+pub struct HttpTransaction {
+    pub client: SocketAddr,
+    pub server: SocketAddr,
+    pub method: String,
+    pub host: Option<String>,
+    pub path: String,
+    pub user_agent: Option<String>,
+    pub status_code: u16,
+    pub request_timestamp: i64,
+    pub latency_ms: i64,
+}
+
+impl HttpTransaction {
+    /// Traffic event with the request line, status and latency as metadata
+    pub fn into_event(self) -> TrafficEvent {
+        let mut metadata = HashMap::new();
+        metadata.insert("http.method".to_string(), self.method);
+        metadata.insert("http.path".to_string(), self.path);
+        metadata.insert("http.status_code".to_string(), self.status_code.to_string());
+        metadata.insert("http.latency_ms".to_string(), self.latency_ms.to_string());
+        if let Some(host) = self.host {
+            metadata.insert("http.host".to_string(), host);
+        }
+        if let Some(user_agent) = self.user_agent {
+            metadata.insert("http.user_agent".to_string(), user_agent);
+        }
+
+        TrafficEvent {
+            timestamp: self.request_timestamp,
+            protocol: Protocol::HTTP,
+            src_ip: self.client.ip().to_string(),
+            dst_ip: self.server.ip().to_string(),
+            src_port: self.client.port(),
+            dst_port: self.server.port(),
+            bytes: 0,
+            packets: 0,
+            metadata,
+            sample_rate: 1.0,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum StreamState {
+    Unknown,
+    Http,
+    NotHttp,
+}
+
+#[derive(Debug)]
+struct RequestHead {
+    method: String,
+    path: String,
+    host: Option<String>,
+    user_agent: Option<String>,
+    timestamp: i64,
+}
+
+#[derive(Debug)]
+enum HttpMessage {
+    Request(RequestHead),
+    Response { status_code: u16, timestamp: i64 },
+}
+
+/// In-order payload of one direction of a TCP connection
+struct StreamBuffer {
+    state: StreamState,
+    next_seq: Option<u32>,
+    data: Vec<u8>,
+    /// Timestamp of the first packet contributing to `data`
+    message_start: i64,
+    /// Body bytes still to be skipped before the next message head
+    body_remaining: usize,
+    /// Inside a chunked body, which is skipped chunk by chunk
+    chunked: bool,
+    last_seen: i64,
+}
+
+impl StreamBuffer {
+    fn new(timestamp: i64) -> Self {
+        Self {
+            state: StreamState::Unknown,
+            next_seq: None,
+            data: Vec::new(),
+            message_start: timestamp,
+            body_remaining: 0,
+            chunked: false,
+            last_seen: timestamp,
+        }
+    }
+
+    /// Append a segment, resynchronising on gaps and ignoring retransmissions
+    fn append(&mut self, seq: u32, payload: &[u8], timestamp: i64) {
+        self.last_seen = timestamp;
+
+        if let Some(expected) = self.next_seq {
+            let offset = seq.wrapping_sub(expected) as i32;
+            if offset < 0 {
+                // Retransmission of data we already have
+                return;
+            }
+            if offset > 0 {
+                // Missed segments: whatever was buffered can no longer be parsed
+                self.data.clear();
+                self.body_remaining = 0;
+                self.chunked = false;
+            }
+        }
+        self.next_seq = Some(seq.wrapping_add(payload.len() as u32));
+
+        let mut payload = payload;
+        if self.body_remaining > 0 {
+            let skip = self.body_remaining.min(payload.len());
+            self.body_remaining -= skip;
+            payload = &payload[skip..];
+        }
+
+        if payload.is_empty() {
+            return;
+        }
+        if self.data.is_empty() {
+            self.message_start = timestamp;
+        }
+        self.data.extend_from_slice(payload);
+
+        if self.data.len() > MAX_BUFFER_BYTES {
+            self.data.clear();
+            if self.state == StreamState::Unknown {
+                self.state = StreamState::NotHttp;
+            }
+        }
+    }
+
+    /// Parse every complete message head currently buffered
+    fn drain_messages(&mut self) -> Vec<HttpMessage> {
+        let mut messages = Vec::new();
+
+        while !self.data.is_empty() {
+            if self.chunked {
+                match self.skip_chunks() {
+                    Ok(true) => continue,
+                    Ok(false) => break,
+                    Err(()) => {
+                        self.data.clear();
+                        self.chunked = false;
+                        break;
+                    }
+                }
+            }
+
+            let parsed = if self.data.starts_with(b"HTTP/") {
+                parse_response(&self.data, self.message_start)
+            } else {
+                parse_request(&self.data, self.message_start)
+            };
+
+            match parsed {
+                Ok(Some((message, head_len, body))) => {
+                    self.state = StreamState::Http;
+                    messages.push(message);
+
+                    match body {
+                        Body::Length(body_len) => {
+                            let available = self.data.len() - head_len;
+                            let skip = body_len.min(available);
+                            self.data.drain(..head_len + skip);
+                            self.body_remaining = body_len - skip;
+                        }
+                        Body::Chunked => {
+                            self.data.drain(..head_len);
+                            self.chunked = true;
+                        }
+                    }
+                }
+                Ok(None) => break,
+                Err(()) => {
+                    self.data.clear();
+                    if self.state == StreamState::Unknown {
+                        self.state = StreamState::NotHttp;
+                    }
+                    break;
+                }
+            }
+        }
+
+        messages
+    }
+
+    /// Skip buffered chunks of a chunked body. `Ok(true)` once the last chunk
+    /// and trailers are consumed, `Ok(false)` if more data is needed.
+    fn skip_chunks(&mut self) -> Result<bool, ()> {
+        loop {
+            if self.body_remaining > 0 {
+                let skip = self.body_remaining.min(self.data.len());
+                self.data.drain(..skip);
+                self.body_remaining -= skip;
+                if self.body_remaining > 0 {
+                    return Ok(false);
+                }
+            }
+
+            let (line_len, size) = match httparse::parse_chunk_size(&self.data) {
+                Ok(httparse::Status::Complete(parsed)) => parsed,
+                Ok(httparse::Status::Partial) => return Ok(false),
+                Err(_) => return Err(()),
+            };
+
+            if size == 0 {
+                // Optional trailer fields end with an empty line
+                let rest = &self.data[line_len..];
+                let trailer_len = if rest.starts_with(b"\r\n") {
+                    2
+                } else {
+                    match rest.windows(4).position(|w| w == b"\r\n\r\n") {
+                        Some(pos) => pos + 4,
+                        None => return Ok(false),
+                    }
+                };
+                self.data.drain(..line_len + trailer_len);
+                self.chunked = false;
+                self.message_start = self.last_seen;
+                return Ok(true);
+            }
+
+            // Chunk data is followed by CRLF
+            self.data.drain(..line_len);
+            self.body_remaining = usize::try_from(size)
+                .ok()
+                .and_then(|size| size.checked_add(2))
+                .ok_or(())?;
+        }
+    }
+}
+
+/// Tracks TCP payloads per connection and extracts HTTP/1.x transactions
+#[derive(Default)]
+pub struct HttpTracker {
+    streams: HashMap<FlowKey, StreamBuffer>,
+    /// Unanswered requests, keyed by the client-to-server direction
+    pending: HashMap<FlowKey, VecDeque<RequestHead>>,
+}
+
+impl HttpTracker {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Feed one TCP segment and return any transactions it completed
+    pub fn process_segment(
+        &mut self,
+        flow: FlowKey,
+        seq: u32,
+        payload: &[u8],
+        timestamp: i64,
+    ) -> Vec<HttpTransaction> {
+        if payload.is_empty() {
+            return Vec::new();
+        }
+
+        if !self.streams.contains_key(&flow) && self.streams.len() >= MAX_STREAMS {
+            return Vec::new();
+        }
+
+        let stream = self
+            .streams
+            .entry(flow)
+            .or_insert_with(|| StreamBuffer::new(timestamp));
+
+        if stream.state == StreamState::NotHttp {
+            stream.last_seen = timestamp;
+            return Vec::new();
+        }
+
+        stream.append(seq, payload, timestamp);
+        let messages = stream.drain_messages();
+
+        let mut transactions = Vec::new();
+        for message in messages {
+            match message {
+                HttpMessage::Request(request) => {
+                    let queue = self.pending.entry(flow).or_default();
+                    if queue.len() >= MAX_PENDING_REQUESTS {
+                        queue.pop_front();
+                    }
+                    queue.push_back(request);
+                }
+                HttpMessage::Response { status_code, timestamp } => {
+                    // Informational responses precede the final one
+                    if (100..200).contains(&status_code) {
+                        continue;
+                    }
+
+                    let client_flow = flow.reversed();
+                    let request = match self
+                        .pending
+                        .get_mut(&client_flow)
+                        .and_then(VecDeque::pop_front)
+                    {
+                        Some(request) => request,
+                        None => continue,
+                    };
+
+                    transactions.push(HttpTransaction {
+                        client: client_flow.src,
+                        server: client_flow.dst,
+                        method: request.method,
+                        host: request.host,
+                        path: request.path,
+                        user_agent: request.user_agent,
+                        status_code,
+                        request_timestamp: request.timestamp,
+                        latency_ms: (timestamp - request.timestamp).max(0),
+                    });
+                }
+            }
+        }
+
+        transactions
+    }
+
+    /// Whether either direction of the connection has been identified as HTTP
+    pub fn is_http(&self, flow: &FlowKey) -> bool {
+        [flow, &flow.reversed()].iter().any(|key| {
+            self.streams
+                .get(key)
+                .is_some_and(|s| s.state == StreamState::Http)
+        })
+    }
+
+    /// Forget both directions of a connection
+    pub fn close(&mut self, flow: &FlowKey) {
+        let reversed = flow.reversed();
+        self.streams.remove(flow);
+        self.streams.remove(&reversed);
+        self.pending.remove(flow);
+        self.pending.remove(&reversed);
+    }
+
+    /// Drop state for streams that have been idle too long
+    pub fn expire(&mut self, now: i64) {
+        self.streams
+            .retain(|_, stream| now - stream.last_seen <= IDLE_TIMEOUT_MS);
+        let streams = &self.streams;
+        self.pending.retain(|flow, queue| {
+            !queue.is_empty() && (streams.contains_key(flow) || streams.contains_key(&flow.reversed()))
+        });
+    }
+}
+
+/// How the body following a message head is delimited
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Body {
+    Length(usize),
+    Chunked,
+}
+
+type ParseResult = Result<Option<(HttpMessage, usize, Body)>, ()>;
+
+fn parse_request(data: &[u8], timestamp: i64) -> ParseResult {
+    let mut headers = [httparse::EMPTY_HEADER; MAX_HEADERS];
+    let mut request = httparse::Request::new(&mut headers);
+
+    let head_len = match request.parse(data) {
+        Ok(httparse::Status::Complete(len)) => len,
+        Ok(httparse::Status::Partial) => return Ok(None),
+        Err(_) => return Err(()),
+    };
+
+    let body = body_length(request.headers)?;
+    let head = RequestHead {
+        method: request.method.unwrap_or_default().to_string(),
+        path: request.path.unwrap_or_default().to_string(),
+        host: header_value(request.headers, "host"),
+        user_agent: header_value(request.headers, "user-agent"),
+        timestamp,
+    };
+
+    Ok(Some((HttpMessage::Request(head), head_len, body)))
+}
+
+fn parse_response(data: &[u8], timestamp: i64) -> ParseResult {
+    let mut headers = [httparse::EMPTY_HEADER; MAX_HEADERS];
+    let mut response = httparse::Response::new(&mut headers);
+
+    let head_len = match response.parse(data) {
+        Ok(httparse::Status::Complete(len)) => len,
+        Ok(httparse::Status::Partial) => return Ok(None),
+        Err(_) => return Err(()),
+    };
+
+    let status_code = response.code.unwrap_or_default();
+    let body = match status_code {
+        100..=199 | 204 | 304 => Body::Length(0),
+        _ => body_length(response.headers)?,
+    };
+
+    Ok(Some((
+        HttpMessage::Response { status_code, timestamp },
+        head_len,
+        body,
+    )))
+}
+
+fn header_value(headers: &[httparse::Header], name: &str) -> Option<String> {
+    headers
+        .iter()
+        .find(|h| h.name.eq_ignore_ascii_case(name))
+        .map(|h| String::from_utf8_lossy(h.value).trim().to_string())
+}
+
+/// Body framing from Transfer-Encoding or Content-Length
+fn body_length(headers: &[httparse::Header]) -> Result<Body, ()> {
+    if let Some(encoding) = header_value(headers, "transfer-encoding") {
+        if encoding.to_ascii_lowercase().contains("chunked") {
+            return Ok(Body::Chunked);
+        }
+    }
+
+    match header_value(headers, "content-length") {
+        Some(len) => len.parse().map(Body::Length).map_err(|_| ()),
+        None => Ok(Body::Length(0)),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn flow(src: &str, dst: &str) -> FlowKey {
+        FlowKey::new(6, src.parse().unwrap(), dst.parse().unwrap())
+    }
+
+    #[test]
+    fn test_request_response_transaction() {
+        let mut tracker = HttpTracker::new();
+        let request = flow("10.0.0.1:50000", "10.0.0.2:8080");
+
+        let req = b"GET /api/users?id=1 HTTP/1.1\r\nHost: example.com\r\nUser-Agent: curl/8.0\r\n\r\n";
+        assert!(tracker.process_segment(request, 1000, req, 100).is_empty());
+        assert!(tracker.is_http(&request));
+
+        let resp = b"HTTP/1.1 404 Not Found\r\nContent-Length: 5\r\n\r\nnope!";
+        let transactions = tracker.process_segment(request.reversed(), 5000, resp, 145);
+
+        assert_eq!(transactions.len(), 1);
+        let tx = &transactions[0];
+        assert_eq!(tx.method, "GET");
+        assert_eq!(tx.path, "/api/users?id=1");
+        assert_eq!(tx.host.as_deref(), Some("example.com"));
+        assert_eq!(tx.user_agent.as_deref(), Some("curl/8.0"));
+        assert_eq!(tx.status_code, 404);
+        assert_eq!(tx.latency_ms, 45);
+        assert_eq!(tx.client, request.src);
+    }
+
+    #[test]
+    fn test_request_split_across_segments() {
+        let mut tracker = HttpTracker::new();
+        let request = flow("10.0.0.1:50000", "10.0.0.2:80");
+
+        let part1 = b"POST /submit HTTP/1.1\r\nHost: exa";
+        let part2 = b"mple.com\r\nContent-Length: 4\r\n\r\nbody";
+        tracker.process_segment(request, 1, part1, 10);
+        tracker.process_segment(request, 1 + part1.len() as u32, part2, 11);
+
+        let resp = b"HTTP/1.1 201 Created\r\nContent-Length: 0\r\n\r\n";
+        let transactions = tracker.process_segment(request.reversed(), 1, resp, 30);
+
+        assert_eq!(transactions.len(), 1);
+        assert_eq!(transactions[0].method, "POST");
+        assert_eq!(transactions[0].host.as_deref(), Some("example.com"));
+        assert_eq!(transactions[0].latency_ms, 20);
+    }
+
+    #[test]
+    fn test_chunked_response_keeps_connection_in_sync() {
+        let mut tracker = HttpTracker::new();
+        let request = flow("10.0.0.1:50000", "10.0.0.2:80");
+        let response = request.reversed();
+
+        let req1 = b"GET /first HTTP/1.1\r\nHost: a\r\n\r\n";
+        tracker.process_segment(request, 1, req1, 10);
+
+        // The first chunk is split across segments
+        let part1 = b"HTTP/1.1 200 OK\r\nTransfer-Encoding: chunked\r\n\r\n5\r\nhel";
+        let part2 = b"lo\r\n6\r\n world\r\n0\r\n\r\n";
+        let first = tracker.process_segment(response, 1, part1, 20);
+        assert_eq!(first.len(), 1);
+        assert_eq!(first[0].path, "/first");
+        assert!(tracker
+            .process_segment(response, 1 + part1.len() as u32, part2, 21)
+            .is_empty());
+
+        let req2 = b"GET /second HTTP/1.1\r\nHost: a\r\n\r\n";
+        tracker.process_segment(request, 1 + req1.len() as u32, req2, 30);
+        let resp2 = b"HTTP/1.1 204 No Content\r\n\r\n";
+        let seq = 1 + (part1.len() + part2.len()) as u32;
+        let second = tracker.process_segment(response, seq, resp2, 35);
+
+        assert_eq!(second.len(), 1);
+        assert_eq!(second[0].path, "/second");
+        assert_eq!(second[0].status_code, 204);
+        assert_eq!(second[0].latency_ms, 5);
+    }
+
+    #[test]
+    fn test_retransmission_ignored() {
+        let mut tracker = HttpTracker::new();
+        let request = flow("10.0.0.1:50000", "10.0.0.2:80");
+
+        let req = b"GET / HTTP/1.1\r\nHost: a\r\n\r\n";
+        tracker.process_segment(request, 1, req, 10);
+        tracker.process_segment(request, 1, req, 20);
+
+        let resp = b"HTTP/1.1 200 OK\r\nContent-Length: 0\r\n\r\n";
+        let transactions = tracker.process_segment(request.reversed(), 1, resp, 30);
+        assert_eq!(transactions.len(), 1);
+
+        let none = tracker.process_segment(request.reversed(), 1 + resp.len() as u32, resp, 40);
+        assert!(none.is_empty());
+    }
+
+    #[test]
+    fn test_non_http_stream_detected() {
+        let mut tracker = HttpTracker::new();
+        let ssh = flow("10.0.0.1:50000", "10.0.0.2:80");
+
+        tracker.process_segment(ssh, 1, b"\x16\x03\x01\x02\x00\x01\x00\x01\xfc\x03\x03", 10);
+        assert!(!tracker.is_http(&ssh));
+    }
+
+    #[test]
+    fn test_expire_idle_streams() {
+        let mut tracker = HttpTracker::new();
+        let request = flow("10.0.0.1:50000", "10.0.0.2:80");
+
+        tracker.process_segment(request, 1, b"GET / HTTP/1.1\r\n\r\n", 0);
+        tracker.expire(IDLE_TIMEOUT_MS + 1);
+
+        assert!(!tracker.is_http(&request));
+        assert!(tracker.pending.is_empty());
+    }
+}
@@ -1,3 +1,15 @@
+#[cfg(feature = "pcap-capture")]
+mod dns;
+#[cfg(feature = "pcap-capture")]
+mod flow;
+#[cfg(feature = "pcap-capture")]
+mod http;
+#[cfg(feature = "pcap-capture")]
+mod tcp_health;
+#[cfg(feature = "pcap-capture")]
+mod threats;
+#[cfg(feature = "pcap-capture")]
+mod tls;
 mod pcap_collector;
 
 use crate::config::TrafficCollectorConfig;
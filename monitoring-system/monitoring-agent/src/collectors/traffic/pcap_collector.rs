@@ -1,11 +1,27 @@
 #[cfg(feature = "pcap-capture")]
+use super::dns::{DnsTracker, DNS_PORT};
+#[cfg(feature = "pcap-capture")]
+use super::flow::FlowKey;
+#[cfg(feature = "pcap-capture")]
+use super::http::HttpTracker;
+#[cfg(feature = "pcap-capture")]
+use super::tcp_health::{TcpFlowSummary, TcpHealthTracker, TcpSegment};
+#[cfg(feature = "pcap-capture")]
+use super::threats::ThreatDetector;
+#[cfg(feature = "pcap-capture")]
+use super::tls::{TlsHandshake, TlsTracker};
+#[cfg(feature = "pcap-capture")]
 use crate::buffer::RingBuffer;
 #[cfg(feature = "pcap-capture")]
 use crate::config::TrafficCollectorConfig;
 #[cfg(feature = "pcap-capture")]
-use anyhow::{Context, Result};
+use anyhow::{anyhow, Context, Result};
 #[cfg(feature = "pcap-capture")]
-use monitoring_common::{Event, Protocol, TrafficEvent};
+use crossbeam::channel::{self, Receiver, RecvTimeoutError, Sender, TrySendError};
+#[cfg(feature = "pcap-capture")]
+use monitoring_common::{Event, LogEvent, MetricEvent, MetricType, Protocol, TrafficEvent};
+#[cfg(feature = "pcap-capture")]
+use parking_lot::Mutex;
 #[cfg(feature = "pcap-capture")]
 use pcap::{Capture, Device};
 #[cfg(feature = "pcap-capture")]
@@ -15,7 +31,7 @@ use pnet::packet::ip::IpNextHeaderProtocols;
 #[cfg(feature = "pcap-capture")]
 use pnet::packet::ipv4::Ipv4Packet;
 #[cfg(feature = "pcap-capture")]
-use pnet::packet::tcp::TcpPacket;
+use pnet::packet::tcp::{TcpFlags, TcpPacket};
 #[cfg(feature = "pcap-capture")]
 use pnet::packet::udp::UdpPacket;
 #[cfg(feature = "pcap-capture")]
@@ -23,15 +39,77 @@ use pnet::packet::Packet;
 #[cfg(feature = "pcap-capture")]
 use std::collections::HashMap;
 #[cfg(feature = "pcap-capture")]
+use std::net::{IpAddr, SocketAddr};
+#[cfg(feature = "pcap-capture")]
 use std::sync::Arc;
 #[cfg(feature = "pcap-capture")]
+use std::thread;
+#[cfg(feature = "pcap-capture")]
+use std::time::Duration;
+#[cfg(feature = "pcap-capture")]
+use tokio::sync::oneshot;
+#[cfg(feature = "pcap-capture")]
 use tracing::{debug, info, warn};
 
+/// How often idle tracking state is swept and aggregated metrics are reported
+#[cfg(feature = "pcap-capture")]
+const SWEEP_INTERVAL_MS: i64 = 10_000;
+
+/// How often kernel capture statistics are reported
+#[cfg(feature = "pcap-capture")]
+const STATS_INTERVAL_MS: i64 = 10_000;
+
+/// How long an idle worker waits for a packet before checking for a sweep
+#[cfg(feature = "pcap-capture")]
+const WORKER_IDLE_TIMEOUT: Duration = Duration::from_secs(1);
+
+/// Owns the pcap handle. Capture and parsing run on dedicated OS threads so
+/// the blocking `next_packet` call never stalls the async runtime.
 #[cfg(feature = "pcap-capture")]
 pub struct PcapCollector {
+    capture: Capture<pcap::Active>,
+    interface: String,
     config: TrafficCollectorConfig,
     buffer: Arc<RingBuffer>,
+}
+
+/// A packet copied out of the capture buffer for hand-off to a worker
+#[cfg(feature = "pcap-capture")]
+struct CapturedPacket {
+    timestamp: i64,
+    data: Vec<u8>,
+}
+
+/// Reads packets off the interface and fans them out to the parsing workers
+#[cfg(feature = "pcap-capture")]
+struct CaptureLoop {
     capture: Capture<pcap::Active>,
+    interface: String,
+    workers: Vec<Sender<CapturedPacket>>,
+    buffer: Arc<RingBuffer>,
+    /// Packets dropped because a worker queue was full
+    queue_dropped: u64,
+    last_stats: i64,
+}
+
+/// Turns captured packets into traffic events
+#[cfg(feature = "pcap-capture")]
+struct PacketProcessor {
+    config: TrafficCollectorConfig,
+    buffer: Arc<RingBuffer>,
+    /// Tags aggregated metrics when more than one worker is running
+    worker: Option<usize>,
+    /// Present only when payload capture is enabled
+    http: Option<HttpTracker>,
+    /// Present only when payload capture is enabled
+    tls: Option<TlsTracker>,
+    /// Present only when DNS decoding is enabled
+    dns: Option<DnsTracker>,
+    /// Present only when TCP analysis is enabled
+    tcp_health: Option<TcpHealthTracker>,
+    /// Shared by all workers so each source's activity is seen as a whole
+    threats: Option<Arc<Mutex<ThreatDetector>>>,
+    last_sweep: i64,
 }
 
 #[cfg(feature = "pcap-capture")]
@@ -60,40 +138,215 @@ impl PcapCollector {
             .timeout(1000)
             .open()?;
 
+        if config.capture_payload {
+            info!("Payload capture enabled, extracting HTTP/1.x transactions and TLS handshakes");
+        }
+
         Ok(Self {
+            capture,
+            interface: interface_name,
             config,
             buffer,
-            capture,
         })
     }
 
-    pub async fn run(mut self) -> Result<()> {
-        info!("Starting packet capture");
+    pub async fn run(self) -> Result<()> {
+        let worker_count = self.config.capture_workers.max(1);
+        let queue_size = (self.config.capture_queue_size / worker_count).max(1);
+        info!(
+            "Starting packet capture with {} worker(s), queue size {} per worker",
+            worker_count, queue_size
+        );
 
+        // Both directions of a flow hash to the same worker, so each worker's
+        // trackers see complete conversations
+        let threats = self.config.detection.enabled.then(|| {
+            info!("Port-scan and connection-flood detection enabled");
+            Arc::new(Mutex::new(ThreatDetector::new(self.config.detection.clone())))
+        });
+
+        let mut workers = Vec::with_capacity(worker_count);
+        for id in 0..worker_count {
+            let (tx, rx) = channel::bounded(queue_size);
+            let processor = PacketProcessor::new(
+                self.config.clone(),
+                self.buffer.clone(),
+                (worker_count > 1).then_some(id),
+                threats.clone(),
+            );
+            thread::Builder::new()
+                .name(format!("pcap-worker-{}", id))
+                .spawn(move || processor.run(rx))
+                .context("Failed to spawn packet worker thread")?;
+            workers.push(tx);
+        }
+
+        let capture_loop = CaptureLoop {
+            capture: self.capture,
+            interface: self.interface,
+            workers,
+            buffer: self.buffer,
+            queue_dropped: 0,
+            last_stats: 0,
+        };
+
+        let (done_tx, done_rx) = oneshot::channel();
+        thread::Builder::new()
+            .name("pcap-capture".to_string())
+            .spawn(move || {
+                let _ = done_tx.send(capture_loop.run());
+            })
+            .context("Failed to spawn packet capture thread")?;
+
+        done_rx
+            .await
+            .map_err(|_| anyhow!("Packet capture thread exited unexpectedly"))?
+    }
+}
+
+#[cfg(feature = "pcap-capture")]
+impl CaptureLoop {
+    fn run(mut self) -> Result<()> {
         loop {
             match self.capture.next_packet() {
                 Ok(packet) => {
-                    // Sample based on configured rate
-                    if rand::random::<f64>() > self.config.sample_rate {
-                        continue;
-                    }
-
-                    self.process_packet(&packet);
+                    let packet = CapturedPacket {
+                        timestamp: packet_timestamp_ms(packet.header),
+                        data: packet.data.to_vec(),
+                    };
+                    self.dispatch(packet)?;
                 }
                 Err(pcap::Error::TimeoutExpired) => {
                     // Normal timeout, continue
-                    tokio::task::yield_now().await;
                 }
                 Err(e) => {
                     warn!("Packet capture error: {}", e);
-                    tokio::time::sleep(tokio::time::Duration::from_secs(1)).await;
+                    thread::sleep(Duration::from_secs(1));
                 }
             }
+
+            let now = chrono::Utc::now().timestamp_millis();
+            if now - self.last_stats >= STATS_INTERVAL_MS {
+                self.report_stats(now);
+            }
+        }
+    }
+
+    /// Queue a packet for its flow's worker without blocking the capture
+    fn dispatch(&mut self, packet: CapturedPacket) -> Result<()> {
+        let index = match self.workers.len() {
+            1 => 0,
+            n => packet_flow(&packet.data).map(|flow| flow.shard(n)).unwrap_or(0),
+        };
+
+        match self.workers[index].try_send(packet) {
+            Ok(()) => Ok(()),
+            Err(TrySendError::Full(_)) => {
+                self.queue_dropped += 1;
+                Ok(())
+            }
+            Err(TrySendError::Disconnected(_)) => {
+                Err(anyhow!("Packet worker {} stopped", index))
+            }
         }
     }
 
-    fn process_packet(&self, packet: &pcap::Packet) {
-        let ethernet = match EthernetPacket::new(packet.data) {
+    /// Report kernel and queue drop counters
+    fn report_stats(&mut self, timestamp: i64) {
+        self.last_stats = timestamp;
+
+        let stats = match self.capture.stats() {
+            Ok(stats) => stats,
+            Err(e) => {
+                debug!("Failed to read capture statistics: {}", e);
+                return;
+            }
+        };
+
+        if stats.dropped > 0 || stats.if_dropped > 0 || self.queue_dropped > 0 {
+            debug!(
+                "Capture drops on {}: kernel {}, interface {}, queue {}",
+                self.interface, stats.dropped, stats.if_dropped, self.queue_dropped
+            );
+        }
+
+        let mut tags = HashMap::new();
+        tags.insert("interface".to_string(), self.interface.clone());
+
+        let counters = [
+            ("traffic.capture.received", stats.received as u64),
+            ("traffic.capture.dropped", stats.dropped as u64),
+            ("traffic.capture.if_dropped", stats.if_dropped as u64),
+            ("traffic.capture.queue_dropped", self.queue_dropped),
+        ];
+
+        for (name, value) in counters {
+            let metric = MetricEvent {
+                timestamp,
+                name: name.to_string(),
+                value: value as f64,
+                metric_type: MetricType::Counter,
+                tags: tags.clone(),
+                unit: None,
+            };
+            if let Err(e) = self.buffer.push(Event::Metric(metric)) {
+                warn!("Buffer full, dropping capture metric: {}", e);
+            }
+        }
+    }
+}
+
+#[cfg(feature = "pcap-capture")]
+impl PacketProcessor {
+    fn new(
+        config: TrafficCollectorConfig,
+        buffer: Arc<RingBuffer>,
+        worker: Option<usize>,
+        threats: Option<Arc<Mutex<ThreatDetector>>>,
+    ) -> Self {
+        let http = config.capture_payload.then(HttpTracker::new);
+        let tls = config.capture_payload.then(TlsTracker::new);
+        let dns = config.decode_dns.then(DnsTracker::new);
+        let tcp_health = config.analyze_tcp.then(TcpHealthTracker::new);
+
+        Self {
+            config,
+            buffer,
+            worker,
+            http,
+            tls,
+            dns,
+            tcp_health,
+            threats,
+            last_sweep: 0,
+        }
+    }
+
+    /// Process packets until the capture thread goes away
+    fn run(mut self, packets: Receiver<CapturedPacket>) {
+        loop {
+            match packets.recv_timeout(WORKER_IDLE_TIMEOUT) {
+                Ok(packet) => self.process_packet(&packet),
+                Err(RecvTimeoutError::Timeout) => {
+                    // Keep expiring state and reporting metrics on a quiet link
+                    let now = chrono::Utc::now().timestamp_millis();
+                    if now - self.last_sweep >= SWEEP_INTERVAL_MS {
+                        self.sweep(now);
+                    }
+                }
+                Err(RecvTimeoutError::Disconnected) => break,
+            }
+        }
+    }
+
+    fn process_packet(&mut self, packet: &CapturedPacket) {
+        let timestamp = packet.timestamp;
+
+        if timestamp - self.last_sweep >= SWEEP_INTERVAL_MS {
+            self.sweep(timestamp);
+        }
+
+        let ethernet = match EthernetPacket::new(&packet.data) {
             Some(eth) => eth,
             None => return,
         };
@@ -101,7 +354,7 @@ impl PcapCollector {
         match ethernet.get_ethertype() {
             EtherTypes::Ipv4 => {
                 if let Some(ipv4) = Ipv4Packet::new(ethernet.payload()) {
-                    self.process_ipv4(&ipv4);
+                    self.process_ipv4(&ipv4, timestamp);
                 }
             }
             _ => {
@@ -110,19 +363,72 @@ impl PcapCollector {
         }
     }
 
-    fn process_ipv4(&self, ipv4: &Ipv4Packet) {
+    /// Expire idle tracking state and report aggregated metrics
+    fn sweep(&mut self, timestamp: i64) {
+        if let Some(http) = self.http.as_mut() {
+            http.expire(timestamp);
+        }
+
+        if let Some(tls) = self.tls.as_mut() {
+            for handshake in tls.expire(timestamp) {
+                self.emit_tls_handshake(handshake);
+            }
+        }
+
+        if let Some(tcp_health) = self.tcp_health.as_mut() {
+            let summaries = tcp_health.expire(timestamp);
+            let metrics = tcp_health.metrics(timestamp);
+            for summary in summaries {
+                self.emit_tcp_summary(summary);
+            }
+            for metric in metrics {
+                self.emit_metric(metric);
+            }
+        }
+
+        if let Some(dns) = self.dns.as_mut() {
+            dns.expire(timestamp);
+            let metrics = dns.metrics(timestamp);
+            for metric in metrics {
+                self.emit_metric(metric);
+            }
+        }
+
+        if let Some(threats) = self.threats.as_ref() {
+            let alerts = threats.lock().sweep(timestamp);
+            for alert in alerts {
+                self.emit_detection(alert);
+            }
+        }
+
+        self.last_sweep = timestamp;
+    }
+
+    fn process_ipv4(&mut self, ipv4: &Ipv4Packet, timestamp: i64) {
         let src_ip = ipv4.get_source().to_string();
         let dst_ip = ipv4.get_destination().to_string();
         let protocol = ipv4.get_next_level_protocol();
-        let timestamp = chrono::Utc::now().timestamp_millis();
 
         match protocol {
             IpNextHeaderProtocols::Tcp => {
                 if let Some(tcp) = TcpPacket::new(ipv4.payload()) {
                     let src_port = tcp.get_source();
                     let dst_port = tcp.get_destination();
-                    
-                    let proto = self.identify_protocol_by_port(dst_port);
+
+                    let flow = FlowKey::new(
+                        protocol.0,
+                        SocketAddr::new(IpAddr::V4(ipv4.get_source()), src_port),
+                        SocketAddr::new(IpAddr::V4(ipv4.get_destination()), dst_port),
+                    );
+
+                    // Flow analysis needs every segment, so it runs before sampling
+                    self.analyze_tcp(&flow, &tcp, ipv4.get_total_length() as u64, timestamp);
+                    self.detect_threats(&flow, &tcp, timestamp);
+                    let proto = self.identify_protocol(&flow, &tcp, timestamp);
+                    if !self.sampled(&flow) {
+                        return;
+                    }
+
                     let mut metadata = HashMap::new();
                     metadata.insert("flags".to_string(), format!("{:?}", tcp.get_flags()));
 
@@ -136,6 +442,7 @@ impl PcapCollector {
                         bytes: ipv4.get_total_length() as u64,
                         packets: 1,
                         metadata,
+                        sample_rate: self.sample_rate(),
                     });
 
                     if let Err(e) = self.buffer.push(event) {
@@ -148,9 +455,21 @@ impl PcapCollector {
                     let src_port = udp.get_source();
                     let dst_port = udp.get_destination();
 
+                    let flow = FlowKey::new(
+                        protocol.0,
+                        SocketAddr::new(IpAddr::V4(ipv4.get_source()), src_port),
+                        SocketAddr::new(IpAddr::V4(ipv4.get_destination()), dst_port),
+                    );
+
+                    // DNS matching needs every datagram, so classify before sampling
+                    let proto = self.identify_udp_protocol(&flow, &udp, timestamp);
+                    if !self.sampled(&flow) {
+                        return;
+                    }
+
                     let event = Event::Traffic(TrafficEvent {
                         timestamp,
-                        protocol: Protocol::UDP,
+                        protocol: proto,
                         src_ip,
                         dst_ip,
                         src_port,
@@ -158,6 +477,7 @@ impl PcapCollector {
                         bytes: ipv4.get_total_length() as u64,
                         packets: 1,
                         metadata: HashMap::new(),
+                        sample_rate: self.sample_rate(),
                     });
 
                     if let Err(e) = self.buffer.push(event) {
@@ -166,6 +486,15 @@ impl PcapCollector {
                 }
             }
             IpNextHeaderProtocols::Icmp => {
+                let flow = FlowKey::new(
+                    protocol.0,
+                    SocketAddr::new(IpAddr::V4(ipv4.get_source()), 0),
+                    SocketAddr::new(IpAddr::V4(ipv4.get_destination()), 0),
+                );
+                if !self.sampled(&flow) {
+                    return;
+                }
+
                 let event = Event::Traffic(TrafficEvent {
                     timestamp,
                     protocol: Protocol::ICMP,
@@ -176,6 +505,7 @@ impl PcapCollector {
                     bytes: ipv4.get_total_length() as u64,
                     packets: 1,
                     metadata: HashMap::new(),
+                    sample_rate: self.sample_rate(),
                 });
 
                 if let Err(e) = self.buffer.push(event) {
@@ -186,6 +516,165 @@ impl PcapCollector {
         }
     }
 
+    /// Classify a TCP segment. With payload capture enabled the payload is fed
+    /// to the HTTP and TLS trackers and the protocol comes from what they
+    /// recognised; otherwise the destination port is the only hint available.
+    fn identify_protocol(&mut self, flow: &FlowKey, tcp: &TcpPacket, timestamp: i64) -> Protocol {
+        let (http, tls) = match (self.http.as_mut(), self.tls.as_mut()) {
+            (Some(http), Some(tls)) => (http, tls),
+            _ => return self.identify_protocol_by_port(flow.dst.port()),
+        };
+
+        let seq = tcp.get_sequence();
+        let transactions = http.process_segment(*flow, seq, tcp.payload(), timestamp);
+        let mut handshakes: Vec<TlsHandshake> = tls
+            .process_segment(*flow, seq, tcp.payload(), timestamp)
+            .into_iter()
+            .collect();
+
+        let protocol = if http.is_http(flow) {
+            Protocol::HTTP
+        } else {
+            tls.protocol(flow).unwrap_or(Protocol::TCP)
+        };
+
+        // Closed connections are forgotten straight away, the rest expire when idle
+        if tcp.get_flags() & TcpFlags::RST != 0 {
+            http.close(flow);
+            handshakes.extend(tls.close(flow));
+        }
+
+        for transaction in transactions {
+            debug!(
+                "HTTP {} {} -> {} ({}ms)",
+                transaction.method, transaction.path, transaction.status_code, transaction.latency_ms
+            );
+            if let Err(e) = self.buffer.push(Event::Traffic(transaction.into_event())) {
+                warn!("Buffer full, dropping HTTP event: {}", e);
+            }
+        }
+
+        for handshake in handshakes {
+            self.emit_tls_handshake(handshake);
+        }
+
+        protocol
+    }
+
+    /// Update per-flow TCP health state, reporting connections that closed
+    fn analyze_tcp(&mut self, flow: &FlowKey, tcp: &TcpPacket, wire_bytes: u64, timestamp: i64) {
+        let tcp_health = match self.tcp_health.as_mut() {
+            Some(tcp_health) => tcp_health,
+            None => return,
+        };
+
+        let flags = tcp.get_flags();
+        let segment = TcpSegment {
+            seq: tcp.get_sequence(),
+            syn: flags & TcpFlags::SYN != 0,
+            ack: flags & TcpFlags::ACK != 0,
+            fin: flags & TcpFlags::FIN != 0,
+            rst: flags & TcpFlags::RST != 0,
+            window: tcp.get_window(),
+            payload_len: tcp.payload().len(),
+        };
+
+        if let Some(summary) = tcp_health.process_segment(*flow, segment, wire_bytes, timestamp) {
+            self.emit_tcp_summary(summary);
+        }
+    }
+
+    /// Push an aggregated metric, tagged with the worker that produced it so
+    /// per-worker series for the same tags can be summed downstream
+    fn emit_metric(&self, mut metric: MetricEvent) {
+        if let Some(worker) = self.worker {
+            metric.tags.insert("capture_worker".to_string(), worker.to_string());
+        }
+        if let Err(e) = self.buffer.push(Event::Metric(metric)) {
+            warn!("Buffer full, dropping traffic metric: {}", e);
+        }
+    }
+
+    /// Feed connection attempts to the port-scan and flood detectors
+    fn detect_threats(&self, flow: &FlowKey, tcp: &TcpPacket, timestamp: i64) {
+        let threats = match self.threats.as_ref() {
+            Some(threats) => threats,
+            None => return,
+        };
+
+        let flags = tcp.get_flags();
+        if flags & TcpFlags::SYN == 0 {
+            return;
+        }
+
+        let ack = flags & TcpFlags::ACK != 0;
+        let alerts = threats.lock().process_segment(flow, true, ack, timestamp);
+        for alert in alerts {
+            self.emit_detection(alert);
+        }
+    }
+
+    fn emit_detection(&self, alert: LogEvent) {
+        warn!("{}", alert.message);
+        if let Err(e) = self.buffer.push(Event::Log(alert)) {
+            warn!("Buffer full, dropping detection event: {}", e);
+        }
+    }
+
+    fn emit_tcp_summary(&self, summary: TcpFlowSummary) {
+        if let Err(e) = self.buffer.push(Event::Traffic(summary.into_event())) {
+            warn!("Buffer full, dropping TCP flow event: {}", e);
+        }
+    }
+
+    fn emit_tls_handshake(&self, handshake: TlsHandshake) {
+        debug!(
+            "TLS handshake {} -> {} (sni: {:?})",
+            handshake.client,
+            handshake.server,
+            handshake.client_hello.as_ref().and_then(|c| c.sni.as_deref())
+        );
+        if let Err(e) = self.buffer.push(Event::Traffic(handshake.into_event())) {
+            warn!("Buffer full, dropping TLS event: {}", e);
+        }
+    }
+
+    /// Classify a UDP datagram, decoding DNS on port 53 when enabled
+    fn identify_udp_protocol(&mut self, flow: &FlowKey, udp: &UdpPacket, timestamp: i64) -> Protocol {
+        let dns = match self.dns.as_mut() {
+            Some(dns) if flow.src.port() == DNS_PORT || flow.dst.port() == DNS_PORT => dns,
+            _ => return Protocol::UDP,
+        };
+
+        let (is_dns, transaction) = dns.process_datagram(*flow, udp.payload(), timestamp);
+
+        if let Some(transaction) = transaction {
+            debug!(
+                "DNS {} {} -> {} ({}ms)",
+                transaction.qname, transaction.qtype, transaction.rcode, transaction.latency_ms
+            );
+            if let Err(e) = self.buffer.push(Event::Traffic(transaction.into_event())) {
+                warn!("Buffer full, dropping DNS event: {}", e);
+            }
+        }
+
+        if is_dns {
+            Protocol::DNS
+        } else {
+            Protocol::UDP
+        }
+    }
+
+    /// Sample whole flows at the configured rate so per-flow byte and
+    /// packet counts can be scaled back up
+    fn sampled(&self, flow: &FlowKey) -> bool {
+        flow.sampled(self.sample_rate())
+    }
+
+    fn sample_rate(&self) -> f64 {
+        self.config.sample_rate.clamp(0.0, 1.0)
+    }
+
     fn identify_protocol_by_port(&self, port: u16) -> Protocol {
         match port {
             80 => Protocol::HTTP,
@@ -194,3 +683,39 @@ impl PcapCollector {
         }
     }
 }
+
+/// Flow of an Ethernet frame, used to pick the worker that handles it
+#[cfg(feature = "pcap-capture")]
+fn packet_flow(data: &[u8]) -> Option<FlowKey> {
+    let ethernet = EthernetPacket::new(data)?;
+    if ethernet.get_ethertype() != EtherTypes::Ipv4 {
+        return None;
+    }
+
+    let ipv4 = Ipv4Packet::new(ethernet.payload())?;
+    let protocol = ipv4.get_next_level_protocol();
+    let (src_port, dst_port) = match protocol {
+        IpNextHeaderProtocols::Tcp => {
+            let tcp = TcpPacket::new(ipv4.payload())?;
+            (tcp.get_source(), tcp.get_destination())
+        }
+        IpNextHeaderProtocols::Udp => {
+            let udp = UdpPacket::new(ipv4.payload())?;
+            (udp.get_source(), udp.get_destination())
+        }
+        _ => (0, 0),
+    };
+
+    Some(FlowKey::new(
+        protocol.0,
+        SocketAddr::new(IpAddr::V4(ipv4.get_source()), src_port),
+        SocketAddr::new(IpAddr::V4(ipv4.get_destination()), dst_port),
+    ))
+}
+
+/// Capture time of a packet in milliseconds since the epoch
+#[cfg(feature = "pcap-capture")]
+#[allow(clippy::unnecessary_cast)] // time_t and suseconds_t are not i64 on every target
+fn packet_timestamp_ms(header: &pcap::PacketHeader) -> i64 {
+    header.ts.tv_sec as i64 * 1000 + header.ts.tv_usec as i64 / 1000
+}
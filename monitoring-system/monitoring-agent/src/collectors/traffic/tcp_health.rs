@@ -0,0 +1,515 @@
+use super::flow::FlowKey;
+use monitoring_common::{MetricEvent, MetricType, Protocol, TrafficEvent};
+use std::collections::HashMap;
+use std::net::SocketAddr;
+
+/// Maximum number of connections tracked at once
+const MAX_CONNECTIONS: usize = 65536;
+/// Maximum number of destination services with their own metric series
+const MAX_SERVICES: usize = 1024;
+/// Connections idle for longer than this are reported and forgotten
+const IDLE_TIMEOUT_MS: i64 = 120_000;
+/// Closed connections are remembered this long so their trailing ACKs and
+/// retransmitted FINs do not open a new one
+const CLOSED_LINGER_MS: i64 = 10_000;
+/// Service tag used once `MAX_SERVICES` is reached
+const OTHER_SERVICE: &str = "other";
+
+/// TCP header fields the analysis needs
+#[derive(Debug, Clone, Copy)]
+pub struct TcpSegment {
+    pub seq: u32,
+    pub syn: bool,
+    pub ack: bool,
+    pub fin: bool,
+    pub rst: bool,
+    pub window: u16,
+    pub payload_len: usize,
+}
+
+impl TcpSegment {
+    /// Sequence space consumed by the segment (SYN and FIN count as one)
+    fn seq_len(&self) -> u32 {
+        self.payload_len as u32 + u32::from(self.syn) + u32::from(self.fin)
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum CloseReason {
+    Fin,
+    Reset,
+    Timeout,
+}
+
+impl CloseReason {
+    fn as_str(&self) -> &'static str {
+        match self {
+            CloseReason::Fin => "closed",
+            CloseReason::Reset => "reset",
+            CloseReason::Timeout => "timeout",
+        }
+    }
+}
+
+/// Counters for one direction of a connection
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct DirectionStats {
+    pub packets: u64,
+    pub bytes: u64,
+    pub retransmissions: u64,
+    pub out_of_order: u64,
+    pub zero_windows: u64,
+    next_seq: Option<u32>,
+    fin: bool,
+}
+
+/// Health summary of a finished connection
+#[derive(Debug, Clone, PartialEq)]
+pub struct TcpFlowSummary {
+    pub client: SocketAddr,
+    pub server: SocketAddr,
+    pub start_timestamp: i64,
+    pub duration_ms: i64,
+    pub handshake_rtt_ms: Option<i64>,
+    pub client_stats: DirectionStats,
+    pub server_stats: DirectionStats,
+    pub close_reason: CloseReason,
+    /// `true` when the client sent the reset, `false` for the server
+    pub reset_by_client: Option<bool>,
+}
+
+impl TcpFlowSummary {
+    /// Traffic event with the close reason, RTT and retransmissions as metadata
+    pub fn into_event(self) -> TrafficEvent {
+        let total = |f: fn(&DirectionStats) -> u64| f(&self.client_stats) + f(&self.server_stats);
+
+        let mut metadata = HashMap::new();
+        metadata.insert("tcp.state".to_string(), self.close_reason.as_str().to_string());
+        metadata.insert("tcp.duration_ms".to_string(), self.duration_ms.to_string());
+        metadata.insert("tcp.retransmissions".to_string(), total(|s| s.retransmissions).to_string());
+        metadata.insert("tcp.out_of_order".to_string(), total(|s| s.out_of_order).to_string());
+        metadata.insert("tcp.zero_windows".to_string(), total(|s| s.zero_windows).to_string());
+        metadata.insert("tcp.client_bytes".to_string(), self.client_stats.bytes.to_string());
+        metadata.insert("tcp.server_bytes".to_string(), self.server_stats.bytes.to_string());
+        if let Some(rtt) = self.handshake_rtt_ms {
+            metadata.insert("tcp.handshake_rtt_ms".to_string(), rtt.to_string());
+        }
+        if let Some(by_client) = self.reset_by_client {
+            let side = if by_client { "client" } else { "server" };
+            metadata.insert("tcp.reset_by".to_string(), side.to_string());
+        }
+
+        TrafficEvent {
+            timestamp: self.start_timestamp,
+            protocol: Protocol::TCP,
+            src_ip: self.client.ip().to_string(),
+            dst_ip: self.server.ip().to_string(),
+            src_port: self.client.port(),
+            dst_port: self.server.port(),
+            bytes: 0,
+            packets: 0,
+            metadata,
+            sample_rate: 1.0,
+        }
+    }
+}
+
+/// Running state for one connection, keyed by its client-to-server flow
+struct Connection {
+    first_seen: i64,
+    last_seen: i64,
+    syn_timestamp: Option<i64>,
+    syn_ack_seen: bool,
+    handshake_rtt_ms: Option<i64>,
+    client: DirectionStats,
+    server: DirectionStats,
+    reset_by_client: Option<bool>,
+}
+
+impl Connection {
+    fn new(timestamp: i64) -> Self {
+        Self {
+            first_seen: timestamp,
+            last_seen: timestamp,
+            syn_timestamp: None,
+            syn_ack_seen: false,
+            handshake_rtt_ms: None,
+            client: DirectionStats::default(),
+            server: DirectionStats::default(),
+            reset_by_client: None,
+        }
+    }
+
+    fn summary(self, flow: FlowKey, close_reason: CloseReason) -> TcpFlowSummary {
+        TcpFlowSummary {
+            client: flow.src,
+            server: flow.dst,
+            start_timestamp: self.first_seen,
+            duration_ms: self.last_seen - self.first_seen,
+            handshake_rtt_ms: self.handshake_rtt_ms,
+            client_stats: self.client,
+            server_stats: self.server,
+            close_reason,
+            reset_by_client: self.reset_by_client,
+        }
+    }
+}
+
+/// Aggregated health counters for one destination service
+#[derive(Default)]
+struct ServiceStats {
+    connections: u64,
+    resets: u64,
+    retransmissions: u64,
+    out_of_order: u64,
+    zero_windows: u64,
+    window_rtt_sum: i64,
+    window_rtt_count: u64,
+}
+
+/// Per-flow TCP analysis: handshake RTT, retransmissions, reordering,
+/// zero-window advertisements and resets.
+///
+/// Loss and reordering are inferred from sequence numbers at the capture
+/// point: a segment behind the highest sequence seen counts as a
+/// retransmission, one ahead of it as out-of-order.
+#[derive(Default)]
+pub struct TcpHealthTracker {
+    connections: HashMap<FlowKey, Connection>,
+    /// When recently closed connections ended, keyed like `connections`
+    closed: HashMap<FlowKey, i64>,
+    services: HashMap<String, ServiceStats>,
+}
+
+impl TcpHealthTracker {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Feed one TCP segment, returning the summary of the connection it closed
+    pub fn process_segment(
+        &mut self,
+        flow: FlowKey,
+        segment: TcpSegment,
+        wire_bytes: u64,
+        timestamp: i64,
+    ) -> Option<TcpFlowSummary> {
+        let (key, from_client) = if self.connections.contains_key(&flow) {
+            (flow, true)
+        } else if self.connections.contains_key(&flow.reversed()) {
+            (flow.reversed(), false)
+        } else {
+            // Only a new SYN reuses the ports of a connection that just closed
+            let closed = self.closed.contains_key(&flow) || self.closed.contains_key(&flow.reversed());
+            if closed && (!segment.syn || segment.ack) {
+                return None;
+            }
+            if self.connections.len() >= MAX_CONNECTIONS {
+                return None;
+            }
+            let key = orient(&flow, &segment);
+            self.closed.remove(&key);
+            self.connections.insert(key, Connection::new(timestamp));
+            self.service_stats(&key.dst).connections += 1;
+            (key, key == flow)
+        };
+
+        let conn = self.connections.get_mut(&key)?;
+        conn.last_seen = timestamp;
+
+        let mut retransmission = false;
+        let mut out_of_order = false;
+        let mut zero_window = false;
+        let mut handshake_rtt = None;
+
+        // Handshake timing: SYN, SYN/ACK, then the client's first ACK
+        if segment.syn && !segment.ack && from_client {
+            if conn.syn_timestamp.is_some() {
+                retransmission = true;
+            } else {
+                conn.syn_timestamp = Some(timestamp);
+            }
+        } else if segment.syn && segment.ack && !from_client {
+            conn.syn_ack_seen = true;
+        } else if segment.ack && from_client && conn.syn_ack_seen && conn.handshake_rtt_ms.is_none() {
+            if let Some(syn) = conn.syn_timestamp {
+                handshake_rtt = Some((timestamp - syn).max(0));
+                conn.handshake_rtt_ms = handshake_rtt;
+            }
+        }
+
+        let side = if from_client {
+            &mut conn.client
+        } else {
+            &mut conn.server
+        };
+        side.packets += 1;
+        side.bytes += wire_bytes;
+
+        let seq_len = segment.seq_len();
+        if seq_len > 0 && !segment.rst {
+            let end = segment.seq.wrapping_add(seq_len);
+            match side.next_seq {
+                None => side.next_seq = Some(end),
+                Some(expected) => {
+                    let offset = segment.seq.wrapping_sub(expected) as i32;
+                    if offset < 0 {
+                        retransmission |= !segment.syn;
+                    } else if offset > 0 {
+                        out_of_order = true;
+                    }
+                    if end.wrapping_sub(expected) as i32 > 0 {
+                        side.next_seq = Some(end);
+                    }
+                }
+            }
+        }
+
+        if segment.window == 0 && !segment.rst && !segment.syn {
+            zero_window = true;
+        }
+
+        side.retransmissions += u64::from(retransmission);
+        side.out_of_order += u64::from(out_of_order);
+        side.zero_windows += u64::from(zero_window);
+        if segment.fin {
+            side.fin = true;
+        }
+
+        let close_reason = if segment.rst {
+            conn.reset_by_client = Some(from_client);
+            Some(CloseReason::Reset)
+        } else if conn.client.fin && conn.server.fin {
+            Some(CloseReason::Fin)
+        } else {
+            None
+        };
+
+        let service = self.service_stats(&key.dst);
+        service.retransmissions += u64::from(retransmission);
+        service.out_of_order += u64::from(out_of_order);
+        service.zero_windows += u64::from(zero_window);
+        if let Some(rtt) = handshake_rtt {
+            service.window_rtt_sum += rtt;
+            service.window_rtt_count += 1;
+        }
+        if close_reason == Some(CloseReason::Reset) {
+            service.resets += 1;
+        }
+
+        let close_reason = close_reason?;
+        let conn = self.connections.remove(&key)?;
+        if self.closed.len() < MAX_CONNECTIONS {
+            self.closed.insert(key, timestamp);
+        }
+        Some(conn.summary(key, close_reason))
+    }
+
+    /// Report and forget connections that have been idle too long
+    pub fn expire(&mut self, now: i64) -> Vec<TcpFlowSummary> {
+        self.closed.retain(|_, closed_at| now - *closed_at <= CLOSED_LINGER_MS);
+
+        let expired: Vec<FlowKey> = self
+            .connections
+            .iter()
+            .filter(|(_, conn)| now - conn.last_seen > IDLE_TIMEOUT_MS)
+            .map(|(flow, _)| *flow)
+            .collect();
+
+        expired
+            .into_iter()
+            .filter_map(|flow| {
+                self.connections
+                    .remove(&flow)
+                    .map(|conn| conn.summary(flow, CloseReason::Timeout))
+            })
+            .collect()
+    }
+
+    /// Build per-service metric events and start a new RTT window
+    pub fn metrics(&mut self, timestamp: i64) -> Vec<MetricEvent> {
+        let mut metrics = Vec::new();
+
+        for (service, stats) in self.services.iter_mut() {
+            let tags = HashMap::from([("service".to_string(), service.clone())]);
+            let counters = [
+                ("traffic.tcp.connections", stats.connections),
+                ("traffic.tcp.resets", stats.resets),
+                ("traffic.tcp.retransmissions", stats.retransmissions),
+                ("traffic.tcp.out_of_order", stats.out_of_order),
+                ("traffic.tcp.zero_windows", stats.zero_windows),
+            ];
+
+            for (name, value) in counters {
+                metrics.push(MetricEvent {
+                    timestamp,
+                    name: name.to_string(),
+                    value: value as f64,
+                    metric_type: MetricType::Counter,
+                    tags: tags.clone(),
+                    unit: None,
+                });
+            }
+
+            if stats.window_rtt_count > 0 {
+                metrics.push(MetricEvent {
+                    timestamp,
+                    name: "traffic.tcp.handshake_rtt".to_string(),
+                    value: stats.window_rtt_sum as f64 / stats.window_rtt_count as f64,
+                    metric_type: MetricType::Gauge,
+                    tags,
+                    unit: Some("ms".to_string()),
+                });
+                stats.window_rtt_sum = 0;
+                stats.window_rtt_count = 0;
+            }
+        }
+
+        metrics
+    }
+
+    fn service_stats(&mut self, server: &SocketAddr) -> &mut ServiceStats {
+        let key = server.to_string();
+        let key = if self.services.contains_key(&key) || self.services.len() < MAX_SERVICES {
+            key
+        } else {
+            OTHER_SERVICE.to_string()
+        };
+        self.services.entry(key).or_default()
+    }
+}
+
+/// Decide which side of a newly seen connection is the client. The SYN
+/// settles it; for connections already open when capture started the
+/// higher (ephemeral) port is assumed to be the client.
+fn orient(flow: &FlowKey, segment: &TcpSegment) -> FlowKey {
+    let src_is_client = match (segment.syn, segment.ack) {
+        (true, false) => true,
+        (true, true) => false,
+        _ => flow.src.port() >= flow.dst.port(),
+    };
+
+    if src_is_client {
+        *flow
+    } else {
+        flow.reversed()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn flow(src: &str, dst: &str) -> FlowKey {
+        FlowKey::new(6, src.parse().unwrap(), dst.parse().unwrap())
+    }
+
+    fn segment(seq: u32, flags: &str, payload_len: usize) -> TcpSegment {
+        TcpSegment {
+            seq,
+            syn: flags.contains('S'),
+            ack: flags.contains('A'),
+            fin: flags.contains('F'),
+            rst: flags.contains('R'),
+            window: if flags.contains('Z') { 0 } else { 65535 },
+            payload_len,
+        }
+    }
+
+    #[test]
+    fn test_handshake_rtt_and_fin_close() {
+        let mut tracker = TcpHealthTracker::new();
+        let c2s = flow("10.0.0.1:50000", "10.0.0.2:443");
+        let s2c = c2s.reversed();
+
+        assert!(tracker.process_segment(c2s, segment(100, "S", 0), 60, 1000).is_none());
+        assert!(tracker.process_segment(s2c, segment(900, "SA", 0), 60, 1020).is_none());
+        assert!(tracker.process_segment(c2s, segment(101, "A", 0), 52, 1041).is_none());
+        assert!(tracker.process_segment(c2s, segment(101, "A", 100), 152, 1050).is_none());
+        assert!(tracker.process_segment(s2c, segment(901, "FA", 0), 52, 1100).is_none());
+
+        let summary = tracker.process_segment(c2s, segment(201, "FA", 0), 52, 1110).unwrap();
+        assert_eq!(summary.client, c2s.src);
+        assert_eq!(summary.handshake_rtt_ms, Some(41));
+        assert_eq!(summary.close_reason, CloseReason::Fin);
+        assert_eq!(summary.client_stats.retransmissions, 0);
+        assert_eq!(summary.duration_ms, 110);
+
+        let metrics = tracker.metrics(2000);
+        let rtt = metrics.iter().find(|m| m.name == "traffic.tcp.handshake_rtt").unwrap();
+        assert_eq!(rtt.value, 41.0);
+        assert_eq!(rtt.tags["service"], "10.0.0.2:443");
+    }
+
+    #[test]
+    fn test_final_ack_after_close_is_ignored() {
+        let mut tracker = TcpHealthTracker::new();
+        let c2s = flow("10.0.0.1:50000", "10.0.0.2:443");
+        let s2c = c2s.reversed();
+
+        tracker.process_segment(c2s, segment(100, "S", 0), 60, 0);
+        tracker.process_segment(s2c, segment(900, "SA", 0), 60, 1);
+        tracker.process_segment(c2s, segment(101, "FA", 0), 52, 2);
+        assert!(tracker.process_segment(s2c, segment(901, "FA", 0), 52, 3).is_some());
+
+        assert!(tracker.process_segment(c2s, segment(102, "A", 0), 52, 4).is_none());
+        assert!(tracker.expire(IDLE_TIMEOUT_MS + 10).is_empty());
+        let metrics = tracker.metrics(5);
+        let connections = metrics.iter().find(|m| m.name == "traffic.tcp.connections").unwrap();
+        assert_eq!(connections.value, 1.0);
+
+        // A new SYN on the same ports is a new connection
+        tracker.process_segment(c2s, segment(5000, "S", 0), 60, 6);
+        assert_eq!(tracker.connections.len(), 1);
+    }
+
+    #[test]
+    fn test_retransmission_and_out_of_order() {
+        let mut tracker = TcpHealthTracker::new();
+        let c2s = flow("10.0.0.1:50000", "10.0.0.2:80");
+
+        tracker.process_segment(c2s, segment(1000, "A", 100), 152, 0);
+        tracker.process_segment(c2s, segment(1000, "A", 100), 152, 1);
+        tracker.process_segment(c2s, segment(1300, "A", 100), 152, 2);
+        tracker.process_segment(c2s, segment(1400, "AZ", 0), 52, 3);
+
+        let summary = tracker.expire(IDLE_TIMEOUT_MS + 10).pop().unwrap();
+        assert_eq!(summary.close_reason, CloseReason::Timeout);
+        assert_eq!(summary.client_stats.retransmissions, 1);
+        assert_eq!(summary.client_stats.out_of_order, 1);
+        assert_eq!(summary.client_stats.zero_windows, 1);
+    }
+
+    #[test]
+    fn test_reset_close() {
+        let mut tracker = TcpHealthTracker::new();
+        let c2s = flow("10.0.0.1:50000", "10.0.0.2:5432");
+
+        tracker.process_segment(c2s, segment(1, "S", 0), 60, 0);
+        let summary = tracker
+            .process_segment(c2s.reversed(), segment(0, "RA", 0), 40, 1)
+            .unwrap();
+
+        assert_eq!(summary.close_reason, CloseReason::Reset);
+        assert_eq!(summary.reset_by_client, Some(false));
+
+        let event = summary.into_event();
+        assert_eq!(event.metadata["tcp.state"], "reset");
+        assert_eq!(event.metadata["tcp.reset_by"], "server");
+
+        let metrics = tracker.metrics(2);
+        let resets = metrics.iter().find(|m| m.name == "traffic.tcp.resets").unwrap();
+        assert_eq!(resets.value, 1.0);
+    }
+
+    #[test]
+    fn test_orientation_without_handshake() {
+        let mut tracker = TcpHealthTracker::new();
+        let s2c = flow("10.0.0.2:443", "10.0.0.1:50000");
+
+        tracker.process_segment(s2c, segment(1, "A", 10), 62, 0);
+        let summary = tracker.expire(IDLE_TIMEOUT_MS + 1).pop().unwrap();
+        assert_eq!(summary.client, s2c.dst);
+        assert_eq!(summary.server_stats.packets, 1);
+    }
+}
@@ -0,0 +1,403 @@
+use super::flow::FlowKey;
+use crate::config::ThreatDetectionConfig;
+use monitoring_common::{LogEvent, LogLevel};
+use std::collections::{HashMap, VecDeque};
+use std::hash::Hash;
+use std::net::{IpAddr, SocketAddr};
+
+/// Source used for detection log events
+const DETECTION_SOURCE: &str = "traffic.detection";
+/// Maximum number of source IPs tracked at once
+const MAX_SOURCES: usize = 16384;
+/// Connection attempts remembered per source, oldest are forgotten first
+const MAX_ATTEMPTS_PER_SOURCE: usize = 10000;
+/// Fan-out is compared against its baseline at most this often
+const EVALUATION_INTERVAL_MS: i64 = 10_000;
+/// Weight of the latest window in the fan-out baseline
+const BASELINE_WEIGHT: f64 = 0.2;
+/// Detections this many times over their threshold are raised as critical
+const CRITICAL_MULTIPLIER: usize = 4;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Detection {
+    HorizontalScan,
+    VerticalScan,
+    SynFlood,
+    FanOutSpike,
+}
+
+impl Detection {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Detection::HorizontalScan => "horizontal_scan",
+            Detection::VerticalScan => "vertical_scan",
+            Detection::SynFlood => "syn_flood",
+            Detection::FanOutSpike => "fanout_spike",
+        }
+    }
+}
+
+/// Sliding-window activity of one source IP
+#[derive(Debug, Default)]
+struct SourceActivity {
+    /// Connection attempts (SYN without ACK) in arrival order
+    attempts: VecDeque<(i64, SocketAddr)>,
+    /// SYN-ACKs received by the source
+    answered: VecDeque<i64>,
+    ports_by_host: HashMap<IpAddr, HashMap<u16, u32>>,
+    hosts_by_port: HashMap<u16, HashMap<IpAddr, u32>>,
+    targets: HashMap<SocketAddr, u32>,
+    fanout_baseline: f64,
+    last_alerts: HashMap<Detection, (i64, LogLevel)>,
+    last_seen: i64,
+}
+
+impl SourceActivity {
+    fn record_attempt(&mut self, dst: SocketAddr, timestamp: i64) {
+        if self.attempts.len() >= MAX_ATTEMPTS_PER_SOURCE {
+            self.pop_attempt();
+        }
+        self.attempts.push_back((timestamp, dst));
+        *self.ports_by_host.entry(dst.ip()).or_default().entry(dst.port()).or_default() += 1;
+        *self.hosts_by_port.entry(dst.port()).or_default().entry(dst.ip()).or_default() += 1;
+        *self.targets.entry(dst).or_default() += 1;
+    }
+
+    fn pop_attempt(&mut self) {
+        if let Some((_, dst)) = self.attempts.pop_front() {
+            release(&mut self.ports_by_host, dst.ip(), dst.port());
+            release(&mut self.hosts_by_port, dst.port(), dst.ip());
+            if let Some(count) = self.targets.get_mut(&dst) {
+                *count -= 1;
+                if *count == 0 {
+                    self.targets.remove(&dst);
+                }
+            }
+        }
+    }
+
+    /// Forget everything older than the window
+    fn prune(&mut self, cutoff: i64) {
+        while self.attempts.front().is_some_and(|(ts, _)| *ts < cutoff) {
+            self.pop_attempt();
+        }
+        while self.answered.front().is_some_and(|ts| *ts < cutoff) {
+            self.answered.pop_front();
+        }
+    }
+
+    /// Whether a detection should be raised again. Repeats are suppressed for
+    /// a window unless the severity has gone up.
+    fn should_alert(&mut self, detection: Detection, level: LogLevel, timestamp: i64, window_ms: i64) -> bool {
+        if let Some((last, last_level)) = self.last_alerts.get(&detection) {
+            let escalated = level == LogLevel::Critical && *last_level != LogLevel::Critical;
+            if timestamp - last < window_ms && !escalated {
+                return false;
+            }
+        }
+        self.last_alerts.insert(detection, (timestamp, level));
+        true
+    }
+}
+
+/// Decrement a nested counter, removing entries that reach zero
+fn release<K: Hash + Eq, V: Hash + Eq>(map: &mut HashMap<K, HashMap<V, u32>>, outer: K, inner: V) {
+    if let Some(counts) = map.get_mut(&outer) {
+        if let Some(count) = counts.get_mut(&inner) {
+            *count -= 1;
+            if *count == 0 {
+                counts.remove(&inner);
+            }
+        }
+        if counts.is_empty() {
+            map.remove(&outer);
+        }
+    }
+}
+
+/// Detects port scans, SYN floods and fan-out spikes per source IP from the
+/// connection attempts seen on the wire
+pub struct ThreatDetector {
+    config: ThreatDetectionConfig,
+    sources: HashMap<IpAddr, SourceActivity>,
+    last_evaluation: i64,
+}
+
+impl ThreatDetector {
+    pub fn new(config: ThreatDetectionConfig) -> Self {
+        Self {
+            config,
+            sources: HashMap::new(),
+            last_evaluation: 0,
+        }
+    }
+
+    fn window_ms(&self) -> i64 {
+        self.config.window_secs.max(1) as i64 * 1000
+    }
+
+    /// Feed a TCP segment's handshake flags, returning any new detections
+    pub fn process_segment(&mut self, flow: &FlowKey, syn: bool, ack: bool, timestamp: i64) -> Vec<LogEvent> {
+        if !syn {
+            return Vec::new();
+        }
+
+        let window_ms = self.window_ms();
+        if ack {
+            // SYN-ACK answers an attempt made by the destination
+            if let Some(activity) = self.sources.get_mut(&flow.dst.ip()) {
+                activity.answered.push_back(timestamp);
+                if activity.answered.len() > MAX_ATTEMPTS_PER_SOURCE {
+                    activity.answered.pop_front();
+                }
+            }
+            return Vec::new();
+        }
+
+        let src = flow.src.ip();
+        if !self.sources.contains_key(&src) && self.sources.len() >= MAX_SOURCES {
+            return Vec::new();
+        }
+
+        let activity = self.sources.entry(src).or_default();
+        activity.prune(timestamp - window_ms);
+        activity.record_attempt(flow.dst, timestamp);
+        activity.last_seen = timestamp;
+
+        let mut alerts = Vec::new();
+        let window_secs = self.config.window_secs;
+
+        let hosts = activity.hosts_by_port.get(&flow.dst.port()).map_or(0, |h| h.len());
+        if let Some(level) = severity(hosts, self.config.horizontal_scan_hosts) {
+            if activity.should_alert(Detection::HorizontalScan, level.clone(), timestamp, window_ms) {
+                let mut event = detection_event(Detection::HorizontalScan, level, src, hosts, window_secs, timestamp);
+                event.message = format!(
+                    "Horizontal port scan from {}: {} hosts probed on port {} in {}s",
+                    src, hosts, flow.dst.port(), window_secs
+                );
+                event.fields.insert("dst_port".to_string(), flow.dst.port().to_string());
+                alerts.push(event);
+            }
+        }
+
+        let ports = activity.ports_by_host.get(&flow.dst.ip()).map_or(0, |p| p.len());
+        if let Some(level) = severity(ports, self.config.vertical_scan_ports) {
+            if activity.should_alert(Detection::VerticalScan, level.clone(), timestamp, window_ms) {
+                let mut event = detection_event(Detection::VerticalScan, level, src, ports, window_secs, timestamp);
+                event.message = format!(
+                    "Vertical port scan from {}: {} ports probed on {} in {}s",
+                    src, ports, flow.dst.ip(), window_secs
+                );
+                event.fields.insert("dst_ip".to_string(), flow.dst.ip().to_string());
+                alerts.push(event);
+            }
+        }
+
+        let syns = activity.attempts.len();
+        let answered = activity.answered.len();
+        if answered * 2 < syns {
+            if let Some(level) = severity(syns, self.config.syn_flood_syns) {
+                if activity.should_alert(Detection::SynFlood, level.clone(), timestamp, window_ms) {
+                    let mut event = detection_event(Detection::SynFlood, level, src, activity.targets.len(), window_secs, timestamp);
+                    event.message = format!(
+                        "SYN flood from {}: {} SYNs, {} answered, to {} targets in {}s",
+                        src, syns, answered, activity.targets.len(), window_secs
+                    );
+                    event.fields.insert("syn_count".to_string(), syns.to_string());
+                    event.fields.insert("answered_count".to_string(), answered.to_string());
+                    alerts.push(event);
+                }
+            }
+        }
+
+        alerts
+    }
+
+    /// Expire old activity and compare each source's fan-out with its usual
+    /// level. Runs at most once per evaluation interval.
+    pub fn sweep(&mut self, timestamp: i64) -> Vec<LogEvent> {
+        if timestamp - self.last_evaluation < EVALUATION_INTERVAL_MS {
+            return Vec::new();
+        }
+        self.last_evaluation = timestamp;
+
+        let window_ms = self.window_ms();
+        let window_secs = self.config.window_secs;
+        let min_targets = self.config.fanout_min_targets;
+        let factor = self.config.fanout_factor;
+        let mut alerts = Vec::new();
+
+        self.sources.retain(|src, activity| {
+            activity.prune(timestamp - window_ms);
+            if activity.attempts.is_empty() && timestamp - activity.last_seen > window_ms {
+                return false;
+            }
+
+            let targets = activity.targets.len();
+            let baseline = activity.fanout_baseline;
+            if targets as f64 >= factor * baseline.max(1.0) {
+                if let Some(level) = severity(targets, min_targets) {
+                    if activity.should_alert(Detection::FanOutSpike, level.clone(), timestamp, window_ms) {
+                        let mut event = detection_event(Detection::FanOutSpike, level, *src, targets, window_secs, timestamp);
+                        event.message = format!(
+                            "Fan-out spike from {}: {} destinations in {}s (usual {:.0})",
+                            src, targets, window_secs, baseline
+                        );
+                        event.fields.insert("baseline".to_string(), format!("{:.1}", baseline));
+                        alerts.push(event);
+                    }
+                }
+            }
+            activity.fanout_baseline = baseline * (1.0 - BASELINE_WEIGHT) + targets as f64 * BASELINE_WEIGHT;
+
+            true
+        });
+
+        alerts
+    }
+}
+
+/// Warning at the threshold, critical well beyond it
+fn severity(count: usize, threshold: usize) -> Option<LogLevel> {
+    let threshold = threshold.max(1);
+    if count >= threshold * CRITICAL_MULTIPLIER {
+        Some(LogLevel::Critical)
+    } else if count >= threshold {
+        Some(LogLevel::Warning)
+    } else {
+        None
+    }
+}
+
+fn detection_event(
+    detection: Detection,
+    level: LogLevel,
+    src: IpAddr,
+    target_count: usize,
+    window_secs: u64,
+    timestamp: i64,
+) -> LogEvent {
+    let mut fields = HashMap::new();
+    fields.insert("detection".to_string(), detection.as_str().to_string());
+    fields.insert("src_ip".to_string(), src.to_string());
+    fields.insert("target_count".to_string(), target_count.to_string());
+    fields.insert("window_secs".to_string(), window_secs.to_string());
+
+    LogEvent {
+        timestamp,
+        source: DETECTION_SOURCE.to_string(),
+        level,
+        message: String::new(),
+        fields,
+        tags: vec!["security".to_string()],
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn detector() -> ThreatDetector {
+        ThreatDetector::new(ThreatDetectionConfig {
+            enabled: true,
+            window_secs: 60,
+            horizontal_scan_hosts: 10,
+            vertical_scan_ports: 10,
+            syn_flood_syns: 50,
+            fanout_min_targets: 30,
+            fanout_factor: 5.0,
+        })
+    }
+
+    fn syn(src: &str, dst: &str) -> FlowKey {
+        FlowKey::new(6, src.parse().unwrap(), dst.parse().unwrap())
+    }
+
+    #[test]
+    fn test_vertical_scan() {
+        let mut detector = detector();
+        let mut alerts = Vec::new();
+        for port in 1..=40u16 {
+            let flow = syn("10.0.0.9:40000", &format!("10.0.0.1:{}", port));
+            alerts.extend(detector.process_segment(&flow, true, false, 1000 + port as i64));
+        }
+
+        let levels: Vec<_> = alerts
+            .iter()
+            .filter(|a| a.fields["detection"] == "vertical_scan")
+            .map(|a| a.level.clone())
+            .collect();
+        assert_eq!(levels, vec![LogLevel::Warning, LogLevel::Critical]);
+        assert_eq!(alerts[0].fields["src_ip"], "10.0.0.9");
+        assert_eq!(alerts[0].fields["target_count"], "10");
+        assert_eq!(alerts[0].fields["dst_ip"], "10.0.0.1");
+        assert!(alerts.iter().all(|a| a.fields["detection"] != "horizontal_scan"));
+    }
+
+    #[test]
+    fn test_horizontal_scan_respects_window() {
+        let mut detector = detector();
+
+        // Spread out beyond the window, so never more than a few hosts at once
+        for host in 1..=20 {
+            let flow = syn("10.0.0.9:40000", &format!("10.0.1.{}:22", host));
+            let alerts = detector.process_segment(&flow, true, false, host * 30_000);
+            assert!(alerts.is_empty());
+        }
+
+        let mut alerts = Vec::new();
+        for host in 1..=10 {
+            let flow = syn("10.0.0.9:40000", &format!("10.0.2.{}:22", host));
+            alerts.extend(detector.process_segment(&flow, true, false, 1_000_000 + host));
+        }
+        assert_eq!(alerts.len(), 1);
+        assert_eq!(alerts[0].fields["detection"], "horizontal_scan");
+        assert_eq!(alerts[0].fields["dst_port"], "22");
+        assert_eq!(alerts[0].level, LogLevel::Warning);
+    }
+
+    #[test]
+    fn test_syn_flood_only_when_unanswered() {
+        let mut answered = detector();
+        let mut flooded = detector();
+        let mut alerts = (Vec::new(), Vec::new());
+
+        for i in 0..100 {
+            let flow = syn("10.0.0.9:40000", "10.0.0.1:80");
+            alerts.0.extend(answered.process_segment(&flow, true, false, i));
+            answered.process_segment(&flow.reversed(), true, true, i);
+            alerts.1.extend(flooded.process_segment(&flow, true, false, i));
+        }
+
+        assert!(alerts.0.is_empty());
+        assert_eq!(alerts.1.len(), 1);
+        assert_eq!(alerts.1[0].fields["detection"], "syn_flood");
+        assert_eq!(alerts.1[0].fields["answered_count"], "0");
+    }
+
+    #[test]
+    fn test_fanout_spike_against_baseline() {
+        let mut detector = detector();
+
+        // A steady client talking to a handful of services
+        let mut now = 0;
+        for round in 0..10 {
+            now = round * 10_000 + 10_000;
+            for port in 0..5 {
+                let flow = syn("10.0.0.9:40000", &format!("10.0.3.{}:443", port));
+                detector.process_segment(&flow, true, false, now);
+            }
+            assert!(detector.sweep(now).is_empty());
+        }
+
+        for host in 0..40 {
+            let flow = syn("10.0.0.9:40000", &format!("10.0.4.{}:{}", host, 1000 + host));
+            detector.process_segment(&flow, true, false, now + 1);
+        }
+
+        let alerts = detector.sweep(now + EVALUATION_INTERVAL_MS);
+        assert_eq!(alerts.len(), 1);
+        assert_eq!(alerts[0].fields["detection"], "fanout_spike");
+        assert_eq!(alerts[0].level, LogLevel::Warning);
+    }
+}
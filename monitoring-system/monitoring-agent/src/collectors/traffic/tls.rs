@@ -0,0 +1,823 @@
+use super::flow::FlowKey;
+use md5::Md5;
+use monitoring_common::{Protocol, TrafficEvent};
+use sha2::{Digest, Sha256};
+use std::collections::HashMap;
+use std::net::SocketAddr;
+
+/// Maximum bytes buffered per direction while waiting for a complete hello
+const MAX_HANDSHAKE_BYTES: usize = 16 * 1024;
+/// Maximum number of directed streams tracked at once
+const MAX_STREAMS: usize = 16384;
+/// Streams idle for longer than this are forgotten
+const IDLE_TIMEOUT_MS: i64 = 60_000;
+
+const CONTENT_TYPE_HANDSHAKE: u8 = 22;
+const HANDSHAKE_CLIENT_HELLO: u8 = 1;
+const HANDSHAKE_SERVER_HELLO: u8 = 2;
+
+const EXT_SERVER_NAME: u16 = 0x0000;
+const EXT_SUPPORTED_GROUPS: u16 = 0x000a;
+const EXT_EC_POINT_FORMATS: u16 = 0x000b;
+const EXT_SIGNATURE_ALGORITHMS: u16 = 0x000d;
+const EXT_ALPN: u16 = 0x0010;
+const EXT_SUPPORTED_VERSIONS: u16 = 0x002b;
+
+/// Fields of a ClientHello needed for reporting and fingerprinting
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct ClientHello {
+    pub legacy_version: u16,
+    pub cipher_suites: Vec<u16>,
+    pub extensions: Vec<u16>,
+    pub supported_groups: Vec<u16>,
+    pub ec_point_formats: Vec<u8>,
+    pub signature_algorithms: Vec<u16>,
+    pub supported_versions: Vec<u16>,
+    pub alpn: Vec<String>,
+    pub sni: Option<String>,
+}
+
+impl ClientHello {
+    /// Highest version the client offered, ignoring GREASE values
+    pub fn max_version(&self) -> u16 {
+        self.supported_versions
+            .iter()
+            .copied()
+            .filter(|v| !is_grease(*v))
+            .max()
+            .unwrap_or(self.legacy_version)
+    }
+
+    /// JA3 fingerprint string (before hashing)
+    pub fn ja3(&self) -> String {
+        fn join<T: ToString>(values: impl Iterator<Item = T>) -> String {
+            values.map(|v| v.to_string()).collect::<Vec<_>>().join("-")
+        }
+
+        format!(
+            "{},{},{},{},{}",
+            self.legacy_version,
+            join(self.cipher_suites.iter().filter(|v| !is_grease(**v))),
+            join(self.extensions.iter().filter(|v| !is_grease(**v))),
+            join(self.supported_groups.iter().filter(|v| !is_grease(**v))),
+            join(self.ec_point_formats.iter()),
+        )
+    }
+
+    /// MD5 hash of the JA3 string, as published by most JA3 databases
+    pub fn ja3_hash(&self) -> String {
+        hex::encode(Md5::digest(self.ja3().as_bytes()))
+    }
+
+    /// JA4 fingerprint for TLS over TCP
+    pub fn ja4(&self) -> String {
+        let ciphers: Vec<u16> = self
+            .cipher_suites
+            .iter()
+            .copied()
+            .filter(|v| !is_grease(*v))
+            .collect();
+        let extensions: Vec<u16> = self
+            .extensions
+            .iter()
+            .copied()
+            .filter(|v| !is_grease(*v))
+            .collect();
+
+        let version = match self.max_version() {
+            0x0304 => "13",
+            0x0303 => "12",
+            0x0302 => "11",
+            0x0301 => "10",
+            0x0300 => "s3",
+            _ => "00",
+        };
+        let sni = if self.sni.is_some() { 'd' } else { 'i' };
+        let alpn = self.alpn.first().map_or("00".to_string(), |p| ja4_alpn(p));
+
+        let part_a = format!(
+            "t{}{}{:02}{:02}{}",
+            version,
+            sni,
+            ciphers.len().min(99),
+            extensions.len().min(99),
+            alpn
+        );
+
+        let mut sorted_ciphers = ciphers;
+        sorted_ciphers.sort_unstable();
+        let part_b = ja4_hash(&hex_list(&sorted_ciphers));
+
+        let mut sorted_extensions: Vec<u16> = extensions
+            .into_iter()
+            .filter(|e| *e != EXT_SERVER_NAME && *e != EXT_ALPN)
+            .collect();
+        sorted_extensions.sort_unstable();
+        let mut ext_input = hex_list(&sorted_extensions);
+        if !self.signature_algorithms.is_empty() {
+            ext_input.push('_');
+            ext_input.push_str(&hex_list(&self.signature_algorithms));
+        }
+        let part_c = ja4_hash(&ext_input);
+
+        format!("{}_{}_{}", part_a, part_b, part_c)
+    }
+}
+
+/// Fields of a ServerHello
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct ServerHello {
+    pub legacy_version: u16,
+    pub cipher_suite: u16,
+    pub selected_version: Option<u16>,
+    pub alpn: Option<String>,
+}
+
+impl ServerHello {
+    /// Negotiated version, honouring the TLS 1.3 supported_versions extension
+    pub fn version(&self) -> u16 {
+        self.selected_version.unwrap_or(self.legacy_version)
+    }
+}
+
+/// Handshake metadata for one connection
+#[derive(Debug, Clone, PartialEq)]
+pub struct TlsHandshake {
+    pub client: SocketAddr,
+    pub server: SocketAddr,
+    pub timestamp: i64,
+    pub client_hello: Option<ClientHello>,
+    pub server_hello: Option<ServerHello>,
+}
+
+impl TlsHandshake {
+    /// HTTPS when HTTP was offered through ALPN, plain TLS otherwise
+    pub fn protocol(&self) -> Protocol {
+        let offered = self.client_hello.iter().flat_map(|c| c.alpn.iter());
+        let selected = self.server_hello.iter().flat_map(|s| s.alpn.iter());
+        if offered.chain(selected).any(|p| is_http_alpn(p)) {
+            Protocol::HTTPS
+        } else {
+            Protocol::TLS
+        }
+    }
+
+    /// Traffic event with the SNI, cipher and JA3/JA4 fingerprints as metadata
+    pub fn into_event(self) -> TrafficEvent {
+        let protocol = self.protocol();
+        let mut metadata = HashMap::new();
+
+        if let Some(client) = &self.client_hello {
+            if let Some(sni) = &client.sni {
+                metadata.insert("tls.sni".to_string(), sni.clone());
+            }
+            metadata.insert("tls.client_version".to_string(), version_name(client.max_version()));
+            if !client.alpn.is_empty() {
+                metadata.insert("tls.client_alpn".to_string(), client.alpn.join(","));
+            }
+            metadata.insert("tls.ja3".to_string(), client.ja3());
+            metadata.insert("tls.ja3_hash".to_string(), client.ja3_hash());
+            metadata.insert("tls.ja4".to_string(), client.ja4());
+        }
+
+        if let Some(server) = &self.server_hello {
+            let version = server.version();
+            metadata.insert("tls.version".to_string(), version_name(version));
+            metadata.insert("tls.deprecated".to_string(), (version < 0x0303).to_string());
+            metadata.insert("tls.cipher".to_string(), cipher_name(server.cipher_suite));
+            if let Some(alpn) = &server.alpn {
+                metadata.insert("tls.alpn".to_string(), alpn.clone());
+            }
+        }
+
+        TrafficEvent {
+            timestamp: self.timestamp,
+            protocol,
+            src_ip: self.client.ip().to_string(),
+            dst_ip: self.server.ip().to_string(),
+            src_port: self.client.port(),
+            dst_port: self.server.port(),
+            bytes: 0,
+            packets: 0,
+            metadata,
+            sample_rate: 1.0,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum StreamState {
+    Buffering,
+    NotTls,
+    Done,
+}
+
+/// Start of one direction of a TCP connection, kept until its hello is parsed
+struct HandshakeStream {
+    state: StreamState,
+    /// Set once the parsed hello offered or selected an HTTP ALPN protocol
+    http_alpn: bool,
+    next_seq: Option<u32>,
+    data: Vec<u8>,
+    last_seen: i64,
+}
+
+impl HandshakeStream {
+    fn new(timestamp: i64) -> Self {
+        Self {
+            state: StreamState::Buffering,
+            http_alpn: false,
+            next_seq: None,
+            data: Vec::new(),
+            last_seen: timestamp,
+        }
+    }
+
+    fn append(&mut self, seq: u32, payload: &[u8], timestamp: i64) {
+        self.last_seen = timestamp;
+
+        if let Some(expected) = self.next_seq {
+            let offset = seq.wrapping_sub(expected) as i32;
+            if offset < 0 {
+                return;
+            }
+            if offset > 0 {
+                // A hole before the hello completed; give up on this stream
+                self.state = StreamState::NotTls;
+                self.data = Vec::new();
+                return;
+            }
+        }
+        self.next_seq = Some(seq.wrapping_add(payload.len() as u32));
+
+        self.data.extend_from_slice(payload);
+        if self.data.len() > MAX_HANDSHAKE_BYTES {
+            self.state = StreamState::NotTls;
+            self.data = Vec::new();
+        }
+    }
+}
+
+/// Pending handshake state for one connection, keyed by client-to-server flow
+struct PendingHandshake {
+    timestamp: i64,
+    client_hello: Option<ClientHello>,
+}
+
+/// Parses ClientHello and ServerHello messages from TCP payloads
+#[derive(Default)]
+pub struct TlsTracker {
+    streams: HashMap<FlowKey, HandshakeStream>,
+    pending: HashMap<FlowKey, PendingHandshake>,
+}
+
+impl TlsTracker {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Feed one TCP segment and return the handshake it completed, if any
+    pub fn process_segment(
+        &mut self,
+        flow: FlowKey,
+        seq: u32,
+        payload: &[u8],
+        timestamp: i64,
+    ) -> Option<TlsHandshake> {
+        if payload.is_empty() {
+            return None;
+        }
+
+        if !self.streams.contains_key(&flow) && self.streams.len() >= MAX_STREAMS {
+            return None;
+        }
+
+        let stream = self
+            .streams
+            .entry(flow)
+            .or_insert_with(|| HandshakeStream::new(timestamp));
+
+        if stream.state != StreamState::Buffering {
+            stream.last_seen = timestamp;
+            return None;
+        }
+
+        stream.append(seq, payload, timestamp);
+        if stream.state != StreamState::Buffering {
+            return None;
+        }
+
+        let (handshake_type, body) = match read_handshake(&stream.data) {
+            Ok(Some(message)) => message,
+            Ok(None) => return None,
+            Err(()) => {
+                stream.state = StreamState::NotTls;
+                stream.data = Vec::new();
+                return None;
+            }
+        };
+
+        let parsed = match handshake_type {
+            HANDSHAKE_CLIENT_HELLO => parse_client_hello(&body).map(Hello::Client),
+            HANDSHAKE_SERVER_HELLO => parse_server_hello(&body).map(Hello::Server),
+            _ => None,
+        };
+
+        stream.data = Vec::new();
+        let hello = match parsed {
+            Some(hello) => {
+                stream.state = StreamState::Done;
+                stream.http_alpn = match &hello {
+                    Hello::Client(client) => client.alpn.iter().any(|p| is_http_alpn(p)),
+                    Hello::Server(server) => server.alpn.as_deref().is_some_and(is_http_alpn),
+                };
+                hello
+            }
+            None => {
+                stream.state = StreamState::NotTls;
+                return None;
+            }
+        };
+
+        match hello {
+            Hello::Client(client_hello) => {
+                self.pending.insert(
+                    flow,
+                    PendingHandshake {
+                        timestamp,
+                        client_hello: Some(client_hello),
+                    },
+                );
+                None
+            }
+            Hello::Server(server_hello) => {
+                let client_flow = flow.reversed();
+                let pending = self.pending.remove(&client_flow);
+                Some(TlsHandshake {
+                    client: client_flow.src,
+                    server: client_flow.dst,
+                    timestamp: pending.as_ref().map_or(timestamp, |p| p.timestamp),
+                    client_hello: pending.and_then(|p| p.client_hello),
+                    server_hello: Some(server_hello),
+                })
+            }
+        }
+    }
+
+    /// Protocol of a connection once a hello has been parsed in either
+    /// direction: HTTPS when HTTP was negotiated through ALPN, TLS otherwise
+    pub fn protocol(&self, flow: &FlowKey) -> Option<Protocol> {
+        let reversed = flow.reversed();
+        let done: Vec<&HandshakeStream> = [flow, &reversed]
+            .iter()
+            .filter_map(|key| self.streams.get(key))
+            .filter(|s| s.state == StreamState::Done)
+            .collect();
+
+        if done.is_empty() {
+            None
+        } else if done.iter().any(|s| s.http_alpn) {
+            Some(Protocol::HTTPS)
+        } else {
+            Some(Protocol::TLS)
+        }
+    }
+
+    /// Forget both directions of a connection, returning a ClientHello that
+    /// never got an answer so failed handshakes are still reported
+    pub fn close(&mut self, flow: &FlowKey) -> Option<TlsHandshake> {
+        let reversed = flow.reversed();
+        self.streams.remove(flow);
+        self.streams.remove(&reversed);
+
+        let (client_flow, pending) = match self.pending.remove(flow) {
+            Some(pending) => (*flow, pending),
+            None => (reversed, self.pending.remove(&reversed)?),
+        };
+        Some(unanswered(client_flow, pending))
+    }
+
+    /// Drop idle streams, returning ClientHellos that were never answered
+    pub fn expire(&mut self, now: i64) -> Vec<TlsHandshake> {
+        self.streams
+            .retain(|_, stream| now - stream.last_seen <= IDLE_TIMEOUT_MS);
+
+        let expired: Vec<FlowKey> = self
+            .pending
+            .iter()
+            .filter(|(_, p)| now - p.timestamp > IDLE_TIMEOUT_MS)
+            .map(|(flow, _)| *flow)
+            .collect();
+
+        expired
+            .into_iter()
+            .filter_map(|flow| self.pending.remove(&flow).map(|p| unanswered(flow, p)))
+            .collect()
+    }
+}
+
+enum Hello {
+    Client(ClientHello),
+    Server(ServerHello),
+}
+
+fn unanswered(client_flow: FlowKey, pending: PendingHandshake) -> TlsHandshake {
+    TlsHandshake {
+        client: client_flow.src,
+        server: client_flow.dst,
+        timestamp: pending.timestamp,
+        client_hello: pending.client_hello,
+        server_hello: None,
+    }
+}
+
+/// Reassemble the first handshake message from the leading TLS records.
+/// Returns `Ok(None)` while more data is needed and `Err` if this is not TLS.
+fn read_handshake(data: &[u8]) -> Result<Option<(u8, Vec<u8>)>, ()> {
+    let mut handshake = Vec::new();
+    let mut offset = 0;
+
+    while offset + 5 <= data.len() {
+        let content_type = data[offset];
+        let major = data[offset + 1];
+        let length = u16::from_be_bytes([data[offset + 3], data[offset + 4]]) as usize;
+
+        if content_type != CONTENT_TYPE_HANDSHAKE || major != 3 || length == 0 || length > 16384 + 2048 {
+            return Err(());
+        }
+
+        let fragment = match data.get(offset + 5..offset + 5 + length) {
+            Some(fragment) => fragment,
+            None => break,
+        };
+        handshake.extend_from_slice(fragment);
+        offset += 5 + length;
+
+        if handshake.len() >= 4 {
+            let body_len = u32::from_be_bytes([0, handshake[1], handshake[2], handshake[3]]) as usize;
+            if handshake.len() >= 4 + body_len {
+                return Ok(Some((handshake[0], handshake[4..4 + body_len].to_vec())));
+            }
+        }
+    }
+
+    // A first byte that cannot start a handshake record is decided immediately
+    match data.first() {
+        Some(&first) if first != CONTENT_TYPE_HANDSHAKE => Err(()),
+        _ => Ok(None),
+    }
+}
+
+/// Minimal big-endian reader over a handshake body
+struct Reader<'a> {
+    data: &'a [u8],
+    offset: usize,
+}
+
+impl<'a> Reader<'a> {
+    fn new(data: &'a [u8]) -> Self {
+        Self { data, offset: 0 }
+    }
+
+    fn is_empty(&self) -> bool {
+        self.offset >= self.data.len()
+    }
+
+    fn bytes(&mut self, len: usize) -> Option<&'a [u8]> {
+        let slice = self.data.get(self.offset..self.offset + len)?;
+        self.offset += len;
+        Some(slice)
+    }
+
+    fn u8(&mut self) -> Option<u8> {
+        self.bytes(1).map(|b| b[0])
+    }
+
+    fn u16(&mut self) -> Option<u16> {
+        self.bytes(2).map(|b| u16::from_be_bytes([b[0], b[1]]))
+    }
+
+    /// A vector prefixed by a one-byte length
+    fn vec8(&mut self) -> Option<Reader<'a>> {
+        let len = self.u8()? as usize;
+        self.bytes(len).map(Reader::new)
+    }
+
+    /// A vector prefixed by a two-byte length
+    fn vec16(&mut self) -> Option<Reader<'a>> {
+        let len = self.u16()? as usize;
+        self.bytes(len).map(Reader::new)
+    }
+
+    fn u16_list(mut self) -> Option<Vec<u16>> {
+        let mut values = Vec::new();
+        while !self.is_empty() {
+            values.push(self.u16()?);
+        }
+        Some(values)
+    }
+}
+
+pub fn parse_client_hello(body: &[u8]) -> Option<ClientHello> {
+    let mut reader = Reader::new(body);
+    let mut hello = ClientHello {
+        legacy_version: reader.u16()?,
+        ..Default::default()
+    };
+
+    reader.bytes(32)?; // random
+    reader.vec8()?; // session id
+    hello.cipher_suites = reader.vec16()?.u16_list()?;
+    reader.vec8()?; // compression methods
+
+    if reader.is_empty() {
+        return Some(hello);
+    }
+
+    let mut extensions = reader.vec16()?;
+    while !extensions.is_empty() {
+        let ext_type = extensions.u16()?;
+        let mut data = extensions.vec16()?;
+        hello.extensions.push(ext_type);
+
+        match ext_type {
+            EXT_SERVER_NAME => {
+                let mut list = data.vec16()?;
+                while !list.is_empty() {
+                    let name_type = list.u8()?;
+                    let name = list.vec16()?;
+                    if name_type == 0 {
+                        hello.sni = Some(String::from_utf8_lossy(name.data).to_string());
+                    }
+                }
+            }
+            EXT_SUPPORTED_GROUPS => hello.supported_groups = data.vec16()?.u16_list()?,
+            EXT_EC_POINT_FORMATS => hello.ec_point_formats = data.vec8()?.data.to_vec(),
+            EXT_SIGNATURE_ALGORITHMS => hello.signature_algorithms = data.vec16()?.u16_list()?,
+            EXT_ALPN => hello.alpn = parse_alpn(&mut data)?,
+            EXT_SUPPORTED_VERSIONS => {
+                let mut versions = data.vec8()?;
+                while !versions.is_empty() {
+                    hello.supported_versions.push(versions.u16()?);
+                }
+            }
+            _ => {}
+        }
+    }
+
+    Some(hello)
+}
+
+pub fn parse_server_hello(body: &[u8]) -> Option<ServerHello> {
+    let mut reader = Reader::new(body);
+    let mut hello = ServerHello {
+        legacy_version: reader.u16()?,
+        ..Default::default()
+    };
+
+    reader.bytes(32)?; // random
+    reader.vec8()?; // session id
+    hello.cipher_suite = reader.u16()?;
+    reader.u8()?; // compression method
+
+    if reader.is_empty() {
+        return Some(hello);
+    }
+
+    let mut extensions = reader.vec16()?;
+    while !extensions.is_empty() {
+        let ext_type = extensions.u16()?;
+        let mut data = extensions.vec16()?;
+
+        match ext_type {
+            EXT_SUPPORTED_VERSIONS => hello.selected_version = Some(data.u16()?),
+            EXT_ALPN => hello.alpn = parse_alpn(&mut data)?.into_iter().next(),
+            _ => {}
+        }
+    }
+
+    Some(hello)
+}
+
+fn parse_alpn(data: &mut Reader) -> Option<Vec<String>> {
+    let mut list = data.vec16()?;
+    let mut protocols = Vec::new();
+    while !list.is_empty() {
+        let protocol = list.vec8()?;
+        protocols.push(String::from_utf8_lossy(protocol.data).to_string());
+    }
+    Some(protocols)
+}
+
+/// GREASE values (RFC 8701) are random placeholders excluded from fingerprints
+fn is_grease(value: u16) -> bool {
+    value & 0x0f0f == 0x0a0a && value >> 8 == value & 0xff
+}
+
+fn is_http_alpn(protocol: &str) -> bool {
+    matches!(protocol, "http/1.0" | "http/1.1" | "h2" | "h3")
+}
+
+fn hex_list(values: &[u16]) -> String {
+    values
+        .iter()
+        .map(|v| format!("{:04x}", v))
+        .collect::<Vec<_>>()
+        .join(",")
+}
+
+fn ja4_hash(input: &str) -> String {
+    if input.is_empty() {
+        return "000000000000".to_string();
+    }
+    hex::encode(Sha256::digest(input.as_bytes()))[..12].to_string()
+}
+
+/// First and last characters of the first ALPN value, hex when not alphanumeric
+fn ja4_alpn(protocol: &str) -> String {
+    let bytes = protocol.as_bytes();
+    match (bytes.first(), bytes.last()) {
+        (Some(first), Some(last)) if first.is_ascii_alphanumeric() && last.is_ascii_alphanumeric() => {
+            format!("{}{}", *first as char, *last as char)
+        }
+        (Some(first), Some(last)) => {
+            let first = format!("{:02x}", first);
+            let last = format!("{:02x}", last);
+            format!("{}{}", &first[..1], &last[1..])
+        }
+        _ => "00".to_string(),
+    }
+}
+
+pub fn version_name(version: u16) -> String {
+    match version {
+        0x0300 => "SSLv3".to_string(),
+        0x0301 => "TLSv1.0".to_string(),
+        0x0302 => "TLSv1.1".to_string(),
+        0x0303 => "TLSv1.2".to_string(),
+        0x0304 => "TLSv1.3".to_string(),
+        other => format!("0x{:04x}", other),
+    }
+}
+
+pub fn cipher_name(cipher: u16) -> String {
+    let name = match cipher {
+        0x1301 => "TLS_AES_128_GCM_SHA256",
+        0x1302 => "TLS_AES_256_GCM_SHA384",
+        0x1303 => "TLS_CHACHA20_POLY1305_SHA256",
+        0xc02b => "TLS_ECDHE_ECDSA_WITH_AES_128_GCM_SHA256",
+        0xc02c => "TLS_ECDHE_ECDSA_WITH_AES_256_GCM_SHA384",
+        0xc02f => "TLS_ECDHE_RSA_WITH_AES_128_GCM_SHA256",
+        0xc030 => "TLS_ECDHE_RSA_WITH_AES_256_GCM_SHA384",
+        0xcca8 => "TLS_ECDHE_RSA_WITH_CHACHA20_POLY1305_SHA256",
+        0xcca9 => "TLS_ECDHE_ECDSA_WITH_CHACHA20_POLY1305_SHA256",
+        0xc013 => "TLS_ECDHE_RSA_WITH_AES_128_CBC_SHA",
+        0xc014 => "TLS_ECDHE_RSA_WITH_AES_256_CBC_SHA",
+        0x009c => "TLS_RSA_WITH_AES_128_GCM_SHA256",
+        0x009d => "TLS_RSA_WITH_AES_256_GCM_SHA384",
+        0x002f => "TLS_RSA_WITH_AES_128_CBC_SHA",
+        0x0035 => "TLS_RSA_WITH_AES_256_CBC_SHA",
+        0x000a => "TLS_RSA_WITH_3DES_EDE_CBC_SHA",
+        other => return format!("0x{:04x}", other),
+    };
+    name.to_string()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn flow(src: &str, dst: &str) -> FlowKey {
+        FlowKey::new(6, src.parse().unwrap(), dst.parse().unwrap())
+    }
+
+    fn ext(ext_type: u16, data: &[u8]) -> Vec<u8> {
+        let mut out = ext_type.to_be_bytes().to_vec();
+        out.extend_from_slice(&(data.len() as u16).to_be_bytes());
+        out.extend_from_slice(data);
+        out
+    }
+
+    fn record(handshake_type: u8, body: &[u8]) -> Vec<u8> {
+        let mut handshake = vec![handshake_type];
+        handshake.extend_from_slice(&(body.len() as u32).to_be_bytes()[1..]);
+        handshake.extend_from_slice(body);
+
+        let mut out = vec![CONTENT_TYPE_HANDSHAKE, 3, 1];
+        out.extend_from_slice(&(handshake.len() as u16).to_be_bytes());
+        out.extend_from_slice(&handshake);
+        out
+    }
+
+    fn client_hello_body() -> Vec<u8> {
+        let mut body = vec![3, 3];
+        body.extend_from_slice(&[0u8; 32]);
+        body.push(0);
+        // Ciphers: GREASE, TLS_AES_128_GCM_SHA256, ECDHE-RSA-AES128-GCM
+        body.extend_from_slice(&[0, 6, 0x0a, 0x0a, 0x13, 0x01, 0xc0, 0x2f]);
+        body.extend_from_slice(&[1, 0]);
+
+        let mut extensions = Vec::new();
+        extensions.extend(ext(0x0a0a, &[]));
+        extensions.extend(ext(EXT_SERVER_NAME, &[0, 14, 0, 0, 11, b'e', b'x', b'a', b'm', b'p', b'l', b'e', b'.', b'c', b'o', b'm']));
+        extensions.extend(ext(EXT_SUPPORTED_GROUPS, &[0, 4, 0, 29, 0, 23]));
+        extensions.extend(ext(EXT_EC_POINT_FORMATS, &[1, 0]));
+        extensions.extend(ext(EXT_SIGNATURE_ALGORITHMS, &[0, 4, 0x04, 0x03, 0x08, 0x04]));
+        extensions.extend(ext(EXT_ALPN, &[0, 12, 2, b'h', b'2', 8, b'h', b't', b't', b'p', b'/', b'1', b'.', b'1']));
+        extensions.extend(ext(EXT_SUPPORTED_VERSIONS, &[4, 0x03, 0x04, 0x03, 0x03]));
+
+        body.extend_from_slice(&(extensions.len() as u16).to_be_bytes());
+        body.extend_from_slice(&extensions);
+        body
+    }
+
+    fn server_hello_body() -> Vec<u8> {
+        let mut body = vec![3, 3];
+        body.extend_from_slice(&[0u8; 32]);
+        body.push(0);
+        body.extend_from_slice(&[0x13, 0x01, 0]);
+
+        let mut extensions = Vec::new();
+        extensions.extend(ext(EXT_SUPPORTED_VERSIONS, &[0x03, 0x04]));
+        extensions.extend(ext(EXT_ALPN, &[0, 3, 2, b'h', b'2']));
+
+        body.extend_from_slice(&(extensions.len() as u16).to_be_bytes());
+        body.extend_from_slice(&extensions);
+        body
+    }
+
+    #[test]
+    fn test_parse_client_hello() {
+        let hello = parse_client_hello(&client_hello_body()).unwrap();
+
+        assert_eq!(hello.sni.as_deref(), Some("example.com"));
+        assert_eq!(hello.alpn, vec!["h2".to_string(), "http/1.1".to_string()]);
+        assert_eq!(hello.max_version(), 0x0304);
+        assert_eq!(hello.ja3(), "771,4865-49199,0-10-11-13-16-43,29-23,0");
+        assert_eq!(hello.ja3_hash().len(), 32);
+
+        let ja4 = hello.ja4();
+        assert!(ja4.starts_with("t13d0206h2_"), "{}", ja4);
+        assert_eq!(ja4.len(), "t13d0206h2_".len() + 12 + 1 + 12);
+    }
+
+    #[test]
+    fn test_handshake_across_segments() {
+        let mut tracker = TlsTracker::new();
+        let client = flow("10.0.0.1:50000", "10.0.0.2:8443");
+
+        let hello = record(HANDSHAKE_CLIENT_HELLO, &client_hello_body());
+        let (first, second) = hello.split_at(20);
+        assert!(tracker.process_segment(client, 1, first, 100).is_none());
+        assert_eq!(tracker.protocol(&client), None);
+        assert!(tracker.process_segment(client, 21, second, 101).is_none());
+        assert_eq!(tracker.protocol(&client.reversed()), Some(Protocol::HTTPS));
+
+        let server = record(HANDSHAKE_SERVER_HELLO, &server_hello_body());
+        let handshake = tracker
+            .process_segment(client.reversed(), 1, &server, 130)
+            .unwrap();
+
+        assert_eq!(handshake.client, client.src);
+        assert_eq!(handshake.protocol(), Protocol::HTTPS);
+
+        let event = handshake.into_event();
+        assert_eq!(event.timestamp, 101);
+        assert_eq!(event.metadata["tls.sni"], "example.com");
+        assert_eq!(event.metadata["tls.version"], "TLSv1.3");
+        assert_eq!(event.metadata["tls.cipher"], "TLS_AES_128_GCM_SHA256");
+        assert_eq!(event.metadata["tls.alpn"], "h2");
+        assert_eq!(event.metadata["tls.deprecated"], "false");
+    }
+
+    #[test]
+    fn test_unanswered_client_hello_reported_on_close() {
+        let mut tracker = TlsTracker::new();
+        let client = flow("10.0.0.1:50000", "10.0.0.2:443");
+
+        let hello = record(HANDSHAKE_CLIENT_HELLO, &client_hello_body());
+        tracker.process_segment(client, 1, &hello, 100);
+
+        let handshake = tracker.close(&client.reversed()).unwrap();
+        assert_eq!(handshake.client, client.src);
+        assert!(handshake.server_hello.is_none());
+        assert!(tracker.close(&client).is_none());
+    }
+
+    #[test]
+    fn test_plaintext_is_not_tls() {
+        let mut tracker = TlsTracker::new();
+        let client = flow("10.0.0.1:50000", "10.0.0.2:443");
+
+        tracker.process_segment(client, 1, b"GET / HTTP/1.1\r\n\r\n", 100);
+        assert_eq!(tracker.protocol(&client), None);
+    }
+
+    #[test]
+    fn test_grease_detection() {
+        assert!(is_grease(0x0a0a));
+        assert!(is_grease(0xfafa));
+        assert!(!is_grease(0x0a1a));
+        assert!(!is_grease(0x1301));
+    }
+}
@@ -8,6 +8,12 @@ pub struct AgentConfig {
     pub collector: CollectorSettings,
     pub buffer: BufferSettings,
     pub collectors: CollectorConfigs,
+    /// Processing stages applied, in order, to events before batching
+    #[serde(default)]
+    pub transforms: Vec<TransformConfig>,
+    /// Destinations that receive batches alongside `collector`
+    #[serde(default)]
+    pub sinks: Vec<SinkConfig>,
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
@@ -20,28 +26,169 @@ pub struct AgentSettings {
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct CollectorSettings {
     pub endpoint: String,
+    /// More collectors for the configured transport, after `endpoint` (or
+    /// `grpc_endpoint`) in priority order
+    #[serde(default)]
+    pub endpoints: Vec<String>,
+    /// "priority", "round_robin" or "least_latency"
+    #[serde(default = "default_endpoint_strategy")]
+    pub endpoint_strategy: String,
+    /// Consecutive failures after which a collector is skipped
+    #[serde(default = "default_circuit_failure_threshold")]
+    pub circuit_failure_threshold: u32,
+    /// How long a failing collector is skipped before it is tried again
+    #[serde(default = "default_circuit_open")]
+    pub circuit_open_secs: u64,
+    /// How often an idle connection moves to a preferred collector, such as
+    /// a recovered primary
+    #[serde(default = "default_rebalance_interval")]
+    pub rebalance_interval_secs: u64,
+    /// "websocket" sends to `endpoint`; "grpc" streams to `grpc_endpoint`
+    /// and needs the grpc-transport feature
+    #[serde(default = "default_transport")]
+    pub transport: String,
     #[serde(default)]
     pub grpc_endpoint: Option<String>,
     pub auth_token: Option<String>,
+    /// CA bundle used instead of the system roots
     pub tls_ca_cert: Option<String>,
+    /// Client certificate and key presented for mutual TLS
     pub client_cert: Option<String>,
     pub client_key: Option<String>,
+    /// Hex SHA-256 hashes of accepted server public keys (SPKI); when set,
+    /// the collector must present a certificate with one of them
+    #[serde(default)]
+    pub tls_pin_sha256: Vec<String>,
     #[serde(default = "default_connect_timeout")]
     pub connect_timeout_secs: u64,
+    /// Longest wait for a response before the connection is reset
     #[serde(default = "default_request_timeout")]
     pub request_timeout_secs: u64,
+    /// Batches sent before waiting for acknowledgements; 1 sends one batch
+    /// per round trip
+    #[serde(default = "default_max_in_flight")]
+    pub max_in_flight: usize,
+    /// Batches waiting for the collector while `[[sinks]]` are configured.
+    /// Beyond it new ones are kept on disk if `buffer.disk` is enabled and
+    /// dropped otherwise.
+    #[serde(default = "default_sink_queue")]
+    pub queue_batches: usize,
+    /// Proxy for WebSocket connections
+    #[serde(default)]
+    pub proxy: ProxySettings,
+}
+
+/// Proxy for outgoing connections. Unset fields fall back to HTTPS_PROXY
+/// (HTTP_PROXY for plain endpoints), ALL_PROXY and NO_PROXY.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct ProxySettings {
+    /// "http://", "socks5://" or "socks5h://" URL, optionally with
+    /// "user:password@"; empty to ignore the environment
+    #[serde(default)]
+    pub url: Option<String>,
+    /// Hosts reached directly: names (with their subdomains), IPs, CIDR
+    /// ranges or "*"
+    #[serde(default)]
+    pub no_proxy: Option<Vec<String>>,
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct BufferSettings {
     #[serde(default = "default_max_events")]
     pub max_events: usize,
+    /// Limit on the estimated size of all buffered events
+    #[serde(default = "default_max_buffer_bytes")]
+    pub max_bytes: usize,
+    /// Larger events are truncated and tagged "truncated"
+    #[serde(default = "default_max_event_bytes")]
+    pub max_event_bytes: usize,
     #[serde(default = "default_flush_interval")]
     pub flush_interval_secs: u64,
     #[serde(default = "default_max_batch_size")]
     pub max_batch_size: usize,
+    /// Flush as soon as buffered events reach this estimated size
+    #[serde(default = "default_max_batch_bytes")]
+    pub max_batch_bytes: usize,
+    /// Longest an event waits in the buffer before a partial batch is sent
+    #[serde(default = "default_max_event_age")]
+    pub max_event_age_ms: u64,
     #[serde(default = "default_compression")]
     pub compression: String,
+    /// zstd level, 1 (fastest) to 22
+    #[serde(default = "default_compression_level")]
+    pub compression_level: i32,
+    /// Use zstd dictionaries offered by the collector
+    #[serde(default = "default_compression_dictionary")]
+    pub compression_dictionary: bool,
+    /// "msgpack" or "json"; batches fall back to JSON for collectors that
+    /// do not accept the preferred encoding
+    #[serde(default = "default_encoding")]
+    pub encoding: String,
+    #[serde(default)]
+    pub disk: DiskBufferSettings,
+    /// "drop_newest", "drop_oldest" or "block"
+    #[serde(default = "default_overflow")]
+    pub overflow: String,
+    #[serde(default = "default_block_timeout")]
+    pub block_timeout_ms: u64,
+    #[serde(default)]
+    pub quotas: BufferQuotas,
+}
+
+/// Share of `max_events` reserved for each class of event
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct BufferQuotas {
+    /// Error and critical logs
+    #[serde(default = "default_priority_logs_quota")]
+    pub priority_logs: f64,
+    #[serde(default = "default_logs_quota")]
+    pub logs: f64,
+    #[serde(default = "default_metrics_quota")]
+    pub metrics: f64,
+    #[serde(default = "default_traffic_quota")]
+    pub traffic: f64,
+}
+
+impl Default for BufferQuotas {
+    fn default() -> Self {
+        Self {
+            priority_logs: default_priority_logs_quota(),
+            logs: default_logs_quota(),
+            metrics: default_metrics_quota(),
+            traffic: default_traffic_quota(),
+        }
+    }
+}
+
+/// On-disk write-ahead queue for batches awaiting acknowledgement
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct DiskBufferSettings {
+    #[serde(default)]
+    pub enabled: bool,
+    #[serde(default = "default_disk_buffer_path")]
+    pub path: String,
+    #[serde(default = "default_disk_buffer_max_bytes")]
+    pub max_bytes: u64,
+    #[serde(default = "default_disk_buffer_segment_bytes")]
+    pub segment_bytes: u64,
+    /// "always", "interval" or "never"
+    #[serde(default = "default_fsync")]
+    pub fsync: String,
+    #[serde(default = "default_fsync_interval")]
+    pub fsync_interval_ms: u64,
+}
+
+impl Default for DiskBufferSettings {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            path: default_disk_buffer_path(),
+            max_bytes: default_disk_buffer_max_bytes(),
+            segment_bytes: default_disk_buffer_segment_bytes(),
+            fsync: default_fsync(),
+            fsync_interval_ms: default_fsync_interval(),
+        }
+    }
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
@@ -59,8 +206,193 @@ pub struct LogCollectorConfig {
     pub files: Vec<String>,
     #[serde(default)]
     pub journald_units: Vec<String>,
+    /// Regexes; log events whose source matches one are dropped
     #[serde(default)]
     pub exclude_patterns: Vec<String>,
+    /// Rate limit repeated lines before they are buffered
+    #[serde(default)]
+    pub throttle: Option<LogThrottleConfig>,
+}
+
+/// Token bucket per key; suppressed lines are reported as one "message
+/// repeated N times" event per interval
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct LogThrottleConfig {
+    /// "fingerprint" (source, level and message with numbers masked) or
+    /// "source"
+    #[serde(default = "default_throttle_key")]
+    pub key: String,
+    /// Sustained events per second allowed per key
+    #[serde(default = "default_throttle_rate")]
+    pub rate: f64,
+    /// Events allowed at once before the rate applies
+    #[serde(default = "default_throttle_burst")]
+    pub burst: u32,
+    #[serde(default = "default_throttle_summary_interval")]
+    pub summary_interval_secs: u64,
+}
+
+impl Default for LogThrottleConfig {
+    fn default() -> Self {
+        Self {
+            key: default_throttle_key(),
+            rate: default_throttle_rate(),
+            burst: default_throttle_burst(),
+            summary_interval_secs: default_throttle_summary_interval(),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct TransformConfig {
+    /// Only apply the stage to events matching this condition
+    #[serde(default)]
+    pub when: Option<ConditionConfig>,
+    #[serde(flatten)]
+    pub stage: TransformStage,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum TransformStage {
+    /// Discard the event
+    Drop,
+    Rename { from: String, to: String },
+    Remove { fields: Vec<String> },
+    /// "key:value" tags, merged like the agent's own tags
+    AddTags { tags: Vec<String> },
+    /// Copy the named groups of a regex match on `field` into fields
+    Extract { field: String, pattern: String },
+    /// Rewrite `field`, replacing every match of `pattern`
+    Replace {
+        field: String,
+        pattern: String,
+        replacement: String,
+    },
+    /// Mask or hash secrets and personal data in log messages, log fields
+    /// and traffic metadata
+    Redact {
+        /// Built-in detectors: jwt, aws_key, credit_card, email, ip
+        #[serde(default = "default_redact_detectors")]
+        detectors: Vec<String>,
+        #[serde(default)]
+        custom: Vec<RedactPattern>,
+        /// "mask" or "hash"
+        #[serde(default = "default_redact_mode")]
+        mode: String,
+        /// Key mixed into hashes so common values cannot be looked up
+        #[serde(default)]
+        hash_key: Option<String>,
+    },
+    /// Replace raw samples of the listed metrics with per-window statistics
+    /// for each name and tag set
+    Aggregate {
+        /// Metric names or globs ("system.cpu.*"); others pass through
+        metrics: Vec<String>,
+        #[serde(default = "default_aggregate_window")]
+        window_secs: u64,
+        /// Quantiles such as 0.5 or 0.99, estimated with a DDSketch
+        #[serde(default)]
+        percentiles: Vec<f64>,
+    },
+    /// Count matching log lines, or summarise a number taken from them,
+    /// as a metric per window
+    LogToMetric(LogToMetricConfig),
+}
+
+/// Extra destination for batches. Every sink, including `collector`, then
+/// gets its own queue; one that falls behind loses batches rather than
+/// slowing the others, except that `collector` spills to the disk queue
+/// when it is enabled.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct SinkConfig {
+    /// Used in logs; defaults to the endpoint or path
+    #[serde(default)]
+    pub name: Option<String>,
+    /// Only send events matching this condition
+    #[serde(default)]
+    pub when: Option<ConditionConfig>,
+    /// Batches waiting for the sink before new ones are dropped
+    #[serde(default = "default_sink_queue")]
+    pub queue_batches: usize,
+    #[serde(flatten)]
+    pub kind: SinkKind,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum SinkKind {
+    /// Another collector, with the same settings as `[collector]`
+    Collector(Box<CollectorSettings>),
+    /// Events appended to a local file, one JSON object per line
+    File(FileSinkConfig),
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct FileSinkConfig {
+    pub path: String,
+    /// Rotate once the file reaches this size; 0 never rotates
+    #[serde(default = "default_file_sink_max_bytes")]
+    pub max_bytes: u64,
+    /// Rotated files kept as `<path>.1` (newest) to `<path>.<max_files>`
+    #[serde(default = "default_file_sink_max_files")]
+    pub max_files: usize,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct RedactPattern {
+    pub name: String,
+    pub pattern: String,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct LogToMetricConfig {
+    /// Name of the generated metric
+    pub metric: String,
+    /// "counter" counts matching lines; "histogram" reports min, max, avg,
+    /// sum, count and percentiles of `value_field`
+    #[serde(default = "default_log_metric_kind")]
+    pub kind: String,
+    /// Glob the log source must match
+    #[serde(default)]
+    pub source: Option<String>,
+    /// Log levels to consider; empty means all
+    #[serde(default)]
+    pub levels: Vec<String>,
+    /// Regex the message must match; its named groups can be used as
+    /// `value_field` and `tag_fields`
+    #[serde(default)]
+    pub pattern: Option<String>,
+    #[serde(default)]
+    pub value_field: Option<String>,
+    /// Groups or log fields copied into the metric's tags
+    #[serde(default)]
+    pub tag_fields: Vec<String>,
+    #[serde(default)]
+    pub unit: Option<String>,
+    #[serde(default = "default_aggregate_window")]
+    pub window_secs: u64,
+    #[serde(default)]
+    pub percentiles: Vec<f64>,
+    /// Drop the log lines once they have been counted
+    #[serde(default)]
+    pub drop_original: bool,
+}
+
+/// Every given criterion must hold for an event to match
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct ConditionConfig {
+    /// "log", "metric" or "traffic"
+    #[serde(default)]
+    pub event_type: Option<String>,
+    /// Without `equals` or `matches`, the field only has to be present
+    #[serde(default)]
+    pub field: Option<String>,
+    #[serde(default)]
+    pub equals: Option<String>,
+    /// Regex the field value must match
+    #[serde(default)]
+    pub matches: Option<String>,
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
@@ -73,6 +405,9 @@ pub struct MetricsCollectorConfig {
     pub prometheus_endpoints: Vec<String>,
     #[serde(default)]
     pub include_process_metrics: bool,
+    /// Proxy for Prometheus scrapes
+    #[serde(default)]
+    pub proxy: ProxySettings,
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
@@ -86,6 +421,55 @@ pub struct TrafficCollectorConfig {
     pub sample_rate: f64,
     #[serde(default)]
     pub capture_payload: bool,
+    #[serde(default = "default_decode_dns")]
+    pub decode_dns: bool,
+    #[serde(default = "default_analyze_tcp")]
+    pub analyze_tcp: bool,
+    #[serde(default = "default_capture_workers")]
+    pub capture_workers: usize,
+    #[serde(default = "default_capture_queue_size")]
+    pub capture_queue_size: usize,
+    #[serde(default)]
+    pub detection: ThreatDetectionConfig,
+}
+
+/// Port-scan and connection-flood detection thresholds. Counts are per
+/// source IP over a sliding window of `window_secs`.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ThreatDetectionConfig {
+    #[serde(default)]
+    pub enabled: bool,
+    #[serde(default = "default_detection_window")]
+    pub window_secs: u64,
+    /// Distinct hosts probed on one port
+    #[serde(default = "default_horizontal_scan_hosts")]
+    pub horizontal_scan_hosts: usize,
+    /// Distinct ports probed on one host
+    #[serde(default = "default_vertical_scan_ports")]
+    pub vertical_scan_ports: usize,
+    /// SYNs sent, when fewer than half of them are answered
+    #[serde(default = "default_syn_flood_syns")]
+    pub syn_flood_syns: usize,
+    /// Distinct destinations contacted before a fan-out spike is considered
+    #[serde(default = "default_fanout_min_targets")]
+    pub fanout_min_targets: usize,
+    /// How far above the source's usual fan-out a spike must be
+    #[serde(default = "default_fanout_factor")]
+    pub fanout_factor: f64,
+}
+
+impl Default for ThreatDetectionConfig {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            window_secs: default_detection_window(),
+            horizontal_scan_hosts: default_horizontal_scan_hosts(),
+            vertical_scan_ports: default_vertical_scan_ports(),
+            syn_flood_syns: default_syn_flood_syns(),
+            fanout_min_targets: default_fanout_min_targets(),
+            fanout_factor: default_fanout_factor(),
+        }
+    }
 }
 
 // Default values
@@ -93,6 +477,14 @@ fn default_max_events() -> usize {
     10000
 }
 
+fn default_max_buffer_bytes() -> usize {
+    64 * 1024 * 1024
+}
+
+fn default_max_event_bytes() -> usize {
+    256 * 1024
+}
+
 fn default_flush_interval() -> u64 {
     60
 }
@@ -101,10 +493,109 @@ fn default_max_batch_size() -> usize {
     1000
 }
 
+fn default_max_batch_bytes() -> usize {
+    4 * 1024 * 1024
+}
+
+fn default_max_event_age() -> u64 {
+    5000
+}
+
 fn default_compression() -> String {
     "snappy".to_string()
 }
 
+fn default_overflow() -> String {
+    "drop_newest".to_string()
+}
+
+fn default_block_timeout() -> u64 {
+    100
+}
+
+fn default_priority_logs_quota() -> f64 {
+    0.1
+}
+
+fn default_logs_quota() -> f64 {
+    0.3
+}
+
+fn default_metrics_quota() -> f64 {
+    0.3
+}
+
+fn default_traffic_quota() -> f64 {
+    0.3
+}
+
+fn default_disk_buffer_path() -> String {
+    "/var/lib/monitoring-agent/buffer".to_string()
+}
+
+fn default_disk_buffer_max_bytes() -> u64 {
+    256 * 1024 * 1024
+}
+
+fn default_disk_buffer_segment_bytes() -> u64 {
+    16 * 1024 * 1024
+}
+
+fn default_fsync() -> String {
+    "interval".to_string()
+}
+
+fn default_fsync_interval() -> u64 {
+    1000
+}
+
+fn default_compression_level() -> i32 {
+    3
+}
+
+fn default_compression_dictionary() -> bool {
+    true
+}
+
+fn default_encoding() -> String {
+    "msgpack".to_string()
+}
+
+fn default_redact_detectors() -> Vec<String> {
+    ["jwt", "aws_key", "credit_card", "email", "ip"]
+        .iter()
+        .map(|name| name.to_string())
+        .collect()
+}
+
+fn default_redact_mode() -> String {
+    "mask".to_string()
+}
+
+fn default_aggregate_window() -> u64 {
+    60
+}
+
+fn default_log_metric_kind() -> String {
+    "counter".to_string()
+}
+
+fn default_throttle_key() -> String {
+    "fingerprint".to_string()
+}
+
+fn default_throttle_rate() -> f64 {
+    10.0
+}
+
+fn default_throttle_burst() -> u32 {
+    100
+}
+
+fn default_throttle_summary_interval() -> u64 {
+    10
+}
+
 fn default_system_interval() -> u64 {
     10
 }
@@ -113,6 +604,50 @@ fn default_sample_rate() -> f64 {
     0.1
 }
 
+fn default_decode_dns() -> bool {
+    true
+}
+
+fn default_analyze_tcp() -> bool {
+    true
+}
+
+fn default_capture_workers() -> usize {
+    1
+}
+
+fn default_capture_queue_size() -> usize {
+    10000
+}
+
+fn default_detection_window() -> u64 {
+    60
+}
+
+fn default_horizontal_scan_hosts() -> usize {
+    20
+}
+
+fn default_vertical_scan_ports() -> usize {
+    20
+}
+
+fn default_syn_flood_syns() -> usize {
+    500
+}
+
+fn default_fanout_min_targets() -> usize {
+    100
+}
+
+fn default_fanout_factor() -> f64 {
+    5.0
+}
+
+fn default_transport() -> String {
+    "websocket".to_string()
+}
+
 fn default_connect_timeout() -> u64 {
     30
 }
@@ -121,6 +656,38 @@ fn default_request_timeout() -> u64 {
     60
 }
 
+fn default_max_in_flight() -> usize {
+    8
+}
+
+fn default_endpoint_strategy() -> String {
+    "priority".to_string()
+}
+
+fn default_circuit_failure_threshold() -> u32 {
+    3
+}
+
+fn default_circuit_open() -> u64 {
+    30
+}
+
+fn default_rebalance_interval() -> u64 {
+    60
+}
+
+fn default_sink_queue() -> usize {
+    100
+}
+
+fn default_file_sink_max_bytes() -> u64 {
+    100 * 1024 * 1024
+}
+
+fn default_file_sink_max_files() -> usize {
+    5
+}
+
 impl AgentConfig {
     pub fn load(path: &Path) -> Result<Self> {
         let config_str = std::fs::read_to_string(path)
@@ -137,21 +704,37 @@ impl AgentConfig {
                 .unwrap_or_else(|| "unknown".to_string());
         }
 
-        // Expand environment variables in auth token
-        if let Some(token) = &config.collector.auth_token {
-            if token.starts_with("${") && token.ends_with("}") {
-                let var_name = &token[2..token.len() - 1];
-                config.collector.auth_token = std::env::var(var_name).ok();
+        config.expand_env();
+
+        Ok(config)
+    }
+
+    /// Expand environment variables in secrets
+    fn expand_env(&mut self) {
+        expand_env(&mut self.collector.auth_token);
+        for transform in &mut self.transforms {
+            if let TransformStage::Redact { hash_key, .. } = &mut transform.stage {
+                expand_env(hash_key);
             }
         }
+    }
+}
 
-        Ok(config)
+/// Replace a "${VAR}" value with the variable, or `None` if it is unset
+fn expand_env(value: &mut Option<String>) {
+    let var_name = value
+        .as_deref()
+        .and_then(|value| value.strip_prefix("${"))
+        .and_then(|value| value.strip_suffix('}'));
+    if let Some(var_name) = var_name {
+        *value = std::env::var(var_name).ok();
     }
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
+    use std::collections::HashMap;
 
     #[test]
     fn test_default_config() {
@@ -163,19 +746,40 @@ mod tests {
             },
             collector: CollectorSettings {
                 endpoint: "wss://localhost:8080/ingest".to_string(),
+                endpoints: vec![],
+                endpoint_strategy: "priority".to_string(),
+                circuit_failure_threshold: 3,
+                circuit_open_secs: 30,
+                rebalance_interval_secs: 60,
+                transport: "websocket".to_string(),
                 grpc_endpoint: None,
                 auth_token: Some("test-token".to_string()),
                 tls_ca_cert: None,
                 client_cert: None,
                 client_key: None,
+                tls_pin_sha256: vec![],
                 connect_timeout_secs: 30,
                 request_timeout_secs: 60,
+                max_in_flight: 8,
+                queue_batches: 100,
+                proxy: ProxySettings::default(),
             },
             buffer: BufferSettings {
                 max_events: 10000,
+                max_bytes: 64 * 1024 * 1024,
+                max_event_bytes: 256 * 1024,
                 flush_interval_secs: 60,
                 max_batch_size: 1000,
+                max_batch_bytes: 4 * 1024 * 1024,
+                max_event_age_ms: 5000,
                 compression: "snappy".to_string(),
+                compression_level: 3,
+                compression_dictionary: true,
+                encoding: "msgpack".to_string(),
+                disk: DiskBufferSettings::default(),
+                overflow: "drop_newest".to_string(),
+                block_timeout_ms: 100,
+                quotas: BufferQuotas::default(),
             },
             collectors: CollectorConfigs {
                 logs: LogCollectorConfig {
@@ -183,12 +787,14 @@ mod tests {
                     files: vec![],
                     journald_units: vec![],
                     exclude_patterns: vec![],
+                    throttle: None,
                 },
                 metrics: MetricsCollectorConfig {
                     enabled: true,
                     system_interval_secs: 10,
                     prometheus_endpoints: vec![],
                     include_process_metrics: false,
+                    proxy: ProxySettings::default(),
                 },
                 traffic: TrafficCollectorConfig {
                     enabled: false,
@@ -196,10 +802,92 @@ mod tests {
                     protocols: vec![],
                     sample_rate: 0.1,
                     capture_payload: false,
+                    decode_dns: true,
+                    analyze_tcp: true,
+                    capture_workers: 1,
+                    capture_queue_size: 10000,
+                    detection: ThreatDetectionConfig::default(),
                 },
             },
+            transforms: vec![],
+            sinks: vec![],
         };
 
         assert_eq!(config.agent.id, "test-agent");
     }
+
+    #[test]
+    fn test_parse_transforms() {
+        let config: Vec<TransformConfig> = toml::from_str::<HashMap<String, Vec<TransformConfig>>>(
+            r#"
+            [[transforms]]
+            type = "drop"
+            when = { event_type = "log", field = "level", equals = "debug" }
+
+            [[transforms]]
+            type = "rename"
+            from = "host"
+            to = "hostname"
+            "#,
+        )
+        .unwrap()
+        .remove("transforms")
+        .unwrap();
+
+        assert!(matches!(config[0].stage, TransformStage::Drop));
+        assert_eq!(config[0].when.as_ref().unwrap().equals.as_deref(), Some("debug"));
+        assert!(config[1].when.is_none());
+        assert!(matches!(config[1].stage, TransformStage::Rename { .. }));
+    }
+
+    #[test]
+    fn test_expand_env() {
+        std::env::set_var("AGENT_TEST_HASH_KEY", "secret");
+        let mut key = Some("${AGENT_TEST_HASH_KEY}".to_string());
+        expand_env(&mut key);
+        assert_eq!(key.as_deref(), Some("secret"));
+
+        let mut missing = Some("${AGENT_TEST_UNSET_VARIABLE}".to_string());
+        expand_env(&mut missing);
+        assert_eq!(missing, None);
+
+        let mut literal = Some("plain".to_string());
+        expand_env(&mut literal);
+        assert_eq!(literal.as_deref(), Some("plain"));
+    }
+
+    #[test]
+    fn test_parse_sinks() {
+        let config: Vec<SinkConfig> = toml::from_str::<HashMap<String, Vec<SinkConfig>>>(
+            r#"
+            [[sinks]]
+            type = "file"
+            path = "/var/lib/monitoring/events.ndjson"
+            when = { event_type = "log" }
+
+            [[sinks]]
+            type = "collector"
+            name = "migration"
+            endpoint = "ws://new-collector:8080/ingest"
+            queue_batches = 10
+            "#,
+        )
+        .unwrap()
+        .remove("sinks")
+        .unwrap();
+
+        match &config[0].kind {
+            SinkKind::File(file) => assert_eq!(file.max_files, 5),
+            other => panic!("unexpected sink {:?}", other),
+        }
+        assert_eq!(config[0].queue_batches, 100);
+        match &config[1].kind {
+            SinkKind::Collector(collector) => {
+                assert_eq!(collector.endpoint, "ws://new-collector:8080/ingest");
+                assert_eq!(collector.max_in_flight, 8);
+            }
+            other => panic!("unexpected sink {:?}", other),
+        }
+        assert_eq!(config[1].name.as_deref(), Some("migration"));
+    }
 }
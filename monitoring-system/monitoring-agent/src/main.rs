@@ -1,4 +1,4 @@
-use anyhow::Result;
+use anyhow::{Context, Result};
 use clap::{Parser, Subcommand};
 use std::path::PathBuf;
 use tracing::{info, error};
@@ -8,7 +8,9 @@ mod config;
 mod collectors;
 mod buffer;
 mod pipeline;
+mod proxy;
 mod transport;
+mod transform;
 
 use config::AgentConfig;
 
@@ -93,9 +95,19 @@ async fn start_agent(config: AgentConfig, foreground: bool) -> Result<()> {
     info!("Agent ID: {}", config.agent.id);
     info!("Hostname: {}", config.agent.hostname);
     info!("Collector endpoint: {}", config.collector.endpoint);
+    if !config.collector.endpoints.is_empty() {
+        info!(
+            "Other collector endpoints ({}): {:?}",
+            config.collector.endpoint_strategy, config.collector.endpoints
+        );
+    }
 
     // Create event buffer
-    let buffer = buffer::RingBuffer::new(config.buffer.max_events);
+    let mut buffer = buffer::RingBuffer::with_settings(&config.buffer);
+    if let Some(throttle) = &config.collectors.logs.throttle {
+        let throttle = buffer::Throttle::new(throttle).context("Invalid collectors.logs.throttle")?;
+        buffer = buffer.with_log_throttle(throttle);
+    }
     let buffer = std::sync::Arc::new(buffer);
 
     // Create shutdown channel
@@ -151,11 +163,18 @@ async fn start_agent(config: AgentConfig, foreground: bool) -> Result<()> {
 
     // Start batcher/compressor pipeline
     info!("Starting event pipeline");
+    let dictionary = pipeline::SharedDictionary::default();
     let batcher = pipeline::Batcher::new(
         config.buffer.clone(),
         buffer.clone(),
         config.agent.clone(),
-    );
+    )
+    .with_dictionary(dictionary.clone())
+    .with_transforms(transform::TransformChain::from_config(
+        &config.agent,
+        &config.collectors.logs,
+        &config.transforms,
+    )?);
     let (batch_tx, batch_rx) = tokio::sync::mpsc::channel(100);
     let batcher_handle = tokio::spawn(async move {
         if let Err(e) = batcher.run(batch_tx).await {
@@ -164,9 +183,49 @@ async fn start_agent(config: AgentConfig, foreground: bool) -> Result<()> {
     });
     handles.push(batcher_handle);
 
+    let disk_queue = if config.buffer.disk.enabled {
+        let disk = &config.buffer.disk;
+        info!("Persisting batches to disk buffer at {}", disk.path);
+        let queue = buffer::DiskQueue::open(
+            &disk.path,
+            disk.max_bytes,
+            disk.segment_bytes,
+            buffer::FsyncPolicy::parse(
+                &disk.fsync,
+                std::time::Duration::from_millis(disk.fsync_interval_ms),
+            ),
+        )?;
+        Some(std::sync::Arc::new(parking_lot::Mutex::new(queue)))
+    } else {
+        None
+    };
+
+    // With extra sinks, each gets its own copy of the batches
+    let batch_rx = if config.sinks.is_empty() {
+        batch_rx
+    } else {
+        let mut fanout =
+            transport::Fanout::new(dictionary.clone(), config.buffer.compression_level);
+        let collector_rx =
+            fanout.add_collector(config.collector.queue_batches, disk_queue.clone());
+        for sink in &config.sinks {
+            handles.push(transport::spawn_sink(sink, &mut fanout)?);
+        }
+        handles.push(tokio::spawn(async move {
+            if let Err(e) = fanout.run(batch_rx).await {
+                error!("Fan-out error: {}", e);
+            }
+        }));
+        collector_rx
+    };
+
     // Start transport
     info!("Starting transport layer");
-    let transport = transport::Transport::new(config.collector.clone());
+    let mut transport =
+        transport::Transport::new(config.collector.clone()).with_dictionary(dictionary);
+    if let Some(queue) = disk_queue {
+        transport = transport.with_disk_queue(queue);
+    }
     let transport_handle = tokio::spawn(async move {
         if let Err(e) = transport.run(batch_rx).await {
             error!("Transport error: {}", e);
@@ -213,11 +272,19 @@ fn check_config(config: AgentConfig) -> Result<()> {
     println!("  Agent ID: {}", config.agent.id);
     println!("  Hostname: {}", config.agent.hostname);
     println!("  Collector: {}", config.collector.endpoint);
+    for endpoint in &config.collector.endpoints {
+        println!("  Collector ({}): {}", config.collector.endpoint_strategy, endpoint);
+    }
     println!("  Log collection: {}", config.collectors.logs.enabled);
     println!("  Metrics collection: {}", config.collectors.metrics.enabled);
     println!("  Traffic collection: {}", config.collectors.traffic.enabled);
-    println!("  Buffer size: {}", config.buffer.max_events);
+    println!(
+        "  Buffer size: {} events / {} bytes ({})",
+        config.buffer.max_events, config.buffer.max_bytes, config.buffer.overflow
+    );
     println!("  Flush interval: {}s", config.buffer.flush_interval_secs);
+    println!("  Disk buffer: {}", config.buffer.disk.enabled);
+    println!("  Extra sinks: {}", config.sinks.len());
     Ok(())
 }
 
@@ -1,17 +1,29 @@
 use crate::buffer::RingBuffer;
 use crate::config::{AgentSettings, BufferSettings};
-use crate::pipeline::Compressor;
-use anyhow::Result;
-use monitoring_common::{Batch, CompressionType, UncompressedBatch};
+use crate::pipeline::{Compressor, SharedDictionary, ZstdOptions};
+use crate::transform::TransformChain;
+use anyhow::{Context, Result};
+use monitoring_common::{Batch, CompressionType, Event, PayloadEncoding, UncompressedBatch};
 use std::sync::Arc;
+use std::time::{Duration, Instant};
 use tokio::sync::mpsc::Sender;
-use tracing::{debug, info};
+use tokio::time::MissedTickBehavior;
+use tracing::{debug, info, warn};
 use uuid::Uuid;
 
+/// How often the buffer is checked for a ready batch
+const POLL_INTERVAL: Duration = Duration::from_millis(50);
+/// Minimum time between reports of dropped events
+const DROP_REPORT_INTERVAL: Duration = Duration::from_secs(10);
+/// How often transforms get a chance to emit events on their own
+const TRANSFORM_TICK_INTERVAL: Duration = Duration::from_secs(1);
+
 pub struct Batcher {
     config: BufferSettings,
     agent: AgentSettings,
     buffer: Arc<RingBuffer>,
+    dictionary: Option<SharedDictionary>,
+    transforms: TransformChain,
 }
 
 impl Batcher {
@@ -24,69 +36,257 @@ impl Batcher {
             config,
             agent,
             buffer,
+            dictionary: None,
+            transforms: TransformChain::default(),
         }
     }
 
-    pub async fn run(self, batch_tx: Sender<Batch>) -> Result<()> {
-        info!("Starting batcher (max_size: {}, flush_interval: {}s)",
+    /// Run drained events through a transform chain before batching them
+    pub fn with_transforms(mut self, transforms: TransformChain) -> Self {
+        self.transforms = transforms;
+        self
+    }
+
+    /// Compress zstd batches with the latest dictionary the collector offered
+    pub fn with_dictionary(mut self, dictionary: SharedDictionary) -> Self {
+        self.dictionary = Some(dictionary);
+        self
+    }
+
+    /// Flush whenever a full batch is waiting or the oldest buffered event
+    /// reaches the maximum age, draining back to back while a backlog remains
+    pub async fn run(mut self, batch_tx: Sender<Batch>) -> Result<()> {
+        let max_age = self.max_event_age();
+        info!("Starting batcher (max_size: {}, max_bytes: {}, max_age: {:?})",
             self.config.max_batch_size,
-            self.config.flush_interval_secs);
+            self.config.max_batch_bytes,
+            max_age);
+
+        let mut poll = tokio::time::interval(POLL_INTERVAL);
+        poll.set_missed_tick_behavior(MissedTickBehavior::Skip);
 
-        let mut interval = tokio::time::interval(
-            tokio::time::Duration::from_secs(self.config.flush_interval_secs)
-        );
+        // When the buffer was first seen non-empty since the last flush
+        let mut pending_since: Option<Instant> = None;
+        let mut reported_drops = 0;
+        let mut last_drop_report = Instant::now();
+        let mut last_transform_tick = Instant::now();
 
         loop {
-            interval.tick().await;
+            poll.tick().await;
+
+            if last_drop_report.elapsed() >= DROP_REPORT_INTERVAL {
+                reported_drops = self.report_drops(reported_drops);
+                last_drop_report = Instant::now();
+            }
+
+            // Closed aggregation windows and the like must not wait for input
+            if last_transform_tick.elapsed() >= TRANSFORM_TICK_INTERVAL {
+                self.buffer.flush_throttled(chrono::Utc::now().timestamp_millis());
+                let generated = self.transforms.tick();
+                self.send(generated, &batch_tx).await?;
+                last_transform_tick = Instant::now();
+            }
 
             // Check if we have events to batch
             if self.buffer.is_empty() {
-                debug!("Buffer empty, skipping batch");
+                pending_since = None;
                 continue;
             }
 
-            // Drain events from buffer
-            let events = self.buffer.drain(self.config.max_batch_size);
-            
-            if events.is_empty() {
+            let since = *pending_since.get_or_insert_with(Instant::now);
+            if !self.batch_ready() && since.elapsed() < max_age {
                 continue;
             }
 
-            debug!("Creating batch with {} events", events.len());
-
-            // Create uncompressed batch
-            let uncompressed_batch = UncompressedBatch {
-                batch_id: Uuid::new_v4().to_string(),
-                agent_id: self.agent.id.clone(),
-                hostname: self.agent.hostname.clone(),
-                timestamp: chrono::Utc::now().timestamp_millis(),
-                events,
-            };
-
-            // Compress batch
-            let compression = self.parse_compression_type();
-            let batch = match Compressor::compress(uncompressed_batch, compression) {
-                Ok(batch) => batch,
-                Err(e) => {
-                    tracing::error!("Failed to compress batch: {}", e);
-                    continue;
+            loop {
+                self.flush(&batch_tx).await?;
+                if !self.batch_ready() {
+                    break;
                 }
-            };
+                debug!("Backlog of {} events, flushing again", self.buffer.len());
+            }
 
-            // Send batch to transport
-            if let Err(e) = batch_tx.send(batch).await {
-                tracing::error!("Failed to send batch to transport: {}", e);
+            pending_since = (!self.buffer.is_empty()).then(Instant::now);
+        }
+    }
+
+    /// Whether a full batch, by count or size, is waiting
+    fn batch_ready(&self) -> bool {
+        self.buffer.len() >= self.config.max_batch_size
+            || self.buffer.bytes() >= self.config.max_batch_bytes
+    }
+
+    /// The flush interval still caps latency if it is the tighter bound
+    fn max_event_age(&self) -> Duration {
+        Duration::from_millis(self.config.max_event_age_ms)
+            .min(Duration::from_secs(self.config.flush_interval_secs))
+    }
+
+    /// Drain one batch, transform and compress it and hand it to the transport
+    async fn flush(&mut self, batch_tx: &Sender<Batch>) -> Result<()> {
+        // Drain events from buffer
+        let events = self.buffer.drain_batch(self.config.max_batch_size, self.config.max_batch_bytes);
+        let events = self.transforms.apply(events);
+        self.send(events, batch_tx).await
+    }
+
+    /// Compress events into a batch and hand it to the transport
+    async fn send(&mut self, events: Vec<Event>, batch_tx: &Sender<Batch>) -> Result<()> {
+        if events.is_empty() {
+            return Ok(());
+        }
+
+        debug!("Creating batch with {} events", events.len());
+
+        // Create uncompressed batch
+        let uncompressed_batch = UncompressedBatch {
+            batch_id: Uuid::new_v4().to_string(),
+            agent_id: self.agent.id.clone(),
+            hostname: self.agent.hostname.clone(),
+            timestamp: chrono::Utc::now().timestamp_millis(),
+            events,
+        };
+
+        // Compress batch
+        let compression = self.parse_compression_type();
+        let encoding = self.parse_encoding();
+        let zstd = self.zstd_options();
+        let batch = match Compressor::compress(uncompressed_batch, compression, encoding, &zstd) {
+            Ok(batch) => batch,
+            Err(e) => {
+                tracing::error!("Failed to compress batch: {}", e);
+                return Ok(());
             }
+        };
+
+        // Send batch to transport
+        batch_tx.send(batch).await.ok().context("Transport channel closed")
+    }
+
+    /// Log events dropped by the buffer since the last report, returning
+    /// the new total
+    fn report_drops(&self, reported: u64) -> u64 {
+        let dropped = self.buffer.dropped();
+        let total: u64 = dropped.iter().map(|(_, count)| count).sum();
+        if total > reported {
+            let lanes: Vec<String> = dropped
+                .iter()
+                .filter(|(_, count)| *count > 0)
+                .map(|(lane, count)| format!("{}={}", lane, count))
+                .collect();
+            warn!(
+                "Buffer dropped {} events since last report (totals: {})",
+                total - reported,
+                lanes.join(", ")
+            );
+        }
+        total
+    }
+
+    fn zstd_options(&self) -> ZstdOptions {
+        let dictionary = match &self.dictionary {
+            Some(dictionary) if self.config.compression_dictionary => dictionary.read().latest(),
+            _ => None,
+        };
+        ZstdOptions {
+            level: self.config.compression_level,
+            dictionary,
         }
     }
 
+    fn parse_encoding(&self) -> PayloadEncoding {
+        PayloadEncoding::parse(&self.config.encoding).unwrap_or_default()
+    }
+
     fn parse_compression_type(&self) -> CompressionType {
         match self.config.compression.as_str() {
             "snappy" => CompressionType::Snappy,
             "lz4" => CompressionType::Lz4,
             "gzip" => CompressionType::Gzip,
+            "zstd" => CompressionType::Zstd,
             "none" => CompressionType::None,
             _ => CompressionType::Snappy,
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::{BufferQuotas, DiskBufferSettings};
+    use monitoring_common::{MetricEvent, MetricType};
+    use std::collections::HashMap;
+    use tokio::sync::mpsc;
+
+    fn settings(max_batch_size: usize, max_event_age_ms: u64) -> BufferSettings {
+        BufferSettings {
+            max_events: 1000,
+            max_bytes: 1 << 20,
+            max_event_bytes: 1 << 16,
+            flush_interval_secs: 60,
+            max_batch_size,
+            max_batch_bytes: 1 << 20,
+            max_event_age_ms,
+            compression: "none".to_string(),
+            compression_level: 3,
+            compression_dictionary: false,
+            encoding: "msgpack".to_string(),
+            disk: DiskBufferSettings::default(),
+            overflow: "drop_newest".to_string(),
+            block_timeout_ms: 100,
+            quotas: BufferQuotas::default(),
+        }
+    }
+
+    fn start(settings: BufferSettings, events: usize) -> mpsc::Receiver<Batch> {
+        let buffer = Arc::new(RingBuffer::new(1000));
+        for i in 0..events {
+            buffer
+                .push(Event::Metric(MetricEvent {
+                    timestamp: i as i64,
+                    name: "test".to_string(),
+                    value: 1.0,
+                    metric_type: MetricType::Gauge,
+                    tags: HashMap::new(),
+                    unit: None,
+                }))
+                .unwrap();
+        }
+
+        let agent = AgentSettings {
+            id: "agent".to_string(),
+            hostname: "host".to_string(),
+            tags: vec![],
+        };
+        let (tx, rx) = mpsc::channel(10);
+        tokio::spawn(Batcher::new(settings, buffer, agent).run(tx));
+        rx
+    }
+
+    #[tokio::test]
+    async fn test_flushes_full_batches_back_to_back() {
+        let mut rx = start(settings(10, 60_000), 25);
+
+        for _ in 0..2 {
+            let batch = tokio::time::timeout(Duration::from_secs(1), rx.recv())
+                .await
+                .expect("full batch flushed without waiting for the timer")
+                .unwrap();
+            assert_eq!(batch.event_count, 10);
+        }
+
+        // The remainder waits for its age limit
+        assert!(tokio::time::timeout(Duration::from_millis(300), rx.recv()).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn test_flushes_partial_batch_at_max_age() {
+        let mut rx = start(settings(100, 200), 5);
+
+        let batch = tokio::time::timeout(Duration::from_secs(2), rx.recv())
+            .await
+            .expect("partial batch flushed at max age")
+            .unwrap();
+        assert_eq!(batch.event_count, 5);
+    }
+}
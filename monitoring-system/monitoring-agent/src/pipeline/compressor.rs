@@ -1,30 +1,124 @@
-use anyhow::Result;
-use monitoring_common::{Batch, CompressionType, UncompressedBatch};
+use anyhow::{Context, Result};
+use monitoring_common::encoding::{decode_events, encode_events};
+use monitoring_common::{
+    Batch, CompressionDictionary, CompressionType, PayloadEncoding, UncompressedBatch,
+};
+use parking_lot::RwLock;
 use sha2::{Digest, Sha256};
+use std::collections::{HashMap, VecDeque};
+use std::sync::Arc;
+
+/// Earlier dictionaries kept for batches compressed before a rotation
+const MAX_RECENT_DICTIONARIES: usize = 4;
+
+/// Dictionaries offered by the collectors, shared between the transport
+/// that receives them and the batcher that compresses with the latest
+pub type SharedDictionary = Arc<RwLock<Dictionaries>>;
+
+/// Dictionaries each collector offered, since every collector trains its
+/// own. Only those of the collector currently connected are used for new
+/// batches or left in batches sent to it.
+#[derive(Debug, Default)]
+pub struct Dictionaries {
+    /// URL of the collector batches go to
+    endpoint: String,
+    /// IDs each collector offered and still has, newest last
+    offered: HashMap<String, VecDeque<u32>>,
+    /// Every recently offered dictionary, newest last, so batches
+    /// compressed with one can still be decoded
+    recent: VecDeque<Arc<CompressionDictionary>>,
+}
+
+impl Dictionaries {
+    /// Dictionary for new batches: the latest the current collector offered
+    pub fn latest(&self) -> Option<Arc<CompressionDictionary>> {
+        let id = self.offered.get(&self.endpoint)?.back()?;
+        self.get(*id)
+    }
+
+    /// Dictionary a batch was compressed with, from whichever collector
+    pub fn get(&self, id: u32) -> Option<Arc<CompressionDictionary>> {
+        self.recent.iter().find(|dictionary| dictionary.id == id).cloned()
+    }
+
+    /// Whether the current collector can decompress batches that use `id`
+    pub fn accepts(&self, id: u32) -> bool {
+        self.offered
+            .get(&self.endpoint)
+            .is_some_and(|offered| offered.contains(&id))
+    }
+
+    /// Switch to the dictionaries of the collector at `endpoint`
+    pub fn set_endpoint(&mut self, endpoint: &str) {
+        endpoint.clone_into(&mut self.endpoint);
+    }
+
+    /// Keep a dictionary the current collector offered
+    pub fn insert(&mut self, dictionary: CompressionDictionary) {
+        let offered = self.offered.entry(self.endpoint.clone()).or_default();
+        offered.retain(|id| *id != dictionary.id);
+        if offered.len() > MAX_RECENT_DICTIONARIES {
+            offered.pop_front();
+        }
+        offered.push_back(dictionary.id);
+
+        self.recent.retain(|existing| existing.id != dictionary.id);
+        self.recent.push_back(Arc::new(dictionary));
+        let limit = (MAX_RECENT_DICTIONARIES + 1) * self.offered.len();
+        while self.recent.len() > limit {
+            if let Some(oldest) = self.recent.pop_front() {
+                for offered in self.offered.values_mut() {
+                    offered.retain(|id| *id != oldest.id);
+                }
+            }
+        }
+    }
+
+    /// Stop using a dictionary the current collector says it does not have
+    pub fn evict(&mut self, id: u32) {
+        if let Some(offered) = self.offered.get_mut(&self.endpoint) {
+            offered.retain(|offered| *offered != id);
+        }
+    }
+}
+
+/// Settings used when compressing with `CompressionType::Zstd`
+#[derive(Debug, Clone, Default)]
+pub struct ZstdOptions {
+    /// Compression level; 0 selects the zstd default
+    pub level: i32,
+    pub dictionary: Option<Arc<CompressionDictionary>>,
+}
 
 pub struct Compressor;
 
 impl Compressor {
-    pub fn compress(uncompressed: UncompressedBatch, compression: CompressionType) -> Result<Batch> {
-        // Serialize events to JSON
-        let json_data = serde_json::to_vec(&uncompressed.events)?;
+    pub fn compress(
+        uncompressed: UncompressedBatch,
+        compression: CompressionType,
+        encoding: PayloadEncoding,
+        zstd: &ZstdOptions,
+    ) -> Result<Batch> {
+        // Encode events
+        let payload = encode_events(&uncompressed.events, encoding)?;
+        let mut dictionary_id = None;
 
         // Compress based on type
         let compressed_data = match compression {
-            CompressionType::None => json_data.clone(),
+            CompressionType::None => payload.clone(),
             CompressionType::Snappy => {
                 let mut encoder = snap::raw::Encoder::new();
-                encoder.compress_vec(&json_data)?
+                encoder.compress_vec(&payload)?
             }
             #[cfg(feature = "lz4-compression")]
             CompressionType::Lz4 => {
-                lz4::block::compress(&json_data, None, false)?
+                lz4::block::compress(&payload, None, false)?
             }
             #[cfg(not(feature = "lz4-compression"))]
             CompressionType::Lz4 => {
                 tracing::warn!("LZ4 compression not compiled in, using Snappy");
                 let mut encoder = snap::raw::Encoder::new();
-                encoder.compress_vec(&json_data)?
+                encoder.compress_vec(&payload)?
             }
             CompressionType::Gzip => {
                 use std::io::Write;
@@ -32,9 +126,17 @@ impl Compressor {
                     Vec::new(),
                     flate2::Compression::default(),
                 );
-                encoder.write_all(&json_data)?;
+                encoder.write_all(&payload)?;
                 encoder.finish()?
             }
+            CompressionType::Zstd => match &zstd.dictionary {
+                Some(dictionary) => {
+                    dictionary_id = Some(dictionary.id);
+                    zstd::bulk::Compressor::with_dictionary(zstd.level, &dictionary.data)?
+                        .compress(&payload)?
+                }
+                None => zstd::bulk::compress(&payload, zstd.level)?,
+            },
         };
 
         // Calculate checksum
@@ -43,7 +145,7 @@ impl Compressor {
         let checksum = hex::encode(hasher.finalize());
 
         // Calculate compression ratio
-        let original_size = json_data.len();
+        let original_size = payload.len();
         let compressed_size = compressed_data.len();
         let ratio = (compressed_size as f64 / original_size as f64) * 100.0;
         
@@ -61,12 +163,40 @@ impl Compressor {
             timestamp: uncompressed.timestamp,
             event_count: uncompressed.events.len(),
             compression,
+            encoding,
+            dictionary_id,
             compressed_data,
             checksum,
         })
     }
 
-    pub fn decompress(batch: &Batch) -> Result<Vec<monitoring_common::Event>> {
+    /// Re-encode a batch's events, keeping its identity and compression
+    pub fn transcode(batch: &Batch, encoding: PayloadEncoding, zstd: &ZstdOptions) -> Result<Batch> {
+        let uncompressed = Self::unpack(batch, zstd.dictionary.as_deref())?;
+        Self::compress(uncompressed, batch.compression.clone(), encoding, zstd)
+    }
+
+    /// Compress a batch again without the dictionary it uses, for a
+    /// collector that does not have it
+    pub fn without_dictionary(batch: &Batch, dictionary: &CompressionDictionary) -> Result<Batch> {
+        let uncompressed = Self::unpack(batch, Some(dictionary))?;
+        Self::compress(uncompressed, batch.compression.clone(), batch.encoding, &ZstdOptions::default())
+    }
+
+    fn unpack(batch: &Batch, dictionary: Option<&CompressionDictionary>) -> Result<UncompressedBatch> {
+        Ok(UncompressedBatch {
+            batch_id: batch.batch_id.clone(),
+            agent_id: batch.agent_id.clone(),
+            hostname: batch.hostname.clone(),
+            timestamp: batch.timestamp,
+            events: Self::decompress(batch, dictionary)?,
+        })
+    }
+
+    pub fn decompress(
+        batch: &Batch,
+        dictionary: Option<&CompressionDictionary>,
+    ) -> Result<Vec<monitoring_common::Event>> {
         let decompressed_data = match batch.compression {
             CompressionType::None => batch.compressed_data.clone(),
             CompressionType::Snappy => {
@@ -88,6 +218,22 @@ impl Compressor {
                 decoder.read_to_end(&mut decompressed)?;
                 decompressed
             }
+            CompressionType::Zstd => match batch.dictionary_id {
+                Some(id) => {
+                    use std::io::Read;
+                    let dictionary = dictionary
+                        .filter(|dictionary| dictionary.id == id)
+                        .with_context(|| format!("Compression dictionary {} not available", id))?;
+                    let mut decoder = zstd::stream::read::Decoder::with_dictionary(
+                        &batch.compressed_data[..],
+                        &dictionary.data,
+                    )?;
+                    let mut decompressed = Vec::new();
+                    decoder.read_to_end(&mut decompressed)?;
+                    decompressed
+                }
+                None => zstd::stream::decode_all(&batch.compressed_data[..])?,
+            },
         };
 
         // Verify checksum
@@ -100,7 +246,7 @@ impl Compressor {
         }
 
         // Deserialize events
-        let events = serde_json::from_slice(&decompressed_data)?;
+        let events = decode_events(&decompressed_data, batch.encoding)?;
         Ok(events)
     }
 }
@@ -130,9 +276,89 @@ mod tests {
             events: events.clone(),
         };
 
-        let batch = Compressor::compress(uncompressed, CompressionType::Snappy).unwrap();
-        let decompressed = Compressor::decompress(&batch).unwrap();
+        let batch = Compressor::compress(uncompressed, CompressionType::Snappy, PayloadEncoding::Json, &ZstdOptions::default()).unwrap();
+        let decompressed = Compressor::decompress(&batch, None).unwrap();
 
         assert_eq!(decompressed.len(), events.len());
     }
+
+    #[test]
+    fn test_transcode() {
+        let uncompressed = UncompressedBatch {
+            batch_id: "test-batch".to_string(),
+            agent_id: "test-agent".to_string(),
+            hostname: "test-host".to_string(),
+            timestamp: 123,
+            events: monitoring_common::test_data::generate_log_events(10),
+        };
+
+        let zstd = ZstdOptions::default();
+        let batch = Compressor::compress(uncompressed, CompressionType::Gzip, PayloadEncoding::MessagePack, &zstd).unwrap();
+        let json = Compressor::transcode(&batch, PayloadEncoding::Json, &zstd).unwrap();
+
+        assert_eq!(json.batch_id, batch.batch_id);
+        assert_eq!(json.compression, CompressionType::Gzip);
+        assert_eq!(json.encoding, PayloadEncoding::Json);
+        assert_eq!(Compressor::decompress(&json, None).unwrap().len(), 10);
+    }
+
+    #[test]
+    fn test_zstd_with_dictionary() {
+        let samples: Vec<Vec<u8>> = (0..200)
+            .map(|_| {
+                let events = monitoring_common::test_data::generate_log_events(5);
+                serde_json::to_vec(&events).unwrap()
+            })
+            .collect();
+        let dictionary = Arc::new(CompressionDictionary {
+            id: 7,
+            data: zstd::dict::from_samples(&samples, 4096).unwrap(),
+        });
+
+        let uncompressed = UncompressedBatch {
+            batch_id: "test-batch".to_string(),
+            agent_id: "test-agent".to_string(),
+            hostname: "test-host".to_string(),
+            timestamp: 123,
+            events: monitoring_common::test_data::generate_log_events(5),
+        };
+        let plain = Compressor::compress(uncompressed.clone(), CompressionType::Zstd, PayloadEncoding::Json, &ZstdOptions::default()).unwrap();
+        let zstd = ZstdOptions { level: 3, dictionary: Some(dictionary.clone()) };
+        let batch = Compressor::compress(uncompressed, CompressionType::Zstd, PayloadEncoding::Json, &zstd).unwrap();
+
+        assert_eq!(plain.dictionary_id, None);
+        assert_eq!(batch.dictionary_id, Some(7));
+        assert!(batch.compressed_data.len() < plain.compressed_data.len());
+        assert_eq!(Compressor::decompress(&plain, None).unwrap().len(), 5);
+        assert_eq!(Compressor::decompress(&batch, Some(&dictionary)).unwrap().len(), 5);
+        assert!(Compressor::decompress(&batch, None).is_err());
+
+        // A batch from before a rotation still finds its dictionary
+        let mut dictionaries = Dictionaries::default();
+        dictionaries.insert((*dictionary).clone());
+        dictionaries.insert(CompressionDictionary { id: 8, data: dictionary.data.clone() });
+        assert_eq!(dictionaries.latest().unwrap().id, 8);
+        let previous = dictionaries.get(batch.dictionary_id.unwrap()).unwrap();
+        let zstd = ZstdOptions { level: 0, dictionary: Some(previous) };
+        let json = Compressor::transcode(&batch, PayloadEncoding::Json, &zstd).unwrap();
+        assert_eq!(json.dictionary_id, Some(7));
+
+        // One the collector lost is not used again, but still decodes
+        dictionaries.evict(8);
+        assert!(!dictionaries.accepts(8));
+        assert_eq!(dictionaries.latest().unwrap().id, 7);
+        let plain = Compressor::without_dictionary(&batch, &dictionaries.get(7).unwrap()).unwrap();
+        assert_eq!((plain.batch_id.as_str(), plain.dictionary_id), ("test-batch", None));
+        assert_eq!(Compressor::decompress(&plain, None).unwrap().len(), 5);
+
+        // Another collector has its own dictionaries
+        dictionaries.set_endpoint("ws://other:8080");
+        assert!(dictionaries.latest().is_none());
+        assert!(!dictionaries.accepts(7));
+        assert!(dictionaries.get(7).is_some());
+        dictionaries.insert(CompressionDictionary { id: 9, data: dictionary.data.clone() });
+        dictionaries.set_endpoint("");
+        assert_eq!(dictionaries.latest().unwrap().id, 7);
+        assert!(!dictionaries.accepts(9));
+    }
 }
@@ -2,4 +2,4 @@ mod batcher;
 mod compressor;
 
 pub use batcher::Batcher;
-pub use compressor::Compressor;
+pub use compressor::{Compressor, SharedDictionary, ZstdOptions};
@@ -0,0 +1,260 @@
+use super::Transform;
+use anyhow::{Context, Result};
+use glob::Pattern;
+use monitoring_common::{Event, MetricEvent, MetricType};
+use sketches_ddsketch::{Config, DDSketch};
+use std::collections::HashMap;
+
+/// Metric name plus its tags, sorted so equal tag sets compare equal
+type GroupKey = (String, Vec<(String, String)>);
+
+struct Group {
+    tags: HashMap<String, String>,
+    unit: Option<String>,
+    sketch: DDSketch,
+}
+
+/// What each group is reported as when its window closes
+pub enum Report {
+    /// `<name>.min`, `.max`, `.avg`, `.sum`, `.count` and `.pNN` gauges
+    Summary { percentiles: Vec<f64> },
+    /// A single `<name>` counter holding the number of values
+    Count,
+}
+
+/// Values grouped by metric name and tags over wall-clock aligned windows
+pub struct Rollup {
+    report: Report,
+    window_ms: i64,
+    groups: HashMap<GroupKey, Group>,
+    /// End of the window being collected
+    window_end: Option<i64>,
+}
+
+impl Rollup {
+    pub fn new(window_secs: u64, report: Report) -> Result<Self> {
+        if window_secs == 0 {
+            anyhow::bail!("window_secs must be positive");
+        }
+        if let Report::Summary { percentiles } = &report {
+            if let Some(invalid) = percentiles.iter().find(|q| !(0.0..=1.0).contains(*q)) {
+                anyhow::bail!("percentile {} is outside 0.0..=1.0", invalid);
+            }
+        }
+
+        Ok(Self {
+            report,
+            window_ms: window_secs as i64 * 1000,
+            groups: HashMap::new(),
+            window_end: None,
+        })
+    }
+
+    pub fn add(&mut self, name: &str, tags: &HashMap<String, String>, unit: Option<&str>, value: f64) {
+        let mut key: Vec<(String, String)> = tags
+            .iter()
+            .map(|(key, value)| (key.clone(), value.clone()))
+            .collect();
+        key.sort();
+
+        let group = self
+            .groups
+            .entry((name.to_string(), key))
+            .or_insert_with(|| Group {
+                tags: tags.clone(),
+                unit: unit.map(str::to_string),
+                sketch: DDSketch::new(Config::defaults()),
+            });
+        group.sketch.add(value);
+    }
+
+    /// Report every group once the current window has closed
+    pub fn emit(&mut self, now: i64, out: &mut Vec<Event>) {
+        if self.groups.is_empty() {
+            return;
+        }
+
+        let window_end = *self
+            .window_end
+            .get_or_insert((now / self.window_ms + 1) * self.window_ms);
+        if now < window_end {
+            return;
+        }
+
+        self.flush(window_end - self.window_ms, out);
+        self.window_end = None;
+    }
+
+    fn flush(&mut self, window_start: i64, out: &mut Vec<Event>) {
+        for ((name, _), group) in self.groups.drain() {
+            let sketch = &group.sketch;
+            let count = sketch.count();
+            if count == 0 {
+                continue;
+            }
+
+            let percentiles = match &self.report {
+                Report::Count => {
+                    out.push(Event::Metric(MetricEvent {
+                        timestamp: window_start,
+                        name,
+                        value: count as f64,
+                        metric_type: MetricType::Counter,
+                        tags: group.tags,
+                        unit: None,
+                    }));
+                    continue;
+                }
+                Report::Summary { percentiles } => percentiles,
+            };
+
+            let sum = sketch.sum().unwrap_or_default();
+            let mut stats = vec![
+                ("min".to_string(), sketch.min().unwrap_or_default(), group.unit.clone()),
+                ("max".to_string(), sketch.max().unwrap_or_default(), group.unit.clone()),
+                ("avg".to_string(), sum / count as f64, group.unit.clone()),
+                ("sum".to_string(), sum, group.unit.clone()),
+                ("count".to_string(), count as f64, None),
+            ];
+            for &q in percentiles {
+                if let Ok(Some(value)) = sketch.quantile(q) {
+                    stats.push((percentile_name(q), value, group.unit.clone()));
+                }
+            }
+
+            for (stat, value, unit) in stats {
+                out.push(Event::Metric(MetricEvent {
+                    timestamp: window_start,
+                    name: format!("{}.{}", name, stat),
+                    value,
+                    metric_type: MetricType::Gauge,
+                    tags: group.tags.clone(),
+                    unit,
+                }));
+            }
+        }
+    }
+}
+
+/// Roll samples of selected metrics up into min, max, avg, sum, count and
+/// optional percentiles per window, instead of shipping every sample
+pub struct Aggregate {
+    metrics: Vec<Pattern>,
+    rollup: Rollup,
+}
+
+impl Aggregate {
+    pub fn new(metrics: &[String], window_secs: u64, percentiles: &[f64]) -> Result<Self> {
+        if metrics.is_empty() {
+            anyhow::bail!("aggregate needs at least one metric name");
+        }
+
+        Ok(Self {
+            metrics: metrics
+                .iter()
+                .map(|name| Pattern::new(name).with_context(|| format!("Invalid metric glob {:?}", name)))
+                .collect::<Result<_>>()?,
+            rollup: Rollup::new(
+                window_secs,
+                Report::Summary {
+                    percentiles: percentiles.to_vec(),
+                },
+            )?,
+        })
+    }
+}
+
+impl Transform for Aggregate {
+    fn apply(&mut self, event: &mut Event) -> bool {
+        match event {
+            Event::Metric(metric)
+                if self.metrics.iter().any(|pattern| pattern.matches(&metric.name)) =>
+            {
+                self.rollup
+                    .add(&metric.name, &metric.tags, metric.unit.as_deref(), metric.value);
+                // The raw sample is replaced by the window's statistics
+                false
+            }
+            _ => true,
+        }
+    }
+
+    fn emit(&mut self, now: i64, out: &mut Vec<Event>) {
+        self.rollup.emit(now, out);
+    }
+}
+
+/// 0.5 -> "p50", 0.999 -> "p99_9"
+fn percentile_name(q: f64) -> String {
+    let percent = format!("{}", (q * 1000.0).round() / 10.0);
+    format!("p{}", percent.replace('.', "_"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn sample(name: &str, core: &str, value: f64) -> Event {
+        Event::Metric(MetricEvent {
+            timestamp: 0,
+            name: name.to_string(),
+            value,
+            metric_type: MetricType::Gauge,
+            tags: HashMap::from([("core".to_string(), core.to_string())]),
+            unit: Some("percent".to_string()),
+        })
+    }
+
+    fn value(events: &[Event], name: &str, core: &str) -> f64 {
+        events
+            .iter()
+            .find_map(|event| match event {
+                Event::Metric(metric) if metric.name == name && metric.tags["core"] == core => {
+                    Some(metric.value)
+                }
+                _ => None,
+            })
+            .unwrap_or_else(|| panic!("missing {} for core {}", name, core))
+    }
+
+    #[test]
+    fn test_rolls_up_per_group_and_window() {
+        let mut aggregate = Aggregate::new(&["cpu.*".to_string()], 60, &[0.5, 0.999]).unwrap();
+
+        for i in 1..=100 {
+            assert!(!aggregate.apply(&mut sample("cpu.usage", "0", i as f64)));
+        }
+        assert!(!aggregate.apply(&mut sample("cpu.usage", "1", 7.0)));
+        assert!(aggregate.apply(&mut sample("memory.used", "0", 1.0)));
+
+        // Nothing until the window closes
+        let mut out = Vec::new();
+        aggregate.emit(61_000, &mut out);
+        assert!(out.is_empty());
+        aggregate.emit(120_000, &mut out);
+
+        assert_eq!(out.len(), 2 * 7);
+        assert_eq!(value(&out, "cpu.usage.min", "0"), 1.0);
+        assert_eq!(value(&out, "cpu.usage.max", "0"), 100.0);
+        assert_eq!(value(&out, "cpu.usage.avg", "0"), 50.5);
+        assert_eq!(value(&out, "cpu.usage.sum", "0"), 5050.0);
+        assert_eq!(value(&out, "cpu.usage.count", "0"), 100.0);
+        assert!((value(&out, "cpu.usage.p50", "0") - 50.0).abs() <= 1.0);
+        assert!((value(&out, "cpu.usage.p99_9", "0") - 100.0).abs() <= 2.0);
+        assert_eq!(value(&out, "cpu.usage.count", "1"), 1.0);
+        assert!(out.iter().all(|event| event.timestamp() == 60_000));
+
+        // The next window starts empty
+        out.clear();
+        aggregate.emit(240_000, &mut out);
+        assert!(out.is_empty());
+    }
+
+    #[test]
+    fn test_percentile_names() {
+        assert_eq!(percentile_name(0.5), "p50");
+        assert_eq!(percentile_name(0.99), "p99");
+        assert_eq!(percentile_name(0.999), "p99_9");
+        assert!(Aggregate::new(&["cpu".to_string()], 60, &[1.5]).is_err());
+    }
+}
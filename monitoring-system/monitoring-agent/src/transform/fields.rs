@@ -0,0 +1,100 @@
+use monitoring_common::{Event, LogLevel};
+use std::borrow::Cow;
+use std::collections::HashMap;
+
+/// Read a value by name. Top-level names address the event's own fields
+/// (`message`, `source`, `level` for logs, `name` for metrics, `src_ip`,
+/// `dst_ip`, `src_port`, `dst_port`, `protocol` for traffic); any other name
+/// addresses log fields, metric tags or traffic metadata.
+pub fn get<'a>(event: &'a Event, name: &str) -> Option<Cow<'a, str>> {
+    match (event, name) {
+        (Event::Log(log), "message") => Some(Cow::Borrowed(&log.message)),
+        (Event::Log(log), "source") => Some(Cow::Borrowed(&log.source)),
+        (Event::Log(log), "level") => Some(Cow::Borrowed(level_name(&log.level))),
+        (Event::Metric(metric), "name") => Some(Cow::Borrowed(&metric.name)),
+        (Event::Traffic(traffic), "src_ip") => Some(Cow::Borrowed(&traffic.src_ip)),
+        (Event::Traffic(traffic), "dst_ip") => Some(Cow::Borrowed(&traffic.dst_ip)),
+        (Event::Traffic(traffic), "src_port") => Some(Cow::Owned(traffic.src_port.to_string())),
+        (Event::Traffic(traffic), "dst_port") => Some(Cow::Owned(traffic.dst_port.to_string())),
+        (Event::Traffic(traffic), "protocol") => Some(Cow::Owned(
+            format!("{:?}", traffic.protocol).to_lowercase(),
+        )),
+        _ => map(event).get(name).map(|value| Cow::Borrowed(value.as_str())),
+    }
+}
+
+/// Set a value, returning false for top-level fields that cannot be written
+pub fn set(event: &mut Event, name: &str, value: String) -> bool {
+    match (&mut *event, name) {
+        (Event::Log(log), "message") => log.message = value,
+        (Event::Log(log), "source") => log.source = value,
+        (Event::Metric(metric), "name") => metric.name = value,
+        (event, name) if is_top_level(event, name) => return false,
+        (event, name) => {
+            map_mut(event).insert(name.to_string(), value);
+        }
+    }
+    true
+}
+
+/// Remove a value; top-level fields cannot be removed
+pub fn remove(event: &mut Event, name: &str) -> Option<String> {
+    if is_top_level(event, name) {
+        return None;
+    }
+    map_mut(event).remove(name)
+}
+
+/// Attach a "key:value" tag. Logs keep the tag as is; metrics and traffic
+/// store it as a key (a tag without a value maps to "true"). Values already
+/// set by the collector win.
+pub fn add_tag(event: &mut Event, tag: &str) {
+    if let Event::Log(log) = event {
+        if !log.tags.iter().any(|existing| existing == tag) {
+            log.tags.push(tag.to_string());
+        }
+        return;
+    }
+
+    let (key, value) = tag.split_once(':').unwrap_or((tag, "true"));
+    map_mut(event)
+        .entry(key.to_string())
+        .or_insert_with(|| value.to_string());
+}
+
+fn is_top_level(event: &Event, name: &str) -> bool {
+    match event {
+        Event::Log(_) => matches!(name, "message" | "source" | "level"),
+        Event::Metric(_) => name == "name",
+        Event::Traffic(_) => {
+            matches!(name, "src_ip" | "dst_ip" | "src_port" | "dst_port" | "protocol")
+        }
+    }
+}
+
+fn map(event: &Event) -> &HashMap<String, String> {
+    match event {
+        Event::Log(log) => &log.fields,
+        Event::Metric(metric) => &metric.tags,
+        Event::Traffic(traffic) => &traffic.metadata,
+    }
+}
+
+fn map_mut(event: &mut Event) -> &mut HashMap<String, String> {
+    match event {
+        Event::Log(log) => &mut log.fields,
+        Event::Metric(metric) => &mut metric.tags,
+        Event::Traffic(traffic) => &mut traffic.metadata,
+    }
+}
+
+fn level_name(level: &LogLevel) -> &'static str {
+    match level {
+        LogLevel::Trace => "trace",
+        LogLevel::Debug => "debug",
+        LogLevel::Info => "info",
+        LogLevel::Warning => "warning",
+        LogLevel::Error => "error",
+        LogLevel::Critical => "critical",
+    }
+}
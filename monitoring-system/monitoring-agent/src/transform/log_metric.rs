@@ -0,0 +1,215 @@
+use super::aggregate::{Report, Rollup};
+use super::{fields, Transform};
+use crate::config::LogToMetricConfig;
+use anyhow::{Context, Result};
+use glob::Pattern;
+use monitoring_common::Event;
+use regex::Regex;
+use std::collections::HashMap;
+
+/// Turn matching log lines into a per-window counter, or a summary of a
+/// numeric value taken from each line
+pub struct LogToMetric {
+    metric: String,
+    source: Option<Pattern>,
+    levels: Vec<String>,
+    pattern: Option<Regex>,
+    value_field: Option<String>,
+    tag_fields: Vec<String>,
+    unit: Option<String>,
+    drop_original: bool,
+    rollup: Rollup,
+}
+
+impl LogToMetric {
+    pub fn new(config: &LogToMetricConfig) -> Result<Self> {
+        let report = match config.kind.as_str() {
+            "counter" => Report::Count,
+            "histogram" => {
+                if config.value_field.is_none() {
+                    anyhow::bail!("histogram rule {:?} needs a value_field", config.metric);
+                }
+                Report::Summary {
+                    percentiles: config.percentiles.clone(),
+                }
+            }
+            other => anyhow::bail!("Unknown log metric kind {:?}", other),
+        };
+
+        Ok(Self {
+            metric: config.metric.clone(),
+            source: match &config.source {
+                Some(source) => Some(
+                    Pattern::new(source).with_context(|| format!("Invalid source glob {:?}", source))?,
+                ),
+                None => None,
+            },
+            levels: config.levels.clone(),
+            pattern: match &config.pattern {
+                Some(pattern) => Some(
+                    Regex::new(pattern).with_context(|| format!("Invalid regex {:?}", pattern))?,
+                ),
+                None => None,
+            },
+            value_field: config.value_field.clone(),
+            tag_fields: config.tag_fields.clone(),
+            unit: config.unit.clone(),
+            drop_original: config.drop_original,
+            rollup: Rollup::new(config.window_secs, report)?,
+        })
+    }
+
+    /// Named groups of the rule's regex, or None if the log does not match
+    fn captures(&self, event: &Event) -> Option<HashMap<String, String>> {
+        let log = match event {
+            Event::Log(log) => log,
+            _ => return None,
+        };
+        if self.source.as_ref().is_some_and(|source| !source.matches(&log.source)) {
+            return None;
+        }
+        if !self.levels.is_empty() {
+            let level = fields::get(event, "level")?;
+            if !self.levels.iter().any(|wanted| *wanted == level) {
+                return None;
+            }
+        }
+
+        let regex = match &self.pattern {
+            Some(regex) => regex,
+            None => return Some(HashMap::new()),
+        };
+        let captures = regex.captures(&log.message)?;
+        Some(
+            regex
+                .capture_names()
+                .flatten()
+                .filter_map(|name| Some((name.to_string(), captures.name(name)?.as_str().to_string())))
+                .collect(),
+        )
+    }
+}
+
+impl Transform for LogToMetric {
+    fn apply(&mut self, event: &mut Event) -> bool {
+        let captures = match self.captures(event) {
+            Some(captures) => captures,
+            None => return true,
+        };
+        // Regex groups take precedence over log fields of the same name
+        let lookup = |name: &str| {
+            captures
+                .get(name)
+                .cloned()
+                .or_else(|| fields::get(event, name).map(|value| value.into_owned()))
+        };
+
+        let value = match &self.value_field {
+            Some(field) => match lookup(field).and_then(|value| value.trim().parse::<f64>().ok()) {
+                Some(value) => value,
+                // Without a usable value the line is left alone
+                None => return true,
+            },
+            None => 1.0,
+        };
+        let tags: HashMap<String, String> = self
+            .tag_fields
+            .iter()
+            .filter_map(|field| Some((field.clone(), lookup(field)?)))
+            .collect();
+
+        self.rollup.add(&self.metric, &tags, self.unit.as_deref(), value);
+        !self.drop_original
+    }
+
+    fn emit(&mut self, now: i64, out: &mut Vec<Event>) {
+        self.rollup.emit(now, out);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use monitoring_common::{LogEvent, LogLevel, MetricType};
+
+    fn access_log(status: u16, latency: &str) -> Event {
+        Event::Log(LogEvent {
+            timestamp: 0,
+            source: "/var/log/nginx/access.log".to_string(),
+            level: LogLevel::Info,
+            message: format!("\"GET / HTTP/1.1\" {} 512", status),
+            fields: HashMap::from([("latency_ms".to_string(), latency.to_string())]),
+            tags: vec![],
+        })
+    }
+
+    fn rule(toml: &str) -> LogToMetric {
+        LogToMetric::new(&toml::from_str(toml).unwrap()).unwrap()
+    }
+
+    #[test]
+    fn test_counts_matching_lines() {
+        let mut rule = rule(
+            r#"
+            metric = "nginx.http_5xx"
+            source = "/var/log/nginx/*.log"
+            pattern = '" (?P<status>5\d\d) '
+            tag_fields = ["status"]
+            drop_original = true
+            "#,
+        );
+
+        assert!(!rule.apply(&mut access_log(502, "1")));
+        assert!(!rule.apply(&mut access_log(502, "1")));
+        assert!(!rule.apply(&mut access_log(503, "1")));
+        assert!(rule.apply(&mut access_log(200, "1")));
+
+        let mut out = Vec::new();
+        rule.emit(1, &mut out);
+        rule.emit(60_000, &mut out);
+        assert_eq!(out.len(), 2);
+        for event in out {
+            match event {
+                Event::Metric(metric) => {
+                    assert_eq!(metric.name, "nginx.http_5xx");
+                    assert_eq!(metric.metric_type, MetricType::Counter);
+                    let expected = if metric.tags["status"] == "502" { 2.0 } else { 1.0 };
+                    assert_eq!(metric.value, expected);
+                }
+                _ => unreachable!(),
+            }
+        }
+    }
+
+    #[test]
+    fn test_histogram_of_field() {
+        let mut rule = rule(
+            r#"
+            metric = "nginx.latency"
+            kind = "histogram"
+            value_field = "latency_ms"
+            unit = "ms"
+            "#,
+        );
+
+        for latency in ["10", "20", "not-a-number", "30"] {
+            assert!(rule.apply(&mut access_log(200, latency)));
+        }
+
+        let mut out = Vec::new();
+        rule.emit(1, &mut out);
+        rule.emit(60_000, &mut out);
+        let stat = |name: &str| {
+            out.iter()
+                .find_map(|event| match event {
+                    Event::Metric(metric) if metric.name == name => Some(metric.value),
+                    _ => None,
+                })
+                .unwrap()
+        };
+        assert_eq!(stat("nginx.latency.count"), 3.0);
+        assert_eq!(stat("nginx.latency.avg"), 20.0);
+
+        assert!(LogToMetric::new(&toml::from_str("metric = \"m\"\nkind = \"histogram\"").unwrap()).is_err());
+    }
+}
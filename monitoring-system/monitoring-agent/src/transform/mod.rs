@@ -0,0 +1,205 @@
+mod aggregate;
+mod fields;
+mod log_metric;
+mod redact;
+mod stages;
+
+pub use stages::Condition;
+
+use crate::config::{AgentSettings, LogCollectorConfig, TransformConfig, TransformStage};
+use anyhow::{Context, Result};
+use monitoring_common::Event;
+
+/// One processing stage between the collectors and the batcher
+pub trait Transform: Send {
+    /// Transform an event in place, returning false to drop it
+    fn apply(&mut self, event: &mut Event) -> bool;
+
+    /// Add events the stage generates itself, such as self-metrics. Called
+    /// after every run of the chain with the current time in milliseconds.
+    fn emit(&mut self, _now: i64, _out: &mut Vec<Event>) {}
+}
+
+/// Ordered stages applied to every event before it is batched
+#[derive(Default)]
+pub struct TransformChain {
+    stages: Vec<Box<dyn Transform>>,
+}
+
+impl TransformChain {
+    /// Source exclusions run first, then the configured stages, then the
+    /// agent's tags are attached to whatever remains
+    pub fn from_config(
+        agent: &AgentSettings,
+        logs: &LogCollectorConfig,
+        transforms: &[TransformConfig],
+    ) -> Result<Self> {
+        let mut chain = Self::default();
+
+        if !logs.exclude_patterns.is_empty() {
+            let exclude = stages::ExcludeSources::new(&logs.exclude_patterns)
+                .context("Invalid collectors.logs.exclude_patterns")?;
+            chain.push(exclude);
+        }
+
+        for (index, config) in transforms.iter().enumerate() {
+            let stage = build_stage(config)
+                .with_context(|| format!("Invalid transform #{} ({:?})", index + 1, config.stage))?;
+            chain.stages.push(stage);
+        }
+
+        if !agent.tags.is_empty() {
+            chain.push(stages::AddTags {
+                tags: agent.tags.clone(),
+            });
+        }
+
+        Ok(chain)
+    }
+
+    pub fn push(&mut self, stage: impl Transform + 'static) {
+        self.stages.push(Box::new(stage));
+    }
+
+    /// Run every event through the stages, keeping those none dropped
+    pub fn apply(&mut self, events: Vec<Event>) -> Vec<Event> {
+        if self.stages.is_empty() {
+            return events;
+        }
+
+        let mut out: Vec<Event> = events
+            .into_iter()
+            .filter_map(|mut event| {
+                self.stages
+                    .iter_mut()
+                    .all(|stage| stage.apply(&mut event))
+                    .then_some(event)
+            })
+            .collect();
+
+        self.emit(&mut out);
+        out
+    }
+
+    /// Collect events stages generate on their own, such as closed
+    /// aggregation windows; called periodically even when nothing arrives
+    pub fn tick(&mut self) -> Vec<Event> {
+        let mut out = Vec::new();
+        self.emit(&mut out);
+        out
+    }
+
+    /// Generated events continue through the stages after the one that
+    /// produced them, so later stages such as agent tags still apply
+    fn emit(&mut self, out: &mut Vec<Event>) {
+        let now = chrono::Utc::now().timestamp_millis();
+        for index in 0..self.stages.len() {
+            let mut generated = Vec::new();
+            self.stages[index].emit(now, &mut generated);
+            for mut event in generated {
+                if self.stages[index + 1..]
+                    .iter_mut()
+                    .all(|stage| stage.apply(&mut event))
+                {
+                    out.push(event);
+                }
+            }
+        }
+    }
+}
+
+fn build_stage(config: &TransformConfig) -> Result<Box<dyn Transform>> {
+    let stage: Box<dyn Transform> = match &config.stage {
+        TransformStage::Drop => Box::new(stages::Discard),
+        TransformStage::Rename { from, to } => Box::new(stages::Rename {
+            from: from.clone(),
+            to: to.clone(),
+        }),
+        TransformStage::Remove { fields } => Box::new(stages::Remove {
+            fields: fields.clone(),
+        }),
+        TransformStage::AddTags { tags } => Box::new(stages::AddTags { tags: tags.clone() }),
+        TransformStage::Extract { field, pattern } => Box::new(stages::Extract::new(field, pattern)?),
+        TransformStage::Replace {
+            field,
+            pattern,
+            replacement,
+        } => Box::new(stages::Replace::new(field, pattern, replacement)?),
+        TransformStage::Redact {
+            detectors,
+            custom,
+            mode,
+            hash_key,
+        } => Box::new(redact::Redact::new(detectors, custom, mode, hash_key.as_deref())?),
+        TransformStage::Aggregate {
+            metrics,
+            window_secs,
+            percentiles,
+        } => Box::new(aggregate::Aggregate::new(metrics, *window_secs, percentiles)?),
+        TransformStage::LogToMetric(config) => Box::new(log_metric::LogToMetric::new(config)?),
+    };
+
+    Ok(match &config.when {
+        Some(condition) => Box::new(stages::When {
+            condition: stages::Condition::new(condition)?,
+            stage,
+        }),
+        None => stage,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::ConditionConfig;
+    use monitoring_common::{LogEvent, LogLevel};
+    use std::collections::HashMap;
+
+    fn log(level: LogLevel) -> Event {
+        Event::Log(LogEvent {
+            timestamp: 0,
+            source: "/var/log/app.log".to_string(),
+            level,
+            message: "hello".to_string(),
+            fields: HashMap::new(),
+            tags: vec![],
+        })
+    }
+
+    #[test]
+    fn test_chain_drops_and_tags() {
+        let agent = AgentSettings {
+            id: "agent".to_string(),
+            hostname: "host".to_string(),
+            tags: vec!["env:test".to_string()],
+        };
+        let logs = LogCollectorConfig {
+            enabled: true,
+            files: vec![],
+            journald_units: vec![],
+            exclude_patterns: vec![],
+            throttle: None,
+        };
+        let transforms = vec![TransformConfig {
+            when: Some(ConditionConfig {
+                field: Some("level".to_string()),
+                equals: Some("debug".to_string()),
+                ..Default::default()
+            }),
+            stage: TransformStage::Drop,
+        }];
+
+        let mut chain = TransformChain::from_config(&agent, &logs, &transforms).unwrap();
+        assert_eq!(chain.stages.len(), 2);
+
+        let events = chain.apply(vec![log(LogLevel::Debug), log(LogLevel::Info)]);
+        assert_eq!(events.len(), 1);
+        match &events[0] {
+            Event::Log(log) => {
+                assert_eq!(log.level, LogLevel::Info);
+                assert_eq!(log.tags, vec!["env:test"]);
+            }
+            _ => unreachable!(),
+        }
+    }
+}
@@ -0,0 +1,317 @@
+use super::{fields, Transform};
+use crate::config::ConditionConfig;
+use anyhow::{Context, Result};
+use monitoring_common::Event;
+use regex::Regex;
+
+/// Compiled form of a `ConditionConfig`
+pub struct Condition {
+    event_type: Option<String>,
+    field: Option<String>,
+    equals: Option<String>,
+    matches: Option<Regex>,
+}
+
+impl Condition {
+    pub fn new(config: &ConditionConfig) -> Result<Self> {
+        let matches = match &config.matches {
+            Some(pattern) => Some(compile(pattern)?),
+            None => None,
+        };
+        if config.field.is_none() && (config.equals.is_some() || matches.is_some()) {
+            anyhow::bail!("condition with `equals` or `matches` needs a `field`");
+        }
+
+        Ok(Self {
+            event_type: config.event_type.clone(),
+            field: config.field.clone(),
+            equals: config.equals.clone(),
+            matches,
+        })
+    }
+
+    pub fn matches(&self, event: &Event) -> bool {
+        if let Some(event_type) = &self.event_type {
+            if event.event_type() != event_type {
+                return false;
+            }
+        }
+
+        let field = match &self.field {
+            Some(field) => field,
+            None => return true,
+        };
+        let value = match fields::get(event, field) {
+            Some(value) => value,
+            None => return false,
+        };
+
+        self.equals.as_ref().is_none_or(|equals| *equals == value)
+            && self.matches.as_ref().is_none_or(|regex| regex.is_match(&value))
+    }
+}
+
+/// Apply a stage only to events matching a condition
+pub struct When {
+    pub condition: Condition,
+    pub stage: Box<dyn Transform>,
+}
+
+impl Transform for When {
+    fn apply(&mut self, event: &mut Event) -> bool {
+        !self.condition.matches(event) || self.stage.apply(event)
+    }
+
+    fn emit(&mut self, now: i64, out: &mut Vec<Event>) {
+        self.stage.emit(now, out);
+    }
+}
+
+/// Discard every event it sees; combine with a condition
+pub struct Discard;
+
+impl Transform for Discard {
+    fn apply(&mut self, _event: &mut Event) -> bool {
+        false
+    }
+}
+
+/// Drop log events whose source matches any pattern
+pub struct ExcludeSources {
+    patterns: Vec<Regex>,
+}
+
+impl ExcludeSources {
+    pub fn new(patterns: &[String]) -> Result<Self> {
+        Ok(Self {
+            patterns: patterns.iter().map(|pattern| compile(pattern)).collect::<Result<_>>()?,
+        })
+    }
+}
+
+impl Transform for ExcludeSources {
+    fn apply(&mut self, event: &mut Event) -> bool {
+        match event {
+            Event::Log(log) => !self.patterns.iter().any(|regex| regex.is_match(&log.source)),
+            _ => true,
+        }
+    }
+}
+
+pub struct Rename {
+    pub from: String,
+    pub to: String,
+}
+
+impl Transform for Rename {
+    fn apply(&mut self, event: &mut Event) -> bool {
+        if let Some(value) = fields::remove(event, &self.from) {
+            // Read-only targets such as "level" keep the value where it was
+            if !fields::set(event, &self.to, value.clone()) {
+                fields::set(event, &self.from, value);
+            }
+        }
+        true
+    }
+}
+
+pub struct Remove {
+    pub fields: Vec<String>,
+}
+
+impl Transform for Remove {
+    fn apply(&mut self, event: &mut Event) -> bool {
+        for field in &self.fields {
+            fields::remove(event, field);
+        }
+        true
+    }
+}
+
+pub struct AddTags {
+    pub tags: Vec<String>,
+}
+
+impl Transform for AddTags {
+    fn apply(&mut self, event: &mut Event) -> bool {
+        for tag in &self.tags {
+            fields::add_tag(event, tag);
+        }
+        true
+    }
+}
+
+/// Copy the named capture groups of a match into fields
+pub struct Extract {
+    field: String,
+    regex: Regex,
+}
+
+impl Extract {
+    pub fn new(field: &str, pattern: &str) -> Result<Self> {
+        let regex = compile(pattern)?;
+        if regex.capture_names().flatten().next().is_none() {
+            anyhow::bail!("extract pattern {:?} has no named groups", pattern);
+        }
+        Ok(Self {
+            field: field.to_string(),
+            regex,
+        })
+    }
+}
+
+impl Transform for Extract {
+    fn apply(&mut self, event: &mut Event) -> bool {
+        let extracted: Vec<(String, String)> = match fields::get(event, &self.field) {
+            Some(value) => match self.regex.captures(&value) {
+                Some(captures) => self
+                    .regex
+                    .capture_names()
+                    .flatten()
+                    .filter_map(|name| {
+                        let value = captures.name(name)?;
+                        Some((name.to_string(), value.as_str().to_string()))
+                    })
+                    .collect(),
+                None => return true,
+            },
+            None => return true,
+        };
+
+        for (name, value) in extracted {
+            fields::set(event, &name, value);
+        }
+        true
+    }
+}
+
+/// Rewrite a value, replacing every match; `$name` refers to groups
+pub struct Replace {
+    field: String,
+    regex: Regex,
+    replacement: String,
+}
+
+impl Replace {
+    pub fn new(field: &str, pattern: &str, replacement: &str) -> Result<Self> {
+        Ok(Self {
+            field: field.to_string(),
+            regex: compile(pattern)?,
+            replacement: replacement.to_string(),
+        })
+    }
+}
+
+impl Transform for Replace {
+    fn apply(&mut self, event: &mut Event) -> bool {
+        let rewritten = match fields::get(event, &self.field) {
+            Some(value) => match self.regex.replace_all(&value, self.replacement.as_str()) {
+                std::borrow::Cow::Owned(rewritten) => rewritten,
+                std::borrow::Cow::Borrowed(_) => return true,
+            },
+            None => return true,
+        };
+
+        fields::set(event, &self.field, rewritten);
+        true
+    }
+}
+
+fn compile(pattern: &str) -> Result<Regex> {
+    Regex::new(pattern).with_context(|| format!("Invalid regex {:?}", pattern))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use monitoring_common::{LogEvent, LogLevel, MetricEvent, MetricType};
+    use std::collections::HashMap;
+
+    fn log(source: &str, message: &str) -> Event {
+        Event::Log(LogEvent {
+            timestamp: 0,
+            source: source.to_string(),
+            level: LogLevel::Debug,
+            message: message.to_string(),
+            fields: HashMap::from([("user".to_string(), "alice".to_string())]),
+            tags: vec![],
+        })
+    }
+
+    fn metric() -> Event {
+        Event::Metric(MetricEvent {
+            timestamp: 0,
+            name: "cpu.usage".to_string(),
+            value: 1.0,
+            metric_type: MetricType::Gauge,
+            tags: HashMap::from([("host".to_string(), "web-1".to_string())]),
+            unit: None,
+        })
+    }
+
+    #[test]
+    fn test_condition() {
+        let condition = Condition::new(&ConditionConfig {
+            event_type: Some("log".to_string()),
+            field: Some("level".to_string()),
+            equals: Some("debug".to_string()),
+            matches: None,
+        })
+        .unwrap();
+
+        assert!(condition.matches(&log("app", "hi")));
+        assert!(!condition.matches(&metric()));
+
+        let invalid = ConditionConfig {
+            matches: Some("(".to_string()),
+            field: Some("message".to_string()),
+            ..Default::default()
+        };
+        assert!(Condition::new(&invalid).is_err());
+    }
+
+    #[test]
+    fn test_rename_remove_and_tags() {
+        let mut event = metric();
+        assert!(Rename { from: "host".to_string(), to: "hostname".to_string() }.apply(&mut event));
+        assert!(AddTags { tags: vec!["env:prod".to_string(), "hostname:other".to_string()] }.apply(&mut event));
+        assert!(Remove { fields: vec!["name".to_string(), "missing".to_string()] }.apply(&mut event));
+
+        match event {
+            Event::Metric(metric) => {
+                assert_eq!(metric.name, "cpu.usage");
+                assert_eq!(metric.tags.len(), 2);
+                assert_eq!(metric.tags["hostname"], "web-1");
+                assert_eq!(metric.tags["env"], "prod");
+            }
+            _ => unreachable!(),
+        }
+
+        let mut event = log("app", "hi");
+        assert!(Rename { from: "user".to_string(), to: "level".to_string() }.apply(&mut event));
+        assert_eq!(fields::get(&event, "user").as_deref(), Some("alice"));
+    }
+
+    #[test]
+    fn test_extract_and_replace() {
+        let mut event = log("app", "GET /users/42 took 17ms");
+        let mut extract =
+            Extract::new("message", r"^(?P<method>\w+) (?P<path>\S+) took (?P<ms>\d+)ms").unwrap();
+        let mut replace = Replace::new("path", r"/\d+", "/:id").unwrap();
+        assert!(extract.apply(&mut event));
+        assert!(replace.apply(&mut event));
+
+        assert_eq!(fields::get(&event, "method").unwrap(), "GET");
+        assert_eq!(fields::get(&event, "path").unwrap(), "/users/:id");
+        assert_eq!(fields::get(&event, "ms").unwrap(), "17");
+        assert!(Extract::new("message", r"\d+").is_err());
+    }
+
+    #[test]
+    fn test_exclude_sources() {
+        let mut exclude = ExcludeSources::new(&[r"\.gz$".to_string()]).unwrap();
+        assert!(!exclude.apply(&mut log("/var/log/app.log.1.gz", "old")));
+        assert!(exclude.apply(&mut log("/var/log/app.log", "new")));
+        assert!(exclude.apply(&mut metric()));
+    }
+}
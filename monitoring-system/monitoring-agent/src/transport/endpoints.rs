@@ -0,0 +1,232 @@
+use crate::config::CollectorSettings;
+use anyhow::Result;
+use std::time::{Duration, Instant};
+use tracing::{info, warn};
+
+/// Weight of the newest round trip in the latency average
+const LATENCY_WEIGHT: f64 = 0.3;
+
+/// A measured endpoint must be this much faster to move the connection
+const LATENCY_MARGIN: f64 = 0.8;
+
+/// Health of one collector endpoint. After `failure_threshold` consecutive
+/// failures its circuit opens and it is skipped until `open_for` has passed;
+/// the next attempt is a probe, and one more failure opens it again.
+#[derive(Debug, Default)]
+struct Health {
+    consecutive_failures: u32,
+    open_until: Option<Instant>,
+    /// Average time from sending a batch to its response
+    latency: Option<Duration>,
+}
+
+#[derive(Debug)]
+struct Endpoint {
+    url: String,
+    health: Health,
+}
+
+/// The collectors the agent can send to and how it chooses between them:
+/// "priority" uses the first healthy endpoint and fails back to it when it
+/// recovers, "round_robin" rotates through them and "least_latency" prefers
+/// the fastest to answer.
+pub struct EndpointPool {
+    endpoints: Vec<Endpoint>,
+    strategy: String,
+    failure_threshold: u32,
+    open_for: Duration,
+    /// Last endpoint handed out by round robin
+    cursor: usize,
+}
+
+impl EndpointPool {
+    pub fn new(config: &CollectorSettings) -> Result<Self> {
+        let primary = match config.transport.as_str() {
+            "grpc" => config.grpc_endpoint.clone(),
+            _ => Some(config.endpoint.clone()),
+        };
+        let endpoints: Vec<_> = primary
+            .into_iter()
+            .chain(config.endpoints.iter().cloned())
+            .map(|url| Endpoint {
+                url,
+                health: Health::default(),
+            })
+            .collect();
+
+        if endpoints.is_empty() {
+            anyhow::bail!("collector.grpc_endpoint is required for the grpc transport");
+        }
+        if !matches!(
+            config.endpoint_strategy.as_str(),
+            "priority" | "round_robin" | "least_latency"
+        ) {
+            anyhow::bail!("Unknown endpoint strategy {:?}", config.endpoint_strategy);
+        }
+
+        Ok(Self {
+            // Agents started together should not all pick the same collector
+            cursor: rand::random::<usize>() % endpoints.len(),
+            endpoints,
+            strategy: config.endpoint_strategy.clone(),
+            failure_threshold: config.circuit_failure_threshold.max(1),
+            open_for: Duration::from_secs(config.circuit_open_secs),
+        })
+    }
+
+    pub fn url(&self, index: usize) -> &str {
+        &self.endpoints[index].url
+    }
+
+    fn available(&self, index: usize, now: Instant) -> bool {
+        match self.endpoints[index].health.open_until {
+            Some(until) => now >= until,
+            None => true,
+        }
+    }
+
+    /// The endpoint to connect to next, or `None` if every circuit is open
+    pub fn select(&mut self, now: Instant) -> Option<usize> {
+        let count = self.endpoints.len();
+        let mut available = (0..count).filter(|&index| self.available(index, now));
+
+        match self.strategy.as_str() {
+            "round_robin" => {
+                let next = (1..=count)
+                    .map(|step| (self.cursor + step) % count)
+                    .find(|&index| self.available(index, now))?;
+                self.cursor = next;
+                Some(next)
+            }
+            // Unmeasured endpoints come first so each gets measured
+            "least_latency" => available.min_by_key(|&index| self.endpoints[index].health.latency),
+            _ => available.next(),
+        }
+    }
+
+    /// The endpoint whose circuit closes soonest, to wait for when all are open
+    pub fn soonest(&self) -> usize {
+        (0..self.endpoints.len())
+            .min_by_key(|&index| self.endpoints[index].health.open_until)
+            .unwrap_or(0)
+    }
+
+    /// An endpoint worth moving a healthy connection to: a recovered
+    /// higher-priority one, the next in rotation, or a clearly faster one
+    pub fn preferred(&mut self, current: usize, now: Instant) -> Option<usize> {
+        let candidate = match self.strategy.as_str() {
+            "priority" => (0..current).find(|&index| self.available(index, now)),
+            "round_robin" => self.select(now),
+            _ => {
+                let best = self.select(now)?;
+                let faster = match (
+                    self.endpoints[best].health.latency,
+                    self.endpoints[current].health.latency,
+                ) {
+                    (Some(best), Some(current)) => best.as_secs_f64() < current.as_secs_f64() * LATENCY_MARGIN,
+                    _ => true,
+                };
+                Some(best).filter(|_| faster)
+            }
+        };
+        candidate.filter(|&index| index != current)
+    }
+
+    pub fn record_success(&mut self, index: usize, latency: Duration) {
+        let endpoint = &mut self.endpoints[index];
+        if endpoint.health.open_until.take().is_some() {
+            info!("Collector {} recovered", endpoint.url);
+        }
+        endpoint.health.consecutive_failures = 0;
+        endpoint.health.latency = Some(match endpoint.health.latency {
+            Some(average) => average.mul_f64(1.0 - LATENCY_WEIGHT) + latency.mul_f64(LATENCY_WEIGHT),
+            None => latency,
+        });
+    }
+
+    pub fn record_failure(&mut self, index: usize, now: Instant) {
+        let endpoint = &mut self.endpoints[index];
+        endpoint.health.consecutive_failures += 1;
+        if endpoint.health.consecutive_failures >= self.failure_threshold {
+            if endpoint.health.open_until.is_none() {
+                warn!(
+                    "Collector {} failed {} times in a row, trying others for {:?}",
+                    endpoint.url, endpoint.health.consecutive_failures, self.open_for
+                );
+            }
+            endpoint.health.open_until = Some(now + self.open_for);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn pool_with(strategy: &str) -> EndpointPool {
+        let config: CollectorSettings = toml::from_str(&format!(
+            r#"
+            endpoint = "ws://a"
+            endpoints = ["ws://b", "ws://c"]
+            endpoint_strategy = "{}"
+            circuit_failure_threshold = 2
+            circuit_open_secs = 30
+            "#,
+            strategy
+        ))
+        .unwrap();
+        EndpointPool::new(&config).unwrap()
+    }
+
+    #[test]
+    fn test_priority_fails_over_and_back() {
+        let mut pool = pool_with("priority");
+        let now = Instant::now();
+        assert_eq!(pool.select(now), Some(0));
+
+        // One failure is retried, a second opens the circuit
+        pool.record_failure(0, now);
+        assert_eq!(pool.select(now), Some(0));
+        pool.record_failure(0, now);
+        assert_eq!(pool.select(now), Some(1));
+        assert_eq!(pool.preferred(1, now), None);
+
+        // Once the circuit may close again, the primary is probed
+        let later = now + Duration::from_secs(31);
+        assert_eq!(pool.preferred(1, later), Some(0));
+        pool.record_failure(0, later);
+        assert_eq!(pool.preferred(1, later), None);
+
+        pool.record_success(0, Duration::from_millis(5));
+        assert_eq!(pool.select(later), Some(0));
+
+        for index in 0..3 {
+            pool.record_failure(index, later);
+            pool.record_failure(index, later);
+        }
+        assert_eq!(pool.select(later), None);
+        assert_eq!(pool.soonest(), 0);
+    }
+
+    #[test]
+    fn test_round_robin_and_least_latency() {
+        let mut pool = pool_with("round_robin");
+        let now = Instant::now();
+        let first = pool.select(now).unwrap();
+        let second = pool.select(now).unwrap();
+        assert_eq!(second, (first + 1) % 3);
+        pool.record_failure(2, now);
+        pool.record_failure(2, now);
+        let order: Vec<_> = (0..4).filter_map(|_| pool.select(now)).collect();
+        assert!(!order.contains(&2));
+
+        let mut pool = pool_with("least_latency");
+        pool.record_success(0, Duration::from_millis(50));
+        pool.record_success(1, Duration::from_millis(10));
+        assert_eq!(pool.select(now), Some(2));
+        pool.record_success(2, Duration::from_millis(45));
+        assert_eq!(pool.select(now), Some(1));
+        assert_eq!(pool.preferred(0, now), Some(1));
+        assert_eq!(pool.preferred(1, now), None);
+    }
+}
@@ -0,0 +1,297 @@
+use crate::buffer::SharedDiskQueue;
+use crate::pipeline::{Compressor, SharedDictionary, ZstdOptions};
+use crate::transform::Condition;
+use anyhow::Result;
+use monitoring_common::{Batch, Event, UncompressedBatch};
+use tokio::sync::mpsc::{self, error::TrySendError, Receiver};
+use tracing::{error, info, warn};
+
+/// One destination's queue and the events it wants
+struct Sink {
+    name: String,
+    when: Option<Condition>,
+    /// Whether batches may stay compressed with the shared dictionary. Only
+    /// the collector that offered it can rely on having it later.
+    shares_dictionary: bool,
+    tx: mpsc::Sender<Batch>,
+    /// Where batches go when the queue is full, instead of being dropped
+    spill: Option<SharedDiskQueue>,
+    /// Batches dropped or spilled since the queue last had room
+    overflowed: u64,
+}
+
+/// Copies every batch from the batcher to each sink's own queue without
+/// waiting, so a sink that falls behind only loses its own batches
+pub struct Fanout {
+    sinks: Vec<Sink>,
+    dictionary: SharedDictionary,
+    compression_level: i32,
+}
+
+impl Fanout {
+    pub fn new(dictionary: SharedDictionary, compression_level: i32) -> Self {
+        Self {
+            sinks: Vec::new(),
+            dictionary,
+            compression_level,
+        }
+    }
+
+    /// Register a sink and return the queue it reads batches from
+    pub fn add_sink(
+        &mut self,
+        name: impl Into<String>,
+        when: Option<Condition>,
+        shares_dictionary: bool,
+        capacity: usize,
+    ) -> Receiver<Batch> {
+        let (tx, rx) = mpsc::channel(capacity.max(1));
+        self.sinks.push(Sink {
+            name: name.into(),
+            when,
+            shares_dictionary,
+            tx,
+            spill: None,
+            overflowed: 0,
+        });
+        rx
+    }
+
+    /// Register the main collector. With the disk queue its batches are
+    /// persisted rather than dropped when it falls behind, and the
+    /// transport replays them from there.
+    pub fn add_collector(&mut self, capacity: usize, spill: Option<SharedDiskQueue>) -> Receiver<Batch> {
+        let rx = self.add_sink("collector", None, true, capacity);
+        if let Some(sink) = self.sinks.last_mut() {
+            sink.spill = spill;
+        }
+        rx
+    }
+
+    pub async fn run(mut self, mut batch_rx: Receiver<Batch>) -> Result<()> {
+        info!("Fanning batches out to {} sinks", self.sinks.len());
+
+        while let Some(batch) = batch_rx.recv().await {
+            self.dispatch(batch);
+            if self.sinks.is_empty() {
+                anyhow::bail!("All sinks have stopped");
+            }
+        }
+        Ok(())
+    }
+
+    fn dispatch(&mut self, batch: Batch) {
+        // Decoded at most once, and only for sinks that need the events
+        let mut events: Option<Vec<Event>> = None;
+        let mut undecodable = false;
+        let zstd = ZstdOptions {
+            level: self.compression_level,
+            dictionary: batch.dictionary_id.and_then(|id| self.dictionary.read().get(id)),
+        };
+
+        let mut index = 0;
+        while index < self.sinks.len() {
+            let sink = &mut self.sinks[index];
+            let needs_events =
+                sink.when.is_some() || (batch.dictionary_id.is_some() && !sink.shares_dictionary);
+
+            let prepared = if needs_events {
+                if undecodable {
+                    index += 1;
+                    continue;
+                }
+                let decoded = match &events {
+                    Some(events) => events,
+                    None => match Compressor::decompress(&batch, zstd.dictionary.as_deref()) {
+                        Ok(decoded) => events.insert(decoded),
+                        Err(e) => {
+                            // Sinks that take the batch as is still get it
+                            error!("Failed to decode batch {} for filtered sinks: {}", batch.batch_id, e);
+                            undecodable = true;
+                            index += 1;
+                            continue;
+                        }
+                    },
+                };
+                match prepare(&batch, decoded, sink, &zstd) {
+                    Ok(Some(prepared)) => prepared,
+                    Ok(None) => {
+                        index += 1;
+                        continue;
+                    }
+                    Err(e) => {
+                        error!("Failed to prepare batch {} for sink {}: {}", batch.batch_id, sink.name, e);
+                        index += 1;
+                        continue;
+                    }
+                }
+            } else {
+                batch.clone()
+            };
+
+            match sink.tx.try_send(prepared) {
+                Ok(()) => {
+                    if sink.overflowed > 0 {
+                        info!("Sink {} caught up after {} batches overflowed", sink.name, sink.overflowed);
+                        sink.overflowed = 0;
+                    }
+                }
+                Err(TrySendError::Full(batch)) => {
+                    match &sink.spill {
+                        Some(queue) => {
+                            if sink.overflowed == 0 {
+                                warn!("Sink {} is falling behind, keeping batches on disk", sink.name);
+                            }
+                            if let Err(e) = queue.lock().append(&batch) {
+                                error!("Failed to persist batch {} for sink {}: {}", batch.batch_id, sink.name, e);
+                            }
+                        }
+                        None if sink.overflowed == 0 => {
+                            warn!("Sink {} is falling behind, dropping batches", sink.name)
+                        }
+                        None => {}
+                    }
+                    sink.overflowed += 1;
+                }
+                Err(TrySendError::Closed(_)) => {
+                    error!("Sink {} stopped", sink.name);
+                    self.sinks.remove(index);
+                    continue;
+                }
+            }
+            index += 1;
+        }
+    }
+}
+
+/// Rebuild a batch with the events the sink wants, compressed so the sink
+/// can read it. `None` if no event is left.
+fn prepare(batch: &Batch, events: &[Event], sink: &Sink, zstd: &ZstdOptions) -> Result<Option<Batch>> {
+    let events: Vec<Event> = events
+        .iter()
+        .filter(|event| sink.when.as_ref().is_none_or(|when| when.matches(event)))
+        .cloned()
+        .collect();
+    if events.is_empty() {
+        return Ok(None);
+    }
+
+    let uncompressed = UncompressedBatch {
+        batch_id: batch.batch_id.clone(),
+        agent_id: batch.agent_id.clone(),
+        hostname: batch.hostname.clone(),
+        timestamp: batch.timestamp,
+        events,
+    };
+    let zstd = ZstdOptions {
+        level: zstd.level,
+        dictionary: zstd.dictionary.clone().filter(|_| sink.shares_dictionary),
+    };
+    Compressor::compress(uncompressed, batch.compression.clone(), batch.encoding, &zstd).map(Some)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::ConditionConfig;
+    use monitoring_common::{CompressionType, LogEvent, LogLevel, MetricEvent, MetricType, PayloadEncoding};
+    use std::collections::HashMap;
+
+    fn batch() -> Batch {
+        let log = Event::Log(LogEvent {
+            timestamp: 0,
+            source: "app".to_string(),
+            level: LogLevel::Info,
+            message: "hello".to_string(),
+            fields: HashMap::new(),
+            tags: vec![],
+        });
+        let metric = Event::Metric(MetricEvent {
+            timestamp: 0,
+            name: "cpu".to_string(),
+            value: 1.0,
+            metric_type: MetricType::Gauge,
+            unit: None,
+            tags: HashMap::new(),
+        });
+        let uncompressed = UncompressedBatch {
+            batch_id: "b".to_string(),
+            agent_id: "agent".to_string(),
+            hostname: "host".to_string(),
+            timestamp: 0,
+            events: vec![log, metric],
+        };
+        Compressor::compress(uncompressed, CompressionType::Snappy, PayloadEncoding::Json, &ZstdOptions::default())
+            .unwrap()
+    }
+
+    #[tokio::test]
+    async fn test_filters_and_drops_per_sink() {
+        let mut fanout = Fanout::new(SharedDictionary::default(), 3);
+        let mut all = fanout.add_sink("all", None, true, 10);
+        let logs = Condition::new(&ConditionConfig {
+            event_type: Some("log".to_string()),
+            ..Default::default()
+        })
+        .unwrap();
+        let mut logs_only = fanout.add_sink("logs", Some(logs), true, 10);
+        let mut slow = fanout.add_sink("slow", None, true, 1);
+
+        fanout.dispatch(batch());
+        fanout.dispatch(batch());
+
+        assert_eq!(all.recv().await.unwrap().event_count, 2);
+        let filtered = logs_only.recv().await.unwrap();
+        assert_eq!(filtered.event_count, 1);
+        assert!(matches!(
+            Compressor::decompress(&filtered, None).unwrap()[..],
+            [Event::Log(_)]
+        ));
+
+        // The full queue lost the second batch without holding up the others
+        assert!(slow.recv().await.is_some());
+        assert!(slow.try_recv().is_err());
+        assert_eq!(fanout.sinks[2].overflowed, 1);
+        assert!(all.recv().await.is_some());
+    }
+
+    #[tokio::test]
+    async fn test_undecodable_batch_still_reaches_unfiltered_sinks() {
+        let mut fanout = Fanout::new(SharedDictionary::default(), 3);
+        let logs = Condition::new(&ConditionConfig {
+            event_type: Some("log".to_string()),
+            ..Default::default()
+        })
+        .unwrap();
+        let mut logs_only = fanout.add_sink("logs", Some(logs), false, 10);
+        let mut collector = fanout.add_sink("collector", None, true, 10);
+
+        let mut corrupt = batch();
+        corrupt.compressed_data.truncate(3);
+        fanout.dispatch(corrupt);
+
+        assert!(logs_only.try_recv().is_err());
+        assert_eq!(collector.recv().await.unwrap().batch_id, "b");
+    }
+
+    #[tokio::test]
+    async fn test_collector_spills_to_disk_queue() {
+        use crate::buffer::{DiskQueue, FsyncPolicy};
+        use std::sync::Arc;
+
+        let dir = std::env::temp_dir().join(format!("monitoring-agent-spill-{}", uuid::Uuid::new_v4()));
+        let queue = DiskQueue::open(&dir, 1 << 20, 1 << 16, FsyncPolicy::Never).unwrap();
+        let queue = Arc::new(parking_lot::Mutex::new(queue));
+        let mut fanout = Fanout::new(SharedDictionary::default(), 3);
+        let mut collector = fanout.add_collector(1, Some(queue.clone()));
+
+        fanout.dispatch(batch());
+        fanout.dispatch(batch());
+
+        // The second batch waits on disk for the transport to replay it
+        assert!(collector.recv().await.is_some());
+        assert!(collector.try_recv().is_err());
+        assert_eq!(queue.lock().pending_count(), 1);
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,217 @@
+use super::retry::RetryPolicy;
+use crate::config::FileSinkConfig;
+use crate::pipeline::Compressor;
+use anyhow::{Context, Result};
+use monitoring_common::Batch;
+use std::fs::{File, OpenOptions};
+use std::io::{BufWriter, Write};
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::sync::mpsc::Receiver;
+use tracing::{error, info, warn};
+
+/// Appends the events of every batch to a local file as NDJSON, rotating it
+/// by size. Batches must not use a compression dictionary.
+pub struct FileSink {
+    file: RotatingFile,
+    retry_policy: RetryPolicy,
+}
+
+impl FileSink {
+    pub fn new(config: &FileSinkConfig) -> Self {
+        Self {
+            file: RotatingFile::new(config),
+            retry_policy: RetryPolicy::new(
+                3,                       // max_retries
+                Duration::from_secs(1),  // initial_delay
+                Duration::from_secs(10), // max_delay
+            ),
+        }
+    }
+
+    pub async fn run(mut self, mut batch_rx: Receiver<Batch>) -> Result<()> {
+        info!("Writing events to {:?}", self.file.path);
+
+        while let Some(batch) = batch_rx.recv().await {
+            let batch = Arc::new(batch);
+            loop {
+                // Decoding and file I/O block, so they run off the runtime
+                let mut file = self.file;
+                let writing = batch.clone();
+                let (file, result) = tokio::task::spawn_blocking(move || {
+                    let result = file.write(&writing);
+                    (file, result)
+                })
+                .await?;
+                self.file = file;
+
+                match result {
+                    Ok(()) => {
+                        self.retry_policy.reset();
+                        break;
+                    }
+                    Err(e) => {
+                        // Reopen on the next attempt in case the file went away
+                        self.file.writer = None;
+                        match self.retry_policy.next_delay() {
+                            Some(delay) => {
+                                warn!("Failed to write batch {} to {:?}: {:#}", batch.batch_id, self.file.path, e);
+                                tokio::time::sleep(delay).await;
+                            }
+                            None => {
+                                error!("Dropping batch {} after failed writes to {:?}: {:#}", batch.batch_id, self.file.path, e);
+                                self.retry_policy.reset();
+                                break;
+                            }
+                        }
+                    }
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+/// NDJSON file that is rotated once it reaches `max_bytes`
+struct RotatingFile {
+    path: PathBuf,
+    max_bytes: u64,
+    max_files: usize,
+    writer: Option<BufWriter<File>>,
+    /// Size of the current file
+    written: u64,
+}
+
+impl RotatingFile {
+    fn new(config: &FileSinkConfig) -> Self {
+        Self {
+            path: PathBuf::from(&config.path),
+            max_bytes: config.max_bytes,
+            max_files: config.max_files,
+            writer: None,
+            written: 0,
+        }
+    }
+
+    fn write(&mut self, batch: &Batch) -> Result<()> {
+        let events = Compressor::decompress(batch, None)?;
+
+        let mut lines = Vec::new();
+        for event in &events {
+            serde_json::to_writer(&mut lines, event)?;
+            lines.push(b'\n');
+        }
+
+        if self.writer.is_none() {
+            self.open()?;
+        }
+        if self.max_bytes > 0 && self.written > 0 && self.written + lines.len() as u64 > self.max_bytes {
+            self.rotate()?;
+            self.open()?;
+        }
+
+        let file = self.writer.as_mut().expect("file opened above");
+        file.write_all(&lines)?;
+        file.flush()?;
+        self.written += lines.len() as u64;
+        Ok(())
+    }
+
+    fn open(&mut self) -> Result<()> {
+        if let Some(dir) = self.path.parent().filter(|dir| !dir.as_os_str().is_empty()) {
+            std::fs::create_dir_all(dir).with_context(|| format!("Failed to create {:?}", dir))?;
+        }
+        let file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&self.path)
+            .with_context(|| format!("Failed to open {:?}", self.path))?;
+        self.written = file.metadata()?.len();
+        self.writer = Some(BufWriter::new(file));
+        Ok(())
+    }
+
+    /// Shift `<path>.N` to `<path>.N+1`, dropping the oldest, and start a
+    /// new file
+    fn rotate(&mut self) -> Result<()> {
+        self.writer = None;
+
+        if self.max_files == 0 {
+            std::fs::remove_file(&self.path)?;
+        } else {
+            let _ = std::fs::remove_file(rotated(&self.path, self.max_files));
+            for n in (1..self.max_files).rev() {
+                let from = rotated(&self.path, n);
+                if from.exists() {
+                    std::fs::rename(&from, rotated(&self.path, n + 1))?;
+                }
+            }
+            std::fs::rename(&self.path, rotated(&self.path, 1))?;
+        }
+
+        self.written = 0;
+        Ok(())
+    }
+}
+
+fn rotated(path: &Path, n: usize) -> PathBuf {
+    let mut name = path.as_os_str().to_owned();
+    name.push(format!(".{}", n));
+    PathBuf::from(name)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::pipeline::ZstdOptions;
+    use monitoring_common::{
+        CompressionType, Event, LogEvent, LogLevel, PayloadEncoding, UncompressedBatch,
+    };
+    use std::collections::HashMap;
+
+    fn batch(message: &str) -> Batch {
+        let event = Event::Log(LogEvent {
+            timestamp: 0,
+            source: "app".to_string(),
+            level: LogLevel::Info,
+            message: message.to_string(),
+            fields: HashMap::new(),
+            tags: vec![],
+        });
+        let uncompressed = UncompressedBatch {
+            batch_id: message.to_string(),
+            agent_id: "agent".to_string(),
+            hostname: "host".to_string(),
+            timestamp: 0,
+            events: vec![event],
+        };
+        Compressor::compress(uncompressed, CompressionType::Zstd, PayloadEncoding::MessagePack, &ZstdOptions::default())
+            .unwrap()
+    }
+
+    #[test]
+    fn test_writes_ndjson_and_rotates() {
+        let dir = std::env::temp_dir().join(format!("agent-file-sink-{}", std::process::id()));
+        let path = dir.join("events.ndjson");
+        let mut file = RotatingFile::new(&FileSinkConfig {
+            path: path.to_string_lossy().into_owned(),
+            max_bytes: 150,
+            max_files: 2,
+        });
+
+        for message in ["one", "two", "three", "four"] {
+            file.write(&batch(message)).unwrap();
+        }
+
+        // Each line is about 100 bytes, so every batch starts a new file
+        let read = |path: &Path| std::fs::read_to_string(path).unwrap();
+        let line: serde_json::Value = serde_json::from_str(read(&path).lines().next().unwrap()).unwrap();
+        assert_eq!(line["message"], "four");
+        assert!(read(&rotated(&path, 1)).contains("\"three\""));
+        assert!(read(&rotated(&path, 2)).contains("\"two\""));
+        assert!(!rotated(&path, 3).exists());
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
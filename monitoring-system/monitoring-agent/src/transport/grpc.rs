@@ -0,0 +1,159 @@
+use crate::config::CollectorSettings;
+use anyhow::{Context, Result};
+use monitoring_common::proto::monitoring as pb;
+use monitoring_common::{Batch, IngestResponse};
+use std::time::Duration;
+use tokio::sync::mpsc;
+use tonic::transport::{Certificate, Channel, ClientTlsConfig, Endpoint, Identity};
+use tracing::{debug, info};
+
+mod generated {
+    tonic::include_proto!("monitoring");
+}
+
+use generated::monitoring_collector_client::MonitoringCollectorClient;
+
+/// Keeps idle streams from being dropped by proxies and load balancers
+const KEEP_ALIVE_INTERVAL: Duration = Duration::from_secs(30);
+
+/// Both halves of an open `IngestStream` call
+struct IngestStream {
+    batches: mpsc::Sender<pb::Batch>,
+    responses: tonic::Streaming<pb::IngestResponse>,
+}
+
+/// One `IngestStream` call to the collector. Batches are written and
+/// responses read independently, so several batches can be in flight. The
+/// payload encoding travels in the batch, so no negotiation is needed.
+pub struct GrpcClient {
+    config: CollectorSettings,
+    /// Opened on the first `connect` to an endpoint
+    client: Option<MonitoringCollectorClient<Channel>>,
+    stream: Option<IngestStream>,
+}
+
+impl GrpcClient {
+    /// Client for `config.grpc_endpoint`; nothing is sent until `connect`
+    pub fn new(config: CollectorSettings) -> Self {
+        Self {
+            config,
+            client: None,
+            stream: None,
+        }
+    }
+
+    /// Collector used from the next `connect` on
+    pub fn set_endpoint(&mut self, endpoint: &str) {
+        if self.config.grpc_endpoint.as_deref() != Some(endpoint) {
+            self.config.grpc_endpoint = Some(endpoint.to_string());
+            self.client = None;
+        }
+    }
+
+    /// Start a new `IngestStream` call, since a failed one cannot be
+    /// resumed; the channel underneath reconnects on its own
+    pub async fn connect(&mut self) -> Result<()> {
+        self.stream = None;
+        if self.client.is_none() {
+            self.client = Some(MonitoringCollectorClient::new(connect(&self.config).await?));
+        }
+
+        let (batches, rx) = mpsc::channel(1);
+        let outbound = futures_util::stream::unfold(rx, |mut rx| async move {
+            rx.recv().await.map(|batch| (batch, rx))
+        });
+
+        let mut request = tonic::Request::new(outbound);
+        if let Some(token) = &self.config.auth_token {
+            let value = format!("Bearer {}", token)
+                .parse()
+                .context("Auth token is not a valid header value")?;
+            request.metadata_mut().insert("authorization", value);
+        }
+
+        let responses = self
+            .client
+            .as_mut()
+            .expect("client opened above")
+            .ingest_stream(request)
+            .await
+            .context("Failed to open IngestStream")?
+            .into_inner();
+        debug!("IngestStream opened");
+
+        self.stream = Some(IngestStream { batches, responses });
+        Ok(())
+    }
+
+    /// Send a batch without waiting for its response
+    pub async fn write(&mut self, batch: &Batch) -> Result<()> {
+        let stream = self.stream.as_mut().context("IngestStream not open")?;
+        stream
+            .batches
+            .send(pb::Batch::from(batch.clone()))
+            .await
+            .map_err(|_| anyhow::anyhow!("IngestStream closed"))
+    }
+
+    /// Wait for the next response, whichever batch it is for
+    pub async fn read(&mut self) -> Result<IngestResponse> {
+        let stream = self.stream.as_mut().context("IngestStream not open")?;
+        match stream.responses.message().await {
+            Ok(Some(response)) => Ok(IngestResponse::try_from(response)?),
+            Ok(None) => anyhow::bail!("IngestStream closed by collector"),
+            Err(status) => anyhow::bail!("IngestStream failed: {}", status),
+        }
+    }
+
+    pub async fn test_connection(config: &CollectorSettings) -> Result<()> {
+        connect(config).await?;
+        info!("Connection successful");
+        Ok(())
+    }
+}
+
+async fn connect(config: &CollectorSettings) -> Result<Channel> {
+    let url = config
+        .grpc_endpoint
+        .clone()
+        .context("collector.grpc_endpoint is required for the grpc transport")?;
+    info!("Connecting to collector: {}", url);
+
+    let mut endpoint = Endpoint::from_shared(url.clone())
+        .with_context(|| format!("Invalid grpc_endpoint {:?}", url))?
+        .connect_timeout(Duration::from_secs(config.connect_timeout_secs))
+        .http2_keep_alive_interval(KEEP_ALIVE_INTERVAL)
+        .keep_alive_while_idle(true);
+
+    if url.starts_with("https://") {
+        endpoint = endpoint
+            .tls_config(tls_config(config)?)
+            .context("Failed to configure TLS")?;
+    }
+
+    endpoint
+        .connect()
+        .await
+        .with_context(|| format!("Failed to connect to {}", url))
+}
+
+fn tls_config(config: &CollectorSettings) -> Result<ClientTlsConfig> {
+    let mut tls = ClientTlsConfig::new();
+
+    if let Some(path) = &config.tls_ca_cert {
+        let pem = std::fs::read(path).with_context(|| format!("Failed to read {}", path))?;
+        tls = tls.ca_certificate(Certificate::from_pem(pem));
+    }
+
+    match (&config.client_cert, &config.client_key) {
+        (Some(cert), Some(key)) => {
+            let cert = std::fs::read(cert).with_context(|| format!("Failed to read {}", cert))?;
+            let key = std::fs::read(key).with_context(|| format!("Failed to read {}", key))?;
+            tls = tls.identity(Identity::from_pem(cert, key));
+        }
+        (None, None) => {}
+        _ => anyhow::bail!("client_cert and client_key must be set together"),
+    }
+
+    Ok(tls)
+}
@@ -1,30 +1,121 @@
 mod websocket;
+mod endpoints;
+mod fanout;
+mod file;
+#[cfg(feature = "grpc-transport")]
+mod grpc;
 mod retry;
+mod tls;
+mod window;
 
-use crate::config::CollectorSettings;
-use anyhow::Result;
-use monitoring_common::Batch;
+pub use fanout::Fanout;
+
+use crate::buffer::SharedDiskQueue;
+use crate::config::{CollectorSettings, SinkConfig, SinkKind};
+use crate::transform::Condition;
+use crate::pipeline::SharedDictionary;
+use anyhow::{Context, Result};
+use endpoints::EndpointPool;
+use monitoring_common::{Batch, IngestResponse, IngestStatus};
+use std::time::Duration;
 use tokio::sync::mpsc::Receiver;
-use tracing::info;
+use tokio::task::JoinHandle;
+use tracing::{error, info, warn};
+
+/// How often undelivered batches on disk are retried
+const REPLAY_INTERVAL: Duration = Duration::from_secs(30);
+/// Failed deliveries after which a batch on disk is set aside, so one the
+/// collector can never store does not hold up those behind it
+const MAX_DELIVERY_ATTEMPTS: u32 = 5;
 
 pub struct Transport {
     config: CollectorSettings,
+    queue: Option<SharedDiskQueue>,
+    dictionary: Option<SharedDictionary>,
 }
 
 impl Transport {
     pub fn new(config: CollectorSettings) -> Self {
-        Self { config }
+        Self {
+            config,
+            queue: None,
+            dictionary: None,
+        }
+    }
+
+    /// Keep batches on disk until the collector acknowledges them
+    pub fn with_disk_queue(mut self, queue: SharedDiskQueue) -> Self {
+        self.queue = Some(queue);
+        self
+    }
+
+    /// Store compression dictionaries offered by the collector
+    pub fn with_dictionary(mut self, dictionary: SharedDictionary) -> Self {
+        self.dictionary = Some(dictionary);
+        self
     }
 
     pub async fn run(self, mut batch_rx: Receiver<Batch>) -> Result<()> {
         info!("Starting transport layer");
 
-        // Use WebSocket by default
-        let mut ws_client = websocket::WebSocketClient::new(self.config.clone()).await?;
+        let endpoints = EndpointPool::new(&self.config)?;
+        let client = Client::new(&self.config, self.dictionary.clone())?;
+        let mut sender = window::WindowedSender::new(
+            client,
+            endpoints,
+            self.config.max_in_flight,
+            Duration::from_secs(self.config.request_timeout_secs),
+            self.dictionary.clone(),
+        );
+        sender.connect().await?;
+
+        let mut rebalance_timer = rebalance_timer(&self.config);
+        if let Some(queue) = self.queue {
+            return run_with_queue(&mut sender, batch_rx, queue, rebalance_timer).await;
+        }
+
+        loop {
+            tokio::select! {
+                batch = batch_rx.recv(), if sender.has_room() => {
+                    let batch = match batch {
+                        Some(batch) => batch,
+                        None => break,
+                    };
+                    if let Err(e) = sender.send(batch).await {
+                        error!("Failed to send {} batches: {}", sender.abandon().len(), e);
+                    }
+                }
+                received = sender.recv(), if sender.in_flight() > 0 => match received {
+                    Ok((batch, response)) if resend_without_dictionary(&batch, &response) => {
+                        if let Err(e) = sender.send(batch).await {
+                            error!("Failed to send {} batches: {}", sender.abandon().len(), e);
+                        }
+                    }
+                    Ok((batch, response)) => {
+                        if response.status != IngestStatus::Success {
+                            warn!("Collector did not accept batch {}: {:?}", batch.batch_id, response.status);
+                        }
+                    }
+                    Err(e) => {
+                        warn!("Lost connection with {} batches in flight: {}", sender.in_flight(), e);
+                        if let Err(e) = sender.recover().await {
+                            error!("Failed to send {} batches: {}", sender.abandon().len(), e);
+                        }
+                    }
+                },
+                _ = rebalance_timer.tick(), if sender.in_flight() == 0 => {
+                    if let Err(e) = sender.rebalance().await {
+                        error!("Failed to reconnect: {}", e);
+                    }
+                }
+            }
+        }
 
-        while let Some(batch) = batch_rx.recv().await {
-            if let Err(e) = ws_client.send_batch(batch).await {
-                tracing::error!("Failed to send batch: {}", e);
+        // Wait for the last responses, without reconnecting
+        while sender.in_flight() > 0 {
+            if let Err(e) = sender.recv().await {
+                warn!("{} batches unacknowledged at shutdown: {}", sender.in_flight(), e);
+                break;
             }
         }
 
@@ -32,6 +123,401 @@ impl Transport {
     }
 
     pub async fn test_connection(&self) -> Result<()> {
-        websocket::WebSocketClient::test_connection(&self.config).await
+        match self.config.transport.as_str() {
+            "websocket" => websocket::WebSocketClient::test_connection(&self.config).await,
+            #[cfg(feature = "grpc-transport")]
+            "grpc" => grpc::GrpcClient::test_connection(&self.config).await,
+            other => Err(unsupported_transport(other)),
+        }
+    }
+}
+
+/// Start a sink from `[[sinks]]`, fed by the fan-out. Its batches never use
+/// the compression dictionary, which only the main collector knows.
+pub fn spawn_sink(config: &SinkConfig, fanout: &mut Fanout) -> Result<JoinHandle<()>> {
+    let when = config
+        .when
+        .as_ref()
+        .map(Condition::new)
+        .transpose()
+        .context("Invalid sink condition")?;
+
+    let handle = match &config.kind {
+        SinkKind::Collector(settings) => {
+            let name = config.name.clone().unwrap_or_else(|| settings.endpoint.clone());
+            let batch_rx = fanout.add_sink(name.clone(), when, false, config.queue_batches);
+            let transport = Transport::new((**settings).clone());
+            tokio::spawn(async move {
+                if let Err(e) = transport.run(batch_rx).await {
+                    error!("Sink {} error: {}", name, e);
+                }
+            })
+        }
+        SinkKind::File(file) => {
+            let name = config.name.clone().unwrap_or_else(|| file.path.clone());
+            let batch_rx = fanout.add_sink(name.clone(), when, false, config.queue_batches);
+            let sink = file::FileSink::new(file);
+            tokio::spawn(async move {
+                if let Err(e) = sink.run(batch_rx).await {
+                    error!("Sink {} error: {}", name, e);
+                }
+            })
+        }
+    };
+    Ok(handle)
+}
+
+/// Connection to the collector over the configured protocol
+pub enum Client {
+    WebSocket(websocket::WebSocketClient),
+    #[cfg(feature = "grpc-transport")]
+    Grpc(grpc::GrpcClient),
+}
+
+impl Client {
+    fn new(config: &CollectorSettings, dictionary: Option<SharedDictionary>) -> Result<Self> {
+        match config.transport.as_str() {
+            "websocket" => Ok(Self::WebSocket(websocket::WebSocketClient::new(
+                config.clone(),
+                dictionary,
+            )?)),
+            #[cfg(feature = "grpc-transport")]
+            "grpc" => Ok(Self::Grpc(grpc::GrpcClient::new(config.clone()))),
+            other => Err(unsupported_transport(other)),
+        }
+    }
+
+    /// Collector used from the next `connect` on
+    fn set_endpoint(&mut self, endpoint: &str) {
+        match self {
+            Self::WebSocket(client) => client.set_endpoint(endpoint),
+            #[cfg(feature = "grpc-transport")]
+            Self::Grpc(client) => client.set_endpoint(endpoint),
+        }
+    }
+
+    /// Replace the connection with a new one
+    async fn connect(&mut self) -> Result<()> {
+        match self {
+            Self::WebSocket(client) => client.connect().await,
+            #[cfg(feature = "grpc-transport")]
+            Self::Grpc(client) => client.connect().await,
+        }
+    }
+
+    async fn write(&mut self, batch: &Batch) -> Result<()> {
+        match self {
+            Self::WebSocket(client) => client.write(batch).await,
+            #[cfg(feature = "grpc-transport")]
+            Self::Grpc(client) => client.write(batch).await,
+        }
+    }
+
+    async fn read(&mut self) -> Result<IngestResponse> {
+        match self {
+            Self::WebSocket(client) => client.read().await,
+            #[cfg(feature = "grpc-transport")]
+            Self::Grpc(client) => client.read().await,
+        }
+    }
+}
+
+fn unsupported_transport(transport: &str) -> anyhow::Error {
+    if transport == "grpc" {
+        anyhow::anyhow!("The grpc transport needs the agent built with the grpc-transport feature")
+    } else {
+        anyhow::anyhow!("Unknown transport {:?}", transport)
+    }
+}
+
+/// Ticks when an idle connection should check for a preferred collector
+fn rebalance_timer(config: &CollectorSettings) -> tokio::time::Interval {
+    let period = Duration::from_secs(config.rebalance_interval_secs.max(1));
+    let mut timer = tokio::time::interval_at(tokio::time::Instant::now() + period, period);
+    timer.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Delay);
+    timer
+}
+
+/// Keep a dictionary offered with a response for the next batches, and stop
+/// using one the collector does not have
+fn install_dictionary(store: Option<&SharedDictionary>, response: &mut IngestResponse) {
+    if let (Some(store), Some(id)) = (store, response.unknown_dictionary) {
+        warn!("Collector does not have compression dictionary {}", id);
+        store.write().evict(id);
+    }
+    if let (Some(store), Some(offered)) = (store, response.dictionary.take()) {
+        info!(
+            "Using compression dictionary {} ({} bytes) from collector",
+            offered.id,
+            offered.data.len()
+        );
+        store.write().insert(offered);
+    }
+}
+
+/// Send batches through the disk queue. Every batch is persisted before it
+/// is sent; while earlier batches are undelivered, new ones wait on disk and
+/// the backlog is replayed in order once nothing is in flight. The fan-out
+/// may also spill batches into the queue, so anything pending beyond what
+/// is in flight counts as backlog.
+async fn run_with_queue(
+    sender: &mut window::WindowedSender,
+    mut batch_rx: Receiver<Batch>,
+    queue: SharedDiskQueue,
+    mut rebalance_timer: tokio::time::Interval,
+) -> Result<()> {
+    let mut backlog = queue.lock().pending_count() > 0;
+    let mut replay_timer = tokio::time::interval(REPLAY_INTERVAL);
+
+    loop {
+        tokio::select! {
+            batch = batch_rx.recv(), if sender.has_room() => {
+                let batch = match batch {
+                    Some(batch) => batch,
+                    None => break,
+                };
+
+                let persisted = {
+                    let mut queue = queue.lock();
+                    backlog |= queue.pending_count() > sender.in_flight();
+                    match queue.append(&batch) {
+                        Ok(()) => true,
+                        Err(e) => {
+                            error!("Failed to persist batch {}: {}", batch.batch_id, e);
+                            false
+                        }
+                    }
+                };
+
+                // Keep ordering: behind a backlog the batch waits on disk
+                if backlog && persisted {
+                    continue;
+                }
+
+                if let Err(e) = sender.send(batch).await {
+                    error!(
+                        "Failed to send {} batches, will retry from disk: {}",
+                        sender.abandon().len(),
+                        e
+                    );
+                    backlog = true;
+                }
+            }
+            received = sender.recv(), if sender.in_flight() > 0 => match received {
+                Ok((batch, response)) if resend_without_dictionary(&batch, &response) => {
+                    if let Err(e) = sender.send(batch).await {
+                        error!(
+                            "Failed to send {} batches, will retry from disk: {}",
+                            sender.abandon().len(),
+                            e
+                        );
+                        backlog = true;
+                    }
+                }
+                Ok((batch, response)) => match settle(&queue, &batch, &response) {
+                    Ok(true) => {}
+                    Ok(false) => {
+                        warn!(
+                            "Collector did not accept batch {} ({:?}), will retry from disk",
+                            batch.batch_id, response.status
+                        );
+                        backlog = true;
+                    }
+                    Err(e) => error!("Failed to update disk queue: {}", e),
+                },
+                Err(e) => {
+                    warn!("Lost connection with {} batches in flight: {}", sender.in_flight(), e);
+                    if let Err(e) = sender.recover().await {
+                        error!(
+                            "Failed to send {} batches, will retry from disk: {}",
+                            sender.abandon().len(),
+                            e
+                        );
+                        backlog = true;
+                    }
+                }
+            },
+            _ = rebalance_timer.tick(), if sender.in_flight() == 0 => {
+                if let Err(e) = sender.rebalance().await {
+                    error!("Failed to reconnect, will retry from disk: {}", e);
+                    backlog = true;
+                }
+            }
+            // Batches still in flight are pending on disk too; wait for them
+            // so they are not sent twice
+            _ = replay_timer.tick(), if sender.in_flight() == 0 => {
+                if !backlog && queue.lock().pending_count() == 0 {
+                    continue;
+                }
+                match replay(sender, &queue).await {
+                    Ok(()) => {
+                        info!("Disk queue backlog delivered");
+                        backlog = false;
+                    }
+                    Err(e) => {
+                        sender.abandon();
+                        warn!(
+                            "Replay stopped with {} batches pending: {}",
+                            queue.lock().pending_count(),
+                            e
+                        );
+                    }
+                }
+            }
+        }
+    }
+
+    Ok(())
+}
+
+/// Send every undelivered batch on disk, oldest first, keeping the window
+/// full
+async fn replay(sender: &mut window::WindowedSender, queue: &SharedDiskQueue) -> Result<()> {
+    let segments = queue.lock().pending_segments();
+    for segment in segments {
+        let batches = queue.lock().read_pending(segment)?;
+        for batch in batches {
+            while !sender.has_room() {
+                settle_next(sender, queue).await?;
+            }
+            sender.send(batch).await?;
+        }
+    }
+    while sender.in_flight() > 0 {
+        settle_next(sender, queue).await?;
+    }
+    Ok(())
+}
+
+/// Wait for one response during replay and apply it to the disk queue
+async fn settle_next(sender: &mut window::WindowedSender, queue: &SharedDiskQueue) -> Result<()> {
+    let (batch, response) = match sender.recv().await {
+        Ok(received) => received,
+        Err(e) => {
+            warn!("Lost connection with {} batches in flight: {}", sender.in_flight(), e);
+            return sender.recover().await;
+        }
+    };
+
+    if resend_without_dictionary(&batch, &response) {
+        return sender.send(batch).await;
+    }
+    if !settle(queue, &batch, &response)? {
+        anyhow::bail!(
+            "collector did not accept batch {}: {:?}",
+            batch.batch_id,
+            response.status
+        );
+    }
+    Ok(())
+}
+
+/// Whether a batch came back only because the collector lacks its
+/// dictionary, and has been re-compressed without it
+fn resend_without_dictionary(batch: &Batch, response: &IngestResponse) -> bool {
+    response.unknown_dictionary.is_some() && batch.dictionary_id.is_none()
+}
+
+/// Update the disk queue from a collector response, returning whether the
+/// batch is done with. Only `Success` counts as delivered; rejected batches,
+/// and those that failed `MAX_DELIVERY_ATTEMPTS` times, are set aside so
+/// they cannot block the queue.
+fn settle(queue: &SharedDiskQueue, batch: &Batch, response: &IngestResponse) -> Result<bool> {
+    let mut queue = queue.lock();
+    match response.status {
+        IngestStatus::Success => {
+            queue.ack(&batch.batch_id)?;
+            Ok(true)
+        }
+        IngestStatus::Rejected => {
+            queue.reject(batch)?;
+            Ok(true)
+        }
+        IngestStatus::PartialSuccess | IngestStatus::Failed => {
+            if queue.record_failure(&batch.batch_id) < MAX_DELIVERY_ATTEMPTS {
+                return Ok(false);
+            }
+            warn!(
+                "Giving up on batch {} after {} failed deliveries",
+                batch.batch_id, MAX_DELIVERY_ATTEMPTS
+            );
+            queue.reject(batch)?;
+            Ok(true)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::buffer::{DiskQueue, FsyncPolicy};
+    use futures_util::{SinkExt, StreamExt};
+    use monitoring_common::{CompressionType, PayloadEncoding};
+    use std::sync::Arc;
+    use tokio::net::TcpListener;
+    use tokio_tungstenite::tungstenite::Message;
+
+    fn batch(id: &str) -> Batch {
+        Batch {
+            batch_id: id.to_string(),
+            agent_id: "agent".to_string(),
+            hostname: "host".to_string(),
+            timestamp: 0,
+            event_count: 0,
+            compression: CompressionType::None,
+            encoding: PayloadEncoding::Json,
+            dictionary_id: None,
+            compressed_data: b"[]".to_vec(),
+            checksum: String::new(),
+        }
+    }
+
+    #[tokio::test]
+    async fn test_replay_sets_aside_batch_that_always_fails() {
+        // A collector that can never store "b"
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let endpoint = format!("ws://{}", listener.local_addr().unwrap());
+        tokio::spawn(async move {
+            let (stream, _) = listener.accept().await.unwrap();
+            let mut ws = tokio_tungstenite::accept_async(stream).await.unwrap();
+            while let Some(Ok(Message::Text(text))) = ws.next().await {
+                let batch: Batch = serde_json::from_str(&text).unwrap();
+                let status = if batch.batch_id == "b" { IngestStatus::Failed } else { IngestStatus::Success };
+                let response = IngestResponse {
+                    batch_id: batch.batch_id,
+                    status,
+                    error_message: None,
+                    received_at: 0,
+                    dictionary: None,
+                    unknown_dictionary: None,
+                };
+                ws.send(Message::Text(serde_json::to_string(&response).unwrap())).await.unwrap();
+            }
+        });
+
+        let dir = std::env::temp_dir().join(format!("monitoring-agent-replay-{}", uuid::Uuid::new_v4()));
+        let queue = DiskQueue::open(&dir, 1 << 20, 1 << 16, FsyncPolicy::Never).unwrap();
+        let queue: SharedDiskQueue = Arc::new(parking_lot::Mutex::new(queue));
+        for id in ["a", "b", "c"] {
+            queue.lock().append(&batch(id)).unwrap();
+        }
+
+        let config: CollectorSettings = toml::from_str(&format!("endpoint = {:?}", endpoint)).unwrap();
+        let client = Client::new(&config, None).unwrap();
+        let endpoints = EndpointPool::new(&config).unwrap();
+        let mut sender = window::WindowedSender::new(client, endpoints, 1, Duration::from_secs(5), None);
+        sender.connect().await.unwrap();
+
+        // Each replay stops at "b" until it is given up on
+        for _ in 1..MAX_DELIVERY_ATTEMPTS {
+            assert!(replay(&mut sender, &queue).await.is_err());
+            sender.abandon();
+        }
+        assert_eq!(queue.lock().pending_count(), 2);
+        replay(&mut sender, &queue).await.unwrap();
+
+        assert_eq!(queue.lock().pending_count(), 0);
+        assert!(dir.join("rejected").join("b.json").exists());
+        std::fs::remove_dir_all(&dir).unwrap();
     }
 }
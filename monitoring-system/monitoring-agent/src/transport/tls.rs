@@ -0,0 +1,322 @@
+use crate::config::CollectorSettings;
+use anyhow::{Context, Result};
+use rustls::client::danger::{HandshakeSignatureValid, ServerCertVerified, ServerCertVerifier};
+use rustls::client::WebPkiServerVerifier;
+use rustls::pki_types::{CertificateDer, PrivateKeyDer, ServerName, UnixTime};
+use rustls::{CertificateError, ClientConfig, DigitallySignedStruct, RootCertStore, SignatureScheme};
+use sha2::{Digest, Sha256};
+use std::io::BufReader;
+use std::path::PathBuf;
+use std::sync::Arc;
+use std::time::SystemTime;
+use tracing::{info, warn};
+
+/// Builds the rustls client configuration from the files named in
+/// `CollectorSettings`, and rebuilds it when one of them changes on disk so
+/// rotated certificates are used from the next connection on
+pub struct TlsLoader {
+    ca_cert: Option<PathBuf>,
+    client_cert: Option<PathBuf>,
+    client_key: Option<PathBuf>,
+    pins: Vec<[u8; 32]>,
+    /// Modification times the current configuration was built from
+    loaded: Vec<Option<SystemTime>>,
+    current: Option<Arc<ClientConfig>>,
+}
+
+impl TlsLoader {
+    pub fn new(config: &CollectorSettings) -> Result<Self> {
+        if config.client_cert.is_some() != config.client_key.is_some() {
+            anyhow::bail!("client_cert and client_key must be set together");
+        }
+
+        Ok(Self {
+            ca_cert: config.tls_ca_cert.as_ref().map(PathBuf::from),
+            client_cert: config.client_cert.as_ref().map(PathBuf::from),
+            client_key: config.client_key.as_ref().map(PathBuf::from),
+            pins: config
+                .tls_pin_sha256
+                .iter()
+                .map(|pin| parse_pin(pin))
+                .collect::<Result<_>>()?,
+            loaded: Vec::new(),
+            current: None,
+        })
+    }
+
+    /// Configuration for the next connection, reloaded if any file changed.
+    /// A failed reload keeps the previous configuration, since rotation may
+    /// have replaced only some of the files so far.
+    pub fn client_config(&mut self) -> Result<Arc<ClientConfig>> {
+        let modified = self.modified();
+        if let Some(current) = &self.current {
+            if modified == self.loaded {
+                return Ok(current.clone());
+            }
+        }
+
+        match self.build() {
+            Ok(config) => {
+                if self.current.is_some() {
+                    info!("Reloaded TLS certificates");
+                }
+                let config = Arc::new(config);
+                self.loaded = modified;
+                self.current = Some(config.clone());
+                Ok(config)
+            }
+            Err(e) => match &self.current {
+                Some(current) => {
+                    warn!("Failed to reload TLS certificates, keeping the previous ones: {:#}", e);
+                    Ok(current.clone())
+                }
+                None => Err(e),
+            },
+        }
+    }
+
+    fn modified(&self) -> Vec<Option<SystemTime>> {
+        [&self.ca_cert, &self.client_cert, &self.client_key]
+            .into_iter()
+            .flatten()
+            .map(|path| std::fs::metadata(path).and_then(|meta| meta.modified()).ok())
+            .collect()
+    }
+
+    fn build(&self) -> Result<ClientConfig> {
+        let mut roots = RootCertStore::empty();
+        match &self.ca_cert {
+            Some(path) => {
+                for cert in read_certs(path)? {
+                    roots
+                        .add(cert)
+                        .with_context(|| format!("Invalid CA certificate in {:?}", path))?;
+                }
+            }
+            None => {
+                let native = rustls_native_certs::load_native_certs()
+                    .context("Failed to load system root certificates")?;
+                let (_, ignored) = roots.add_parsable_certificates(native);
+                if ignored > 0 {
+                    warn!("Ignored {} unparsable system root certificates", ignored);
+                }
+            }
+        }
+
+        let builder = ClientConfig::builder();
+        let builder = if self.pins.is_empty() {
+            builder.with_root_certificates(roots)
+        } else {
+            let verifier = PinnedVerifier {
+                inner: WebPkiServerVerifier::builder(Arc::new(roots))
+                    .build()
+                    .context("Failed to build certificate verifier")?,
+                pins: self.pins.clone(),
+            };
+            builder
+                .dangerous()
+                .with_custom_certificate_verifier(Arc::new(verifier))
+        };
+
+        match (&self.client_cert, &self.client_key) {
+            (Some(cert), Some(key)) => builder
+                .with_client_auth_cert(read_certs(cert)?, read_key(key)?)
+                .context("Invalid client certificate or key"),
+            _ => Ok(builder.with_no_client_auth()),
+        }
+    }
+}
+
+/// Accepts a server only if the usual chain verification passes and one of
+/// the certificates it presents has a pinned public key
+#[derive(Debug)]
+struct PinnedVerifier {
+    inner: Arc<WebPkiServerVerifier>,
+    pins: Vec<[u8; 32]>,
+}
+
+impl ServerCertVerifier for PinnedVerifier {
+    fn verify_server_cert(
+        &self,
+        end_entity: &CertificateDer<'_>,
+        intermediates: &[CertificateDer<'_>],
+        server_name: &ServerName<'_>,
+        ocsp_response: &[u8],
+        now: UnixTime,
+    ) -> Result<ServerCertVerified, rustls::Error> {
+        let verified =
+            self.inner
+                .verify_server_cert(end_entity, intermediates, server_name, ocsp_response, now)?;
+
+        let pinned = std::iter::once(end_entity)
+            .chain(intermediates)
+            .filter_map(spki_sha256)
+            .any(|hash| self.pins.contains(&hash));
+        if !pinned {
+            warn!("Collector certificate does not match any pinned key");
+            return Err(rustls::Error::InvalidCertificate(
+                CertificateError::ApplicationVerificationFailure,
+            ));
+        }
+        Ok(verified)
+    }
+
+    fn verify_tls12_signature(
+        &self,
+        message: &[u8],
+        cert: &CertificateDer<'_>,
+        dss: &DigitallySignedStruct,
+    ) -> Result<HandshakeSignatureValid, rustls::Error> {
+        self.inner.verify_tls12_signature(message, cert, dss)
+    }
+
+    fn verify_tls13_signature(
+        &self,
+        message: &[u8],
+        cert: &CertificateDer<'_>,
+        dss: &DigitallySignedStruct,
+    ) -> Result<HandshakeSignatureValid, rustls::Error> {
+        self.inner.verify_tls13_signature(message, cert, dss)
+    }
+
+    fn supported_verify_schemes(&self) -> Vec<SignatureScheme> {
+        self.inner.supported_verify_schemes()
+    }
+}
+
+/// SHA-256 of a certificate's DER-encoded SubjectPublicKeyInfo
+fn spki_sha256(cert: &CertificateDer<'_>) -> Option<[u8; 32]> {
+    let cert = webpki::EndEntityCert::try_from(cert).ok()?;
+    Some(Sha256::digest(cert.subject_public_key_info().as_ref()).into())
+}
+
+/// Hex SHA-256 as printed by
+/// `openssl x509 -pubkey -noout | openssl pkey -pubin -outform der | sha256sum`
+fn parse_pin(pin: &str) -> Result<[u8; 32]> {
+    let pin = pin.trim().replace(':', "");
+    let bytes = hex::decode(&pin).with_context(|| format!("Invalid SPKI pin {:?}", pin))?;
+    bytes
+        .try_into()
+        .map_err(|_| anyhow::anyhow!("SPKI pin {:?} is not a SHA-256 hash", pin))
+}
+
+fn read_certs(path: &PathBuf) -> Result<Vec<CertificateDer<'static>>> {
+    let file = std::fs::File::open(path).with_context(|| format!("Failed to open {:?}", path))?;
+    let certs = rustls_pemfile::certs(&mut BufReader::new(file))
+        .collect::<Result<Vec<_>, _>>()
+        .with_context(|| format!("Failed to parse certificates in {:?}", path))?;
+    if certs.is_empty() {
+        anyhow::bail!("No certificates in {:?}", path);
+    }
+    Ok(certs)
+}
+
+fn read_key(path: &PathBuf) -> Result<PrivateKeyDer<'static>> {
+    let file = std::fs::File::open(path).with_context(|| format!("Failed to open {:?}", path))?;
+    rustls_pemfile::private_key(&mut BufReader::new(file))
+        .with_context(|| format!("Failed to parse private key in {:?}", path))?
+        .with_context(|| format!("No private key in {:?}", path))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rcgen::{BasicConstraints, Certificate, CertificateParams, IsCa};
+    use std::path::Path;
+    use std::time::Duration;
+
+    struct Pki {
+        ca: Certificate,
+        server: Certificate,
+    }
+
+    impl Pki {
+        fn new() -> Self {
+            let mut params = CertificateParams::new(vec![]);
+            params.is_ca = IsCa::Ca(BasicConstraints::Unconstrained);
+            Self {
+                ca: Certificate::from_params(params).unwrap(),
+                server: Certificate::from_params(CertificateParams::new(vec!["localhost".to_string()]))
+                    .unwrap(),
+            }
+        }
+
+        fn server_chain(&self) -> CertificateDer<'static> {
+            CertificateDer::from(self.server.serialize_der_with_signer(&self.ca).unwrap())
+        }
+    }
+
+    fn settings(dir: &Path, pins: Vec<String>) -> CollectorSettings {
+        let mut settings: CollectorSettings = toml::from_str("endpoint = \"wss://localhost\"").unwrap();
+        settings.tls_ca_cert = Some(dir.join("ca.pem").to_string_lossy().into_owned());
+        settings.client_cert = Some(dir.join("client.pem").to_string_lossy().into_owned());
+        settings.client_key = Some(dir.join("client-key.pem").to_string_lossy().into_owned());
+        settings.tls_pin_sha256 = pins;
+        settings
+    }
+
+    fn write_client(dir: &Path, pki: &Pki, modified: SystemTime) {
+        let client = rcgen::generate_simple_self_signed(vec!["agent".to_string()]).unwrap();
+        std::fs::write(dir.join("client.pem"), client.serialize_pem_with_signer(&pki.ca).unwrap()).unwrap();
+        std::fs::write(dir.join("client-key.pem"), client.serialize_private_key_pem()).unwrap();
+        for name in ["client.pem", "client-key.pem"] {
+            let file = std::fs::File::options().write(true).open(dir.join(name)).unwrap();
+            file.set_modified(modified).unwrap();
+        }
+    }
+
+    fn temp_dir(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("agent-tls-{}-{}", name, std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    #[test]
+    fn test_pins_server_key() {
+        let pki = Pki::new();
+        let pin = |cert: &Certificate| hex::encode(Sha256::digest(cert.get_key_pair().public_key_der()));
+        let verify = |pins: &[String]| {
+            let mut roots = RootCertStore::empty();
+            roots.add(CertificateDer::from(pki.ca.serialize_der().unwrap())).unwrap();
+            let verifier = PinnedVerifier {
+                inner: WebPkiServerVerifier::builder(Arc::new(roots)).build().unwrap(),
+                pins: pins.iter().map(|pin| parse_pin(pin).unwrap()).collect(),
+            };
+            verifier.verify_server_cert(
+                &pki.server_chain(),
+                &[],
+                &ServerName::try_from("localhost").unwrap(),
+                &[],
+                UnixTime::now(),
+            )
+        };
+
+        assert!(verify(&[pin(&pki.server)]).is_ok());
+        let other = rcgen::generate_simple_self_signed(vec!["localhost".to_string()]).unwrap();
+        assert!(verify(&[pin(&other)]).is_err());
+        assert!(parse_pin("abcd").is_err());
+    }
+
+    #[test]
+    fn test_reloads_rotated_client_certificate() {
+        let dir = temp_dir("reload");
+        let pki = Pki::new();
+        std::fs::write(dir.join("ca.pem"), pki.ca.serialize_pem().unwrap()).unwrap();
+        let start = SystemTime::now() - Duration::from_secs(60);
+        write_client(&dir, &pki, start);
+
+        let mut loader = TlsLoader::new(&settings(&dir, vec![])).unwrap();
+        let first = loader.client_config().unwrap();
+        assert!(Arc::ptr_eq(&first, &loader.client_config().unwrap()));
+
+        write_client(&dir, &pki, start + Duration::from_secs(30));
+        let rotated = loader.client_config().unwrap();
+        assert!(!Arc::ptr_eq(&first, &rotated));
+
+        // A half-written rotation keeps the last good configuration
+        std::fs::write(dir.join("client-key.pem"), "").unwrap();
+        assert!(Arc::ptr_eq(&rotated, &loader.client_config().unwrap()));
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
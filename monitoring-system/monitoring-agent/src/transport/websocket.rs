@@ -1,147 +1,134 @@
 use crate::config::CollectorSettings;
-use crate::transport::retry::RetryPolicy;
+use crate::pipeline::{Compressor, SharedDictionary, ZstdOptions};
+use crate::proxy::{self, ProxyRules};
+use crate::transport::tls::TlsLoader;
 use anyhow::{Context, Result};
 use futures_util::{SinkExt, StreamExt};
-use monitoring_common::{Batch, IngestResponse};
+use monitoring_common::encoding::{parse_encodings_header, PAYLOAD_ENCODINGS_HEADER};
+use monitoring_common::{Batch, IngestResponse, PayloadEncoding};
+use std::borrow::Cow;
+use std::time::Duration;
 use tokio::net::TcpStream;
-use tokio_tungstenite::{connect_async, tungstenite::Message, MaybeTlsStream, WebSocketStream};
-use tracing::{debug, error, info, warn};
-
+use tokio_tungstenite::tungstenite::client::IntoClientRequest;
+use tokio_tungstenite::{
+    client_async_tls_with_config, tungstenite::Message, Connector, MaybeTlsStream, WebSocketStream,
+};
+use tracing::{debug, info};
+
+/// One WebSocket connection to the collector. Batches are written and
+/// responses read independently, so several batches can be in flight.
 pub struct WebSocketClient {
     config: CollectorSettings,
     ws_stream: Option<WebSocketStream<MaybeTlsStream<TcpStream>>>,
-    retry_policy: RetryPolicy,
+    /// Payload encodings the collector advertised during the handshake
+    encodings: Vec<PayloadEncoding>,
+    /// Dictionary used when a batch has to be transcoded
+    dictionary: Option<SharedDictionary>,
+    /// Rebuilt when the certificate files change
+    tls: TlsLoader,
+    proxy: ProxyRules,
 }
 
 impl WebSocketClient {
-    pub async fn new(config: CollectorSettings) -> Result<Self> {
-        let retry_policy = RetryPolicy::new(
-            5,  // max_retries
-            std::time::Duration::from_secs(1),  // initial_delay
-            std::time::Duration::from_secs(60), // max_delay
-        );
-
-        let mut client = Self {
+    /// Client for `config.endpoint`; nothing is sent until `connect`
+    pub fn new(config: CollectorSettings, dictionary: Option<SharedDictionary>) -> Result<Self> {
+        let tls = TlsLoader::new(&config)?;
+        let proxy = ProxyRules::from_settings(&config.proxy)?;
+        Ok(Self {
             config,
             ws_stream: None,
-            retry_policy,
-        };
+            encodings: vec![PayloadEncoding::Json],
+            dictionary,
+            tls,
+            proxy,
+        })
+    }
 
-        client.connect().await?;
-        Ok(client)
+    /// Collector used from the next `connect` on
+    pub fn set_endpoint(&mut self, endpoint: &str) {
+        self.config.endpoint = endpoint.to_string();
     }
 
-    async fn connect(&mut self) -> Result<()> {
+    pub async fn connect(&mut self) -> Result<()> {
         info!("Connecting to collector: {}", self.config.endpoint);
+        self.ws_stream = None;
 
-        // Add authorization header if token is provided
-        let mut request_builder = http::Request::builder()
-            .uri(&self.config.endpoint);
-
-        if let Some(token) = &self.config.auth_token {
-            request_builder = request_builder
-                .header("Authorization", format!("Bearer {}", token));
-        }
+        let (ws_stream, response) = open(&self.config, &mut self.tls, &self.proxy).await?;
 
-        let request = request_builder
-            .body(())
-            .context("Failed to build WebSocket request")?;
+        debug!("WebSocket connected, response: {:?}", response.status());
 
-        let (ws_stream, response) = connect_async(request).await
-            .context("Failed to connect to WebSocket")?;
+        // Renegotiated on every connect since the collector may have changed
+        self.encodings = accepted_encodings(response.headers());
+        debug!("Collector accepts payload encodings: {:?}", self.encodings);
 
-        debug!("WebSocket connected, response: {:?}", response.status());
-        
         self.ws_stream = Some(ws_stream);
-        self.retry_policy.reset();
 
         Ok(())
     }
 
-    pub async fn send_batch(&mut self, batch: Batch) -> Result<()> {
-        let batch_id = batch.batch_id.clone();
-        
-        loop {
-            match self.try_send_batch(&batch).await {
-                Ok(response) => {
-                    debug!("Batch {} sent successfully: {:?}", batch_id, response.status);
-                    return Ok(());
-                }
-                Err(e) => {
-                    error!("Failed to send batch {}: {}", batch_id, e);
-                    
-                    // Try to reconnect and retry
-                    if let Some(delay) = self.retry_policy.next_delay() {
-                        warn!("Retrying in {:?}...", delay);
-                        tokio::time::sleep(delay).await;
-
-                        // Reconnect
-                        if let Err(e) = self.connect().await {
-                            error!("Failed to reconnect: {}", e);
-                            continue;
-                        }
-                    } else {
-                        error!("Max retries exceeded for batch {}", batch_id);
-                        return Err(e);
-                    }
-                }
-            }
-        }
-    }
-
-    async fn try_send_batch(&mut self, batch: &Batch) -> Result<IngestResponse> {
+    /// Send a batch without waiting for its response
+    pub async fn write(&mut self, batch: &Batch) -> Result<()> {
+        let payload = self.negotiate(batch)?;
         let ws_stream = self.ws_stream.as_mut()
             .context("WebSocket not connected")?;
 
         // Serialize batch to JSON
-        let json = serde_json::to_string(batch)?;
-        
+        let json = serde_json::to_string(&payload)?;
+
         // Send as text message
         ws_stream.send(Message::Text(json)).await?;
+        Ok(())
+    }
 
-        // Wait for response
-        match tokio::time::timeout(
-            std::time::Duration::from_secs(30),
-            ws_stream.next()
-        ).await {
-            Ok(Some(Ok(Message::Text(response_text)))) => {
-                let response: IngestResponse = serde_json::from_str(&response_text)?;
-                Ok(response)
-            }
-            Ok(Some(Ok(msg))) => {
-                anyhow::bail!("Unexpected message type: {:?}", msg);
-            }
-            Ok(Some(Err(e))) => {
-                Err(e.into())
-            }
-            Ok(None) => {
-                anyhow::bail!("WebSocket closed");
-            }
-            Err(_) => {
-                anyhow::bail!("Response timeout");
+    /// Wait for the next response, whichever batch it is for
+    pub async fn read(&mut self) -> Result<IngestResponse> {
+        let ws_stream = self.ws_stream.as_mut()
+            .context("WebSocket not connected")?;
+
+        loop {
+            match ws_stream.next().await {
+                Some(Ok(Message::Text(response_text))) => {
+                    return Ok(serde_json::from_str(&response_text)?);
+                }
+                // Control frames are answered by tungstenite itself
+                Some(Ok(Message::Ping(_) | Message::Pong(_))) => continue,
+                Some(Ok(msg)) => anyhow::bail!("Unexpected message type: {:?}", msg),
+                Some(Err(e)) => return Err(e.into()),
+                None => anyhow::bail!("WebSocket closed"),
             }
         }
     }
 
-    pub async fn test_connection(config: &CollectorSettings) -> Result<()> {
-        info!("Testing connection to: {}", config.endpoint);
-
-        let mut request_builder = http::Request::builder()
-            .uri(&config.endpoint);
-
-        if let Some(token) = &config.auth_token {
-            request_builder = request_builder
-                .header("Authorization", format!("Bearer {}", token));
+    /// Fall back to JSON for collectors that do not accept the batch's encoding
+    fn negotiate<'a>(&self, batch: &'a Batch) -> Result<Cow<'a, Batch>> {
+        if self.encodings.contains(&batch.encoding) {
+            return Ok(Cow::Borrowed(batch));
         }
 
-        let request = request_builder
-            .body(())
-            .context("Failed to build WebSocket request")?;
+        debug!(
+            "Collector does not accept {} payloads, sending batch {} as JSON",
+            batch.encoding.as_str(),
+            batch.batch_id
+        );
+        // The batch may predate a dictionary rotation, so use the one it names
+        let zstd = ZstdOptions {
+            level: 0,
+            dictionary: batch
+                .dictionary_id
+                .zip(self.dictionary.as_ref())
+                .and_then(|(id, dictionary)| dictionary.read().get(id)),
+        };
+        Ok(Cow::Owned(Compressor::transcode(batch, PayloadEncoding::Json, &zstd)?))
+    }
 
-        let (ws_stream, response) = connect_async(request).await
-            .context("Failed to connect to WebSocket")?;
+    pub async fn test_connection(config: &CollectorSettings) -> Result<()> {
+        info!("Testing connection to: {}", config.endpoint);
+
+        let proxy = ProxyRules::from_settings(&config.proxy)?;
+        let (ws_stream, response) = open(config, &mut TlsLoader::new(config)?, &proxy).await?;
 
         info!("Connection successful, status: {:?}", response.status());
+        info!("Collector accepts payload encodings: {:?}", accepted_encodings(response.headers()));
         
         // Close the connection  
         drop(ws_stream);
@@ -149,3 +136,72 @@ impl WebSocketClient {
         Ok(())
     }
 }
+
+/// Perform the WebSocket handshake, over TLS for `wss://` endpoints and
+/// through a proxy if one applies
+async fn open(
+    config: &CollectorSettings,
+    tls: &mut TlsLoader,
+    proxy: &ProxyRules,
+) -> Result<(WebSocketStream<MaybeTlsStream<TcpStream>>, http::Response<Option<Vec<u8>>>)> {
+    let request = handshake_request(config)?;
+    let secure = request.uri().scheme_str() == Some("wss");
+    let host = request.uri().host().context("Endpoint has no host")?.to_string();
+    let port = request
+        .uri()
+        .port_u16()
+        .unwrap_or(if secure { 443 } else { 80 });
+
+    let connector = if secure {
+        Some(Connector::Rustls(tls.client_config()?))
+    } else {
+        None
+    };
+
+    let stream = tokio::time::timeout(
+        Duration::from_secs(config.connect_timeout_secs),
+        proxy::connect(proxy, secure, &host, port),
+    )
+    .await
+    .with_context(|| format!("Timed out connecting to {}:{}", host, port))??;
+
+    client_async_tls_with_config(request, stream, None, connector)
+        .await
+        .context("Failed to connect to WebSocket")
+}
+
+/// Upgrade request for the endpoint, with the authorization header if a
+/// token is configured. Built by tungstenite so the WebSocket key and
+/// version headers are present.
+fn handshake_request(config: &CollectorSettings) -> Result<http::Request<()>> {
+    let mut request = config
+        .endpoint
+        .as_str()
+        .into_client_request()
+        .context("Failed to build WebSocket request")?;
+
+    if let Some(token) = &config.auth_token {
+        let value = format!("Bearer {}", token)
+            .parse()
+            .context("Auth token is not a valid header value")?;
+        request.headers_mut().insert(http::header::AUTHORIZATION, value);
+    }
+
+    Ok(request)
+}
+
+/// Encodings advertised in the handshake response. Collectors that predate
+/// negotiation send no header and only understand JSON.
+fn accepted_encodings(headers: &http::HeaderMap) -> Vec<PayloadEncoding> {
+    let encodings = headers
+        .get(PAYLOAD_ENCODINGS_HEADER)
+        .and_then(|value| value.to_str().ok())
+        .map(parse_encodings_header)
+        .unwrap_or_default();
+
+    if encodings.is_empty() {
+        vec![PayloadEncoding::Json]
+    } else {
+        encodings
+    }
+}
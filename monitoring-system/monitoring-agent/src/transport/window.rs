@@ -0,0 +1,511 @@
+use super::endpoints::EndpointPool;
+use super::retry::RetryPolicy;
+use super::{install_dictionary, Client};
+use crate::pipeline::{Compressor, SharedDictionary};
+use anyhow::Result;
+use monitoring_common::{Batch, IngestResponse};
+use std::collections::VecDeque;
+use std::time::{Duration, Instant};
+use tracing::{debug, error, info, warn};
+
+/// Keeps up to `window` batches in flight on one connection instead of
+/// waiting a round trip per batch. Responses are matched to batches by id,
+/// and after a reconnect only the unacknowledged batches are sent again,
+/// to whichever collector the endpoint pool picks.
+pub struct WindowedSender {
+    client: Client,
+    endpoints: EndpointPool,
+    /// Endpoint of the current connection, `None` before the first
+    current: Option<usize>,
+    /// Sent but unanswered batches with when they were sent, oldest first
+    in_flight: VecDeque<(Batch, Instant)>,
+    window: usize,
+    response_timeout: Duration,
+    retry_policy: RetryPolicy,
+    /// Where offered compression dictionaries are stored, if they are used
+    dictionary: Option<SharedDictionary>,
+}
+
+impl WindowedSender {
+    pub fn new(
+        client: Client,
+        endpoints: EndpointPool,
+        window: usize,
+        response_timeout: Duration,
+        dictionary: Option<SharedDictionary>,
+    ) -> Self {
+        Self {
+            client,
+            endpoints,
+            current: None,
+            in_flight: VecDeque::new(),
+            window: window.max(1),
+            response_timeout,
+            retry_policy: RetryPolicy::new(
+                5,  // max_retries
+                Duration::from_secs(1),  // initial_delay
+                Duration::from_secs(60), // max_delay
+            ),
+            dictionary,
+        }
+    }
+
+    pub fn has_room(&self) -> bool {
+        self.in_flight.len() < self.window
+    }
+
+    pub fn in_flight(&self) -> usize {
+        self.in_flight.len()
+    }
+
+    /// Send a batch without waiting for its response, reconnecting if the
+    /// connection is broken. On error the batch is still in flight.
+    pub async fn send(&mut self, mut batch: Batch) -> Result<()> {
+        prepare(self.dictionary.as_ref(), &mut batch);
+        let written = self.client.write(&batch).await;
+        self.in_flight.push_back((batch, Instant::now()));
+
+        match written {
+            Ok(()) => Ok(()),
+            Err(e) => {
+                warn!("Failed to send batch: {}", e);
+                self.recover().await
+            }
+        }
+    }
+
+    /// Wait for the response to any batch in flight. This only reads, so it
+    /// is safe to cancel; after an error, call `recover`.
+    ///
+    /// A batch the collector could not decode for want of its dictionary
+    /// comes back re-compressed without it, ready to `send` again.
+    pub async fn recv(&mut self) -> Result<(Batch, IngestResponse)> {
+        loop {
+            let mut response = match tokio::time::timeout(self.response_timeout, self.client.read()).await {
+                Ok(response) => response?,
+                Err(_) => anyhow::bail!("No response within {:?}", self.response_timeout),
+            };
+
+            let index = match self
+                .in_flight
+                .iter()
+                .position(|(batch, _)| batch.batch_id == response.batch_id)
+            {
+                Some(index) => index,
+                None => {
+                    // A batch given up on, or answered twice around a reconnect
+                    debug!("Ignoring response for batch {} not in flight", response.batch_id);
+                    continue;
+                }
+            };
+
+            let (mut batch, sent) = self.in_flight.remove(index).expect("index of an in-flight batch");
+            if let Some(current) = self.current {
+                self.endpoints.record_success(current, sent.elapsed());
+            }
+            self.retry_policy.reset();
+            install_dictionary(self.dictionary.as_ref(), &mut response);
+            if response.unknown_dictionary.is_some() {
+                prepare(self.dictionary.as_ref(), &mut batch);
+            }
+            return Ok((batch, response));
+        }
+    }
+
+    /// Open the first connection, to the endpoint the strategy picks
+    pub async fn connect(&mut self) -> Result<()> {
+        self.reconnect().await
+    }
+
+    /// Reconnect after the current connection failed and send the
+    /// unacknowledged batches again, oldest first. Fails once retries are
+    /// exhausted.
+    pub async fn recover(&mut self) -> Result<()> {
+        if let Some(current) = self.current {
+            self.endpoints.record_failure(current, Instant::now());
+        }
+        self.reconnect().await
+    }
+
+    /// Move an idle connection to a preferred endpoint, such as a primary
+    /// that has recovered. Nothing is in flight, so nothing is sent twice.
+    pub async fn rebalance(&mut self) -> Result<()> {
+        let current = match self.current {
+            Some(current) if self.in_flight.is_empty() => current,
+            _ => return Ok(()),
+        };
+        let preferred = match self.endpoints.preferred(current, Instant::now()) {
+            Some(preferred) => preferred,
+            None => return Ok(()),
+        };
+
+        info!(
+            "Moving from collector {} to {}",
+            self.endpoints.url(current),
+            self.endpoints.url(preferred)
+        );
+        match self.resend(preferred).await {
+            Ok(()) => Ok(()),
+            Err(e) => {
+                warn!("Failed to connect to {}: {}", self.endpoints.url(preferred), e);
+                self.recover().await
+            }
+        }
+    }
+
+    /// Another healthy endpoint is tried straight away; the same one, or
+    /// any once all circuits are open, only after a backoff delay
+    async fn reconnect(&mut self) -> Result<()> {
+        loop {
+            let (index, wait) = match self.endpoints.select(Instant::now()) {
+                Some(index) => (index, Some(index) == self.current),
+                None => (self.endpoints.soonest(), true),
+            };
+
+            if wait {
+                let delay = match self.retry_policy.next_delay() {
+                    Some(delay) => delay,
+                    None => anyhow::bail!(
+                        "Max retries exceeded with {} batches in flight",
+                        self.in_flight.len()
+                    ),
+                };
+                warn!("Reconnecting to {} in {:?}...", self.endpoints.url(index), delay);
+                tokio::time::sleep(delay).await;
+            }
+
+            match self.resend(index).await {
+                Ok(()) => return Ok(()),
+                Err(e) => {
+                    error!("Failed to connect to {}: {}", self.endpoints.url(index), e);
+                    self.endpoints.record_failure(index, Instant::now());
+                }
+            }
+        }
+    }
+
+    async fn resend(&mut self, index: usize) -> Result<()> {
+        // A failed attempt leaves no connection, so later waits count from here
+        self.current = Some(index);
+        self.client.set_endpoint(self.endpoints.url(index));
+        if let Some(store) = &self.dictionary {
+            store.write().set_endpoint(self.endpoints.url(index));
+        }
+        self.client.connect().await?;
+
+        // Batches compressed for another collector lose its dictionary
+        for (batch, sent) in &mut self.in_flight {
+            prepare(self.dictionary.as_ref(), batch);
+            *sent = Instant::now();
+            self.client.write(batch).await?;
+        }
+        if !self.in_flight.is_empty() {
+            info!("Resent {} unacknowledged batches", self.in_flight.len());
+        }
+        Ok(())
+    }
+
+    /// Stop waiting for every batch in flight and return them
+    pub fn abandon(&mut self) -> Vec<Batch> {
+        self.retry_policy.reset();
+        self.in_flight.drain(..).map(|(batch, _)| batch).collect()
+    }
+}
+
+/// Compress a batch again without its dictionary if the collector does not
+/// have it. Without the dictionary at hand, as after a restart, the batch is
+/// sent as it is.
+fn prepare(store: Option<&SharedDictionary>, batch: &mut Batch) {
+    let (store, id) = match (store, batch.dictionary_id) {
+        (Some(store), Some(id)) => (store, id),
+        _ => return,
+    };
+    let dictionary = {
+        let store = store.read();
+        if store.accepts(id) {
+            return;
+        }
+        store.get(id)
+    };
+
+    match dictionary.map(|dictionary| Compressor::without_dictionary(batch, &dictionary)) {
+        Some(Ok(recompressed)) => {
+            debug!("Re-compressed batch {} without dictionary {}", batch.batch_id, id);
+            *batch = recompressed;
+        }
+        Some(Err(e)) => warn!("Failed to re-compress batch {}: {}", batch.batch_id, e),
+        None => debug!("Compression dictionary {} of batch {} is not available", id, batch.batch_id),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::CollectorSettings;
+    use crate::transport::websocket::WebSocketClient;
+    use futures_util::{SinkExt, StreamExt};
+    use crate::pipeline::ZstdOptions;
+    use monitoring_common::{
+        CompressionDictionary, CompressionType, IngestStatus, PayloadEncoding, UncompressedBatch,
+    };
+    use tokio::net::TcpListener;
+    use tokio_tungstenite::tungstenite::Message;
+
+    fn batch(id: &str) -> Batch {
+        Batch {
+            batch_id: id.to_string(),
+            agent_id: "agent".to_string(),
+            hostname: "host".to_string(),
+            timestamp: 0,
+            event_count: 0,
+            compression: CompressionType::None,
+            encoding: PayloadEncoding::Json,
+            dictionary_id: None,
+            compressed_data: b"[]".to_vec(),
+            checksum: String::new(),
+        }
+    }
+
+    fn ack(id: &str) -> Message {
+        Message::Text(
+            serde_json::to_string(&IngestResponse {
+                batch_id: id.to_string(),
+                status: IngestStatus::Success,
+                error_message: None,
+                received_at: 0,
+                dictionary: None,
+                unknown_dictionary: None,
+            })
+            .unwrap(),
+        )
+    }
+
+    async fn next_batch_id<S>(ws: &mut tokio_tungstenite::WebSocketStream<S>) -> String
+    where
+        S: tokio::io::AsyncRead + tokio::io::AsyncWrite + Unpin,
+    {
+        match ws.next().await.unwrap().unwrap() {
+            Message::Text(text) => serde_json::from_str::<Batch>(&text).unwrap().batch_id,
+            other => panic!("unexpected message {:?}", other),
+        }
+    }
+
+    fn sender(config: CollectorSettings, window: usize) -> WindowedSender {
+        let endpoints = EndpointPool::new(&config).unwrap();
+        let client = Client::WebSocket(WebSocketClient::new(config, None).unwrap());
+        WindowedSender::new(client, endpoints, window, Duration::from_secs(5), None)
+    }
+
+    #[tokio::test]
+    async fn test_matches_responses_and_resends_unacknowledged() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let endpoint = format!("ws://{}", listener.local_addr().unwrap());
+
+        let collector = tokio::spawn(async move {
+            // Answer out of order, then drop the connection with "b" pending
+            let (stream, _) = listener.accept().await.unwrap();
+            let mut ws = tokio_tungstenite::accept_async(stream).await.unwrap();
+            for expected in ["a", "b", "c"] {
+                assert_eq!(next_batch_id(&mut ws).await, expected);
+            }
+            ws.send(ack("c")).await.unwrap();
+            ws.send(ack("a")).await.unwrap();
+            ws.close(None).await.unwrap();
+
+            // Only the unacknowledged batch comes again
+            let (stream, _) = listener.accept().await.unwrap();
+            let mut ws = tokio_tungstenite::accept_async(stream).await.unwrap();
+            let resent = next_batch_id(&mut ws).await;
+            ws.send(ack(&resent)).await.unwrap();
+            resent
+        });
+
+        let config: CollectorSettings = toml::from_str(&format!("endpoint = {:?}", endpoint)).unwrap();
+        let mut sender = sender(config, 3);
+        sender.connect().await.unwrap();
+
+        for id in ["a", "b", "c"] {
+            sender.send(batch(id)).await.unwrap();
+        }
+        assert!(!sender.has_room());
+
+        assert_eq!(sender.recv().await.unwrap().0.batch_id, "c");
+        assert_eq!(sender.recv().await.unwrap().0.batch_id, "a");
+        assert!(sender.recv().await.is_err());
+
+        sender.recover().await.unwrap();
+        assert_eq!(sender.recv().await.unwrap().0.batch_id, "b");
+        assert_eq!(sender.in_flight(), 0);
+        assert_eq!(collector.await.unwrap(), "b");
+    }
+
+    #[tokio::test]
+    async fn test_recompresses_batch_for_unknown_dictionary() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let endpoint = format!("ws://{}", listener.local_addr().unwrap());
+
+        let collector = tokio::spawn(async move {
+            // A restarted collector that has never seen dictionary 7
+            let (stream, _) = listener.accept().await.unwrap();
+            let mut ws = tokio_tungstenite::accept_async(stream).await.unwrap();
+            let mut received = Vec::new();
+            while let Some(Ok(Message::Text(text))) = ws.next().await {
+                let batch: Batch = serde_json::from_str(&text).unwrap();
+                let known = batch.dictionary_id.is_none();
+                let response = IngestResponse {
+                    batch_id: batch.batch_id.clone(),
+                    status: if known { IngestStatus::Success } else { IngestStatus::Failed },
+                    error_message: None,
+                    received_at: 0,
+                    dictionary: Some(CompressionDictionary { id: 9, data: b"other".to_vec() }),
+                    unknown_dictionary: batch.dictionary_id,
+                };
+                ws.send(Message::Text(serde_json::to_string(&response).unwrap())).await.unwrap();
+                received.push(batch);
+            }
+            received
+        });
+
+        let store = SharedDictionary::default();
+        let config: CollectorSettings = toml::from_str(&format!("endpoint = {:?}", endpoint)).unwrap();
+        let endpoints = EndpointPool::new(&config).unwrap();
+        let client = Client::WebSocket(WebSocketClient::new(config, Some(store.clone())).unwrap());
+        let mut sender = WindowedSender::new(client, endpoints, 1, Duration::from_secs(5), Some(store.clone()));
+        sender.connect().await.unwrap();
+        store.write().insert(CompressionDictionary { id: 7, data: b"hello world ".repeat(64) });
+
+        let uncompressed = UncompressedBatch {
+            batch_id: "a".to_string(),
+            agent_id: "agent".to_string(),
+            hostname: "host".to_string(),
+            timestamp: 0,
+            events: monitoring_common::test_data::generate_log_events(1),
+        };
+        let zstd = ZstdOptions { level: 0, dictionary: store.read().latest() };
+        let batch = Compressor::compress(uncompressed, CompressionType::Zstd, PayloadEncoding::Json, &zstd).unwrap();
+        sender.send(batch).await.unwrap();
+
+        // The batch comes back re-compressed, and the collector's own
+        // dictionary replaces the lost one
+        let (batch, response) = sender.recv().await.unwrap();
+        assert_eq!(response.unknown_dictionary, Some(7));
+        assert_eq!(batch.dictionary_id, None);
+        assert!(!store.read().accepts(7));
+        assert_eq!(store.read().latest().unwrap().id, 9);
+
+        sender.send(batch).await.unwrap();
+        assert_eq!(sender.recv().await.unwrap().1.status, IngestStatus::Success);
+        drop(sender);
+
+        let received = collector.await.unwrap();
+        assert_eq!(received.len(), 2);
+        assert_eq!(Compressor::decompress(&received[1], None).unwrap().len(), 1);
+    }
+
+    #[tokio::test]
+    async fn test_fails_over_to_next_endpoint() {
+        // Nothing listens on the primary once its listener is dropped
+        let primary = {
+            let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+            format!("ws://{}", listener.local_addr().unwrap())
+        };
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let secondary = format!("ws://{}", listener.local_addr().unwrap());
+
+        let collector = tokio::spawn(async move {
+            let (stream, _) = listener.accept().await.unwrap();
+            let mut ws = tokio_tungstenite::accept_async(stream).await.unwrap();
+            let id = next_batch_id(&mut ws).await;
+            ws.send(ack(&id)).await.unwrap();
+        });
+
+        let config: CollectorSettings = toml::from_str(&format!(
+            "endpoint = {:?}\nendpoints = [{:?}]\ncircuit_failure_threshold = 1",
+            primary, secondary
+        ))
+        .unwrap();
+        let mut sender = sender(config, 1);
+        sender.connect().await.unwrap();
+        assert_eq!(sender.current, Some(1));
+
+        sender.send(batch("a")).await.unwrap();
+        assert_eq!(sender.recv().await.unwrap().0.batch_id, "a");
+        collector.await.unwrap();
+
+        // The primary is still down, so the connection stays
+        sender.rebalance().await.unwrap();
+        assert_eq!(sender.current, Some(1));
+    }
+
+    #[tokio::test]
+    async fn test_failover_drops_dictionary_of_previous_collector() {
+        let primary = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let secondary = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let config: CollectorSettings = toml::from_str(&format!(
+            "endpoint = \"ws://{}\"\nendpoints = [\"ws://{}\"]\ncircuit_failure_threshold = 1",
+            primary.local_addr().unwrap(),
+            secondary.local_addr().unwrap()
+        ))
+        .unwrap();
+
+        let collectors = tokio::spawn(async move {
+            // The primary offers a dictionary, then fails with a batch
+            // compressed with it in flight
+            let (stream, _) = primary.accept().await.unwrap();
+            let mut ws = tokio_tungstenite::accept_async(stream).await.unwrap();
+            let id = next_batch_id(&mut ws).await;
+            let mut response = serde_json::from_str::<IngestResponse>(ack(&id).to_text().unwrap()).unwrap();
+            response.dictionary = Some(CompressionDictionary { id: 7, data: b"hello world ".repeat(64) });
+            ws.send(Message::Text(serde_json::to_string(&response).unwrap())).await.unwrap();
+            match ws.next().await.unwrap().unwrap() {
+                Message::Text(text) => assert_eq!(serde_json::from_str::<Batch>(&text).unwrap().dictionary_id, Some(7)),
+                other => panic!("unexpected message {:?}", other),
+            }
+            drop(ws);
+            drop(primary);
+
+            // The secondary never had it
+            let (stream, _) = secondary.accept().await.unwrap();
+            let mut ws = tokio_tungstenite::accept_async(stream).await.unwrap();
+            let batch = match ws.next().await.unwrap().unwrap() {
+                Message::Text(text) => serde_json::from_str::<Batch>(&text).unwrap(),
+                other => panic!("unexpected message {:?}", other),
+            };
+            ws.send(ack(&batch.batch_id)).await.unwrap();
+            batch
+        });
+
+        let store = SharedDictionary::default();
+        let endpoints = EndpointPool::new(&config).unwrap();
+        let client = Client::WebSocket(WebSocketClient::new(config, Some(store.clone())).unwrap());
+        let mut sender = WindowedSender::new(client, endpoints, 1, Duration::from_secs(5), Some(store.clone()));
+        sender.connect().await.unwrap();
+        assert_eq!(sender.current, Some(0));
+
+        sender.send(batch("a")).await.unwrap();
+        sender.recv().await.unwrap();
+        let zstd = ZstdOptions { level: 0, dictionary: store.read().latest() };
+        let uncompressed = UncompressedBatch {
+            batch_id: "b".to_string(),
+            agent_id: "agent".to_string(),
+            hostname: "host".to_string(),
+            timestamp: 0,
+            events: monitoring_common::test_data::generate_log_events(1),
+        };
+        let compressed = Compressor::compress(uncompressed, CompressionType::Zstd, PayloadEncoding::Json, &zstd).unwrap();
+        assert_eq!(compressed.dictionary_id, Some(7));
+        sender.send(compressed).await.unwrap();
+
+        assert!(sender.recv().await.is_err());
+        sender.recover().await.unwrap();
+        assert_eq!(sender.current, Some(1));
+        assert_eq!(sender.recv().await.unwrap().0.batch_id, "b");
+
+        // The resent batch was re-compressed without the primary's
+        // dictionary, and new batches do not use it either
+        let resent = collectors.await.unwrap();
+        assert_eq!(resent.dictionary_id, None);
+        assert_eq!(Compressor::decompress(&resent, None).unwrap().len(), 1);
+        assert!(store.read().latest().is_none());
+    }
+}
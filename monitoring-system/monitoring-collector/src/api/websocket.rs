@@ -1,38 +1,57 @@
-use crate::auth::TokenValidator;
+use crate::auth::{ClientIdentity, TokenValidator};
 use crate::config::CollectorConfig;
+use crate::pipeline::DictionaryStore;
 use crate::processor::BatchProcessor;
 use axum::{
     extract::{
         ws::{Message, WebSocket},
-        Query, State, WebSocketUpgrade,
+        Extension, Query, State, WebSocketUpgrade,
     },
-    http::{header::AUTHORIZATION, HeaderMap, StatusCode},
+    http::{header::AUTHORIZATION, HeaderMap, HeaderValue, StatusCode},
     response::{IntoResponse, Response},
 };
 use futures_util::{SinkExt, StreamExt};
-use monitoring_common::{Batch, IngestResponse, IngestStatus};
-use tracing::{debug, error, info};
+use monitoring_common::encoding::{encodings_header, PAYLOAD_ENCODINGS_HEADER};
+use monitoring_common::{Batch, CompressionType, IngestResponse, IngestStatus, PayloadEncoding};
+use std::sync::Arc;
+use tracing::{debug, error, info, warn};
 
 pub async fn handle_websocket(
     ws: WebSocketUpgrade,
     headers: HeaderMap,
     query: Option<Query<std::collections::HashMap<String, String>>>,
+    identity: Option<Extension<ClientIdentity>>,
     State(config): State<CollectorConfig>,
+    State(dictionaries): State<Arc<DictionaryStore>>,
 ) -> Response {
-    if let Err(status) = authorize_request(&headers, query.as_ref(), &config) {
+    let identity = identity.map(|Extension(identity)| identity);
+    if let Err(status) = authorize_request(&headers, query.as_ref(), identity.as_ref(), &config) {
         return status.into_response();
     }
 
-    ws.on_upgrade(|socket| handle_socket(socket, config))
+    let mut response =
+        ws.on_upgrade(|socket| handle_socket(socket, config, dictionaries, identity));
+
+    // Advertise accepted payload encodings so agents can pick the most compact
+    if let Ok(value) = HeaderValue::from_str(&encodings_header(&PayloadEncoding::ALL)) {
+        response.headers_mut().insert(PAYLOAD_ENCODINGS_HEADER, value);
+    }
+
+    response
 }
 
 fn authorize_request(
     headers: &HeaderMap,
     query: Option<&Query<std::collections::HashMap<String, String>>>,
+    identity: Option<&ClientIdentity>,
     config: &CollectorConfig,
 ) -> Result<(), StatusCode> {
-    if !matches!(config.auth.mode.as_str(), "token" | "hybrid") {
-        return Ok(());
+    match config.auth.mode.as_str() {
+        // The TLS layer has already verified the certificate against the CA
+        "mtls" => return identity.map(|_| ()).ok_or(StatusCode::UNAUTHORIZED),
+        "hybrid" if identity.is_some() => return Ok(()),
+        "token" | "hybrid" => {}
+        _ => return Ok(()),
     }
 
     let secret = config
@@ -73,11 +92,23 @@ fn extract_query_token(
         .map(String::as_str)
         .filter(|token| !token.trim().is_empty())
 }
-async fn handle_socket(socket: WebSocket, config: CollectorConfig) {
+
+async fn handle_socket(
+    socket: WebSocket,
+    config: CollectorConfig,
+    dictionaries: Arc<DictionaryStore>,
+    identity: Option<ClientIdentity>,
+) {
     info!("New WebSocket connection established");
 
     let (mut sender, mut receiver) = socket.split();
-    let processor = BatchProcessor::new(config.processor.clone(), config.storage.clone());
+    let processor = BatchProcessor::new(
+        config.processor.clone(),
+        config.storage.clone(),
+        dictionaries.clone(),
+    );
+    // Dictionary last offered on this connection, so it is sent only once
+    let mut offered = None;
 
     while let Some(msg) = receiver.next().await {
         match msg {
@@ -94,6 +125,8 @@ async fn handle_socket(socket: WebSocket, config: CollectorConfig) {
                             status: IngestStatus::Rejected,
                             error_message: Some(format!("Invalid batch format: {}", e)),
                             received_at: chrono::Utc::now().timestamp_millis(),
+                            dictionary: None,
+                            unknown_dictionary: None,
                         };
 
                         if let Ok(response_json) = serde_json::to_string(&error_response) {
@@ -109,15 +142,74 @@ async fn handle_socket(socket: WebSocket, config: CollectorConfig) {
                     batch_id, batch.event_count
                 );
 
+                // A certificate names exactly one agent
+                if let Some(identity) = identity.as_ref().filter(|identity| identity.0 != batch.agent_id) {
+                    error!(
+                        "Batch {} from agent {} on a connection authenticated as {}",
+                        batch_id, batch.agent_id, identity.0
+                    );
+                    let response = IngestResponse {
+                        batch_id,
+                        status: IngestStatus::Rejected,
+                        error_message: Some("agent_id does not match client certificate".to_string()),
+                        received_at: chrono::Utc::now().timestamp_millis(),
+                        dictionary: None,
+                        unknown_dictionary: None,
+                    };
+                    if let Ok(response_json) = serde_json::to_string(&response) {
+                        let _ = sender.send(Message::Text(response_json)).await;
+                    }
+                    continue;
+                }
+
+                // The agent can send the batch again without the dictionary,
+                // and use the latest one from now on
+                if let Some(id) = batch.dictionary_id.filter(|id| dictionaries.get(*id).is_none()) {
+                    warn!("Batch {} uses unknown compression dictionary {}", batch_id, id);
+                    let latest = dictionaries.latest();
+                    offered = latest.as_ref().map(|latest| latest.id);
+                    let response = IngestResponse {
+                        batch_id,
+                        status: IngestStatus::Failed,
+                        error_message: Some(format!("Unknown compression dictionary {}", id)),
+                        received_at: chrono::Utc::now().timestamp_millis(),
+                        dictionary: latest.map(|latest| (*latest).clone()),
+                        unknown_dictionary: Some(id),
+                    };
+                    if let Ok(response_json) = serde_json::to_string(&response) {
+                        let _ = sender.send(Message::Text(response_json)).await;
+                    }
+                    continue;
+                }
+
+                // Offer the latest dictionary to zstd agents not yet using it
+                let offer = match dictionaries.latest() {
+                    Some(latest)
+                        if batch.compression == CompressionType::Zstd
+                            && batch.dictionary_id != Some(latest.id)
+                            && offered != Some(latest.id) =>
+                    {
+                        Some(latest)
+                    }
+                    _ => None,
+                };
+
                 // Process batch
                 let response = match processor.process(batch).await {
                     Ok(_) => {
                         info!("Successfully processed batch: {}", batch_id);
+                        let dictionary = offer.map(|latest| {
+                            debug!("Offering compression dictionary {}", latest.id);
+                            offered = Some(latest.id);
+                            (*latest).clone()
+                        });
                         IngestResponse {
                             batch_id: batch_id.clone(),
                             status: IngestStatus::Success,
                             error_message: None,
                             received_at: chrono::Utc::now().timestamp_millis(),
+                            dictionary,
+                            unknown_dictionary: None,
                         }
                     }
                     Err(e) => {
@@ -127,6 +219,8 @@ async fn handle_socket(socket: WebSocket, config: CollectorConfig) {
                             status: IngestStatus::Failed,
                             error_message: Some(e.to_string()),
                             received_at: chrono::Utc::now().timestamp_millis(),
+                            dictionary: None,
+                            unknown_dictionary: None,
                         }
                     }
                 };
@@ -169,8 +263,11 @@ async fn handle_socket(socket: WebSocket, config: CollectorConfig) {
 
 #[cfg(test)]
 mod tests {
-    use super::{extract_bearer_token, extract_query_token};
+    use super::{authorize_request, extract_bearer_token, extract_query_token};
+    use crate::auth::ClientIdentity;
+    use crate::config::CollectorConfig;
     use axum::{
+        http::StatusCode,
         extract::Query,
         http::{header::AUTHORIZATION, HeaderMap, HeaderValue},
     };
@@ -201,4 +298,30 @@ mod tests {
 
         assert_eq!(extract_query_token(Some(&query)), Some("abc"));
     }
+
+    #[test]
+    fn mtls_requires_client_identity() {
+        let config = |mode: &str| -> CollectorConfig {
+            toml::from_str(&format!(
+                "[server]\nwebsocket_addr = \"127.0.0.1:0\"\n\
+                 [auth]\nmode = \"{}\"\ntoken_secret = \"secret\"\n\
+                 [storage]\nbackend = \"console\"\n[processor]\n",
+                mode
+            ))
+            .unwrap()
+        };
+        let identity = ClientIdentity(String::from("agent-001"));
+        let headers = HeaderMap::new();
+
+        assert_eq!(
+            authorize_request(&headers, None, None, &config("mtls")),
+            Err(StatusCode::UNAUTHORIZED)
+        );
+        assert!(authorize_request(&headers, None, Some(&identity), &config("mtls")).is_ok());
+        assert!(authorize_request(&headers, None, Some(&identity), &config("hybrid")).is_ok());
+        assert_eq!(
+            authorize_request(&headers, None, None, &config("hybrid")),
+            Err(StatusCode::UNAUTHORIZED)
+        );
+    }
 }
@@ -1,3 +1,5 @@
+pub mod mtls;
 pub mod token;
 
+pub use mtls::ClientIdentity;
 pub use token::TokenValidator;
@@ -0,0 +1,63 @@
+use x509_parser::extensions::GeneralName;
+use x509_parser::prelude::parse_x509_certificate;
+
+/// Agent identity taken from a verified client certificate, attached to
+/// every request on the connection
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ClientIdentity(pub String);
+
+/// Read the agent identity from a DER client certificate. `source` is
+/// "cn" for the subject common name or "san" for the first DNS or URI
+/// subject alternative name.
+pub fn identity_from_cert(der: &[u8], source: &str) -> Option<ClientIdentity> {
+    let (_, cert) = parse_x509_certificate(der).ok()?;
+
+    let identity = match source {
+        "san" => cert
+            .subject_alternative_name()
+            .ok()??
+            .value
+            .general_names
+            .iter()
+            .find_map(|name| match name {
+                GeneralName::DNSName(name) | GeneralName::URI(name) => Some(name.to_string()),
+                _ => None,
+            })?,
+        _ => cert
+            .subject()
+            .iter_common_name()
+            .next()?
+            .as_str()
+            .ok()?
+            .to_string(),
+    };
+
+    Some(ClientIdentity(identity))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rcgen::{Certificate, CertificateParams, DnType, SanType};
+
+    #[test]
+    fn test_identity_from_cn_and_san() {
+        let mut params = CertificateParams::new(vec![]);
+        params.distinguished_name.push(DnType::CommonName, "agent-001");
+        params.subject_alt_names = vec![
+            SanType::IpAddress("10.0.0.1".parse().unwrap()),
+            SanType::DnsName("agent-001.example.com".to_string()),
+        ];
+        let der = Certificate::from_params(params).unwrap().serialize_der().unwrap();
+
+        assert_eq!(
+            identity_from_cert(&der, "cn"),
+            Some(ClientIdentity("agent-001".to_string()))
+        );
+        assert_eq!(
+            identity_from_cert(&der, "san"),
+            Some(ClientIdentity("agent-001.example.com".to_string()))
+        );
+        assert_eq!(identity_from_cert(b"not a certificate", "cn"), None);
+    }
+}
@@ -8,15 +8,22 @@ pub struct CollectorConfig {
     pub auth: AuthSettings,
     pub storage: StorageSettings,
     pub processor: ProcessorSettings,
+    #[serde(default)]
+    pub compression: CompressionSettings,
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct ServerSettings {
     pub websocket_addr: String,
     pub grpc_addr: Option<String>,
+    /// Serve over TLS with this certificate chain and key
     pub tls_cert: Option<String>,
     pub tls_key: Option<String>,
+    /// CA that agent client certificates are verified against
     pub mtls_ca_cert: Option<String>,
+    /// How often certificate files are checked for changes
+    #[serde(default = "default_tls_reload_interval")]
+    pub tls_reload_interval_secs: u64,
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
@@ -24,6 +31,10 @@ pub struct AuthSettings {
     #[serde(default = "default_auth_mode")]
     pub mode: String, // "token", "mtls", or "hybrid"
     pub token_secret: Option<String>,
+    /// Client certificate field that names the agent: "cn" (subject common
+    /// name) or "san" (first DNS or URI subject alternative name)
+    #[serde(default = "default_mtls_identity")]
+    pub mtls_identity: String,
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
@@ -43,10 +54,51 @@ pub struct ProcessorSettings {
     pub batch_size: usize,
 }
 
+/// Zstd dictionary training and distribution
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct CompressionSettings {
+    /// Train dictionaries from recent batches and offer them to zstd agents
+    #[serde(default)]
+    pub dictionary_enabled: bool,
+    #[serde(default = "default_dictionary_size")]
+    pub dictionary_size: usize,
+    /// Batch payloads kept for the next training round
+    #[serde(default = "default_dictionary_samples")]
+    pub dictionary_samples: usize,
+    #[serde(default = "default_dictionary_interval")]
+    pub dictionary_interval_secs: u64,
+    /// Older dictionaries kept for batches still compressed with them
+    #[serde(default = "default_dictionary_retain")]
+    pub dictionary_retain: usize,
+    /// Where dictionaries are persisted across restarts
+    pub dictionary_dir: Option<String>,
+}
+
+impl Default for CompressionSettings {
+    fn default() -> Self {
+        Self {
+            dictionary_enabled: false,
+            dictionary_size: default_dictionary_size(),
+            dictionary_samples: default_dictionary_samples(),
+            dictionary_interval_secs: default_dictionary_interval(),
+            dictionary_retain: default_dictionary_retain(),
+            dictionary_dir: None,
+        }
+    }
+}
+
 fn default_auth_mode() -> String {
     "token".to_string()
 }
 
+fn default_mtls_identity() -> String {
+    "cn".to_string()
+}
+
+fn default_tls_reload_interval() -> u64 {
+    30
+}
+
 fn default_workers() -> usize {
     4
 }
@@ -55,6 +107,22 @@ fn default_batch_size() -> usize {
     1000
 }
 
+fn default_dictionary_size() -> usize {
+    112 * 1024
+}
+
+fn default_dictionary_samples() -> usize {
+    2000
+}
+
+fn default_dictionary_interval() -> u64 {
+    3600
+}
+
+fn default_dictionary_retain() -> usize {
+    4
+}
+
 impl CollectorConfig {
     pub fn load(path: &Path) -> Result<Self> {
         let config_str = std::fs::read_to_string(path)
@@ -84,6 +152,23 @@ impl CollectorConfig {
             );
         }
 
+        let server = &config.server;
+        if server.tls_cert.is_some() != server.tls_key.is_some() {
+            anyhow::bail!("server.tls_cert and server.tls_key must be set together");
+        }
+        if server.mtls_ca_cert.is_some() && server.tls_cert.is_none() {
+            anyhow::bail!("server.mtls_ca_cert needs server.tls_cert and server.tls_key");
+        }
+        if matches!(config.auth.mode.as_str(), "mtls" | "hybrid") && server.mtls_ca_cert.is_none() {
+            anyhow::bail!(
+                "server.mtls_ca_cert is required when auth.mode is '{}'",
+                config.auth.mode
+            );
+        }
+        if !matches!(config.auth.mtls_identity.as_str(), "cn" | "san") {
+            anyhow::bail!("auth.mtls_identity must be 'cn' or 'san'");
+        }
+
         Ok(config)
     }
 }
@@ -1,8 +1,9 @@
 use anyhow::Result;
-use axum::{routing::get, Router};
+use axum::{extract::FromRef, routing::get, Router};
 use clap::Parser;
 use std::net::SocketAddr;
 use std::path::PathBuf;
+use std::sync::Arc;
 use tracing::{info, Level};
 use tracing_subscriber::{layer::SubscriberExt, util::SubscriberInitExt};
 
@@ -12,8 +13,18 @@ mod auth;
 mod pipeline;
 mod processor;
 mod storage;
+mod tls;
 
 use config::CollectorConfig;
+use pipeline::DictionaryStore;
+use tls::ServerTls;
+
+/// State shared by request handlers
+#[derive(Clone, FromRef)]
+struct AppState {
+    config: CollectorConfig,
+    dictionaries: Arc<DictionaryStore>,
+}
 
 #[derive(Parser)]
 #[command(
@@ -46,8 +57,14 @@ async fn main() -> Result<()> {
     info!("Starting monitoring collector");
     info!("WebSocket endpoint: {}", config.server.websocket_addr);
     
+    let dictionaries = Arc::new(DictionaryStore::open(config.compression.clone())?);
+    tokio::spawn(dictionaries.clone().run_training());
+
     // Build router
-    let app = build_router(config.clone());
+    let app = build_router(AppState {
+        config: config.clone(),
+        dictionaries,
+    });
 
     // Parse address
     let addr: SocketAddr = config.server.websocket_addr.parse()?;
@@ -56,17 +73,24 @@ async fn main() -> Result<()> {
 
     // Start server
     let listener = tokio::net::TcpListener::bind(&addr).await?;
-    axum::serve(listener, app)
-        .await?;
+    match ServerTls::from_config(&config)? {
+        Some(tls) => {
+            info!("TLS enabled (auth mode: {})", config.auth.mode);
+            let tls = Arc::new(tls);
+            tokio::spawn(tls.clone().run_reload());
+            tls.serve(listener, app).await?;
+        }
+        None => axum::serve(listener, app).await?,
+    }
 
     Ok(())
 }
 
-fn build_router(config: CollectorConfig) -> Router {
+fn build_router(state: AppState) -> Router {
     Router::new()
         .route("/health", get(health_check))
         .route("/ingest", axum::routing::any(api::websocket::handle_websocket))
-        .with_state(config)
+        .with_state(state)
         .layer(
             tower_http::trace::TraceLayer::new_for_http()
                 .make_span_with(tower_http::trace::DefaultMakeSpan::new()
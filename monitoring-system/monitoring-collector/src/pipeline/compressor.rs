@@ -1,14 +1,15 @@
 // Re-use compressor logic from agent
 // This is a simplified version for the collector side
 
-use anyhow::Result;
-use monitoring_common::{Batch, CompressionType, Event};
+use anyhow::{Context, Result};
+use monitoring_common::{Batch, CompressionDictionary, CompressionType};
 use sha2::{Digest, Sha256};
 
 pub struct Compressor;
 
 impl Compressor {
-    pub fn decompress(batch: &Batch) -> Result<Vec<Event>> {
+    /// Verify and decompress a batch's payload without decoding its events
+    pub fn decompress_payload(batch: &Batch, dictionary: Option<&CompressionDictionary>) -> Result<Vec<u8>> {
         let decompressed_data = match batch.compression {
             CompressionType::None => batch.compressed_data.clone(),
             CompressionType::Snappy => {
@@ -30,6 +31,22 @@ impl Compressor {
                 decoder.read_to_end(&mut decompressed)?;
                 decompressed
             }
+            CompressionType::Zstd => match batch.dictionary_id {
+                Some(id) => {
+                    use std::io::Read;
+                    let dictionary = dictionary
+                        .filter(|dictionary| dictionary.id == id)
+                        .with_context(|| format!("Unknown compression dictionary {}", id))?;
+                    let mut decoder = zstd::stream::read::Decoder::with_dictionary(
+                        &batch.compressed_data[..],
+                        &dictionary.data,
+                    )?;
+                    let mut decompressed = Vec::new();
+                    decoder.read_to_end(&mut decompressed)?;
+                    decompressed
+                }
+                None => zstd::stream::decode_all(&batch.compressed_data[..])?,
+            },
         };
 
         // Verify checksum
@@ -41,8 +58,6 @@ impl Compressor {
             anyhow::bail!("Checksum mismatch: expected {}, got {}", batch.checksum, checksum);
         }
 
-        // Deserialize events
-        let events = serde_json::from_slice(&decompressed_data)?;
-        Ok(events)
+        Ok(decompressed_data)
     }
 }
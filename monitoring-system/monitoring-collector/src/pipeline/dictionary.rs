@@ -0,0 +1,282 @@
+use crate::config::CompressionSettings;
+use anyhow::{Context, Result};
+use monitoring_common::CompressionDictionary;
+use sha2::{Digest, Sha256};
+use std::collections::BTreeMap;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex, RwLock};
+use std::time::Duration;
+use tracing::{debug, info, warn};
+
+/// Longest prefix of a batch payload kept as a training sample
+const MAX_SAMPLE_BYTES: usize = 64 * 1024;
+/// Fewest samples worth training a dictionary on
+const MIN_SAMPLES: usize = 100;
+
+/// Zstd dictionaries trained from recent batch payloads. The newest one is
+/// offered to agents; a few older ones are kept so batches already
+/// compressed with them, in flight or on an agent's disk, still decompress.
+///
+/// IDs are derived from the dictionary contents, so one never names a
+/// different dictionary after a restart or on another collector.
+pub struct DictionaryStore {
+    config: CompressionSettings,
+    /// By install order, oldest first
+    dictionaries: RwLock<BTreeMap<u64, Arc<CompressionDictionary>>>,
+    samples: Mutex<Samples>,
+}
+
+#[derive(Default)]
+struct Samples {
+    payloads: Vec<Vec<u8>>,
+    /// Total samples offered, used to overwrite the oldest once full
+    seen: usize,
+}
+
+impl DictionaryStore {
+    /// Create the store, loading dictionaries persisted by a previous run
+    pub fn open(config: CompressionSettings) -> Result<Self> {
+        let store = Self {
+            config,
+            dictionaries: RwLock::new(BTreeMap::new()),
+            samples: Mutex::new(Samples::default()),
+        };
+
+        if let Some(dir) = store.dir() {
+            std::fs::create_dir_all(&dir)
+                .with_context(|| format!("Failed to create dictionary directory {:?}", dir))?;
+            store.load(&dir)?;
+        }
+
+        Ok(store)
+    }
+
+    pub fn get(&self, id: u32) -> Option<Arc<CompressionDictionary>> {
+        self.dictionaries
+            .read()
+            .unwrap()
+            .values()
+            .find(|dictionary| dictionary.id == id)
+            .cloned()
+    }
+
+    /// Dictionary offered to agents, if one has been trained
+    pub fn latest(&self) -> Option<Arc<CompressionDictionary>> {
+        if !self.config.dictionary_enabled {
+            return None;
+        }
+        self.dictionaries
+            .read()
+            .unwrap()
+            .last_key_value()
+            .map(|(_, dictionary)| dictionary.clone())
+    }
+
+    /// Keep a decompressed batch payload for the next training round
+    pub fn add_sample(&self, payload: &[u8]) {
+        if !self.config.dictionary_enabled || self.config.dictionary_samples == 0 {
+            return;
+        }
+
+        let sample = payload[..payload.len().min(MAX_SAMPLE_BYTES)].to_vec();
+        let mut samples = self.samples.lock().unwrap();
+        if samples.payloads.len() < self.config.dictionary_samples {
+            samples.payloads.push(sample);
+        } else {
+            let index = samples.seen % self.config.dictionary_samples;
+            samples.payloads[index] = sample;
+        }
+        samples.seen += 1;
+    }
+
+    /// Periodically train a new dictionary from the collected samples
+    pub async fn run_training(self: Arc<Self>) {
+        if !self.config.dictionary_enabled {
+            return;
+        }
+
+        let mut interval =
+            tokio::time::interval(Duration::from_secs(self.config.dictionary_interval_secs));
+        // The first tick completes immediately, before any samples exist
+        interval.tick().await;
+
+        loop {
+            interval.tick().await;
+
+            let samples = match self.take_samples() {
+                Some(samples) => samples,
+                None => {
+                    debug!("Not enough samples to train a compression dictionary yet");
+                    continue;
+                }
+            };
+
+            let size = self.config.dictionary_size;
+            let trained = tokio::task::spawn_blocking(move || {
+                zstd::dict::from_samples(&samples, size)
+            })
+            .await;
+
+            match trained {
+                Ok(Ok(data)) => match self.install(data) {
+                    Ok(dictionary) => info!(
+                        "Trained compression dictionary {} ({} bytes)",
+                        dictionary.id,
+                        dictionary.data.len()
+                    ),
+                    Err(e) => warn!("Failed to store compression dictionary: {}", e),
+                },
+                Ok(Err(e)) => warn!("Failed to train compression dictionary: {}", e),
+                Err(e) => warn!("Compression dictionary training panicked: {}", e),
+            }
+        }
+    }
+
+    /// Samples for a training round, leaving them in place if there are too few
+    fn take_samples(&self) -> Option<Vec<Vec<u8>>> {
+        let mut samples = self.samples.lock().unwrap();
+        if samples.payloads.len() < MIN_SAMPLES {
+            return None;
+        }
+        samples.seen = 0;
+        Some(std::mem::take(&mut samples.payloads))
+    }
+
+    /// Add a dictionary and make it the one offered
+    pub fn install(&self, data: Vec<u8>) -> Result<Arc<CompressionDictionary>> {
+        let mut dictionaries = self.dictionaries.write().unwrap();
+        let dictionary = Arc::new(CompressionDictionary { id: dictionary_id(&data), data });
+        let existing: Vec<u64> = dictionaries
+            .iter()
+            .filter(|(_, existing)| existing.id == dictionary.id)
+            .map(|(seq, _)| *seq)
+            .collect();
+        let seq = dictionaries.keys().next_back().map_or(1, |seq| seq + 1);
+
+        if let Some(dir) = self.dir() {
+            std::fs::write(dictionary_path(&dir, seq), &dictionary.data)
+                .with_context(|| format!("Failed to persist compression dictionary {}", dictionary.id))?;
+        }
+        dictionaries.insert(seq, dictionary.clone());
+
+        // The same contents trained again move to the front
+        for old in existing {
+            dictionaries.remove(&old);
+            self.remove_file(old);
+        }
+
+        // Retire the oldest beyond the retention limit
+        while dictionaries.len() > self.config.dictionary_retain.max(1) {
+            let (old, _) = dictionaries.pop_first().unwrap();
+            self.remove_file(old);
+        }
+
+        Ok(dictionary)
+    }
+
+    fn remove_file(&self, seq: u64) {
+        if let Some(dir) = self.dir() {
+            if let Err(e) = std::fs::remove_file(dictionary_path(&dir, seq)) {
+                warn!("Failed to remove compression dictionary file {}: {}", seq, e);
+            }
+        }
+    }
+
+    fn load(&self, dir: &Path) -> Result<()> {
+        let mut dictionaries = self.dictionaries.write().unwrap();
+        for entry in std::fs::read_dir(dir)? {
+            let path = entry?.path();
+            if path.extension().and_then(|ext| ext.to_str()) != Some("dict") {
+                continue;
+            }
+            let seq = match path.file_stem().and_then(|stem| stem.to_str()?.parse().ok()) {
+                Some(seq) => seq,
+                None => continue,
+            };
+            let data = std::fs::read(&path)
+                .with_context(|| format!("Failed to read compression dictionary {:?}", path))?;
+            let id = dictionary_id(&data);
+            dictionaries.insert(seq, Arc::new(CompressionDictionary { id, data }));
+        }
+
+        if !dictionaries.is_empty() {
+            info!("Loaded {} compression dictionaries from {:?}", dictionaries.len(), dir);
+        }
+        Ok(())
+    }
+
+    fn dir(&self) -> Option<PathBuf> {
+        self.config.dictionary_dir.as_ref().map(PathBuf::from)
+    }
+}
+
+/// Files are numbered by install order, which decides the one offered
+fn dictionary_path(dir: &Path, seq: u64) -> PathBuf {
+    dir.join(format!("{}.dict", seq))
+}
+
+/// First four bytes of the SHA-256 of the contents
+fn dictionary_id(data: &[u8]) -> u32 {
+    let digest = Sha256::digest(data);
+    u32::from_be_bytes([digest[0], digest[1], digest[2], digest[3]])
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn settings(dir: Option<&Path>) -> CompressionSettings {
+        CompressionSettings {
+            dictionary_enabled: true,
+            dictionary_retain: 2,
+            dictionary_dir: dir.map(|dir| dir.to_string_lossy().into_owned()),
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn test_retains_and_reloads_dictionaries() {
+        let dir = std::env::temp_dir().join(format!("monitoring-dict-{}", uuid::Uuid::new_v4()));
+
+        let ids: Vec<u32> = {
+            let store = DictionaryStore::open(settings(Some(&dir))).unwrap();
+            let ids = [b"one", b"two", b"six"].map(|data| store.install(data.to_vec()).unwrap().id);
+
+            // Only the two newest are kept
+            assert!(store.get(ids[0]).is_none());
+            assert_eq!(store.get(ids[1]).unwrap().data, b"two");
+            assert_eq!(store.latest().unwrap().id, ids[2]);
+            ids.to_vec()
+        };
+
+        // A restarted collector gives the same dictionaries the same IDs,
+        // and never reuses one for other contents
+        let store = DictionaryStore::open(settings(Some(&dir))).unwrap();
+        assert!(store.get(ids[0]).is_none());
+        assert_eq!(store.get(ids[1]).unwrap().data, b"two");
+        assert_eq!(store.latest().unwrap().data, b"six");
+        let ten = store.install(b"ten".to_vec()).unwrap().id;
+        assert!(!ids.contains(&ten));
+        assert_eq!(DictionaryStore::open(settings(None)).unwrap().install(b"six".to_vec()).unwrap().id, ids[2]);
+
+        // Training the same contents again makes them the latest once more
+        store.install(b"two".to_vec()).unwrap();
+        assert_eq!(store.latest().unwrap().id, ids[1]);
+        assert_eq!(store.get(ten).unwrap().data, b"ten");
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_samples_wait_for_minimum() {
+        let store = DictionaryStore::open(settings(None)).unwrap();
+        for i in 0..MIN_SAMPLES - 1 {
+            store.add_sample(format!("sample {}", i).as_bytes());
+        }
+        assert!(store.take_samples().is_none());
+
+        store.add_sample(b"last sample");
+        assert_eq!(store.take_samples().unwrap().len(), MIN_SAMPLES);
+        assert!(store.take_samples().is_none());
+    }
+}
@@ -2,4 +2,6 @@
 pub use monitoring_common::*;
 
 mod compressor;
+mod dictionary;
 pub use compressor::Compressor;
+pub use dictionary::DictionaryStore;
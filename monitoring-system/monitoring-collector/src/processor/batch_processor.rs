@@ -1,22 +1,30 @@
 use crate::config::{ProcessorSettings, StorageSettings};
-use crate::pipeline::Compressor;
+use crate::pipeline::{Compressor, DictionaryStore};
 use crate::storage::StorageBackend;
-use anyhow::Result;
+use anyhow::{Context, Result};
+use monitoring_common::encoding::decode_events;
 use monitoring_common::{Batch, Event};
+use std::sync::Arc;
 use tracing::{debug, info};
 
 pub struct BatchProcessor {
     config: ProcessorSettings,
     storage: Box<dyn StorageBackend + Send + Sync>,
+    dictionaries: Arc<DictionaryStore>,
 }
 
 impl BatchProcessor {
-    pub fn new(config: ProcessorSettings, storage_config: StorageSettings) -> Self {
+    pub fn new(
+        config: ProcessorSettings,
+        storage_config: StorageSettings,
+        dictionaries: Arc<DictionaryStore>,
+    ) -> Self {
         let storage = crate::storage::create_backend(storage_config);
         
         Self {
             config,
             storage,
+            dictionaries,
         }
     }
 
@@ -24,7 +32,17 @@ impl BatchProcessor {
         debug!("Processing batch: {}", batch.batch_id);
 
         // Decompress batch
-        let events = Compressor::decompress(&batch)?;
+        let dictionary = match batch.dictionary_id {
+            Some(id) => Some(
+                self.dictionaries
+                    .get(id)
+                    .with_context(|| format!("Unknown compression dictionary {}", id))?,
+            ),
+            None => None,
+        };
+        let payload = Compressor::decompress_payload(&batch, dictionary.as_deref())?;
+        self.dictionaries.add_sample(&payload);
+        let events = decode_events(&payload, batch.encoding)?;
         
         info!("Decompressed {} events from batch {}", events.len(), batch.batch_id);
 
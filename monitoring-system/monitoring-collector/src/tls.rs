@@ -0,0 +1,260 @@
+use crate::auth::mtls::identity_from_cert;
+use crate::config::CollectorConfig;
+use anyhow::{Context, Result};
+use axum::{extract::Request, Router};
+use hyper_util::rt::{TokioExecutor, TokioIo};
+use hyper_util::server::conn::auto;
+use hyper_util::service::TowerToHyperService;
+use rustls::pki_types::{CertificateDer, PrivateKeyDer};
+use rustls::server::WebPkiClientVerifier;
+use rustls::{RootCertStore, ServerConfig};
+use std::io::BufReader;
+use std::path::PathBuf;
+use std::sync::{Arc, RwLock};
+use std::time::{Duration, SystemTime};
+use tokio::net::TcpListener;
+use tokio_rustls::TlsAcceptor;
+use tower::ServiceExt;
+use tracing::{debug, info, warn};
+
+/// Serves the router over rustls, verifying agent client certificates
+/// against the configured CA. Certificate files are polled and the server
+/// configuration swapped when they change, so rotation needs no restart.
+pub struct ServerTls {
+    cert: PathBuf,
+    key: PathBuf,
+    ca_cert: Option<PathBuf>,
+    /// Reject handshakes without a client certificate ("mtls" mode)
+    require_client_cert: bool,
+    identity_source: String,
+    reload_interval: Duration,
+    current: RwLock<Arc<ServerConfig>>,
+}
+
+impl ServerTls {
+    /// TLS settings from the config, or `None` to serve plain HTTP
+    pub fn from_config(config: &CollectorConfig) -> Result<Option<Self>> {
+        let server = &config.server;
+        let (cert, key) = match (&server.tls_cert, &server.tls_key) {
+            (Some(cert), Some(key)) => (PathBuf::from(cert), PathBuf::from(key)),
+            _ => return Ok(None),
+        };
+
+        let ca_cert = server.mtls_ca_cert.as_ref().map(PathBuf::from);
+        let require_client_cert = config.auth.mode == "mtls";
+        let current = build(&cert, &key, ca_cert.as_ref(), require_client_cert)?;
+
+        Ok(Some(Self {
+            cert,
+            key,
+            ca_cert,
+            require_client_cert,
+            identity_source: config.auth.mtls_identity.clone(),
+            reload_interval: Duration::from_secs(server.tls_reload_interval_secs.max(1)),
+            current: RwLock::new(Arc::new(current)),
+        }))
+    }
+
+    fn config(&self) -> Arc<ServerConfig> {
+        self.current.read().unwrap().clone()
+    }
+
+    /// Rebuild the server configuration whenever a certificate file changes.
+    /// A failed rebuild keeps the previous configuration, since rotation may
+    /// have replaced only some of the files so far.
+    pub async fn run_reload(self: Arc<Self>) {
+        let mut loaded = self.modified();
+        let mut interval = tokio::time::interval(self.reload_interval);
+        interval.tick().await;
+
+        loop {
+            interval.tick().await;
+            let modified = self.modified();
+            if modified == loaded {
+                continue;
+            }
+
+            match self.reload() {
+                Ok(()) => {
+                    info!("Reloaded TLS certificates");
+                    loaded = modified;
+                }
+                Err(e) => warn!("Failed to reload TLS certificates, keeping the previous ones: {:#}", e),
+            }
+        }
+    }
+
+    fn reload(&self) -> Result<()> {
+        let config = build(&self.cert, &self.key, self.ca_cert.as_ref(), self.require_client_cert)?;
+        *self.current.write().unwrap() = Arc::new(config);
+        Ok(())
+    }
+
+    fn modified(&self) -> Vec<Option<SystemTime>> {
+        [Some(&self.cert), Some(&self.key), self.ca_cert.as_ref()]
+            .into_iter()
+            .flatten()
+            .map(|path| std::fs::metadata(path).and_then(|meta| meta.modified()).ok())
+            .collect()
+    }
+
+    /// Accept TLS connections and serve the router on each. The identity
+    /// from a verified client certificate is added to every request.
+    pub async fn serve(self: Arc<Self>, listener: TcpListener, router: Router) -> Result<()> {
+        loop {
+            let (stream, peer) = match listener.accept().await {
+                Ok(accepted) => accepted,
+                Err(e) => {
+                    warn!("Failed to accept connection: {}", e);
+                    continue;
+                }
+            };
+
+            let acceptor = TlsAcceptor::from(self.config());
+            let tls = self.clone();
+            let router = router.clone();
+
+            tokio::spawn(async move {
+                let stream = match acceptor.accept(stream).await {
+                    Ok(stream) => stream,
+                    Err(e) => {
+                        debug!("TLS handshake with {} failed: {}", peer, e);
+                        return;
+                    }
+                };
+
+                let identity = stream
+                    .get_ref()
+                    .1
+                    .peer_certificates()
+                    .and_then(|certs| certs.first())
+                    .and_then(|cert| identity_from_cert(cert, &tls.identity_source));
+                match &identity {
+                    Some(identity) => debug!("Client {} authenticated as {}", peer, identity.0),
+                    None if tls.ca_cert.is_some() => debug!("Client {} sent no usable certificate", peer),
+                    None => {}
+                }
+
+                let service = router.map_request(move |mut request: Request<hyper::body::Incoming>| {
+                    if let Some(identity) = &identity {
+                        request.extensions_mut().insert(identity.clone());
+                    }
+                    request
+                });
+
+                if let Err(e) = auto::Builder::new(TokioExecutor::new())
+                    .serve_connection_with_upgrades(TokioIo::new(stream), TowerToHyperService::new(service))
+                    .await
+                {
+                    debug!("Connection from {} ended with error: {}", peer, e);
+                }
+            });
+        }
+    }
+}
+
+fn build(
+    cert: &PathBuf,
+    key: &PathBuf,
+    ca_cert: Option<&PathBuf>,
+    require_client_cert: bool,
+) -> Result<ServerConfig> {
+    let builder = ServerConfig::builder();
+    let builder = match ca_cert {
+        Some(path) => {
+            let mut roots = RootCertStore::empty();
+            for cert in read_certs(path)? {
+                roots
+                    .add(cert)
+                    .with_context(|| format!("Invalid CA certificate in {:?}", path))?;
+            }
+
+            let verifier = WebPkiClientVerifier::builder(Arc::new(roots));
+            let verifier = if require_client_cert {
+                verifier
+            } else {
+                verifier.allow_unauthenticated()
+            };
+            builder.with_client_cert_verifier(
+                verifier
+                    .build()
+                    .context("Failed to build client certificate verifier")?,
+            )
+        }
+        None => builder.with_no_client_auth(),
+    };
+
+    let mut config = builder
+        .with_single_cert(read_certs(cert)?, read_key(key)?)
+        .context("Invalid server certificate or key")?;
+    config.alpn_protocols = vec![b"h2".to_vec(), b"http/1.1".to_vec()];
+    Ok(config)
+}
+
+fn read_certs(path: &PathBuf) -> Result<Vec<CertificateDer<'static>>> {
+    let file = std::fs::File::open(path).with_context(|| format!("Failed to open {:?}", path))?;
+    let certs = rustls_pemfile::certs(&mut BufReader::new(file))
+        .collect::<Result<Vec<_>, _>>()
+        .with_context(|| format!("Failed to parse certificates in {:?}", path))?;
+    if certs.is_empty() {
+        anyhow::bail!("No certificates in {:?}", path);
+    }
+    Ok(certs)
+}
+
+fn read_key(path: &PathBuf) -> Result<PrivateKeyDer<'static>> {
+    let file = std::fs::File::open(path).with_context(|| format!("Failed to open {:?}", path))?;
+    rustls_pemfile::private_key(&mut BufReader::new(file))
+        .with_context(|| format!("Failed to parse private key in {:?}", path))?
+        .with_context(|| format!("No private key in {:?}", path))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::path::Path;
+
+    fn write_server(dir: &Path) {
+        let cert = rcgen::generate_simple_self_signed(vec!["localhost".to_string()]).unwrap();
+        std::fs::write(dir.join("server.pem"), cert.serialize_pem().unwrap()).unwrap();
+        std::fs::write(dir.join("server-key.pem"), cert.serialize_private_key_pem()).unwrap();
+        std::fs::write(dir.join("ca.pem"), cert.serialize_pem().unwrap()).unwrap();
+    }
+
+    #[test]
+    fn test_reload_keeps_previous_config_on_failure() {
+        let dir = std::env::temp_dir().join(format!("collector-tls-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        write_server(&dir);
+
+        let mut config: CollectorConfig = toml::from_str(
+            r#"
+            [server]
+            websocket_addr = "127.0.0.1:0"
+            [auth]
+            mode = "mtls"
+            [processor]
+            [storage]
+            backend = "console"
+            "#,
+        )
+        .unwrap();
+        config.server.tls_cert = Some(dir.join("server.pem").to_string_lossy().into_owned());
+        config.server.tls_key = Some(dir.join("server-key.pem").to_string_lossy().into_owned());
+        config.server.mtls_ca_cert = Some(dir.join("ca.pem").to_string_lossy().into_owned());
+
+        let tls = ServerTls::from_config(&config).unwrap().unwrap();
+        let first = tls.config();
+
+        write_server(&dir);
+        tls.reload().unwrap();
+        let rotated = tls.config();
+        assert!(!Arc::ptr_eq(&first, &rotated));
+
+        std::fs::write(dir.join("server-key.pem"), "").unwrap();
+        assert!(tls.reload().is_err());
+        assert!(Arc::ptr_eq(&rotated, &tls.config()));
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,77 @@
+use crate::error::{MonitoringError, Result};
+use crate::models::{Event, PayloadEncoding};
+
+/// Handshake header listing the payload encodings a collector accepts,
+/// most preferred first
+pub const PAYLOAD_ENCODINGS_HEADER: &str = "x-payload-encodings";
+
+/// Serialize events for a batch payload
+pub fn encode_events(events: &[Event], encoding: PayloadEncoding) -> Result<Vec<u8>> {
+    match encoding {
+        PayloadEncoding::Json => Ok(serde_json::to_vec(events)?),
+        // Named fields are required by the internally tagged `Event` enum
+        PayloadEncoding::MessagePack => {
+            rmp_serde::to_vec_named(events).map_err(|e| MonitoringError::Encoding(e.to_string()))
+        }
+    }
+}
+
+/// Deserialize the events of a batch payload
+pub fn decode_events(data: &[u8], encoding: PayloadEncoding) -> Result<Vec<Event>> {
+    match encoding {
+        PayloadEncoding::Json => Ok(serde_json::from_slice(data)?),
+        PayloadEncoding::MessagePack => {
+            rmp_serde::from_slice(data).map_err(|e| MonitoringError::Encoding(e.to_string()))
+        }
+    }
+}
+
+/// Value for `PAYLOAD_ENCODINGS_HEADER`
+pub fn encodings_header(encodings: &[PayloadEncoding]) -> String {
+    encodings
+        .iter()
+        .map(PayloadEncoding::as_str)
+        .collect::<Vec<_>>()
+        .join(", ")
+}
+
+/// Parse a `PAYLOAD_ENCODINGS_HEADER` value, skipping unknown names
+pub fn parse_encodings_header(value: &str) -> Vec<PayloadEncoding> {
+    value.split(',').filter_map(PayloadEncoding::parse).collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_data::{generate_log_events, generate_metric_events, generate_traffic_events};
+
+    #[test]
+    fn test_round_trip_all_encodings() {
+        let mut events = generate_log_events(5);
+        events.extend(generate_metric_events(5));
+        events.extend(generate_traffic_events(5));
+
+        for encoding in PayloadEncoding::ALL {
+            let data = encode_events(&events, encoding).unwrap();
+            let decoded = decode_events(&data, encoding).unwrap();
+            assert_eq!(
+                serde_json::to_value(&decoded).unwrap(),
+                serde_json::to_value(&events).unwrap(),
+                "{:?}",
+                encoding
+            );
+        }
+
+        let json = encode_events(&events, PayloadEncoding::Json).unwrap();
+        let msgpack = encode_events(&events, PayloadEncoding::MessagePack).unwrap();
+        assert!(msgpack.len() < json.len());
+    }
+
+    #[test]
+    fn test_encodings_header() {
+        let header = encodings_header(&PayloadEncoding::ALL);
+        assert_eq!(header, "msgpack, json");
+        assert_eq!(parse_encodings_header(&header), PayloadEncoding::ALL.to_vec());
+        assert_eq!(parse_encodings_header("zstd-proto, json"), vec![PayloadEncoding::Json]);
+    }
+}
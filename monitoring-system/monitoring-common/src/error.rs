@@ -8,6 +8,9 @@ pub enum MonitoringError {
     #[error("Serialization error: {0}")]
     Serialization(#[from] serde_json::Error),
     
+    #[error("Encoding error: {0}")]
+    Encoding(String),
+    
     #[error("Transport error: {0}")]
     Transport(String),
     
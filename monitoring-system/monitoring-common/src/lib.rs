@@ -1,6 +1,7 @@
 pub mod models;
 pub mod proto;
 pub mod error;
+pub mod encoding;
 pub mod test_data;
 
 pub use models::*;
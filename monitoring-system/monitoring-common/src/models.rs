@@ -64,6 +64,13 @@ pub struct TrafficEvent {
     pub bytes: u64,
     pub packets: u64,
     pub metadata: HashMap<String, String>,
+    /// Fraction of flows kept by the agent's sampler (1.0 = unsampled)
+    #[serde(default = "default_sample_rate")]
+    pub sample_rate: f64,
+}
+
+fn default_sample_rate() -> f64 {
+    1.0
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
@@ -74,6 +81,8 @@ pub enum Protocol {
     TCP,
     UDP,
     ICMP,
+    DNS,
+    TLS,
     Other(String),
 }
 
@@ -86,6 +95,12 @@ pub struct Batch {
     pub timestamp: i64,
     pub event_count: usize,
     pub compression: CompressionType,
+    /// Serialization of the events before compression
+    #[serde(default)]
+    pub encoding: PayloadEncoding,
+    /// Collector-issued dictionary the payload was zstd-compressed with
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub dictionary_id: Option<u32>,
     pub compressed_data: Vec<u8>,
     pub checksum: String,
 }
@@ -97,6 +112,44 @@ pub enum CompressionType {
     Snappy,
     Lz4,
     Gzip,
+    Zstd,
+}
+
+/// Zstandard dictionary trained by the collector and shared with agents
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
+pub struct CompressionDictionary {
+    pub id: u32,
+    pub data: Vec<u8>,
+}
+
+/// How events are serialized inside a batch payload
+#[derive(Debug, Clone, Copy, Default, Serialize, Deserialize, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum PayloadEncoding {
+    #[default]
+    Json,
+    #[serde(rename = "msgpack")]
+    MessagePack,
+}
+
+impl PayloadEncoding {
+    /// Every encoding this build can read and write
+    pub const ALL: [PayloadEncoding; 2] = [PayloadEncoding::MessagePack, PayloadEncoding::Json];
+
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            PayloadEncoding::Json => "json",
+            PayloadEncoding::MessagePack => "msgpack",
+        }
+    }
+
+    pub fn parse(name: &str) -> Option<Self> {
+        match name.trim() {
+            "json" => Some(PayloadEncoding::Json),
+            "msgpack" => Some(PayloadEncoding::MessagePack),
+            _ => None,
+        }
+    }
 }
 
 /// Uncompressed batch for internal use
@@ -116,6 +169,13 @@ pub struct IngestResponse {
     pub status: IngestStatus,
     pub error_message: Option<String>,
     pub received_at: i64,
+    /// Newer compression dictionary offered to the agent
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub dictionary: Option<CompressionDictionary>,
+    /// Dictionary the batch named but the collector does not have. The
+    /// batch can be sent again without it.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub unknown_dictionary: Option<u32>,
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
@@ -127,6 +187,26 @@ pub enum IngestStatus {
     Rejected,
 }
 
+impl TrafficEvent {
+    /// Bytes scaled back up by the sampling rate
+    pub fn estimated_bytes(&self) -> f64 {
+        self.bytes as f64 / self.effective_sample_rate()
+    }
+
+    /// Packets scaled back up by the sampling rate
+    pub fn estimated_packets(&self) -> f64 {
+        self.packets as f64 / self.effective_sample_rate()
+    }
+
+    fn effective_sample_rate(&self) -> f64 {
+        if self.sample_rate > 0.0 && self.sample_rate <= 1.0 {
+            self.sample_rate
+        } else {
+            1.0
+        }
+    }
+}
+
 impl Event {
     pub fn timestamp(&self) -> i64 {
         match self {
@@ -143,4 +223,28 @@ impl Event {
             Event::Traffic(_) => "traffic",
         }
     }
+
+    /// Approximate serialized size in bytes, used for memory accounting
+    pub fn estimated_size(&self) -> usize {
+        // Field names, numbers and JSON punctuation
+        const FIXED_OVERHEAD: usize = 96;
+
+        fn map_size(map: &HashMap<String, String>) -> usize {
+            map.iter().map(|(k, v)| k.len() + v.len() + 6).sum()
+        }
+
+        FIXED_OVERHEAD
+            + match self {
+                Event::Log(e) => {
+                    e.source.len()
+                        + e.message.len()
+                        + map_size(&e.fields)
+                        + e.tags.iter().map(|t| t.len() + 3).sum::<usize>()
+                }
+                Event::Metric(e) => {
+                    e.name.len() + map_size(&e.tags) + e.unit.as_ref().map_or(0, String::len)
+                }
+                Event::Traffic(e) => e.src_ip.len() + e.dst_ip.len() + map_size(&e.metadata),
+            }
+    }
 }
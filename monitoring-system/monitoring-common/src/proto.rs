@@ -5,3 +5,220 @@
 pub mod monitoring {
     include!(concat!(env!("OUT_DIR"), "/monitoring.rs"));
 }
+
+#[cfg(feature = "grpc")]
+mod convert {
+    use super::monitoring as pb;
+    use crate::error::{MonitoringError, Result};
+    use crate::models::{
+        Batch, CompressionDictionary, CompressionType, IngestResponse, IngestStatus, PayloadEncoding,
+    };
+
+    impl From<Batch> for pb::Batch {
+        fn from(batch: Batch) -> Self {
+            Self {
+                batch_id: batch.batch_id,
+                agent_id: batch.agent_id,
+                hostname: batch.hostname,
+                timestamp: batch.timestamp,
+                event_count: batch.event_count as u32,
+                compression: pb::CompressionType::from(batch.compression) as i32,
+                compressed_data: batch.compressed_data,
+                checksum: batch.checksum,
+                encoding: pb::PayloadEncoding::from(batch.encoding) as i32,
+                dictionary_id: batch.dictionary_id,
+            }
+        }
+    }
+
+    impl TryFrom<pb::Batch> for Batch {
+        type Error = MonitoringError;
+
+        fn try_from(batch: pb::Batch) -> Result<Self> {
+            Ok(Self {
+                batch_id: batch.batch_id,
+                agent_id: batch.agent_id,
+                hostname: batch.hostname,
+                timestamp: batch.timestamp,
+                event_count: batch.event_count as usize,
+                compression: enum_value::<pb::CompressionType>(batch.compression, "compression")?.into(),
+                encoding: enum_value::<pb::PayloadEncoding>(batch.encoding, "encoding")?.into(),
+                dictionary_id: batch.dictionary_id,
+                compressed_data: batch.compressed_data,
+                checksum: batch.checksum,
+            })
+        }
+    }
+
+    impl From<IngestResponse> for pb::IngestResponse {
+        fn from(response: IngestResponse) -> Self {
+            Self {
+                batch_id: response.batch_id,
+                status: pb::IngestStatus::from(response.status) as i32,
+                error_message: response.error_message,
+                received_at: response.received_at,
+                dictionary: response.dictionary.map(Into::into),
+                unknown_dictionary: response.unknown_dictionary,
+            }
+        }
+    }
+
+    impl TryFrom<pb::IngestResponse> for IngestResponse {
+        type Error = MonitoringError;
+
+        fn try_from(response: pb::IngestResponse) -> Result<Self> {
+            Ok(Self {
+                batch_id: response.batch_id,
+                status: enum_value::<pb::IngestStatus>(response.status, "status")?.into(),
+                error_message: response.error_message,
+                received_at: response.received_at,
+                dictionary: response.dictionary.map(Into::into),
+                unknown_dictionary: response.unknown_dictionary,
+            })
+        }
+    }
+
+    impl From<CompressionDictionary> for pb::CompressionDictionary {
+        fn from(dictionary: CompressionDictionary) -> Self {
+            Self {
+                id: dictionary.id,
+                data: dictionary.data,
+            }
+        }
+    }
+
+    impl From<pb::CompressionDictionary> for CompressionDictionary {
+        fn from(dictionary: pb::CompressionDictionary) -> Self {
+            Self {
+                id: dictionary.id,
+                data: dictionary.data,
+            }
+        }
+    }
+
+    impl From<CompressionType> for pb::CompressionType {
+        fn from(compression: CompressionType) -> Self {
+            match compression {
+                CompressionType::None => Self::None,
+                CompressionType::Snappy => Self::Snappy,
+                CompressionType::Lz4 => Self::Lz4,
+                CompressionType::Gzip => Self::Gzip,
+                CompressionType::Zstd => Self::Zstd,
+            }
+        }
+    }
+
+    impl From<pb::CompressionType> for CompressionType {
+        fn from(compression: pb::CompressionType) -> Self {
+            match compression {
+                pb::CompressionType::None => Self::None,
+                pb::CompressionType::Snappy => Self::Snappy,
+                pb::CompressionType::Lz4 => Self::Lz4,
+                pb::CompressionType::Gzip => Self::Gzip,
+                pb::CompressionType::Zstd => Self::Zstd,
+            }
+        }
+    }
+
+    impl From<PayloadEncoding> for pb::PayloadEncoding {
+        fn from(encoding: PayloadEncoding) -> Self {
+            match encoding {
+                PayloadEncoding::Json => Self::Json,
+                PayloadEncoding::MessagePack => Self::Msgpack,
+            }
+        }
+    }
+
+    impl From<pb::PayloadEncoding> for PayloadEncoding {
+        fn from(encoding: pb::PayloadEncoding) -> Self {
+            match encoding {
+                pb::PayloadEncoding::Json => Self::Json,
+                pb::PayloadEncoding::Msgpack => Self::MessagePack,
+            }
+        }
+    }
+
+    impl From<IngestStatus> for pb::IngestStatus {
+        fn from(status: IngestStatus) -> Self {
+            match status {
+                IngestStatus::Success => Self::Success,
+                IngestStatus::PartialSuccess => Self::PartialSuccess,
+                IngestStatus::Failed => Self::Failed,
+                IngestStatus::Rejected => Self::Rejected,
+            }
+        }
+    }
+
+    impl From<pb::IngestStatus> for IngestStatus {
+        fn from(status: pb::IngestStatus) -> Self {
+            match status {
+                pb::IngestStatus::Success => Self::Success,
+                pb::IngestStatus::PartialSuccess => Self::PartialSuccess,
+                pb::IngestStatus::Failed => Self::Failed,
+                pb::IngestStatus::Rejected => Self::Rejected,
+            }
+        }
+    }
+
+    /// Proto enums arrive as plain integers; values added by newer peers are
+    /// an error rather than silently mapped to the default
+    fn enum_value<T: TryFrom<i32>>(value: i32, field: &str) -> Result<T> {
+        T::try_from(value)
+            .map_err(|_| MonitoringError::Encoding(format!("unknown {} value {}", field, value)))
+    }
+
+    #[cfg(test)]
+    mod tests {
+        use super::*;
+
+        #[test]
+        fn test_batch_round_trip() {
+            let batch = Batch {
+                batch_id: "batch-1".to_string(),
+                agent_id: "agent".to_string(),
+                hostname: "host".to_string(),
+                timestamp: 1_700_000_000_000,
+                event_count: 3,
+                compression: CompressionType::Zstd,
+                encoding: PayloadEncoding::MessagePack,
+                dictionary_id: Some(7),
+                compressed_data: vec![1, 2, 3],
+                checksum: "abc".to_string(),
+            };
+
+            let proto = pb::Batch::from(batch.clone());
+            assert_eq!(proto.compression, pb::CompressionType::Zstd as i32);
+            let decoded = Batch::try_from(proto).unwrap();
+            assert_eq!(decoded.batch_id, batch.batch_id);
+            assert_eq!(decoded.compression, batch.compression);
+            assert_eq!(decoded.encoding, batch.encoding);
+            assert_eq!(decoded.dictionary_id, batch.dictionary_id);
+            assert_eq!(decoded.compressed_data, batch.compressed_data);
+
+            let mut unknown = pb::Batch::from(batch);
+            unknown.compression = 42;
+            assert!(Batch::try_from(unknown).is_err());
+        }
+
+        #[test]
+        fn test_response_round_trip() {
+            let response = IngestResponse {
+                batch_id: "batch-1".to_string(),
+                status: IngestStatus::Failed,
+                error_message: Some("unknown dictionary".to_string()),
+                received_at: 1,
+                dictionary: Some(CompressionDictionary {
+                    id: 2,
+                    data: vec![0xab],
+                }),
+                unknown_dictionary: Some(1),
+            };
+
+            let decoded = IngestResponse::try_from(pb::IngestResponse::from(response.clone())).unwrap();
+            assert_eq!(decoded.status, response.status);
+            assert_eq!(decoded.error_message, response.error_message);
+            assert_eq!(decoded.dictionary, response.dictionary);
+            assert_eq!(decoded.unknown_dictionary, response.unknown_dictionary);
+        }
+    }
+}
@@ -118,6 +118,7 @@ pub fn generate_traffic_events(count: usize) -> Vec<Event> {
             bytes: ((i % 1000) + 100) as u64 * 1024,
             packets: ((i % 50) + 1) as u64,
             metadata: HashMap::new(),
+            sample_rate: 1.0,
         });
         events.push(event);
     }
@@ -172,6 +173,7 @@ mod tests {
                     assert!(!traffic.src_ip.is_empty());
                     assert!(!traffic.dst_ip.is_empty());
                     assert!(traffic.bytes > 0);
+                    assert_eq!(traffic.estimated_bytes(), traffic.bytes as f64);
                 }
                 _ => panic!("Expected traffic event"),
             }